@@ -0,0 +1,51 @@
+// how many chunk boundaries each chunker keeps when a file's content shifts by a byte, and how
+// fast it chunks. run with cargo bench -p builder.
+use std::time::Instant;
+
+use fastrand::Rng;
+
+use builder::{boundary_stability, chunk_boundaries};
+use format::{ChunkingAlgorithm, ChunkingConfig};
+
+const DATA_SIZE: usize = 64 * 1024 * 1024;
+
+fn main() {
+    let rng = Rng::with_seed(42);
+    let data = (0..DATA_SIZE).map(|_| rng.u8(..)).collect::<Vec<_>>();
+
+    // scaled down from the defaults, so a sample this size still has plenty of chunks
+    let chunkers = [
+        (
+            "fastcdc",
+            ChunkingConfig {
+                min: 64 * 1024,
+                avg: 256 * 1024,
+                max: 1024 * 1024,
+                algo: ChunkingAlgorithm::FastCDC,
+            },
+        ),
+        (
+            "fixed",
+            ChunkingConfig {
+                min: 0,
+                avg: 256 * 1024,
+                max: 0,
+                algo: ChunkingAlgorithm::Fixed,
+            },
+        ),
+    ];
+    for (name, config) in &chunkers {
+        let start = Instant::now();
+        chunk_boundaries(config, &data).unwrap();
+        let speed = DATA_SIZE as f64 / (1024.0 * 1024.0) / start.elapsed().as_secs_f64();
+        let stability = boundary_stability(config, &data).unwrap();
+        println!(
+            "{}: {:.0} MB/s, {} boundaries, {} kept after an insertion, {} after a deletion",
+            name,
+            speed,
+            stability.boundaries,
+            stability.kept_after_insert,
+            stability.kept_after_delete
+        );
+    }
+}
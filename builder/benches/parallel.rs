@@ -0,0 +1,95 @@
+// how build throughput scales with the threads chunks are hashed and compressed on: the same
+// synthetic tree is built with RAYON_NUM_THREADS set to 1, 2 and the number of cores, each build in
+// a process of its own since rayon only looks at it once. set PUZZLEFS_BENCH_SIZE (in MB) for a
+// smaller tree than the default 4 GB. run with cargo bench -p builder --bench parallel.
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::process::Command;
+use std::time::Instant;
+
+use fastrand::Rng;
+use tempfile::tempdir;
+
+use builder::{build_initial_rootfs_with_options, BuildOptions, ChunkCompression};
+use format::{ChunkingAlgorithm, ChunkingConfig};
+use oci::Image;
+
+const SIZE_MB: usize = 4096;
+const FILE_MB: usize = 8;
+const PER_DIR: usize = 16;
+
+// what a child process builds
+const ROOTFS_VAR: &str = "PUZZLEFS_BENCH_ROOTFS";
+
+fn make_tree(rootfs: &Path, size_mb: usize) {
+    let rng = Rng::with_seed(42);
+    let mut buf = vec![0_u8; 1024 * 1024];
+    for i in 0..(size_mb + FILE_MB - 1) / FILE_MB {
+        let dir = rootfs.join(format!("dir-{}", i / PER_DIR));
+        fs::create_dir_all(&dir).unwrap();
+        let mut f = fs::File::create(dir.join(format!("file-{}", i))).unwrap();
+        for _ in 0..FILE_MB {
+            buf.iter_mut().for_each(|b| *b = rng.u8(..));
+            f.write_all(&buf).unwrap();
+        }
+    }
+}
+
+fn build(rootfs: &Path) {
+    let dir = tempdir().unwrap();
+    let image = Image::new(dir.path()).unwrap();
+    // scaled down from the defaults so there are plenty of chunks to spread over the threads, and
+    // compressed, since that's where most of the time goes
+    let options = BuildOptions {
+        chunking: ChunkingConfig {
+            min: 256 * 1024,
+            avg: 1024 * 1024,
+            max: 4 * 1024 * 1024,
+            algo: ChunkingAlgorithm::FastCDC,
+        },
+        compression: ChunkCompression::Zstd { level: 3 },
+        ..BuildOptions::default()
+    };
+    let bytes = fs::read_dir(rootfs)
+        .unwrap()
+        .flat_map(|d| fs::read_dir(d.unwrap().path()).unwrap())
+        .map(|f| f.unwrap().metadata().unwrap().len())
+        .sum::<u64>();
+    let start = Instant::now();
+    build_initial_rootfs_with_options(rootfs, &image, &options).unwrap();
+    let elapsed = start.elapsed();
+    println!(
+        "{} threads: {:.0} MB/s ({:?})",
+        rayon::current_num_threads(),
+        bytes as f64 / (1024.0 * 1024.0) / elapsed.as_secs_f64(),
+        elapsed
+    );
+}
+
+fn main() {
+    if let Ok(rootfs) = env::var(ROOTFS_VAR) {
+        build(Path::new(&rootfs));
+        return;
+    }
+    let size_mb = env::var("PUZZLEFS_BENCH_SIZE")
+        .map(|n| n.parse().unwrap())
+        .unwrap_or(SIZE_MB);
+    let dir = tempdir().unwrap();
+    let rootfs = dir.path().join("rootfs");
+    make_tree(&rootfs, size_mb);
+
+    let cores = rayon::current_num_threads();
+    let mut threads = vec![1, 2, cores];
+    threads.sort_unstable();
+    threads.dedup();
+    for n in threads {
+        let status = Command::new(env::current_exe().unwrap())
+            .env(ROOTFS_VAR, &rootfs)
+            .env("RAYON_NUM_THREADS", n.to_string())
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+}
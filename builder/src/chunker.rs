@@ -0,0 +1,134 @@
+// The chunking algorithm is something we want to be able to experiment with, so the builder only
+// talks to chunkers through this trait. Everything written to a chunker comes back out as a
+// sequence of chunks which exactly cover the input, in order.
+use std::io::{self, Write};
+
+use format::{ChunkingAlgorithm, ChunkingConfig, Result};
+
+use crate::fastcdc_fs::{self, FastCDCWrapper};
+use crate::fixed_size::{self, FixedSizeChunker};
+
+#[derive(Clone)]
+pub struct ChunkWithData {
+    pub offset: usize,
+    pub length: usize,
+    pub data: Box<[u8]>,
+}
+
+pub trait Chunker: io::Write {
+    /// Moves all chunks that have been generated so far into `pending`.
+    fn get_pending_chunks(&mut self, pending: &mut Vec<ChunkWithData>);
+
+    /// Signals the end of input, so that any remaining data is rendered as a final chunk.
+    fn finish(&mut self);
+}
+
+pub fn new_chunker(config: &ChunkingConfig) -> Result<Box<dyn Chunker>> {
+    let (min, avg, max) = (
+        config.min as usize,
+        config.avg as usize,
+        config.max as usize,
+    );
+    match config.algo {
+        ChunkingAlgorithm::FastCDC => {
+            fastcdc_fs::check_sizes(min, avg, max)?;
+            Ok(Box::new(FastCDCWrapper::new_with_sizes(min, avg, max)))
+        }
+        ChunkingAlgorithm::Fixed => {
+            fixed_size::check_size(avg)?;
+            Ok(Box::new(FixedSizeChunker::new(avg)))
+        }
+    }
+}
+
+/// Where a chunker cuts `data`: the end of every chunk but the last.
+pub fn chunk_boundaries(config: &ChunkingConfig, data: &[u8]) -> Result<Vec<usize>> {
+    let mut chunker = new_chunker(config)?;
+    chunker.write_all(data)?;
+    chunker.finish();
+    let mut chunks = Vec::new();
+    chunker.get_pending_chunks(&mut chunks);
+    chunks.pop();
+    Ok(chunks.iter().map(|c| c.offset + c.length).collect())
+}
+
+/// How many of a file's chunk boundaries are still there after an edit moves everything after it
+/// along. Every boundary that moves means a chunk that has to be stored (and fetched) again.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BoundaryStability {
+    pub boundaries: usize,
+    /// Still cut in the same place once a byte is inserted at the front.
+    pub kept_after_insert: usize,
+    /// Still cut in the same place once the first byte is deleted.
+    pub kept_after_delete: usize,
+}
+
+/// Measures how well a chunker copes with content shifting around in `data`.
+pub fn boundary_stability(config: &ChunkingConfig, data: &[u8]) -> Result<BoundaryStability> {
+    let boundaries = chunk_boundaries(config, data)?;
+    let kept = |edited: &[u8], shift: isize| -> Result<usize> {
+        let edited = chunk_boundaries(config, edited)?;
+        Ok(boundaries
+            .iter()
+            .filter(|&&b| {
+                edited
+                    .binary_search(&((b as isize + shift) as usize))
+                    .is_ok()
+            })
+            .count())
+    };
+    let mut inserted = Vec::with_capacity(data.len() + 1);
+    inserted.push(0);
+    inserted.extend_from_slice(data);
+    Ok(BoundaryStability {
+        boundaries: boundaries.len(),
+        kept_after_insert: kept(&inserted, 1)?,
+        kept_after_delete: kept(&data[1..], -1)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use fastrand::Rng;
+
+    use super::*;
+
+    fn random_data(len: usize) -> Vec<u8> {
+        let rng = Rng::with_seed(42);
+        (0..len).map(|_| rng.u8(..)).collect()
+    }
+
+    #[test]
+    fn test_boundary_stability() {
+        let data = random_data(4 * 1024 * 1024);
+        let fastcdc = boundary_stability(
+            &ChunkingConfig {
+                min: 8192,
+                avg: 16384,
+                max: 32768,
+                algo: ChunkingAlgorithm::FastCDC,
+            },
+            &data,
+        )
+        .unwrap();
+        assert!(fastcdc.boundaries > 100);
+        // the boundaries come from the content, so at most the first chunk cuts somewhere else
+        assert!(fastcdc.kept_after_insert >= fastcdc.boundaries - 1);
+        assert!(fastcdc.kept_after_delete >= fastcdc.boundaries - 1);
+
+        // whereas fixed size chunks all move along
+        let fixed = boundary_stability(
+            &ChunkingConfig {
+                min: 0,
+                avg: 16384,
+                max: 0,
+                algo: ChunkingAlgorithm::Fixed,
+            },
+            &data,
+        )
+        .unwrap();
+        assert_eq!(fixed.boundaries, 255);
+        assert_eq!(fixed.kept_after_insert, 0);
+        assert_eq!(fixed.kept_after_delete, 0);
+    }
+}
@@ -16,67 +16,89 @@
 // memory and hope for the best. There is definitely room for improvement here.
 //
 // The most mature of the rust fastcdc implementations seems to be fastcdc-rs, which we wrap below.
+use std::backtrace::Backtrace;
 use std::cmp::min;
+use std::collections::VecDeque;
 use std::io;
 
 use fastcdc::FastCDC;
 
+use format::{Result, WireFormatError};
+
+use crate::chunker::{ChunkWithData, Chunker};
+
 // 'ubuntu' base image is ~40M, as are other base images. If we have any hope of wanting to share
 // these, we should allow small chunks.
-const MIN_CHUNK_SIZE: usize = 10 * 1024 * 1024;
-const AVG_CHUNK_SIZE: usize = 40 * 1024 * 1024;
-const MAX_CHUNK_SIZE: usize = 256 * 1024 * 1024;
-
-#[derive(Clone)]
-pub struct ChunkWithData {
-    pub offset: usize,
-    pub length: usize,
-    pub data: Box<[u8]>,
+pub(crate) const MIN_CHUNK_SIZE: usize = 10 * 1024 * 1024;
+pub(crate) const AVG_CHUNK_SIZE: usize = 40 * 1024 * 1024;
+pub(crate) const MAX_CHUNK_SIZE: usize = 256 * 1024 * 1024;
+
+// fastcdc-rs asserts its parameters are within these bounds, so we check them up front to give a
+// reasonable error instead of a panic.
+pub(crate) fn check_sizes(min: usize, avg: usize, max: usize) -> Result<()> {
+    let bad = |msg: String| {
+        Err(WireFormatError::InvalidChunkingParams(
+            msg,
+            Backtrace::capture(),
+        ))
+    };
+
+    if min == 0 {
+        return bad("min chunk size must be greater than zero".to_string());
+    }
+    if min > avg || avg > max {
+        return bad(format!(
+            "need min <= avg <= max, got {} {} {}",
+            min, avg, max
+        ));
+    }
+    for (name, val, low, high) in [
+        ("min", min, fastcdc::MINIMUM_MIN, fastcdc::MINIMUM_MAX),
+        ("avg", avg, fastcdc::AVERAGE_MIN, fastcdc::AVERAGE_MAX),
+        ("max", max, fastcdc::MAXIMUM_MIN, fastcdc::MAXIMUM_MAX),
+    ] {
+        if val < low || val > high {
+            return bad(format!(
+                "{} chunk size {} must be between {} and {}",
+                name, val, low, high
+            ));
+        }
+    }
+    Ok(())
 }
 
 pub struct FastCDCWrapper {
     min: usize,
     avg: usize,
     max: usize,
-    buf: Vec<u8>,
-    buf_offset: usize,
+    // only the bytes we haven't cut into chunks yet; this never grows beyond max. a VecDeque lets us
+    // drop the rendered prefix without shifting the leftover bytes back to the front.
+    buf: VecDeque<u8>,
     global_offset: usize,
     chunks: Vec<ChunkWithData>,
 }
 
 impl FastCDCWrapper {
-    pub fn new() -> Self {
-        Self::new_with_sizes(MIN_CHUNK_SIZE, AVG_CHUNK_SIZE, MAX_CHUNK_SIZE)
-    }
-
-    // we don't expose this since we don't want people to change the algo params, but we do use
-    // custom sizes in the tests.
-    fn new_with_sizes(min: usize, avg: usize, max: usize) -> Self {
+    // callers should check_sizes() first, since fastcdc-rs will panic on bad sizes.
+    pub fn new_with_sizes(min: usize, avg: usize, max: usize) -> Self {
         FastCDCWrapper {
             min,
             avg,
             max,
-            buf: vec![0_u8; max],
-            buf_offset: 0,
+            buf: VecDeque::new(),
             global_offset: 0,
             chunks: Vec::<ChunkWithData>::new(),
         }
     }
 
-    pub fn get_pending_chunks(&mut self, pending: &mut Vec<ChunkWithData>) {
-        pending.clone_from(&self.chunks);
-        self.chunks.clear();
-    }
-
     fn render_chunks(&mut self, eof: bool) {
-        let chunks = FastCDC::with_eof(
-            &self.buf[0..self.buf_offset],
-            self.min,
-            self.avg,
-            self.max,
-            eof,
-        )
-        .collect::<Vec<_>>();
+        // nothing left over at the end doesn't make an empty chunk
+        if self.buf.is_empty() {
+            return;
+        }
+        // fastcdc-rs wants a contiguous slice; this only moves data around if the ring has wrapped.
+        let buf = self.buf.make_contiguous();
+        let chunks = FastCDC::with_eof(buf, self.min, self.avg, self.max, eof).collect::<Vec<_>>();
         if chunks.is_empty() {
             return;
         }
@@ -84,8 +106,7 @@ impl FastCDCWrapper {
         let mut used = 0;
         for chunk in chunks {
             // fix up the offset to be relative to everything that's been written
-            let mut data = vec![0; chunk.length].into_boxed_slice();
-            data.copy_from_slice(&self.buf[used..used + chunk.length]);
+            let data = buf[used..used + chunk.length].to_vec().into_boxed_slice();
             used += chunk.length;
             self.chunks.push(ChunkWithData {
                 offset: self.global_offset + chunk.offset,
@@ -94,15 +115,17 @@ impl FastCDCWrapper {
             })
         }
         self.global_offset += used;
+        self.buf.drain(..used);
+    }
+}
 
-        let leftover = self.buf_offset - used;
-        let mut bytes = vec![0_u8; leftover].into_boxed_slice();
-        bytes.copy_from_slice(&self.buf[used..used + leftover]);
-        self.buf[0..leftover].copy_from_slice(&bytes);
-        self.buf_offset = leftover;
+impl Chunker for FastCDCWrapper {
+    fn get_pending_chunks(&mut self, pending: &mut Vec<ChunkWithData>) {
+        pending.clone_from(&self.chunks);
+        self.chunks.clear();
     }
 
-    pub fn finish(&mut self) {
+    fn finish(&mut self) {
         self.render_chunks(true)
     }
 }
@@ -111,17 +134,13 @@ impl io::Write for FastCDCWrapper {
     fn write(&mut self, write: &[u8]) -> io::Result<usize> {
         let mut write_offset = 0;
         while write_offset != write.len() {
-            // copy as much of this write as we can
-            let room = min(self.buf.len() - self.buf_offset, write.len() - write_offset);
-            let cur = &write[write_offset..write_offset + room];
-            if self.buf_offset + cur.len() <= self.buf.len() {
-                self.buf[self.buf_offset..self.buf_offset + cur.len()].copy_from_slice(cur);
-                self.buf_offset += cur.len();
-                write_offset += cur.len();
-            }
+            // copy as much of this write as we can without growing past max
+            let room = min(self.max - self.buf.len(), write.len() - write_offset);
+            self.buf.extend(&write[write_offset..write_offset + room]);
+            write_offset += room;
 
-            // is our buffer full? chunk it
-            if self.buf_offset == self.buf.len() {
+            // do we have a full window? chunk it
+            if self.buf.len() == self.max {
                 self.render_chunks(false);
             }
         }
@@ -137,6 +156,7 @@ impl io::Write for FastCDCWrapper {
 #[cfg(test)]
 mod tests {
     use std::fs;
+    use std::io::Write;
 
     use fastcdc::Chunk;
     use fastrand::Rng;
@@ -165,6 +185,24 @@ mod tests {
         assert_eq!(fcdc_results.len(), chunks.len(), "number of chunks");
     }
 
+    #[test]
+    fn test_finish_leftovers() {
+        // whatever is left at the end is a chunk even if it's smaller than min, but nothing at all
+        // isn't one
+        let mut wrapper = FastCDCWrapper::new_with_sizes(8192, 16384, 32768);
+        let mut chunks = Vec::<ChunkWithData>::new();
+        wrapper.finish();
+        wrapper.get_pending_chunks(&mut chunks);
+        assert!(chunks.is_empty());
+
+        io::copy(&mut &b"x"[..], &mut wrapper).unwrap();
+        wrapper.finish();
+        wrapper.get_pending_chunks(&mut chunks);
+        assert_eq!(chunks.len(), 1);
+        assert_eq!((chunks[0].offset, chunks[0].length), (0, 1));
+        assert_eq!(&*chunks[0].data, b"x");
+    }
+
     fn split_buf<T>(mut buf: Vec<T>, chunk_size: usize) -> Vec<Vec<T>> {
         let mut acc = Vec::new();
 
@@ -214,6 +252,107 @@ mod tests {
         multiple_writes_size(100 * 1024 * 1024 * 1024);
     }
 
+    #[test]
+    fn test_buffer_is_bounded() {
+        let min = 8192;
+        let avg = 16384;
+        let max = 32768;
+        let rng = Rng::with_seed(0);
+        let data = (0..10 * max).map(|_| rng.u8(..)).collect::<Vec<_>>();
+        let mut wrapper = FastCDCWrapper::new_with_sizes(min, avg, max);
+
+        let mut total = 0;
+        let mut chunks = Vec::<ChunkWithData>::new();
+        for write in split_buf(data.clone(), 5000) {
+            io::copy(&mut write.as_slice(), &mut wrapper).unwrap();
+            assert!(wrapper.buf.len() <= max);
+            wrapper.get_pending_chunks(&mut chunks);
+            for c in &chunks {
+                assert_eq!(&*c.data, &data[c.offset..c.offset + c.length]);
+                total += c.length;
+            }
+        }
+        wrapper.finish();
+        wrapper.get_pending_chunks(&mut chunks);
+        total += chunks.iter().map(|c| c.length).sum::<usize>();
+        assert_eq!(total, data.len());
+    }
+
+    #[test]
+    fn test_random_write_patterns() {
+        let min = 8192;
+        let avg = 16384;
+        let max = 32768;
+        for seed in 0..200 {
+            let rng = Rng::with_seed(seed);
+            // sometimes data that never cuts early, so chunks come out at max
+            let data = if rng.bool() {
+                (0..rng.usize(0..8 * max))
+                    .map(|_| rng.u8(..))
+                    .collect::<Vec<_>>()
+            } else {
+                vec![0; rng.usize(0..8 * max)]
+            };
+            let mut wrapper = FastCDCWrapper::new_with_sizes(min, avg, max);
+
+            let mut chunks = Vec::<ChunkWithData>::new();
+            let mut pending = Vec::<ChunkWithData>::new();
+            let mut written = 0;
+            while written < data.len() {
+                // empty writes, tiny ones, ones around the chunk sizes and ones spanning several
+                let size = match rng.u8(0..4) {
+                    0 => rng.usize(0..2),
+                    1 => rng.usize(1..min),
+                    2 => rng.usize(min..=max),
+                    _ => rng.usize(max..3 * max),
+                };
+                let end = (written + size).min(data.len());
+                wrapper.write_all(&data[written..end]).unwrap();
+                written = end;
+                if rng.bool() {
+                    wrapper.get_pending_chunks(&mut pending);
+                    chunks.append(&mut pending);
+                }
+            }
+            wrapper.finish();
+            wrapper.get_pending_chunks(&mut pending);
+            chunks.append(&mut pending);
+
+            let mut offset = 0;
+            for c in &chunks {
+                assert_eq!(c.offset, offset, "seed {}", seed);
+                assert_eq!(c.length, c.data.len(), "seed {}", seed);
+                assert!(c.length > 0 && c.length <= max, "seed {}", seed);
+                offset += c.length;
+            }
+            let joined = chunks.iter().flat_map(|c| c.data.iter().copied());
+            assert!(joined.eq(data.iter().copied()), "seed {}", seed);
+
+            // and how the data was written doesn't change where it's cut
+            let expected = FastCDC::new(&data, min, avg, max)
+                .map(|c| (c.offset, c.length))
+                .collect::<Vec<_>>();
+            let got = chunks
+                .iter()
+                .map(|c| (c.offset, c.length))
+                .collect::<Vec<_>>();
+            assert_eq!(got, expected, "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn test_check_sizes() {
+        check_sizes(MIN_CHUNK_SIZE, AVG_CHUNK_SIZE, MAX_CHUNK_SIZE).unwrap();
+        check_sizes(8192, 16384, 32768).unwrap();
+        check_sizes(8192, 8192, 8192).unwrap();
+
+        // zero, out of order, or out of fastcdc's range are all bad
+        check_sizes(0, 16384, 32768).unwrap_err();
+        check_sizes(16384, 8192, 32768).unwrap_err();
+        check_sizes(8192, 32768, 16384).unwrap_err();
+        check_sizes(1, 16384, 32768).unwrap_err();
+    }
+
     #[test]
     fn test_stabilization_rate() {
         const FCDC_MIN: usize = 8192;
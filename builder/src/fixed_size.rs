@@ -0,0 +1,112 @@
+// The simplest possible chunker: every chunk is exactly the same size, except for the last one.
+// This is mostly useful as a baseline for comparing how well content defined chunking shares
+// data, since any insertion or deletion shifts every chunk boundary after it.
+use std::backtrace::Backtrace;
+use std::io;
+
+use format::{Result, WireFormatError};
+
+use crate::chunker::{ChunkWithData, Chunker};
+
+pub(crate) fn check_size(size: usize) -> Result<()> {
+    if size == 0 {
+        return Err(WireFormatError::InvalidChunkingParams(
+            "fixed chunk size must be greater than zero".to_string(),
+            Backtrace::capture(),
+        ));
+    }
+    Ok(())
+}
+
+pub struct FixedSizeChunker {
+    size: usize,
+    buf: Vec<u8>,
+    global_offset: usize,
+    chunks: Vec<ChunkWithData>,
+}
+
+impl FixedSizeChunker {
+    pub fn new(size: usize) -> Self {
+        FixedSizeChunker {
+            size,
+            buf: Vec::new(),
+            global_offset: 0,
+            chunks: Vec::new(),
+        }
+    }
+
+    fn render_chunk(&mut self, length: usize) {
+        let data = self
+            .buf
+            .drain(0..length)
+            .collect::<Vec<u8>>()
+            .into_boxed_slice();
+        self.chunks.push(ChunkWithData {
+            offset: self.global_offset,
+            length,
+            data,
+        });
+        self.global_offset += length;
+    }
+}
+
+impl Chunker for FixedSizeChunker {
+    fn get_pending_chunks(&mut self, pending: &mut Vec<ChunkWithData>) {
+        pending.clone_from(&self.chunks);
+        self.chunks.clear();
+    }
+
+    fn finish(&mut self) {
+        if !self.buf.is_empty() {
+            self.render_chunk(self.buf.len())
+        }
+    }
+}
+
+impl io::Write for FixedSizeChunker {
+    fn write(&mut self, write: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(write);
+        while self.buf.len() >= self.size {
+            self.render_chunk(self.size);
+        }
+        Ok(write.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fixed_size_chunks() {
+        let data = (0..10000_u32).map(|i| i as u8).collect::<Vec<u8>>();
+        let mut chunker = FixedSizeChunker::new(4096);
+        for write in data.chunks(1000) {
+            io::copy(&mut &*write, &mut chunker).unwrap();
+        }
+        chunker.finish();
+
+        let mut chunks = Vec::new();
+        chunker.get_pending_chunks(&mut chunks);
+        let lengths = chunks.iter().map(|c| c.length).collect::<Vec<_>>();
+        assert_eq!(lengths, vec![4096, 4096, 10000 - 2 * 4096]);
+        let offsets = chunks.iter().map(|c| c.offset).collect::<Vec<_>>();
+        assert_eq!(offsets, vec![0, 4096, 2 * 4096]);
+
+        let rebuilt = chunks
+            .iter()
+            .flat_map(|c| c.data.iter().copied())
+            .collect::<Vec<u8>>();
+        assert_eq!(rebuilt, data);
+    }
+
+    #[test]
+    fn test_zero_size_is_invalid() {
+        check_size(0).unwrap_err();
+        check_size(1).unwrap();
+    }
+}
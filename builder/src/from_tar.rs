@@ -0,0 +1,460 @@
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+
+use tar::{Archive, EntryType};
+
+use format::{InodeAdditional, InodeMode, Result, Timestamp, Xattr, PERMISSION_BITS};
+use oci::{Descriptor, Image};
+
+use crate::{BuildOptions, BuildStats, Entry, EntryKind, RootfsBuilder};
+
+// what GNU tar and libarchive call xattrs in pax headers
+pub(crate) const PAX_XATTR_PREFIX: &str = "SCHILY.xattr.";
+
+pub fn build_from_tar<R: io::Read>(tar: R, oci: &Image) -> Result<Descriptor> {
+    build_from_tar_with_options(tar, oci, &BuildOptions::default())
+}
+
+/// Builds an image from the entries of a tar archive, without unpacking it anywhere first. Only
+/// the ownership, permissions, xattrs, symlink targets and device numbers in the headers make it
+/// into the image, just like when building from a directory.
+pub fn build_from_tar_with_options<R: io::Read>(
+    tar: R,
+    oci: &Image,
+    options: &BuildOptions,
+) -> Result<Descriptor> {
+    build_from_tar_with_stats(tar, oci, options).map(|(desc, _)| desc)
+}
+
+/// Like build_from_tar_with_options(), but also says how well the chunks deduplicated.
+pub fn build_from_tar_with_stats<R: io::Read>(
+    tar: R,
+    oci: &Image,
+    options: &BuildOptions,
+) -> Result<(Descriptor, BuildStats)> {
+    let mut builder = RootfsBuilder::new(oci, options)?;
+    // tar archives don't necessarily have entries for the root or for every parent directory, so
+    // make those up as we go; if an entry for one of them shows up later it wins.
+    builder.add(implicit_dir(PathBuf::new()))?;
+
+    let mut archive = Archive::new(tar);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = archive_path(&entry.path()?)?;
+
+        let header = entry.header();
+        let entry_type = header.entry_type();
+        let uid = header.uid()? as u32;
+        let gid = header.gid()? as u32;
+        let permissions = (header.mode()? & PERMISSION_BITS) as u16;
+        // ustar only has whole seconds, pax headers may have better
+        let mut mtime = Timestamp {
+            sec: header.mtime()? as i64,
+            nsec: 0,
+        };
+        let mut atime = mtime;
+        // other entries tend to leave these fields blank, so don't try to parse them
+        let (major, minor) = if entry_type.is_character_special() || entry_type.is_block_special() {
+            (
+                header.device_major()?.unwrap_or(0) as u64,
+                header.device_minor()?.unwrap_or(0) as u64,
+            )
+        } else {
+            (0, 0)
+        };
+        let link_name = entry.link_name()?.map(|l| l.into_owned());
+
+        let mut xattrs = Vec::new();
+        if let Some(extensions) = entry.pax_extensions()? {
+            for extension in extensions {
+                let extension = extension?;
+                let key = extension.key_bytes();
+                if let Some(key) = key.strip_prefix(PAX_XATTR_PREFIX.as_bytes()) {
+                    xattrs.push(Xattr {
+                        key: OsString::from(OsStr::from_bytes(key)),
+                        val: Some(extension.value_bytes().to_vec()),
+                    });
+                } else if key == b"mtime" {
+                    mtime = pax_time(extension.value_bytes(), &path)?;
+                } else if key == b"atime" {
+                    atime = pax_time(extension.value_bytes(), &path)?;
+                }
+            }
+        }
+
+        let mut symlink_target = None;
+        let kind = match entry_type {
+            EntryType::XGlobalHeader => continue,
+            // the tar crate fills in the holes of GNU sparse files when reading them
+            EntryType::Regular | EntryType::Continuous | EntryType::GNUSparse => {
+                EntryKind::File(&mut entry, Vec::new())
+            }
+            EntryType::Directory => EntryKind::Dir,
+            EntryType::Link => {
+                let target = link_name.ok_or_else(|| missing_link_name(&path))?;
+                EntryKind::HardLink(archive_path(&target)?)
+            }
+            EntryType::Symlink => {
+                let target = link_name.ok_or_else(|| missing_link_name(&path))?;
+                symlink_target = Some(target.into_os_string());
+                EntryKind::Other(InodeMode::Lnk)
+            }
+            EntryType::Char => EntryKind::Other(InodeMode::Chr { major, minor }),
+            EntryType::Block => EntryKind::Other(InodeMode::Blk { major, minor }),
+            EntryType::Fifo => EntryKind::Other(InodeMode::Fifo),
+            t => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("unsupported tar entry type {:?} for {}", t, path.display()),
+                )
+                .into())
+            }
+        };
+
+        if let Some(parent) = path.parent() {
+            let mut dir = PathBuf::new();
+            for c in parent.components() {
+                dir.push(c);
+                if !builder.has_dir(&dir) {
+                    builder.add(implicit_dir(dir.clone()))?;
+                }
+            }
+        }
+
+        let additional = if symlink_target.is_none() && xattrs.is_empty() {
+            None
+        } else {
+            Some(InodeAdditional {
+                xattrs,
+                symlink_target,
+                file_digest: None,
+            })
+        };
+        builder.add(Entry {
+            path,
+            uid,
+            gid,
+            mtime,
+            atime,
+            permissions,
+            kind,
+            additional,
+        })?;
+    }
+
+    builder.finish()
+}
+
+fn implicit_dir<'a>(path: PathBuf) -> Entry<'a> {
+    Entry {
+        path,
+        uid: 0,
+        gid: 0,
+        mtime: Timestamp::default(),
+        atime: Timestamp::default(),
+        permissions: 0o755,
+        kind: EntryKind::Dir,
+        additional: None,
+    }
+}
+
+// archive paths may be relative ("foo", "./foo") or absolute ("/foo"); all of them are relative to
+// the root of the image. anything that would escape it is refused.
+fn archive_path(p: &Path) -> io::Result<PathBuf> {
+    let mut path = PathBuf::new();
+    for c in p.components() {
+        match c {
+            Component::Normal(name) => path.push(name),
+            Component::RootDir | Component::CurDir => (),
+            Component::ParentDir | Component::Prefix(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("bad path in tar archive: {}", p.display()),
+                ))
+            }
+        }
+    }
+    Ok(path)
+}
+
+// pax times are decimal seconds since the epoch, possibly negative and with a fractional part
+fn pax_time(value: &[u8], p: &Path) -> io::Result<Timestamp> {
+    let bad = || {
+        io::Error::new(
+            io::ErrorKind::Other,
+            format!("bad pax time for {}", p.display()),
+        )
+    };
+    let value = std::str::from_utf8(value).map_err(|_| bad())?;
+    let (sec, frac) = value.split_once('.').unwrap_or((value, ""));
+    if !frac.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(bad());
+    }
+    let sec = sec.parse::<i64>().map_err(|_| bad())?;
+    // anything past nanoseconds is dropped
+    let digits = &frac[..frac.len().min(9)];
+    let nsec = format!("{:0<9}", digits)
+        .parse::<u32>()
+        .map_err(|_| bad())?;
+    if value.starts_with('-') && nsec > 0 {
+        // -1.25 is a quarter second before -1, i.e. -2 plus 0.75
+        Ok(Timestamp {
+            sec: sec - 1,
+            nsec: 1_000_000_000 - nsec,
+        })
+    } else {
+        Ok(Timestamp { sec, nsec })
+    }
+}
+
+fn missing_link_name(p: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Other,
+        format!("no link name for {}", p.display()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+
+    use tempfile::tempdir;
+
+    use format::{DirList, Rootfs};
+    use tar::{Builder, Header};
+
+    use super::*;
+    use crate::OnConflict;
+
+    fn append(tar: &mut Builder<Vec<u8>>, path: &str, entry_type: EntryType, data: &[u8]) {
+        let mut header = Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_uid(1000);
+        header.set_gid(1000);
+        tar.append_data(&mut header, path, data).unwrap();
+    }
+
+    #[test]
+    fn test_implicit_parents_and_long_names() {
+        let long = format!("{}/file", "x".repeat(200));
+        let mut tar = Builder::new(Vec::new());
+        append(
+            &mut tar,
+            &format!("./a/{}", long),
+            EntryType::Regular,
+            b"data",
+        );
+        append(&mut tar, "a", EntryType::Directory, b"");
+        let tar = tar.into_inner().unwrap();
+
+        let dir = tempdir().unwrap();
+        let image = Image::new(dir.path()).unwrap();
+        let rootfs_desc = build_from_tar(&*tar, &image).unwrap();
+        let rootfs = Rootfs::open(
+            image
+                .open_compressed_blob::<compression::Noop>(&rootfs_desc.digest)
+                .unwrap(),
+        )
+        .unwrap();
+        let metadata_digest = rootfs.metadatas[0].try_into().unwrap();
+        let mut blob = image
+            .open_metadata_blob::<compression::Noop>(&metadata_digest)
+            .unwrap();
+        let inodes = blob.read_inodes().unwrap();
+        // /, a, a/xxx..., a/xxx.../file
+        assert_eq!(inodes.len(), 4);
+
+        // a was made up before its entry showed up, which then filled in its owner and
+        // permissions; xxx... never had one
+        let a = blob.find_inode(2).unwrap().unwrap();
+        assert_eq!(a.uid, 1000);
+        assert_eq!(a.permissions, 0o644);
+        assert_eq!(blob.find_inode(3).unwrap().unwrap().permissions, 0o755);
+        let file = blob.find_inode(4).unwrap().unwrap();
+        assert_eq!(file.uid, 1000);
+        if let InodeMode::Dir { offset } = inodes[2].mode {
+            let dir_list: DirList = blob.read_dir_list(offset).unwrap();
+            assert_eq!(dir_list.entries.len(), 1);
+            assert_eq!(dir_list.entries[0].name, "file");
+        } else {
+            panic!("bad inode mode: {:?}", inodes[2].mode);
+        }
+        match file.mode {
+            InodeMode::Reg { offset } => {
+                let chunks = blob.read_file_chunks(offset).unwrap();
+                assert_eq!(chunks.iter().map(|c| c.len).sum::<u64>(), 4);
+            }
+            _ => panic!("bad inode mode: {:?}", file.mode),
+        }
+    }
+
+    #[test]
+    fn test_whiteouts() {
+        let mut tar = Builder::new(Vec::new());
+        append(&mut tar, "dir", EntryType::Directory, b"");
+        append(&mut tar, "dir/.wh..wh..opq", EntryType::Regular, b"");
+        append(&mut tar, ".wh.foo", EntryType::Regular, b"");
+        let tar = tar.into_inner().unwrap();
+
+        let dir = tempdir().unwrap();
+        let image = Image::new(dir.path()).unwrap();
+        let options = BuildOptions {
+            whiteouts: true,
+            ..BuildOptions::default()
+        };
+        let rootfs_desc = build_from_tar_with_options(&*tar, &image, &options).unwrap();
+        let rootfs = Rootfs::open(
+            image
+                .open_compressed_blob::<compression::Noop>(&rootfs_desc.digest)
+                .unwrap(),
+        )
+        .unwrap();
+        let metadata_digest = rootfs.metadatas[0].try_into().unwrap();
+        let mut blob = image
+            .open_metadata_blob::<compression::Noop>(&metadata_digest)
+            .unwrap();
+        // /, dir, foo; the opaque marker isn't an inode of its own
+        let inodes = blob.read_inodes().unwrap();
+        assert_eq!(inodes.len(), 3);
+
+        let dir_list = |blob: &mut format::MetadataBlob, ino| -> DirList {
+            match blob.find_inode(ino).unwrap().unwrap().mode {
+                InodeMode::Dir { offset } => blob.read_dir_list(offset).unwrap(),
+                mode => panic!("bad inode mode: {:?}", mode),
+            }
+        };
+        let root = dir_list(&mut blob, 1);
+        assert!(root.look_below);
+        let mut names = root.entries.iter().map(|de| &de.name).collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, vec!["dir", "foo"]);
+        let foo = root.entries.iter().find(|de| de.name == "foo").unwrap().ino;
+        assert_eq!(blob.find_inode(foo).unwrap().unwrap().mode, InodeMode::Wht);
+        let opaque = dir_list(&mut blob, 2);
+        assert!(!opaque.look_below);
+        assert!(opaque.entries.is_empty());
+    }
+
+    #[test]
+    fn test_unsupported_entry_type() {
+        let mut tar = Builder::new(Vec::new());
+        append(&mut tar, "volume", EntryType::new(b'V'), b"");
+        let tar = tar.into_inner().unwrap();
+
+        let dir = tempdir().unwrap();
+        let image = Image::new(dir.path()).unwrap();
+        let err = build_from_tar(&*tar, &image).unwrap_err();
+        assert!(
+            err.to_string().contains("unsupported tar entry type"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_pax_time() {
+        let p = Path::new("file");
+        let t = |sec, nsec| Timestamp { sec, nsec };
+        assert_eq!(pax_time(b"1626000000", p).unwrap(), t(1626000000, 0));
+        assert_eq!(
+            pax_time(b"1626000000.5", p).unwrap(),
+            t(1626000000, 500_000_000)
+        );
+        assert_eq!(pax_time(b"1.0000000019", p).unwrap(), t(1, 1));
+        assert_eq!(pax_time(b"-1.25", p).unwrap(), t(-2, 750_000_000));
+        pax_time(b"yesterday", p).unwrap_err();
+        pax_time(b"1.-5", p).unwrap_err();
+    }
+
+    #[test]
+    fn test_paths_outside_the_image() {
+        let mut tar = Builder::new(Vec::new());
+        let mut header = Header::new_gnu();
+        header.set_entry_type(EntryType::Regular);
+        header.set_size(0);
+        // append_data() refuses "..", so sneak it into the header ourselves
+        header.as_gnu_mut().unwrap().name[..9].copy_from_slice(b"../escape");
+        header.set_cksum();
+        tar.append(&header, &[][..]).unwrap();
+        let tar = tar.into_inner().unwrap();
+
+        let dir = tempdir().unwrap();
+        let image = Image::new(dir.path()).unwrap();
+        build_from_tar(&*tar, &image).unwrap_err();
+    }
+
+    #[test]
+    fn test_on_conflict() {
+        let mut tar = Builder::new(Vec::new());
+        append(&mut tar, "a", EntryType::Regular, b"first");
+        append(&mut tar, "dir/file", EntryType::Regular, b"data");
+        let mut header = Header::new_gnu();
+        header.set_entry_type(EntryType::Link);
+        header.set_size(0);
+        header.set_mode(0o644);
+        header.set_uid(1000);
+        header.set_gid(1000);
+        header.set_link_name("a").unwrap();
+        tar.append_data(&mut header, "link", &[][..]).unwrap();
+        // a file where a file was, and a file where a directory was
+        append(&mut tar, "a", EntryType::Regular, b"second");
+        append(&mut tar, "dir", EntryType::Regular, b"");
+        let tar = tar.into_inner().unwrap();
+
+        let dir = tempdir().unwrap();
+        let image = Image::new(dir.path()).unwrap();
+        let err = build_from_tar(&*tar, &image).unwrap_err();
+        assert!(err.to_string().contains("duplicate entry for a"), "{}", err);
+
+        let options = BuildOptions {
+            on_conflict: OnConflict::Overwrite,
+            ..BuildOptions::default()
+        };
+        let rootfs_desc = build_from_tar_with_options(&*tar, &image, &options).unwrap();
+        let rootfs = Rootfs::open(
+            image
+                .open_compressed_blob::<compression::Noop>(&rootfs_desc.digest)
+                .unwrap(),
+        )
+        .unwrap();
+        let metadata_digest = rootfs.metadatas[0].try_into().unwrap();
+        let mut blob = image
+            .open_metadata_blob::<compression::Noop>(&metadata_digest)
+            .unwrap();
+        // /, the new a and dir, and the old a, which link keeps; the old dir and dir/file are gone
+        let inodes = blob.read_inodes().unwrap();
+        assert_eq!(inodes.len(), 4);
+        assert!(blob.find_inode(2).unwrap().is_some());
+        assert!(blob.find_inode(3).unwrap().is_none());
+        let root = blob.find_inode(1).unwrap().unwrap();
+        // nothing's left for a ".." to link back to it
+        assert_eq!(root.nlink, 2);
+        let root = match root.mode {
+            InodeMode::Dir { offset } => blob.read_dir_list(offset).unwrap(),
+            mode => panic!("bad inode mode: {:?}", mode),
+        };
+        let mut names = root.entries.iter().map(|de| &de.name).collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, vec!["a", "dir", "link"]);
+        let a = root.entries.iter().find(|de| de.name == "a").unwrap().ino;
+        match blob.find_inode(a).unwrap().unwrap().mode {
+            InodeMode::Reg { offset } => {
+                let chunks = blob.read_file_chunks(offset).unwrap();
+                assert_eq!(chunks.iter().map(|c| c.len).sum::<u64>(), 6);
+            }
+            mode => panic!("bad inode mode: {:?}", mode),
+        }
+        let link = root
+            .entries
+            .iter()
+            .find(|de| de.name == "link")
+            .unwrap()
+            .ino;
+        assert_eq!(link, 2);
+        assert_eq!(blob.find_inode(link).unwrap().unwrap().nlink, 1);
+    }
+}
@@ -0,0 +1,347 @@
+use std::io;
+
+// a zlib stream (RFC 1950) decoder, for what squashfs calls gzip compression. there's nothing
+// clever about it: squashfs blocks are small and decompressed once each, so simple beats fast.
+
+const MAX_BITS: usize = 15;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+// the order the lengths of the code length code come in
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+fn corrupt(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("corrupt zlib stream: {}", what),
+    )
+}
+
+struct Bits<'a> {
+    data: &'a [u8],
+    pos: usize,
+    buf: u32,
+    count: u32,
+}
+
+impl Bits<'_> {
+    fn take(&mut self, n: u32) -> io::Result<u32> {
+        while self.count < n {
+            let byte = *self
+                .data
+                .get(self.pos)
+                .ok_or_else(|| corrupt("truncated"))?;
+            self.buf |= (byte as u32) << self.count;
+            self.pos += 1;
+            self.count += 8;
+        }
+        let bits = self.buf & ((1 << n) - 1);
+        self.buf >>= n;
+        self.count -= n;
+        Ok(bits)
+    }
+
+    // stored blocks start at a byte boundary
+    fn align(&mut self) {
+        self.buf = 0;
+        self.count = 0;
+    }
+}
+
+// a canonical Huffman code, as the number of codes of each length and the symbols in code order
+struct Huffman {
+    counts: [u16; MAX_BITS + 1],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> io::Result<Huffman> {
+        let mut counts = [0_u16; MAX_BITS + 1];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+        // more codes of some length than there's room for; fewer is fine, e.g. a single distance
+        let mut left = 1_i32;
+        for &count in &counts[1..] {
+            left = (left << 1) - count as i32;
+            if left < 0 {
+                return Err(corrupt("over-subscribed code"));
+            }
+        }
+        let mut offsets = [0_u16; MAX_BITS + 2];
+        for len in 1..=MAX_BITS {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0; offsets[MAX_BITS + 1] as usize];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+        Ok(Huffman { counts, symbols })
+    }
+
+    fn decode(&self, bits: &mut Bits) -> io::Result<u16> {
+        // codes are packed starting with their most significant bit
+        let (mut code, mut first, mut index) = (0_i32, 0_i32, 0_i32);
+        for &count in &self.counts[1..] {
+            code |= bits.take(1)? as i32;
+            let count = count as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(corrupt("bad code"))
+    }
+}
+
+fn fixed_codes() -> io::Result<(Huffman, Huffman)> {
+    let mut lengths = [0_u8; 288];
+    lengths[..144].iter_mut().for_each(|l| *l = 8);
+    lengths[144..256].iter_mut().for_each(|l| *l = 9);
+    lengths[256..280].iter_mut().for_each(|l| *l = 7);
+    lengths[280..].iter_mut().for_each(|l| *l = 8);
+    Ok((Huffman::new(&lengths)?, Huffman::new(&[5; 30])?))
+}
+
+fn dynamic_codes(bits: &mut Bits) -> io::Result<(Huffman, Huffman)> {
+    let literals = bits.take(5)? as usize + 257;
+    let distances = bits.take(5)? as usize + 1;
+    let code_lengths = bits.take(4)? as usize + 4;
+    if literals > 286 || distances > 30 {
+        return Err(corrupt("too many codes"));
+    }
+    let mut lengths = [0_u8; 19];
+    for &i in &CODE_LENGTH_ORDER[..code_lengths] {
+        lengths[i] = bits.take(3)? as u8;
+    }
+    let code_length_code = Huffman::new(&lengths)?;
+
+    let mut lengths = vec![0_u8; literals + distances];
+    let mut i = 0;
+    while i < lengths.len() {
+        let symbol = code_length_code.decode(bits)?;
+        let (len, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 if i > 0 => (lengths[i - 1], 3 + bits.take(2)? as usize),
+            16 => return Err(corrupt("repeat with nothing to repeat")),
+            17 => (0, 3 + bits.take(3)? as usize),
+            _ => (0, 11 + bits.take(7)? as usize),
+        };
+        if i + repeat > lengths.len() {
+            return Err(corrupt("too many code lengths"));
+        }
+        lengths[i..i + repeat].iter_mut().for_each(|l| *l = len);
+        i += repeat;
+    }
+    if lengths[256] == 0 {
+        return Err(corrupt("no end of block code"));
+    }
+    Ok((
+        Huffman::new(&lengths[..literals])?,
+        Huffman::new(&lengths[literals..])?,
+    ))
+}
+
+fn inflate_block(
+    bits: &mut Bits,
+    out: &mut Vec<u8>,
+    limit: usize,
+    literal: &Huffman,
+    distance: &Huffman,
+) -> io::Result<()> {
+    loop {
+        let symbol = literal.decode(bits)? as usize;
+        match symbol {
+            0..=255 => out.push(symbol as u8),
+            256 => return Ok(()),
+            _ => {
+                let symbol = symbol - 257;
+                if symbol >= LENGTH_BASE.len() {
+                    return Err(corrupt("bad length"));
+                }
+                let len =
+                    LENGTH_BASE[symbol] as usize + bits.take(LENGTH_EXTRA[symbol] as u32)? as usize;
+                let symbol = distance.decode(bits)? as usize;
+                if symbol >= DISTANCE_BASE.len() {
+                    return Err(corrupt("bad distance"));
+                }
+                let dist = DISTANCE_BASE[symbol] as usize
+                    + bits.take(DISTANCE_EXTRA[symbol] as u32)? as usize;
+                if dist > out.len() {
+                    return Err(corrupt("distance too far back"));
+                }
+                // the copy may overlap what it's making, so a byte at a time
+                for _ in 0..len {
+                    out.push(out[out.len() - dist]);
+                }
+            }
+        }
+        if out.len() > limit {
+            return Err(corrupt("more data than expected"));
+        }
+    }
+}
+
+pub(crate) fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1_u32, 0_u32);
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+/// Decompresses a whole zlib stream, which is expected to hold no more than `limit` bytes.
+pub(crate) fn zlib_decompress(data: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+    if data.len() < 6 {
+        return Err(corrupt("truncated"));
+    }
+    let (cmf, flg) = (data[0], data[1]);
+    if cmf & 0x0f != 8 || (cmf as u16 * 256 + flg as u16) % 31 != 0 {
+        return Err(corrupt("bad header"));
+    }
+    if flg & 0x20 != 0 {
+        return Err(corrupt("preset dictionaries aren't supported"));
+    }
+
+    let mut bits = Bits {
+        data: &data[2..],
+        pos: 0,
+        buf: 0,
+        count: 0,
+    };
+    let mut out = Vec::new();
+    loop {
+        let last = bits.take(1)? == 1;
+        match bits.take(2)? {
+            0 => {
+                bits.align();
+                let header = bits
+                    .data
+                    .get(bits.pos..bits.pos + 4)
+                    .ok_or_else(|| corrupt("truncated"))?;
+                let len = u16::from_le_bytes([header[0], header[1]]) as usize;
+                if len != !u16::from_le_bytes([header[2], header[3]]) as usize {
+                    return Err(corrupt("bad stored block length"));
+                }
+                let start = bits.pos + 4;
+                let stored = bits
+                    .data
+                    .get(start..start + len)
+                    .ok_or_else(|| corrupt("truncated"))?;
+                out.extend_from_slice(stored);
+                bits.pos = start + len;
+            }
+            1 => {
+                let (literal, distance) = fixed_codes()?;
+                inflate_block(&mut bits, &mut out, limit, &literal, &distance)?;
+            }
+            2 => {
+                let (literal, distance) = dynamic_codes(&mut bits)?;
+                inflate_block(&mut bits, &mut out, limit, &literal, &distance)?;
+            }
+            _ => return Err(corrupt("bad block type")),
+        }
+        if out.len() > limit {
+            return Err(corrupt("more data than expected"));
+        }
+        if last {
+            break;
+        }
+    }
+
+    // whatever's left of the last byte is padding, then comes the checksum
+    let checksum = bits
+        .data
+        .get(bits.pos..bits.pos + 4)
+        .ok_or_else(|| corrupt("truncated"))?;
+    if u32::from_be_bytes([checksum[0], checksum[1], checksum[2], checksum[3]]) != adler32(&out) {
+        return Err(corrupt("bad checksum"));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unhex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_zlib_decompress() {
+        let rocks = "meshuggah rocks\n".repeat(4);
+        // made by python's zlib.compress() at levels 0 and 9, which give a stored block and one
+        // with the fixed codes
+        let stored = unhex(concat!(
+            "7801014000bfff6d657368756767616820726f636b730a6d657368756767616820726f636b730a6d6573",
+            "68756767616820726f636b730a6d657368756767616820726f636b730a1bb11815",
+        ));
+        let fixed = unhex("78dacb4d2dce284d4f4fcc5028ca4fce2ee6ca25910f001bb11815");
+        for data in &[&stored, &fixed] {
+            assert_eq!(zlib_decompress(data, 64).unwrap(), rocks.as_bytes());
+            // too much data, or a bad checksum
+            zlib_decompress(data, 63).unwrap_err();
+            let mut bad = data.to_vec();
+            *bad.last_mut().unwrap() ^= 1;
+            zlib_decompress(&bad, 64).unwrap_err();
+            zlib_decompress(&data[..data.len() - 5], 64).unwrap_err();
+        }
+
+        // and one with codes of its own
+        let varied = (0..200)
+            .map(|i| format!("{} meshuggah {} rocks\n", i, "very ".repeat(i % 5)))
+            .collect::<String>();
+        let dynamic = unhex(concat!(
+            "78da8dd84b4a43411484e1b9abc812bafaddcb111105112141c1dd3b1282569d53d39b3b683e34f9abcb",
+            "e5fdf9f6faf9f2f2f87ab95c3f9ede6e0fb87bf4f57cfdfe7d5eff3ebfffb0d10fefdfe8fa8dfbd7c6ff",
+            "034d71a0151d68a7073ade81500891324288845c092613881314144229e45430ad2ab1aaf2ef29b4aab9",
+            "5535ad2ab1aacaaa865635b7aaa65523564d59b5f89f2fb76aa65523564d59b5d0aae556cdb4eac4aa2b",
+            "ab1e5a75e39bcab4eac4aa2bab1e5af5dcaa9b5683580d653542ab915b0df76b9d580d653542ab915b0d",
+            "d36a12aba9ac66683573ab695a4df61ba8ac66683573ab695a2d62b594d50aad566eb54cab45ac960c86",
+            "d06ae556cbb4dac46a2bab1d5aeddc6a9b569b586d65b5e3bacaadb6697588d5515627b43ab9d531ad0e",
+            "b13acaea8456c74851bb45598c1659a325ced162f4687183b4b0222d32494bdca4c588d2e2aaf184976a",
+            "49c43b156f673ced781df249c93b29efb63c58cca3eae513ab193d0f37e8c18a1e32e911373d8ca8875b",
+            "f560590fd9f568c96034d4dcb4076b7bc8b8475cf730f21e6edf83053e64e1234e7c746767bb6aacf221",
+            "331f71e7c3087db8a50f96fa90ad8f38f661d43e867d3dc1d464f0232e7e18c90fb7f9c1a21fb2fa1167",
+            "3f8cee871bfe98f45647aac5ed0f23fee1d63f58fe43f63fe201006301c09d00601b004b5f86c56ac60c",
+            "80bb03c08600e412403c05606c01b863006c0d40ce01ece40ed150731701d82480dc048847018c550077",
+            "1680ed02c861807819e03857afa6da0f1445410d",
+        ));
+        assert_eq!(
+            zlib_decompress(&dynamic, varied.len()).unwrap(),
+            varied.as_bytes()
+        );
+
+        zlib_decompress(b"not zlib at all", 64).unwrap_err();
+    }
+}
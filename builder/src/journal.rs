@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use oci::Digest;
+
+/// The chunk blobs a build has written so far and whether each is compressed, noted down in a file
+/// as they are written. A build that gets interrupted has nothing referring to its blobs yet, so
+/// without this building again couldn't tell how to read them, and would write them all over.
+pub(crate) struct Journal {
+    file: Mutex<fs::File>,
+    // what earlier attempts at the build wrote
+    chunks: HashMap<Digest, bool>,
+}
+
+impl Journal {
+    pub(crate) fn open(path: &Path) -> io::Result<Journal> {
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let mut chunks = HashMap::new();
+        // a build killed halfway through writing a line leaves the end of it off, so anything
+        // that doesn't parse is skipped
+        for line in contents.lines() {
+            let (digest, compressed) = match line.split_once(' ') {
+                Some((digest, "zstd")) => (digest, true),
+                Some((digest, "none")) => (digest, false),
+                _ => continue,
+            };
+            if let Ok(digest) = digest.parse::<Digest>() {
+                chunks.insert(digest, compressed);
+            }
+        }
+        // and what gets written after it mustn't end up on the same line
+        if !contents.is_empty() && !contents.ends_with('\n') {
+            file.write_all(b"\n")?;
+        }
+        Ok(Journal {
+            file: Mutex::new(file),
+            chunks,
+        })
+    }
+
+    /// Whether an earlier attempt wrote the blob `digest` compressed, if it wrote it at all.
+    pub(crate) fn get(&self, digest: &Digest) -> Option<bool> {
+        self.chunks.get(digest).copied()
+    }
+
+    /// Notes down a blob that's been written out.
+    pub(crate) fn record(&self, digest: &Digest, compressed: bool) -> io::Result<()> {
+        let line = format!(
+            "{} {}\n",
+            digest.reference(),
+            if compressed { "zstd" } else { "none" }
+        );
+        // a line per write, so lines from different threads don't get mixed up
+        self.file.lock().unwrap().write_all(line.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use format::{DigestAlgorithm, RawDigest};
+
+    use super::*;
+
+    #[test]
+    fn test_journal() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("journal");
+        let journal = Journal::open(&path).unwrap();
+        let digest = |b: u8| Digest::from([b; 32]);
+        assert_eq!(journal.get(&digest(1)), None);
+        journal.record(&digest(1), true).unwrap();
+        journal.record(&digest(2), false).unwrap();
+        // the same bytes hashed another way are some other blob, and not all digests are 32 bytes
+        let blake3 = Digest::new([1; 32].into(), DigestAlgorithm::Blake3);
+        journal.record(&blake3, false).unwrap();
+        let sha512 = Digest::new(RawDigest::new(&[5; 64]), DigestAlgorithm::Sha512);
+        journal.record(&sha512, true).unwrap();
+        drop(journal);
+
+        // and a line that got cut off
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&digest(3).reference().as_bytes()[..20])
+            .unwrap();
+        let journal = Journal::open(&path).unwrap();
+        assert_eq!(journal.get(&digest(1)), Some(true));
+        assert_eq!(journal.get(&digest(2)), Some(false));
+        assert_eq!(journal.get(&digest(3)), None);
+        assert_eq!(journal.get(&blake3), Some(false));
+        assert_eq!(journal.get(&sha512), Some(true));
+        journal.record(&digest(4), false).unwrap();
+        drop(journal);
+        assert_eq!(Journal::open(&path).unwrap().get(&digest(4)), Some(false));
+    }
+}
@@ -1,38 +1,232 @@
+#![feature(backtrace)]
+
 use std::cmp::min;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::OsStr;
 use std::fs;
 use std::io;
-use std::os::unix::fs::MetadataExt;
-use std::path::Path;
+use std::ops::Range;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use nix::errno::Errno;
+use nix::unistd::{lseek, Whence};
+use rayon::prelude::*;
+use serde::Serialize;
 use walkdir::WalkDir;
 
 use format::{
-    BlobRef, BlobRefKind, DirEnt, DirList, FileChunk, FileChunkList, Ino, Inode, InodeAdditional,
-    Result, Rootfs,
+    chunk_list_digest, BlobRef, BlobRefKind, ChunkingAlgorithm, ChunkingConfig, DirEnt, DirList,
+    FileChunk, FileChunkList, Ino, Inode, InodeAdditional, InodeMode, Result, Rootfs, Timestamp,
+    FORMAT_VERSION, OPAQUE_WHITEOUT, OVERLAY_OPAQUE_XATTR, PERMISSION_BITS, WHITEOUT_PREFIX,
 };
 use oci::media_types;
-use oci::{Descriptor, Image};
+use oci::{Descriptor, Digest, Image};
+
+mod chunker;
+use chunker::{ChunkWithData, Chunker};
 
 mod fastcdc_fs;
-use fastcdc_fs::{ChunkWithData, FastCDCWrapper};
+mod fixed_size;
+
+mod journal;
+use journal::Journal;
+
+mod from_tar;
+mod inflate;
+mod squashfs;
+pub use chunker::{boundary_stability, chunk_boundaries, BoundaryStability};
+pub use from_tar::{build_from_tar, build_from_tar_with_options, build_from_tar_with_stats};
+pub use squashfs::{
+    build_from_squashfs, build_from_squashfs_with_options, build_from_squashfs_with_stats,
+};
+
+/// Knobs for how an image is built; the defaults are what `build_initial_rootfs()` uses.
+pub struct BuildOptions {
+    pub chunking: ChunkingConfig,
+    pub compression: ChunkCompression,
+    // chunks that this image already has are reused instead of being written out again
+    pub base: Option<BaseImage>,
+    // turn the whiteouts and opaque directory markers of an OCI layer or overlayfs upper dir into
+    // whiteout inodes and opaque directories, instead of storing them as they are
+    pub whiteouts: bool,
+    /// Gitignore style patterns for paths to leave out of the image, relative to the root of the
+    /// tree being built. Only directory builds look at these.
+    pub exclude: Vec<String>,
+    /// Store what symlinks point to instead of the symlinks themselves, like `tar -h`. Only
+    /// directory builds look at this.
+    pub dereference: bool,
+    /// Called after every entry is added to the image, and once more when the build is done.
+    pub progress: Option<Box<dyn Fn(&BuildProgress)>>,
+    /// A file to note down the chunk blobs the build writes in as it goes. If the build doesn't
+    /// get to finish, building again with the same journal reuses them instead of writing them
+    /// all over again.
+    pub journal: Option<PathBuf>,
+    /// Store the metadata blob zstd compressed. It compresses well, but has to be decompressed
+    /// whole when the image is opened.
+    pub compress_metadata: bool,
+    /// Store a digest of every file's chunk list, so tampering with one can be caught without
+    /// reading any file content; see `format::chunk_list_digest()`.
+    pub file_digests: bool,
+    /// What to do when something is added at a path the image already has something at, other
+    /// than a directory at a directory (those are merged, the later metadata winning).
+    pub on_conflict: OnConflict,
+    /// Leave out whatever can't be read (e.g. a file without read permission) instead of failing
+    /// the build; `BuildStats::skipped` says what was. Only directory builds look at this.
+    pub skip_errors: bool,
+    /// Called with each thing `skip_errors` leaves out, as it happens.
+    pub on_skip: Option<Box<dyn Fn(&SkippedEntry)>>,
+}
+
+/// How far along a build is.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct BuildProgress {
+    /// Directories, files, etc. added to the image so far.
+    pub entries: u64,
+    /// How much file content has been handed to the chunker.
+    pub bytes_chunked: u64,
+    /// How many new chunk blobs have been written out.
+    pub blobs_written: u64,
+}
+
+/// An existing image (which may live in a different OCI dir) to reuse chunk blobs from.
+pub struct BaseImage {
+    pub oci_dir: PathBuf,
+    pub tag: String,
+}
+
+/// How file content chunks are stored in the image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChunkCompression {
+    None,
+    Zstd { level: u32 },
+}
+
+/// What a build does when a tar archive has several entries for the same path, or merged roots
+/// have different kinds of file at it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OnConflict {
+    /// Fail the build.
+    Error,
+    /// The last one wins, the way extracting the archive would leave things. If that replaces a
+    /// directory, everything that was in it goes too.
+    Overwrite,
+}
+
+impl Default for BuildOptions {
+    fn default() -> Self {
+        BuildOptions {
+            chunking: ChunkingConfig {
+                min: fastcdc_fs::MIN_CHUNK_SIZE as u64,
+                avg: fastcdc_fs::AVG_CHUNK_SIZE as u64,
+                max: fastcdc_fs::MAX_CHUNK_SIZE as u64,
+                algo: ChunkingAlgorithm::FastCDC,
+            },
+            compression: ChunkCompression::None,
+            base: None,
+            whiteouts: false,
+            exclude: Vec::new(),
+            dereference: false,
+            progress: None,
+            journal: None,
+            compress_metadata: false,
+            file_digests: false,
+            on_conflict: OnConflict::Error,
+            skip_errors: false,
+            on_skip: None,
+        }
+    }
+}
+
+// the chunk blobs a base image's files are made of, and whether each one is compressed
+struct BaseChunks {
+    image: Image,
+    chunks: HashMap<Digest, bool>,
+}
+
+impl BaseChunks {
+    fn open(base: &BaseImage) -> Result<Self> {
+        let image = Image::open(&base.oci_dir)?;
+        let rootfs = image.open_rootfs_blob::<compression::Noop>(&base.tag)?;
+        let mut chunks = HashMap::new();
+        for md in rootfs.metadatas.iter() {
+            let mut blob = image.open_rootfs_metadata(md)?;
+            for inode in blob.read_inodes()? {
+                if let InodeMode::Reg { offset } = inode.mode {
+                    for chunk in blob.read_file_chunks(offset)?.iter().filter_map(|c| c.blob) {
+                        if let BlobRefKind::Other { digest, algorithm } = chunk.kind {
+                            chunks.insert(Digest::new(digest, algorithm), chunk.compressed);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(BaseChunks { image, chunks })
+    }
+}
+
+fn exclusions(rootfs: &Path, patterns: &[String]) -> io::Result<Gitignore> {
+    let mut builder = GitignoreBuilder::new(rootfs);
+    for pattern in patterns {
+        builder.add_line(None, pattern).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("bad exclude pattern {}: {}", pattern, e),
+            )
+        })?;
+    }
+    builder
+        .build()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))
+}
 
-fn walker(rootfs: &Path) -> WalkDir {
+fn walker(rootfs: &Path, follow_links: bool) -> WalkDir {
     // breadth first search for sharing, don't cross filesystems just to be safe, order by file
-    // name.
+    // name. when following links, walkdir errors out on links to a directory's own ancestors.
     WalkDir::new(rootfs)
         .contents_first(false)
-        .follow_links(false)
+        .follow_links(follow_links)
         .same_file_system(true)
         .sort_by(|a, b| a.file_name().cmp(b.file_name()))
 }
 
+// what the builder needs to know about something going into the image, whether it comes from a
+// directory on the host or from a tar archive
+struct Entry<'a> {
+    // relative to the root of the image, which itself is ""
+    path: PathBuf,
+    uid: u32,
+    gid: u32,
+    mtime: Timestamp,
+    atime: Timestamp,
+    permissions: u16,
+    kind: EntryKind<'a>,
+    additional: Option<InodeAdditional>,
+}
+
+enum EntryKind<'a> {
+    Dir,
+    // the file's data, and the holes in it if it's sparse. the data doesn't include the holes.
+    File(&'a mut dyn io::Read, Vec<Range<u64>>),
+    // a hard link to a non-directory path that has already been added
+    HardLink(PathBuf),
+    Other(InodeMode),
+}
+
 // a struct to hold a directory's information before it can be rendered into a InodeSpecific::Dir
 // (aka the offset is unknown because we haven't accumulated all the inodes yet)
 struct Dir {
     ino: u64,
     dir_list: DirList,
-    md: fs::Metadata,
+    uid: u32,
+    gid: u32,
+    mtime: Timestamp,
+    atime: Timestamp,
+    permissions: u16,
     additional: Option<InodeAdditional>,
 }
 
@@ -53,34 +247,122 @@ impl Dir {
 struct File {
     ino: u64,
     chunk_list: FileChunkList,
-    md: fs::Metadata,
+    // how much data the file has, not counting holes; that's what its chunks add up to until the
+    // holes are put back in when it's rendered
+    len: u64,
+    holes: Vec<Range<u64>>,
+    uid: u32,
+    gid: u32,
+    mtime: Timestamp,
+    atime: Timestamp,
+    permissions: u16,
     additional: Option<InodeAdditional>,
 }
 
 struct Other {
     ino: u64,
-    md: fs::Metadata,
+    mode: InodeMode,
+    uid: u32,
+    gid: u32,
+    mtime: Timestamp,
+    atime: Timestamp,
+    permissions: u16,
     additional: Option<InodeAdditional>,
 }
 
-fn write_chunks_to_oci(oci: &Image, fcdc: &mut FastCDCWrapper) -> Result<Vec<FileChunk>> {
+/// How well the chunks of a build deduplicated. Every chunk either got written out as a new blob,
+/// or had the same content as another chunk of the image (or one of the base image, or one an
+/// earlier attempt at the build wrote to the journal) and shares its blob.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct BuildStats {
+    pub chunks_written: u64,
+    pub chunks_deduplicated: u64,
+    /// The total size of the blobs that were written, before compression.
+    pub blob_bytes: u64,
+    /// What `BuildOptions::skip_errors` left out of the image.
+    pub skipped: Vec<SkippedEntry>,
+}
+
+/// Something that was left out of an image because it couldn't be read.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SkippedEntry {
+    /// Where it is on the host.
+    pub path: PathBuf,
+    pub error: String,
+}
+
+fn write_chunks_to_oci(
+    oci: &Image,
+    chunker: &mut dyn Chunker,
+    compression: ChunkCompression,
+    base: Option<&BaseChunks>,
+    journal: Option<&Journal>,
+    written: &Mutex<HashSet<Digest>>,
+    stats: &mut BuildStats,
+) -> Result<Vec<FileChunk>> {
     let mut pending_chunks = Vec::<ChunkWithData>::new();
-    fcdc.get_pending_chunks(&mut pending_chunks);
-    pending_chunks
-        .iter_mut()
+    chunker.get_pending_chunks(&mut pending_chunks);
+    // hashing and compressing chunks is the expensive part of a build, so do it in parallel.
+    // collect() keeps the chunks in order.
+    let chunks = pending_chunks
+        .par_iter()
         .map(|c| {
-            let desc = oci.put_blob::<_, compression::Noop, media_types::Chunk>(&*c.data)?;
-            Ok(FileChunk {
-                blob: BlobRef {
-                    kind: BlobRefKind::Other {
-                        digest: desc.digest.underlying(),
-                    },
-                    offset: 0,
-                },
-                len: desc.size,
-            })
+            // digests are of the uncompressed data, so we can tell whether we already have this
+            // chunk before compressing or writing anything
+            let algorithm = oci.digest_algorithm();
+            let digest = algorithm.digest(&c.data);
+            let blob = Digest::new(digest, algorithm);
+            let chunk = |compressed| FileChunk {
+                blob: Some(blob.blob_ref(compressed)),
+                len: c.data.len() as u64,
+            };
+            if let Some(base) = base {
+                if let Some(&compressed) = base.chunks.get(&blob) {
+                    oci.reuse_blob(&base.image, &blob)?;
+                    return Ok((chunk(compressed), false));
+                }
+            }
+            // garbage collection may have gotten to it since
+            if let Some(compressed) = journal.and_then(|journal| journal.get(&blob)) {
+                if oci.store().has_blob(&blob) {
+                    return Ok((chunk(compressed), false));
+                }
+            }
+            // whoever got here first with this content writes the blob, the rest can use it (the
+            // whole build finishes before anything refers to it)
+            if !written.lock().unwrap().insert(blob.clone()) {
+                return Ok((chunk(compression != ChunkCompression::None), false));
+            }
+
+            match compression {
+                ChunkCompression::None => {
+                    oci.put_blob::<_, compression::Noop, media_types::Chunk>(&*c.data)?
+                }
+                ChunkCompression::Zstd { level } => oci
+                    .put_blob_with_level::<_, compression::Zstd, media_types::Chunk>(
+                        &*c.data, level,
+                    )?,
+            };
+            let compressed = compression != ChunkCompression::None;
+            if let Some(journal) = journal {
+                journal.record(&blob, compressed)?;
+            }
+            Ok((chunk(compressed), true))
+        })
+        .collect::<Result<Vec<(FileChunk, bool)>>>()?;
+
+    Ok(chunks
+        .into_iter()
+        .map(|(chunk, was_written)| {
+            if was_written {
+                stats.chunks_written += 1;
+                stats.blob_bytes += chunk.len;
+            } else {
+                stats.chunks_deduplicated += 1;
+            }
+            chunk
         })
-        .collect::<Result<Vec<FileChunk>>>()
+        .collect())
 }
 
 fn take_first_chunk<FileChunk>(v: &mut Vec<FileChunk>) -> io::Result<FileChunk> {
@@ -101,18 +383,16 @@ fn merge_chunks_and_prev_files(
 
     for mut file in prev_files.drain(..) {
         let mut file_used: u64 = Iterator::sum(file.chunk_list.chunks.iter().map(|c| c.len));
-        while file_used < file.md.len() {
+        while file_used < file.len {
             if chunk_used == chunk.len {
                 chunk_used = 0;
                 chunk = take_first_chunk(chunks)?;
             }
 
-            let room = min(file.md.len() - file_used, chunk.len - chunk_used);
-            let blob = BlobRef {
-                offset: chunk_used,
-                kind: chunk.blob.kind,
-            };
-            file.chunk_list.chunks.push(FileChunk { blob, len: room });
+            let room = min(file.len - file_used, chunk.len - chunk_used);
+            file.chunk_list
+                .chunks
+                .push(chunk_slice(&chunk, chunk_used, room));
             chunk_used += room;
             file_used += room;
         }
@@ -123,254 +403,941 @@ fn merge_chunks_and_prev_files(
         take_first_chunk(chunks)
     } else {
         // fix up the first chunk to have the right offset for this file
-        Ok(FileChunk {
-            blob: BlobRef {
-                kind: chunk.blob.kind,
-                offset: chunk_used,
-            },
-            len: chunk.len - chunk_used,
-        })
+        Ok(chunk_slice(&chunk, chunk_used, chunk.len - chunk_used))
+    }
+}
+
+// the `len` bytes of a chunk that start `offset` bytes into it
+fn chunk_slice(chunk: &FileChunk, offset: u64, len: u64) -> FileChunk {
+    FileChunk {
+        blob: chunk.blob.map(|blob| BlobRef {
+            offset: blob.offset + offset,
+            ..blob
+        }),
+        len,
     }
 }
 
+// puts a sparse file's holes back in between the chunks of its data
+fn punch_holes(chunks: Vec<FileChunk>, holes: &[Range<u64>]) -> Vec<FileChunk> {
+    let hole = |h: &Range<u64>| FileChunk {
+        blob: None,
+        len: h.end - h.start,
+    };
+    let mut punched = Vec::with_capacity(chunks.len() + holes.len() * 2);
+    let mut holes = holes.iter().peekable();
+    let mut offset = 0;
+    for chunk in chunks {
+        let mut chunk_used = 0;
+        loop {
+            while let Some(h) = holes.next_if(|h| h.start <= offset) {
+                punched.push(hole(h));
+                offset = h.end;
+            }
+            if chunk_used == chunk.len {
+                break;
+            }
+            // a hole in the middle of a chunk splits it in two
+            let room = holes.peek().map_or(u64::MAX, |h| h.start - offset);
+            let len = min(room, chunk.len - chunk_used);
+            punched.push(chunk_slice(&chunk, chunk_used, len));
+            chunk_used += len;
+            offset += len;
+        }
+    }
+    punched.extend(holes.map(hole));
+    punched
+}
+
+// where the holes in a file of `len` bytes are. filesystems that don't do holes say it's all data.
+fn find_holes(f: &fs::File, len: u64) -> io::Result<Vec<Range<u64>>> {
+    let seek = |offset: u64, whence| lseek(f.as_raw_fd(), offset as i64, whence).map(|o| o as u64);
+    let to_io = |e: nix::Error| io::Error::from(e.as_errno().unwrap_or(Errno::EINVAL));
+    let mut holes = Vec::new();
+    let mut offset = 0;
+    while offset < len {
+        let data = match seek(offset, Whence::SeekData) {
+            Ok(data) => min(data, len),
+            // it's a hole from here to the end
+            Err(nix::Error::Sys(Errno::ENXIO)) => len,
+            Err(e) => return Err(to_io(e)),
+        };
+        if data > offset {
+            holes.push(offset..data);
+        }
+        if data == len {
+            break;
+        }
+        offset = seek(data, Whence::SeekHole).map_err(to_io)?;
+    }
+    Ok(holes)
+}
+
+// reads only the data of a sparse file, skipping over its holes
+struct SparseReader<'a> {
+    file: &'a fs::File,
+    holes: Vec<Range<u64>>,
+    offset: u64,
+    len: u64,
+}
+
+impl io::Read for SparseReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(hole) = self.holes.first() {
+            if hole.start <= self.offset {
+                self.offset = hole.end;
+                self.holes.remove(0);
+            }
+        }
+        let end = self.holes.first().map_or(self.len, |h| h.start);
+        let n = min(buf.len() as u64, end.saturating_sub(self.offset)) as usize;
+        if n == 0 {
+            return Ok(0);
+        }
+        let n = self.file.read_at(&mut buf[..n], self.offset)?;
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+// removes overlayfs' opaque marker from a directory's xattrs, returning whether it was there
+fn take_opaque_xattr(additional: &mut Option<InodeAdditional>) -> bool {
+    let add = match additional {
+        Some(add) => add,
+        None => return false,
+    };
+    let before = add.xattrs.len();
+    add.xattrs
+        .retain(|xa| !(xa.key == OVERLAY_OPAQUE_XATTR && xa.val.as_deref() == Some(b"y")));
+    if add.xattrs.len() == before {
+        return false;
+    }
+    if add.xattrs.is_empty() && add.symlink_target.is_none() {
+        *additional = None;
+    }
+    true
+}
+
 fn inode_encoded_size(num_inodes: usize) -> usize {
     format::cbor_size_of_list_header(num_inodes) + num_inodes * format::INODE_WIRE_SIZE
 }
 
 pub fn build_initial_rootfs(rootfs: &Path, oci: &Image) -> Result<Descriptor> {
-    let mut dirs = HashMap::<u64, Dir>::new();
-    let mut files = Vec::<File>::new();
-    let mut others = Vec::<Other>::new();
-    let mut pfs_inodes = Vec::<Inode>::new();
+    build_initial_rootfs_with_options(rootfs, oci, &BuildOptions::default())
+}
+
+pub fn build_initial_rootfs_with_options(
+    rootfs: &Path,
+    oci: &Image,
+    options: &BuildOptions,
+) -> Result<Descriptor> {
+    build_initial_rootfs_with_stats(rootfs, oci, options).map(|(desc, _)| desc)
+}
+
+/// Like build_initial_rootfs_with_options(), but also says how well the chunks deduplicated.
+pub fn build_initial_rootfs_with_stats(
+    rootfs: &Path,
+    oci: &Image,
+    options: &BuildOptions,
+) -> Result<(Descriptor, BuildStats)> {
+    build_merged_rootfs_with_stats(&[rootfs], oci, options)
+}
+
+/// Builds one image out of several directories stacked on top of each other, the way overlayfs
+/// would stack them: what a later root has at some path shadows whatever the earlier ones have
+/// there, and directories that are in several roots are merged, with the metadata of the last
+/// one. Something that's a different kind of file in different roots is an error, unless
+/// `options.on_conflict` says the later one wins.
+pub fn build_merged_rootfs_with_stats(
+    roots: &[&Path],
+    oci: &Image,
+    options: &BuildOptions,
+) -> Result<(Descriptor, BuildStats)> {
+    let mut builder = RootfsBuilder::new(oci, options)?;
+
+    // host (dev, ino) to the first path we saw it at, for hard link deteciton
+    let mut host_paths = HashMap::<(u64, u64), PathBuf>::new();
+
+    if let [rootfs] = roots {
+        // nothing to merge, so there's no need to walk the whole thing up front
+        for entry in walk_rootfs(rootfs, options)? {
+            match entry {
+                Ok(e) => add_host_entry(&mut builder, &mut host_paths, rootfs, e)?,
+                Err(e) => builder.skip_walk_error(rootfs, e)?,
+            }
+        }
+    } else {
+        for (rootfs, e) in merge_roots(&mut builder, roots)? {
+            add_host_entry(&mut builder, &mut host_paths, rootfs, e)?;
+        }
+    }
+
+    builder.finish()
+}
+
+fn walk_rootfs(
+    rootfs: &Path,
+    options: &BuildOptions,
+) -> io::Result<impl Iterator<Item = walkdir::Result<walkdir::DirEntry>>> {
+    // excluded directories are skipped whole, without reading what's in them
+    let excluded = exclusions(rootfs, &options.exclude)?;
+    Ok(walker(rootfs, options.dereference)
+        .into_iter()
+        .filter_entry(move |e| {
+            e.depth() == 0
+                || !excluded
+                    .matched(e.path(), e.file_type().is_dir())
+                    .is_ignore()
+        }))
+}
+
+fn relative_path(rootfs: &Path, path: &Path) -> io::Result<PathBuf> {
+    path.strip_prefix(rootfs)
+        .map(Path::to_path_buf)
+        .map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!("{} is outside the rootfs", path.display()),
+            )
+        })
+}
+
+fn file_kind(file_type: fs::FileType) -> &'static str {
+    if file_type.is_dir() {
+        "directory"
+    } else if file_type.is_file() {
+        "file"
+    } else if file_type.is_symlink() {
+        "symlink"
+    } else {
+        "special file"
+    }
+}
+
+// what's in each of the roots, with the later ones shadowing the earlier ones, in the order walker()
+// would have found it had it all been in the one directory: paths sort component by component, so
+// parents come before what's in them, and what's in a directory comes in order of name
+fn merge_roots<'r>(
+    builder: &mut RootfsBuilder,
+    roots: &[&'r Path],
+) -> Result<Vec<(&'r Path, walkdir::DirEntry)>> {
+    let options = builder.options;
+    let mut merged = BTreeMap::<PathBuf, (&Path, walkdir::DirEntry)>::new();
+    for rootfs in roots {
+        for entry in walk_rootfs(rootfs, options)? {
+            let e = match entry {
+                Ok(e) => e,
+                Err(e) => {
+                    builder.skip_walk_error(rootfs, e)?;
+                    continue;
+                }
+            };
+            let path = relative_path(rootfs, e.path())?;
+            if let Some((other, shadowed)) = merged.get(&path) {
+                if shadowed.file_type() != e.file_type() {
+                    if options.on_conflict == OnConflict::Overwrite {
+                        // a directory that is shadowed takes what was in it along
+                        if shadowed.file_type().is_dir() {
+                            merged.retain(|p, _| !p.starts_with(&path));
+                        }
+                        merged.insert(path, (*rootfs, e));
+                        continue;
+                    }
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "{} is a {} in {} but a {} in {}",
+                            Path::new("/").join(&path).display(),
+                            file_kind(shadowed.file_type()),
+                            other.display(),
+                            file_kind(e.file_type()),
+                            rootfs.display()
+                        ),
+                    )
+                    .into());
+                }
+            }
+            merged.insert(path, (*rootfs, e));
+        }
+    }
+    Ok(merged.into_values().collect())
+}
+
+fn add_host_entry(
+    builder: &mut RootfsBuilder,
+    host_paths: &mut HashMap<(u64, u64), PathBuf>,
+    rootfs: &Path,
+    e: walkdir::DirEntry,
+) -> Result<()> {
+    let mut path = relative_path(rootfs, e.path())?;
+    let md = match e.metadata() {
+        Ok(md) => md,
+        Err(err) => return builder.skip(rootfs, e.path(), err.into()),
+    };
 
-    // host to puzzlefs inode mapping for hard link deteciton
-    let mut host_to_pfs = HashMap::<u64, Ino>::new();
+    // building a single file rather than a tree puts it at the root of an image of its own, in a
+    // directory owned by whoever owns the file
+    if e.depth() == 0 && !md.is_dir() {
+        builder.add(Entry {
+            path: PathBuf::new(),
+            uid: md.uid(),
+            gid: md.gid(),
+            mtime: Timestamp::mtime(&md),
+            atime: Timestamp::atime(&md),
+            permissions: 0o755,
+            kind: EntryKind::Dir,
+            additional: None,
+        })?;
+        path = PathBuf::from(e.file_name());
+    }
+
+    // is this a hard link? if so, just point it at what we already rendered
+    let host_ino = (md.dev(), md.ino());
+    if !md.is_dir() {
+        if let Some(target) = host_paths.get(&host_ino) {
+            return builder.add(Entry {
+                path,
+                uid: md.uid(),
+                gid: md.gid(),
+                mtime: Timestamp::mtime(&md),
+                atime: Timestamp::atime(&md),
+                permissions: (md.mode() & PERMISSION_BITS) as u16,
+                kind: EntryKind::HardLink(target.clone()),
+                additional: None,
+            });
+        }
+    }
+
+    let (additional, content) = match read_host_entry(builder.options, &e, &md) {
+        Ok(read) => read,
+        Err(err) => return builder.skip(rootfs, e.path(), err),
+    };
+    if !md.is_dir() {
+        host_paths.insert(host_ino, path.clone());
+    }
+    let mut sparse;
+    let kind = if md.is_dir() {
+        EntryKind::Dir
+    } else if let Some((f, holes)) = &content {
+        sparse = SparseReader {
+            file: f,
+            holes: holes.clone(),
+            offset: 0,
+            len: md.len(),
+        };
+        EntryKind::File(&mut sparse, holes.clone())
+    } else {
+        EntryKind::Other(InodeMode::new_other(&md)?)
+    };
+    builder.add(Entry {
+        path,
+        uid: md.uid(),
+        gid: md.gid(),
+        mtime: Timestamp::mtime(&md),
+        // this is the atime from before we read the file below
+        atime: Timestamp::atime(&md),
+        permissions: (md.mode() & PERMISSION_BITS) as u16,
+        kind,
+        additional,
+    })
+}
+
+// a host file opened for reading, and where its holes are
+type HostContent = (fs::File, Vec<Range<u64>>);
+
+// what add_host_entry() reads from the host besides an entry's metadata: its xattrs and, for a
+// regular file, its content. this is what fails when e.g. a file can't be read by whoever is
+// building the image.
+fn read_host_entry(
+    options: &BuildOptions,
+    e: &walkdir::DirEntry,
+    md: &fs::Metadata,
+) -> io::Result<(Option<InodeAdditional>, Option<HostContent>)> {
+    // md is already the target's, but reading the xattrs doesn't follow links
+    let additional = if options.dereference && e.path_is_symlink() {
+        InodeAdditional::new(&fs::canonicalize(e.path())?, md)?
+    } else {
+        InodeAdditional::new(e.path(), md)?
+    };
+    let content = if md.is_file() {
+        let f = fs::File::open(e.path())?;
+        let holes = find_holes(&f, md.len())?;
+        Some((f, holes))
+    } else {
+        None
+    };
+    Ok((additional, content))
+}
+
+// accumulates the inodes of an image as entries are added, and writes file content out to chunks
+// as it goes. entries must be added parents first, starting with the root directory.
+struct RootfsBuilder<'a> {
+    oci: &'a Image,
+    options: &'a BuildOptions,
+    chunker: Box<dyn Chunker>,
+    base: Option<BaseChunks>,
+    journal: Option<Journal>,
+
+    dirs: HashMap<PathBuf, Dir>,
+    files: Vec<File>,
+    others: Vec<Other>,
+    prev_files: Vec<File>,
+
+    // the inode each non-directory path was rendered as, so hard links can find it
+    rendered: HashMap<PathBuf, Ino>,
+
+    // the number of links (i.e. dirents) each puzzlefs inode has in the image
+    nlinks: HashMap<Ino, u32>,
+
+    // the digests of the chunk blobs this build has written
+    written: Mutex<HashSet<Digest>>,
+    stats: BuildStats,
+    progress: BuildProgress,
+
+    cur_ino: Ino,
+}
+
+impl<'a> RootfsBuilder<'a> {
+    fn new(oci: &'a Image, options: &'a BuildOptions) -> Result<Self> {
+        Ok(RootfsBuilder {
+            oci,
+            options,
+            chunker: chunker::new_chunker(&options.chunking)?,
+            base: options.base.as_ref().map(BaseChunks::open).transpose()?,
+            journal: options.journal.as_deref().map(Journal::open).transpose()?,
+            dirs: HashMap::new(),
+            files: Vec::new(),
+            others: Vec::new(),
+            prev_files: Vec::new(),
+            rendered: HashMap::new(),
+            nlinks: HashMap::new(),
+            written: Mutex::new(HashSet::new()),
+            stats: BuildStats::default(),
+            progress: BuildProgress::default(),
+            cur_ino: 1,
+        })
+    }
+
+    fn has_dir(&self, path: &Path) -> bool {
+        self.dirs.contains_key(path)
+    }
+
+    fn add(&mut self, entry: Entry) -> Result<()> {
+        self.add_unreported(entry)?;
+        self.progress.entries += 1;
+        self.report_progress();
+        Ok(())
+    }
 
-    let mut cur_ino: u64 = 1;
+    fn report_progress(&mut self) {
+        if let Some(progress) = &self.options.progress {
+            self.progress.blobs_written = self.stats.chunks_written;
+            progress(&self.progress);
+        }
+    }
 
-    let mut fcdc = FastCDCWrapper::new();
-    let mut prev_files = Vec::<File>::new();
+    fn add_unreported(&mut self, mut entry: Entry) -> Result<()> {
+        if !self.options.whiteouts {
+            return self.add_entry(entry);
+        }
 
-    for entry in walker(rootfs) {
-        let e = entry.map_err(io::Error::from)?;
-        let md = e.metadata().map_err(io::Error::from)?;
+        let name = entry
+            .path
+            .file_name()
+            .map(|n| n.as_bytes().to_vec())
+            .unwrap_or_default();
+        if name == OPAQUE_WHITEOUT.as_bytes() {
+            let parent = entry.path.parent().unwrap_or_else(|| Path::new(""));
+            return self.make_opaque(parent);
+        }
+        if let Some(deleted) = name.strip_prefix(WHITEOUT_PREFIX.as_bytes()) {
+            entry.path.set_file_name(OsStr::from_bytes(deleted));
+            entry.kind = EntryKind::Other(InodeMode::Wht);
+            entry.additional = None;
+            return self.add_entry(entry);
+        }
+        match entry.kind {
+            // overlayfs' own whiteouts
+            EntryKind::Other(InodeMode::Chr { major: 0, minor: 0 }) => {
+                entry.kind = EntryKind::Other(InodeMode::Wht)
+            }
+            EntryKind::Dir if take_opaque_xattr(&mut entry.additional) => {
+                let path = entry.path.clone();
+                self.add_entry(entry)?;
+                return self.make_opaque(&path);
+            }
+            _ => {}
+        }
+        self.add_entry(entry)
+    }
+
+    fn make_opaque(&mut self, path: &Path) -> Result<()> {
+        let dir = self.dirs.get_mut(path).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!("no pfs inode for {}", path.display()),
+            )
+        })?;
+        dir.dir_list.look_below = false;
+        Ok(())
+    }
+
+    fn add_entry(&mut self, entry: Entry) -> Result<()> {
+        if let Some(dir) = self.dirs.get_mut(&entry.path) {
+            // seeing a directory again (e.g. a tar archive having an explicit entry for a
+            // directory we had to make up earlier) just updates its metadata
+            if let EntryKind::Dir = entry.kind {
+                dir.uid = entry.uid;
+                dir.gid = entry.gid;
+                dir.mtime = entry.mtime;
+                dir.atime = entry.atime;
+                dir.permissions = entry.permissions;
+                dir.additional = entry.additional;
+                return Ok(());
+            }
+        }
+        if self.dirs.contains_key(&entry.path) || self.rendered.contains_key(&entry.path) {
+            if self.options.on_conflict == OnConflict::Error {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("duplicate entry for {}", entry.path.display()),
+                )
+                .into());
+            }
+            self.unlink(&entry.path)?;
+        }
+
+        // is this a hard link? if so, just use the existing ino we have rendered. otherewise, use
+        // a new one
+        let link_ino = match &entry.kind {
+            EntryKind::HardLink(target) => Some(*self.rendered.get(target).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::Other,
+                    format!(
+                        "hard link {} to unknown path {}",
+                        entry.path.display(),
+                        target.display()
+                    ),
+                )
+            })?),
+            _ => None,
+        };
+        let the_ino = link_ino.unwrap_or(self.cur_ino);
+        let is_dir = matches!(entry.kind, EntryKind::Dir);
 
         // now that we know the ino of this thing, let's put it in the parent directory (assuming
         // this is not "/" for our image, aka inode #1)
-        if cur_ino != 1 {
-            // is this a hard link? if so, just use the existing ino we have rendered. otherewise,
-            // use a new one
-            let the_ino = host_to_pfs.get(&md.ino()).copied().unwrap_or(cur_ino);
-            let parent_path = e.path().parent().ok_or_else(|| {
+        if self.cur_ino != 1 {
+            let parent_path = entry.path.parent().ok_or_else(|| {
                 io::Error::new(
                     io::ErrorKind::Other,
-                    format!("no parent for {}", e.path().display()),
+                    format!("no parent for {}", entry.path.display()),
                 )
             })?;
-            let parent = dirs
-                .get_mut(&fs::symlink_metadata(parent_path)?.ino())
-                .ok_or_else(|| {
-                    io::Error::new(
-                        io::ErrorKind::Other,
-                        format!("no pfs inode for {}", e.path().display()),
-                    )
-                })?;
-            parent.add_entry(e.path(), the_ino)?;
+            let parent = self.dirs.get_mut(parent_path).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("no pfs inode for {}", parent_path.display()),
+                )
+            })?;
+            parent.add_entry(&entry.path, the_ino)?;
+
+            if is_dir {
+                // a subdirectory's ".." is a link to its parent
+                *self.nlinks.entry(parent.ino).or_insert(0) += 1;
+            } else {
+                *self.nlinks.entry(the_ino).or_insert(0) += 1;
+            }
 
             // if it was a hard link, we don't need to actually render it again
-            if host_to_pfs.get(&md.ino()).is_some() {
-                continue;
+            if link_ino.is_some() {
+                self.rendered.insert(entry.path, the_ino);
+                return Ok(());
             }
+        } else if !is_dir {
+            return Err(
+                io::Error::new(io::ErrorKind::Other, "the root must be a directory").into(),
+            );
         }
 
-        host_to_pfs.insert(md.ino(), cur_ino);
-
         // render as much of the inode as we can
-        let additional = InodeAdditional::new(e.path(), &md)?;
-        if md.is_dir() {
-            dirs.insert(
-                md.ino(),
-                Dir {
-                    ino: cur_ino,
-                    md,
-                    dir_list: DirList {
-                        entries: Vec::<DirEnt>::new(),
-                        look_below: false,
+        match entry.kind {
+            EntryKind::Dir => {
+                // directories are linked by their entry in the parent and their own "."
+                *self.nlinks.entry(self.cur_ino).or_insert(0) += 2;
+                self.dirs.insert(
+                    entry.path,
+                    Dir {
+                        ino: self.cur_ino,
+                        dir_list: DirList {
+                            entries: Vec::<DirEnt>::new(),
+                            look_below: true,
+                        },
+                        uid: entry.uid,
+                        gid: entry.gid,
+                        mtime: entry.mtime,
+                        atime: entry.atime,
+                        permissions: entry.permissions,
+                        additional: entry.additional,
                     },
-                    additional,
-                },
-            );
-        } else if md.is_file() {
-            let mut f = fs::File::open(e.path())?;
-            io::copy(&mut f, &mut fcdc)?;
-
-            let mut written_chunks = write_chunks_to_oci(oci, &mut fcdc)?;
-            let mut file = File {
-                ino: cur_ino,
-                md,
-                chunk_list: FileChunkList {
-                    chunks: Vec::<FileChunk>::new(),
-                },
-                additional,
-            };
+                );
+            }
+            EntryKind::File(content, holes) => {
+                let len = io::copy(content, &mut *self.chunker)?;
+                self.progress.bytes_chunked += len;
 
-            if written_chunks.is_empty() {
-                // this file wasn't big enough to cause a chunk to be generated, add it to the list
-                // of files pending for this chunk
-                prev_files.push(file);
-            } else {
-                let fixed_chunk =
-                    merge_chunks_and_prev_files(&mut written_chunks, &mut files, &mut prev_files)?;
-                file.chunk_list.chunks.push(fixed_chunk);
-                file.chunk_list.chunks.append(&mut written_chunks);
+                let mut written_chunks = write_chunks_to_oci(
+                    self.oci,
+                    &mut *self.chunker,
+                    self.options.compression,
+                    self.base.as_ref(),
+                    self.journal.as_ref(),
+                    &self.written,
+                    &mut self.stats,
+                )?;
+                let mut file = File {
+                    ino: self.cur_ino,
+                    chunk_list: FileChunkList {
+                        chunks: Vec::<FileChunk>::new(),
+                    },
+                    len,
+                    holes,
+                    uid: entry.uid,
+                    gid: entry.gid,
+                    mtime: entry.mtime,
+                    atime: entry.atime,
+                    permissions: entry.permissions,
+                    additional: entry.additional,
+                };
+
+                if len == 0 {
+                    // there's nothing of it in any chunk, so it's done already; it has no chunks
+                    // at all rather than an empty one
+                    self.files.push(file);
+                } else if written_chunks.is_empty() {
+                    // this file wasn't big enough to cause a chunk to be generated, add it to the
+                    // list of files pending for this chunk
+                    self.prev_files.push(file);
+                } else {
+                    let fixed_chunk = merge_chunks_and_prev_files(
+                        &mut written_chunks,
+                        &mut self.files,
+                        &mut self.prev_files,
+                    )?;
+                    file.chunk_list.chunks.push(fixed_chunk);
+                    file.chunk_list.chunks.append(&mut written_chunks);
+
+                    // the end of this file may still be sitting in the chunker, in which case it
+                    // needs to pick up the rest of its chunks later
+                    let file_used: u64 = file.chunk_list.chunks.iter().map(|c| c.len).sum();
+                    if file_used < file.len {
+                        self.prev_files.push(file);
+                    } else {
+                        self.files.push(file);
+                    }
+                }
+                self.rendered.insert(entry.path, self.cur_ino);
             }
-        } else {
-            let o = Other {
-                ino: cur_ino,
-                md,
-                additional,
-            };
-            others.push(o);
-        }
-
-        cur_ino += 1;
-    }
-
-    // all inodes done, we need to finish up the cdc chunking
-    fcdc.finish();
-    let mut written_chunks = write_chunks_to_oci(oci, &mut fcdc)?;
-
-    // if we have chunks, we should have files too
-    assert!(written_chunks.is_empty() || !prev_files.is_empty());
-    assert!(!written_chunks.is_empty() || prev_files.is_empty());
-
-    if !written_chunks.is_empty() {
-        // merge everything leftover with all previous files. we expect an error here, since the in
-        // put shoudl be exactly consumed and the final take_first_chunk() call should fail. TODO:
-        // rearrange this to be less ugly.
-        merge_chunks_and_prev_files(&mut written_chunks, &mut files, &mut prev_files).unwrap_err();
-
-        // we should have consumed all the chunks.
-        assert!(written_chunks.is_empty());
-    }
-
-    // total inode serailized size
-    let num_inodes = pfs_inodes.len() + dirs.len() + files.len() + others.len();
-    let inodes_serial_size = inode_encoded_size(num_inodes);
-
-    // TODO: not render this whole thing in memory, stick it all in the same blob, etc.
-    let mut dir_buf = Vec::<u8>::new();
-
-    // render dirs
-    pfs_inodes.extend(
-        dirs.values_mut()
-            .collect::<Vec<_>>()
-            .drain(..)
-            .map(|d| {
-                let dir_list_offset = inodes_serial_size + dir_buf.len();
-                serde_cbor::to_writer(&mut dir_buf, &d.dir_list)?;
-                let additional_ref = d
-                    .additional
-                    .as_ref()
-                    .map::<Result<BlobRef>, _>(|add| {
-                        let offset = inodes_serial_size + dir_buf.len();
-                        serde_cbor::to_writer(&mut dir_buf, &add)?;
-                        Ok(BlobRef {
-                            offset: offset as u64,
-                            kind: BlobRefKind::Local,
+            EntryKind::Other(mode) => {
+                self.others.push(Other {
+                    ino: self.cur_ino,
+                    mode,
+                    uid: entry.uid,
+                    gid: entry.gid,
+                    mtime: entry.mtime,
+                    atime: entry.atime,
+                    permissions: entry.permissions,
+                    additional: entry.additional,
+                });
+                self.rendered.insert(entry.path, self.cur_ino);
+            }
+            EntryKind::HardLink(_) => unreachable!(),
+        }
+
+        self.cur_ino += 1;
+        Ok(())
+    }
+
+    // with skip_errors, leaves path, which is where something is on the host, out of the image
+    // because of error; otherwise that's the end of the build. there's no leaving out the root.
+    fn skip(&mut self, rootfs: &Path, path: &Path, error: io::Error) -> Result<()> {
+        if !self.options.skip_errors || path == rootfs {
+            return Err(error.into());
+        }
+        let skipped = SkippedEntry {
+            path: path.to_path_buf(),
+            error: error.to_string(),
+        };
+        if let Some(on_skip) = &self.options.on_skip {
+            on_skip(&skipped);
+        }
+        self.stats.skipped.push(skipped);
+        Ok(())
+    }
+
+    // what walkdir can't do is read a directory (which it has already given us, so it's left
+    // empty) or follow a link
+    fn skip_walk_error(&mut self, rootfs: &Path, error: walkdir::Error) -> Result<()> {
+        let path = error.path().unwrap_or(rootfs).to_path_buf();
+        self.skip(rootfs, &path, error.into())
+    }
+
+    // takes path (and if it's a directory, everything in it) back out of the image. the inodes
+    // that are left without any links are dropped when the image is finished; their content may
+    // well share chunks with other files', so that stays where it is.
+    fn unlink(&mut self, path: &Path) -> Result<()> {
+        let parent_path = path
+            .parent()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "the root must be a directory"))?;
+        let name = path.file_name().unwrap_or_default();
+        let parent = self.dirs.get_mut(parent_path).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!("no pfs inode for {}", parent_path.display()),
+            )
+        })?;
+        parent.dir_list.entries.retain(|de| de.name != name);
+        let parent_ino = parent.ino;
+
+        if self.dirs.contains_key(path) {
+            *self.nlinks.entry(parent_ino).or_insert(0) -= 1;
+            let gone = self
+                .dirs
+                .keys()
+                .filter(|p| p.starts_with(path))
+                .cloned()
+                .collect::<Vec<_>>();
+            for p in gone {
+                let dir = self.dirs.remove(&p).unwrap();
+                self.nlinks.insert(dir.ino, 0);
+            }
+        }
+        // what's hard linked from somewhere else lives on there
+        let gone = self
+            .rendered
+            .keys()
+            .filter(|p| p.starts_with(path))
+            .cloned()
+            .collect::<Vec<_>>();
+        for p in gone {
+            let ino = self.rendered.remove(&p).unwrap();
+            *self.nlinks.entry(ino).or_insert(1) -= 1;
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<(Descriptor, BuildStats)> {
+        let RootfsBuilder {
+            oci,
+            options,
+            mut chunker,
+            base,
+            journal,
+            written,
+            mut stats,
+            progress,
+            mut dirs,
+            mut files,
+            mut others,
+            mut prev_files,
+            nlinks,
+            ..
+        } = self;
+        let mut pfs_inodes = Vec::<Inode>::new();
+
+        // all inodes done, we need to finish up the cdc chunking
+        chunker.finish();
+        let mut written_chunks = write_chunks_to_oci(
+            oci,
+            &mut *chunker,
+            options.compression,
+            base.as_ref(),
+            journal.as_ref(),
+            &written,
+            &mut stats,
+        )?;
+        if let Some(report) = &options.progress {
+            report(&BuildProgress {
+                blobs_written: stats.chunks_written,
+                ..progress
+            });
+        }
+
+        // if we have chunks, we should have files too
+        assert!(written_chunks.is_empty() || !prev_files.is_empty());
+        assert!(!written_chunks.is_empty() || prev_files.is_empty());
+
+        if !written_chunks.is_empty() {
+            // merge everything leftover with all previous files. we expect an error here, since
+            // the in put shoudl be exactly consumed and the final take_first_chunk() call should
+            // fail. TODO: rearrange this to be less ugly.
+            merge_chunks_and_prev_files(&mut written_chunks, &mut files, &mut prev_files)
+                .unwrap_err();
+
+            // we should have consumed all the chunks.
+            assert!(written_chunks.is_empty());
+        }
+
+        // anything overwritten that nothing links to anymore
+        files.retain(|f| nlinks.get(&f.ino) != Some(&0));
+        others.retain(|o| nlinks.get(&o.ino) != Some(&0));
+
+        // total inode serailized size
+        let num_inodes = pfs_inodes.len() + dirs.len() + files.len() + others.len();
+        let inodes_serial_size = inode_encoded_size(num_inodes);
+
+        // TODO: not render this whole thing in memory, stick it all in the same blob, etc.
+        let mut dir_buf = Vec::<u8>::new();
+
+        // render dirs, in a stable order so that building the same thing twice gives the same
+        // metadata blob
+        let mut dirs = dirs.drain().map(|(_, d)| d).collect::<Vec<_>>();
+        dirs.sort_by_key(|d| d.ino);
+        pfs_inodes.extend(
+            dirs.iter()
+                .map(|d| {
+                    let dir_list_offset = inodes_serial_size + dir_buf.len();
+                    serde_cbor::to_writer(&mut dir_buf, &d.dir_list)?;
+                    let additional_ref = d
+                        .additional
+                        .as_ref()
+                        .map::<Result<BlobRef>, _>(|add| {
+                            let offset = inodes_serial_size + dir_buf.len();
+                            serde_cbor::to_writer(&mut dir_buf, &add)?;
+                            Ok(BlobRef {
+                                offset: offset as u64,
+                                kind: BlobRefKind::Local,
+                                compressed: false,
+                            })
                         })
+                        .transpose()?;
+                    let mode = InodeMode::Dir {
+                        offset: dir_list_offset as u64,
+                    };
+                    Ok(Inode {
+                        permissions: d.permissions,
+                        ..Inode::new(d.ino, mode, d.uid, d.gid, d.mtime, d.atime, additional_ref)
                     })
-                    .transpose()?;
-                Ok(Inode::new_dir(
-                    d.ino,
-                    &d.md,
-                    dir_list_offset as u64,
-                    additional_ref,
-                )?)
-            })
-            .collect::<Result<Vec<Inode>>>()?,
-    );
-
-    let mut files_buf = Vec::<u8>::new();
-
-    // render files
-    pfs_inodes.extend(
-        files
-            .drain(..)
-            .map(|f| {
-                let chunk_offset = inodes_serial_size + dir_buf.len() + files_buf.len();
-                serde_cbor::to_writer(&mut files_buf, &f.chunk_list)?;
-                let additional_ref = f
-                    .additional
-                    .as_ref()
-                    .map::<Result<BlobRef>, _>(|add| {
-                        let offset = inodes_serial_size + dir_buf.len() + files_buf.len();
-                        serde_cbor::to_writer(&mut files_buf, &add)?;
-                        Ok(BlobRef {
-                            offset: offset as u64,
-                            kind: BlobRefKind::Local,
+                })
+                .collect::<Result<Vec<Inode>>>()?,
+        );
+
+        let mut files_buf = Vec::<u8>::new();
+
+        // render files
+        pfs_inodes.extend(
+            files
+                .drain(..)
+                .map(|f| {
+                    let chunk_offset = inodes_serial_size + dir_buf.len() + files_buf.len();
+                    let chunk_list = FileChunkList {
+                        chunks: punch_holes(f.chunk_list.chunks, &f.holes),
+                    };
+                    serde_cbor::to_writer(&mut files_buf, &chunk_list)?;
+                    let mut additional = f.additional;
+                    if options.file_digests {
+                        additional
+                            .get_or_insert_with(InodeAdditional::default)
+                            .file_digest = Some(chunk_list_digest(&chunk_list.chunks));
+                    }
+                    let additional_ref = additional
+                        .as_ref()
+                        .map::<Result<BlobRef>, _>(|add| {
+                            let offset = inodes_serial_size + dir_buf.len() + files_buf.len();
+                            serde_cbor::to_writer(&mut files_buf, &add)?;
+                            Ok(BlobRef {
+                                offset: offset as u64,
+                                kind: BlobRefKind::Local,
+                                compressed: false,
+                            })
                         })
+                        .transpose()?;
+                    let mode = InodeMode::Reg {
+                        offset: chunk_offset as u64,
+                    };
+                    Ok(Inode {
+                        permissions: f.permissions,
+                        ..Inode::new(f.ino, mode, f.uid, f.gid, f.mtime, f.atime, additional_ref)
                     })
-                    .transpose()?;
-                Ok(Inode::new_file(
-                    f.ino,
-                    &f.md,
-                    chunk_offset as u64,
-                    additional_ref,
-                )?)
-            })
-            .collect::<Result<Vec<Inode>>>()?,
-    );
-
-    let mut others_buf = Vec::<u8>::new();
-
-    pfs_inodes.extend(
-        others
-            .drain(..)
-            .map(|o| {
-                let additional_ref = o
-                    .additional
-                    .as_ref()
-                    .map::<Result<BlobRef>, _>(|add| {
-                        let offset =
-                            inodes_serial_size + dir_buf.len() + files_buf.len() + others_buf.len();
-                        serde_cbor::to_writer(&mut others_buf, &add)?;
-                        Ok(BlobRef {
-                            offset: offset as u64,
-                            kind: BlobRefKind::Local,
+                })
+                .collect::<Result<Vec<Inode>>>()?,
+        );
+
+        let mut others_buf = Vec::<u8>::new();
+
+        pfs_inodes.extend(
+            others
+                .drain(..)
+                .map(|o| {
+                    let additional_ref = o
+                        .additional
+                        .as_ref()
+                        .map::<Result<BlobRef>, _>(|add| {
+                            let offset = inodes_serial_size
+                                + dir_buf.len()
+                                + files_buf.len()
+                                + others_buf.len();
+                            serde_cbor::to_writer(&mut others_buf, &add)?;
+                            Ok(BlobRef {
+                                offset: offset as u64,
+                                kind: BlobRefKind::Local,
+                                compressed: false,
+                            })
                         })
+                        .transpose()?;
+                    Ok(Inode {
+                        permissions: o.permissions,
+                        ..Inode::new(
+                            o.ino,
+                            o.mode,
+                            o.uid,
+                            o.gid,
+                            o.mtime,
+                            o.atime,
+                            additional_ref,
+                        )
                     })
-                    .transpose()?;
-                Ok(Inode::new_other(o.ino, &o.md, additional_ref)?)
-            })
-            .collect::<Result<Vec<Inode>>>()?,
-    );
+                })
+                .collect::<Result<Vec<Inode>>>()?,
+        );
 
-    pfs_inodes.sort_by(|a, b| a.ino.cmp(&b.ino));
+        pfs_inodes.sort_by(|a, b| a.ino.cmp(&b.ino));
 
-    let mut md_buf = Vec::<u8>::with_capacity(
-        inodes_serial_size + dir_buf.len() + files_buf.len() + others_buf.len(),
-    );
-    serde_cbor::to_writer(&mut md_buf, &pfs_inodes)?;
+        for inode in pfs_inodes.iter_mut() {
+            inode.nlink = nlinks.get(&inode.ino).copied().unwrap_or(1);
+        }
 
-    assert_eq!(md_buf.len(), inodes_serial_size);
+        let mut md_buf = Vec::<u8>::with_capacity(
+            inodes_serial_size + dir_buf.len() + files_buf.len() + others_buf.len(),
+        );
+        serde_cbor::to_writer(&mut md_buf, &pfs_inodes)?;
 
-    md_buf.append(&mut dir_buf);
-    md_buf.append(&mut files_buf);
-    md_buf.append(&mut others_buf);
+        assert_eq!(md_buf.len(), inodes_serial_size);
 
-    let desc = oci.put_blob::<_, compression::Noop, media_types::Inodes>(md_buf.as_slice())?;
-    let metadatas = [BlobRef {
-        offset: 0,
-        kind: BlobRefKind::Other {
-            digest: desc.digest.underlying(),
-        },
-    }]
-    .to_vec();
+        md_buf.append(&mut dir_buf);
+        md_buf.append(&mut files_buf);
+        md_buf.append(&mut others_buf);
 
-    let mut rootfs_buf = Vec::new();
-    serde_cbor::to_writer(&mut rootfs_buf, &Rootfs { metadatas })?;
-    oci.put_blob::<_, compression::Noop, media_types::Rootfs>(rootfs_buf.as_slice())
+        let desc = if options.compress_metadata {
+            oci.put_blob::<_, compression::Zstd, media_types::Inodes>(md_buf.as_slice())?
+        } else {
+            oci.put_blob::<_, compression::Noop, media_types::Inodes>(md_buf.as_slice())?
+        };
+        let metadatas = [desc.digest.blob_ref(options.compress_metadata)].to_vec();
+
+        let mut rootfs_buf = Vec::new();
+        serde_cbor::to_writer(
+            &mut rootfs_buf,
+            &Rootfs {
+                version: FORMAT_VERSION,
+                metadatas,
+                chunking: options.chunking,
+            },
+        )?;
+        let desc =
+            oci.put_blob::<_, compression::Noop, media_types::Rootfs>(rootfs_buf.as_slice())?;
+        Ok((desc, stats))
+    }
 }
 
 // TODO: figure out how to guard this with #[cfg(test)]
@@ -382,11 +1349,33 @@ pub fn build_test_fs(image: &Image) -> Result<Descriptor> {
 pub mod tests {
     use super::*;
 
+    use std::cell::RefCell;
     use std::convert::TryInto;
+    use std::rc::Rc;
 
+    use fastrand::Rng;
+    use nix::sys::stat::{utimensat, UtimensatFlags};
+    use nix::sys::time::TimeSpec;
     use tempfile::tempdir;
 
-    use format::{DirList, InodeMode};
+    use format::{DigestAlgorithm, DirList};
+
+    // builds store atimes, and reading a tree to build it can bump them. give everything in it
+    // the same times, with an atime later than now so that relatime leaves it alone.
+    fn pin_times(rootfs: &Path) {
+        let atime = TimeSpec::from(nix::libc::timespec {
+            tv_sec: 4_000_000_000,
+            tv_nsec: 0,
+        });
+        let mtime = TimeSpec::from(nix::libc::timespec {
+            tv_sec: 1_000_000_000,
+            tv_nsec: 0,
+        });
+        for e in WalkDir::new(rootfs) {
+            let path = e.unwrap().into_path();
+            utimensat(None, &path, &atime, &mtime, UtimensatFlags::NoFollowSymlink).unwrap();
+        }
+    }
 
     #[test]
     fn test_fs_generation() {
@@ -410,7 +1399,7 @@ pub mod tests {
         const FILE_DIGEST: &str =
             "d9e749d9367fc908876749d6502eb212fee88c9a94892fb07da5ef3ba8bc39ed";
 
-        let md = fs::symlink_metadata(image.blob_path().join(FILE_DIGEST)).unwrap();
+        let md = fs::symlink_metadata(image.blob_path().unwrap().join(FILE_DIGEST)).unwrap();
         assert!(md.is_file());
 
         let metadata_digest = rootfs.metadatas[0].try_into().unwrap();
@@ -448,4 +1437,746 @@ pub mod tests {
             panic!("bad inode mode: {:?}", inodes[1].mode);
         }
     }
+
+    #[test]
+    fn test_blob_totals() {
+        let dir = tempdir().unwrap();
+        let image = Image::new(dir.path()).unwrap();
+        build_test_fs(&image).unwrap();
+        let sizes = fs::read_dir(image.blob_path().unwrap())
+            .unwrap()
+            .map(|ent| ent.unwrap().metadata().unwrap().len())
+            .collect::<Vec<_>>();
+        // the rootfs, the metadata and the one chunk
+        assert_eq!(image.blob_count().unwrap(), 3);
+        assert_eq!(sizes.len(), 3);
+        assert_eq!(image.total_blob_bytes().unwrap(), sizes.iter().sum::<u64>());
+    }
+
+    #[test]
+    fn test_referenced_blobs() {
+        let dir = tempdir().unwrap();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(rootfs.join("dir")).unwrap();
+        fs::write(rootfs.join("a"), "meshuggah rocks\n".repeat(1024)).unwrap();
+        fs::write(rootfs.join("dir/b"), "meshuggah still rocks\n").unwrap();
+        fs::write(rootfs.join("empty"), "").unwrap();
+        let image = Image::new(&dir.path().join("oci")).unwrap();
+        let options = BuildOptions {
+            chunking: ChunkingConfig {
+                min: 0,
+                avg: 4096,
+                max: 0,
+                algo: ChunkingAlgorithm::Fixed,
+            },
+            compress_metadata: true,
+            ..BuildOptions::default()
+        };
+        let desc = build_initial_rootfs_with_options(&rootfs, &image, &options).unwrap();
+        image.add_tag("test".to_string(), desc.clone()).unwrap();
+
+        // an image on its own in the store refers to everything in it
+        let blobs = image.referenced_blobs("test").unwrap();
+        assert!(blobs.len() > 2);
+        assert_eq!(blobs.len(), image.blob_count().unwrap());
+        assert!(blobs.contains(&desc.digest));
+        assert_eq!(
+            image
+                .referenced_blobs(&format!("@sha256:{}", desc.digest))
+                .unwrap(),
+            blobs
+        );
+        image.referenced_blobs("nope").unwrap_err();
+    }
+
+    #[test]
+    fn test_hard_links_share_inode() {
+        let dir = tempdir().unwrap();
+        let oci_dir = dir.path().join("oci");
+        let image = Image::new(&oci_dir).unwrap();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs).unwrap();
+
+        // random data so that the chunker can't dedup anything even if we did write the file
+        // twice
+        const FILE_SIZE: usize = 20 * 1024 * 1024;
+        let rng = Rng::with_seed(0);
+        let data = (0..FILE_SIZE).map(|_| rng.u8(..)).collect::<Vec<u8>>();
+        fs::write(rootfs.join("a"), data).unwrap();
+        fs::hard_link(rootfs.join("a"), rootfs.join("b")).unwrap();
+
+        let rootfs_desc = build_initial_rootfs(&rootfs, &image).unwrap();
+        let rootfs_blob = Rootfs::open(
+            image
+                .open_compressed_blob::<compression::Noop>(&rootfs_desc.digest)
+                .unwrap(),
+        )
+        .unwrap();
+        let metadata_digest = rootfs_blob.metadatas[0].try_into().unwrap();
+        let mut blob = image
+            .open_metadata_blob::<compression::Noop>(&metadata_digest)
+            .unwrap();
+        let inodes = blob.read_inodes().unwrap();
+        assert_eq!(inodes.len(), 2);
+
+        if let InodeMode::Dir { offset } = inodes[0].mode {
+            let dir_list: DirList = blob.read_dir_list(offset).unwrap();
+            assert_eq!(dir_list.entries.len(), 2);
+            assert_eq!(dir_list.entries[0].ino, 2);
+            assert_eq!(dir_list.entries[1].ino, 2);
+        } else {
+            panic!("bad inode mode: {:?}", inodes[0].mode);
+        }
+        assert_eq!(inodes[0].nlink, 2);
+        assert_eq!(inodes[1].nlink, 2);
+
+        // the file's content should only have been written once: everything in the blob dir
+        // other than the metadata and rootfs blobs is file content.
+        let chunk_bytes: u64 = fs::read_dir(image.blob_path().unwrap())
+            .unwrap()
+            .map(|ent| ent.unwrap())
+            .filter(|ent| {
+                let name = ent.file_name();
+                name != *metadata_digest.to_string() && name != *rootfs_desc.digest.to_string()
+            })
+            .map(|ent| ent.metadata().unwrap().len())
+            .sum();
+        assert_eq!(chunk_bytes, FILE_SIZE as u64);
+    }
+
+    #[test]
+    fn test_custom_chunk_sizes() {
+        let dir = tempdir().unwrap();
+        let image = Image::new(dir.path()).unwrap();
+
+        let options = BuildOptions {
+            chunking: ChunkingConfig {
+                min: 8192,
+                avg: 16384,
+                max: 32768,
+                algo: ChunkingAlgorithm::FastCDC,
+            },
+            ..BuildOptions::default()
+        };
+        let rootfs_desc =
+            build_initial_rootfs_with_options(Path::new("test"), &image, &options).unwrap();
+        let rootfs = Rootfs::open(
+            image
+                .open_compressed_blob::<compression::Noop>(&rootfs_desc.digest)
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(rootfs.chunking, options.chunking);
+
+        // the test file is ~100k, so it should be split into several chunks now
+        let metadata_digest = rootfs.metadatas[0].try_into().unwrap();
+        let mut blob = image
+            .open_metadata_blob::<compression::Noop>(&metadata_digest)
+            .unwrap();
+        let inode = blob.find_inode(2).unwrap().unwrap();
+        if let InodeMode::Reg { offset } = inode.mode {
+            let chunks = blob.read_file_chunks(offset).unwrap();
+            assert!(chunks.len() > 1);
+            assert!(chunks.iter().all(|c| c.len <= 32768));
+        } else {
+            panic!("bad inode mode: {:?}", inode.mode);
+        }
+    }
+
+    #[test]
+    fn test_fixed_size_chunker() {
+        let dir = tempdir().unwrap();
+        let image = Image::new(dir.path()).unwrap();
+
+        let options = BuildOptions {
+            chunking: ChunkingConfig {
+                min: 0,
+                avg: 4096,
+                max: 0,
+                algo: ChunkingAlgorithm::Fixed,
+            },
+            ..BuildOptions::default()
+        };
+        let rootfs_desc =
+            build_initial_rootfs_with_options(Path::new("test"), &image, &options).unwrap();
+        let rootfs = Rootfs::open(
+            image
+                .open_compressed_blob::<compression::Noop>(&rootfs_desc.digest)
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(rootfs.chunking.algo, ChunkingAlgorithm::Fixed);
+
+        let metadata_digest = rootfs.metadatas[0].try_into().unwrap();
+        let mut blob = image
+            .open_metadata_blob::<compression::Noop>(&metadata_digest)
+            .unwrap();
+        let inode = blob.find_inode(2).unwrap().unwrap();
+        if let InodeMode::Reg { offset } = inode.mode {
+            let chunks = blob.read_file_chunks(offset).unwrap();
+            assert_eq!(chunks.len(), (109466 + 4095) / 4096);
+            assert!(chunks[..chunks.len() - 1].iter().all(|c| c.len == 4096));
+        } else {
+            panic!("bad inode mode: {:?}", inode.mode);
+        }
+    }
+
+    #[test]
+    fn test_identical_chunks_share_a_blob() {
+        let dir = tempdir().unwrap();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs).unwrap();
+        fs::write(rootfs.join("zeros"), vec![0_u8; 64 * 4096]).unwrap();
+        let oci_dir = dir.path().join("oci");
+        let image = Image::new(&oci_dir).unwrap();
+
+        let options = BuildOptions {
+            chunking: ChunkingConfig {
+                min: 0,
+                avg: 4096,
+                max: 0,
+                algo: ChunkingAlgorithm::Fixed,
+            },
+            ..BuildOptions::default()
+        };
+        let (rootfs_desc, stats) =
+            build_initial_rootfs_with_stats(&rootfs, &image, &options).unwrap();
+        assert_eq!(
+            stats,
+            BuildStats {
+                chunks_written: 1,
+                chunks_deduplicated: 63,
+                blob_bytes: 4096,
+                skipped: Vec::new(),
+            }
+        );
+        let rootfs = Rootfs::open(
+            image
+                .open_compressed_blob::<compression::Noop>(&rootfs_desc.digest)
+                .unwrap(),
+        )
+        .unwrap();
+        let metadata_digest = rootfs.metadatas[0].try_into().unwrap();
+        let mut blob = image
+            .open_metadata_blob::<compression::Noop>(&metadata_digest)
+            .unwrap();
+        let inode = blob.find_inode(2).unwrap().unwrap();
+        let chunks = match inode.mode {
+            InodeMode::Reg { offset } => blob.read_file_chunks(offset).unwrap(),
+            _ => panic!("bad inode mode: {:?}", inode.mode),
+        };
+        assert_eq!(chunks.len(), 64);
+        assert!(chunks.iter().all(|c| c.blob == chunks[0].blob));
+
+        // one chunk blob, the metadata blob, and the rootfs blob
+        assert_eq!(fs::read_dir(image.blob_path().unwrap()).unwrap().count(), 3);
+    }
+
+    #[test]
+    fn test_empty_and_tiny_files() {
+        let dir = tempdir().unwrap();
+        let oci_dir = dir.path().join("oci");
+        let image = Image::new(&oci_dir).unwrap();
+        // an image of nothing but empty files used to trip over the leftover chunk accounting
+        for files in [&[("empty", &b""[..])][..], &[("empty", b""), ("one", b"x")]] {
+            let rootfs = dir.path().join(format!("rootfs{}", files.len()));
+            fs::create_dir_all(&rootfs).unwrap();
+            for (name, data) in files {
+                fs::write(rootfs.join(name), data).unwrap();
+            }
+            let rootfs_desc = build_initial_rootfs(&rootfs, &image).unwrap();
+            let rootfs = Rootfs::open(
+                image
+                    .open_compressed_blob::<compression::Noop>(&rootfs_desc.digest)
+                    .unwrap(),
+            )
+            .unwrap();
+            let metadata_digest = rootfs.metadatas[0].try_into().unwrap();
+            let mut blob = image
+                .open_metadata_blob::<compression::Noop>(&metadata_digest)
+                .unwrap();
+            for (i, (_, data)) in files.iter().enumerate() {
+                let inode = blob.find_inode(i as u64 + 2).unwrap().unwrap();
+                let chunks = match inode.mode {
+                    InodeMode::Reg { offset } => blob.read_file_chunks(offset).unwrap(),
+                    _ => panic!("bad inode mode: {:?}", inode.mode),
+                };
+                let len: u64 = chunks.iter().map(|c| c.len).sum();
+                assert_eq!(len, data.len() as u64);
+                assert_eq!(chunks.is_empty(), data.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn test_sparse_files() {
+        let dir = tempdir().unwrap();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs).unwrap();
+        // a gigabyte of hole, with a bit of data at the start and in the middle
+        let f = fs::File::create(rootfs.join("sparse")).unwrap();
+        f.set_len(1 << 30).unwrap();
+        f.write_at(b"meshuggah", 0).unwrap();
+        f.write_at(b"rocks", 1 << 29).unwrap();
+
+        let oci_dir = dir.path().join("oci");
+        let image = Image::new(&oci_dir).unwrap();
+        let options = BuildOptions::default();
+        let (rootfs_desc, stats) =
+            build_initial_rootfs_with_stats(&rootfs, &image, &options).unwrap();
+        // the filesystem's holes are block sized, so the data is too
+        assert!(stats.blob_bytes <= 2 * 64 * 1024, "{:?}", stats);
+        let stored: u64 = fs::read_dir(oci_dir.join("blobs/sha256"))
+            .unwrap()
+            .map(|e| e.unwrap().metadata().unwrap().len())
+            .sum();
+        assert!(stored < 1024 * 1024, "{} bytes of blobs", stored);
+
+        let rootfs = Rootfs::open(
+            image
+                .open_compressed_blob::<compression::Noop>(&rootfs_desc.digest)
+                .unwrap(),
+        )
+        .unwrap();
+        let metadata_digest = rootfs.metadatas[0].try_into().unwrap();
+        let mut blob = image
+            .open_metadata_blob::<compression::Noop>(&metadata_digest)
+            .unwrap();
+        let inode = blob.find_inode(2).unwrap().unwrap();
+        let chunks = match inode.mode {
+            InodeMode::Reg { offset } => blob.read_file_chunks(offset).unwrap(),
+            _ => panic!("bad inode mode: {:?}", inode.mode),
+        };
+        assert_eq!(chunks.iter().map(|c| c.len).sum::<u64>(), 1 << 30);
+        // data, hole, data, hole
+        let kinds = chunks.iter().map(|c| c.blob.is_some()).collect::<Vec<_>>();
+        assert_eq!(kinds, vec![true, false, true, false]);
+    }
+
+    #[test]
+    fn test_punch_holes() {
+        let blob = |offset| {
+            Some(BlobRef {
+                offset,
+                kind: BlobRefKind::Other {
+                    digest: [0; 32].into(),
+                    algorithm: DigestAlgorithm::Sha256,
+                },
+                compressed: false,
+            })
+        };
+        let chunks = vec![
+            FileChunk {
+                blob: blob(0),
+                len: 100,
+            },
+            FileChunk {
+                blob: blob(0),
+                len: 50,
+            },
+        ];
+        // one before everything, one splitting the first chunk, one between the chunks and one
+        // at the end
+        let holes = [0..10, 40..60, 130..140, 190..300];
+        let punched = punch_holes(chunks, &holes)
+            .into_iter()
+            .map(|c| (c.blob.map(|b| b.offset), c.len))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            punched,
+            vec![
+                (None, 10),
+                (Some(0), 30),
+                (None, 20),
+                (Some(30), 70),
+                (None, 10),
+                (Some(0), 50),
+                (None, 110),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_zstd_chunks_are_smaller() {
+        let dir = tempdir().unwrap();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs).unwrap();
+        let data = "meshuggah rocks\n".repeat(64 * 1024);
+        fs::write(rootfs.join("lyrics"), &data).unwrap();
+
+        fn chunk_blob_bytes(oci_dir: &Path, options: &BuildOptions) -> u64 {
+            let image = Image::new(oci_dir).unwrap();
+            let rootfs_desc = build_initial_rootfs_with_options(
+                &oci_dir.parent().unwrap().join("rootfs"),
+                &image,
+                options,
+            )
+            .unwrap();
+            let rootfs = Rootfs::open(
+                image
+                    .open_compressed_blob::<compression::Noop>(&rootfs_desc.digest)
+                    .unwrap(),
+            )
+            .unwrap();
+            let metadata_digest = rootfs.metadatas[0].try_into().unwrap();
+            let mut blob = image
+                .open_metadata_blob::<compression::Noop>(&metadata_digest)
+                .unwrap();
+            let inode = blob.find_inode(2).unwrap().unwrap();
+            let chunks = match inode.mode {
+                InodeMode::Reg { offset } => blob.read_file_chunks(offset).unwrap(),
+                _ => panic!("bad inode mode: {:?}", inode.mode),
+            };
+            chunks
+                .iter()
+                .map(|c| {
+                    let digest: oci::Digest = c.blob.unwrap().try_into().unwrap();
+                    fs::metadata(image.blob_path().unwrap().join(digest.to_string()))
+                        .unwrap()
+                        .len()
+                })
+                .sum()
+        }
+
+        let uncompressed = chunk_blob_bytes(&dir.path().join("plain"), &BuildOptions::default());
+        assert_eq!(uncompressed, data.len() as u64);
+
+        let zstd = BuildOptions {
+            compression: ChunkCompression::Zstd {
+                level: compression::DEFAULT_ZSTD_LEVEL,
+            },
+            ..BuildOptions::default()
+        };
+        let compressed = chunk_blob_bytes(&dir.path().join("zstd"), &zstd);
+        assert!(compressed < uncompressed / 10, "{} bytes", compressed);
+    }
+
+    #[test]
+    fn test_rebuild_reuses_base_blobs() {
+        let dir = tempdir().unwrap();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs).unwrap();
+
+        // file sizes are a multiple of the chunk size so that changing one file doesn't shift
+        // the chunk boundaries of the others
+        let rng = Rng::with_seed(0);
+        for i in 0..16 {
+            let data = (0..4 * 4096).map(|_| rng.u8(..)).collect::<Vec<u8>>();
+            fs::write(rootfs.join(format!("file{}", i)), data).unwrap();
+        }
+        let chunking = ChunkingConfig {
+            min: 0,
+            avg: 4096,
+            max: 0,
+            algo: ChunkingAlgorithm::Fixed,
+        };
+
+        let base_dir = dir.path().join("base");
+        let base_image = Image::new(&base_dir).unwrap();
+        let options = BuildOptions {
+            chunking,
+            ..BuildOptions::default()
+        };
+        let desc = build_initial_rootfs_with_options(&rootfs, &base_image, &options).unwrap();
+        base_image.add_tag("base".to_string(), desc).unwrap();
+
+        let data = (0..4 * 4096).map(|_| rng.u8(..)).collect::<Vec<u8>>();
+        fs::write(rootfs.join("file7"), data).unwrap();
+
+        let new_dir = dir.path().join("new");
+        let new_image = Image::new(&new_dir).unwrap();
+        let options = BuildOptions {
+            chunking,
+            base: Some(BaseImage {
+                oci_dir: base_dir,
+                tag: "base".to_string(),
+            }),
+            ..BuildOptions::default()
+        };
+        let rootfs_desc = build_initial_rootfs_with_options(&rootfs, &new_image, &options).unwrap();
+
+        // reused blobs are hard linked from the base image, the rest were written fresh
+        let rootfs = Rootfs::open(
+            new_image
+                .open_compressed_blob::<compression::Noop>(&rootfs_desc.digest)
+                .unwrap(),
+        )
+        .unwrap();
+        let metadata_digest: oci::Digest = rootfs.metadatas[0].try_into().unwrap();
+        let mut blob = new_image
+            .open_metadata_blob::<compression::Noop>(&metadata_digest)
+            .unwrap();
+        let mut reused = 0;
+        let mut written = 0;
+        for inode in blob.read_inodes().unwrap() {
+            if let InodeMode::Reg { offset } = inode.mode {
+                for chunk in blob.read_file_chunks(offset).unwrap() {
+                    let digest: oci::Digest = chunk.blob.unwrap().try_into().unwrap();
+                    let md = fs::metadata(new_image.blob_path().unwrap().join(digest.to_string()))
+                        .unwrap();
+                    if md.nlink() > 1 {
+                        reused += 1;
+                    } else {
+                        written += 1;
+                    }
+                }
+            }
+        }
+        assert_eq!(reused, 15 * 4);
+        assert_eq!(written, 4);
+    }
+
+    #[test]
+    fn test_bad_chunk_sizes() {
+        let dir = tempdir().unwrap();
+        let image = Image::new(dir.path()).unwrap();
+
+        let mut options = BuildOptions::default();
+        options.chunking.min = options.chunking.max + 1;
+        build_initial_rootfs_with_options(Path::new("test"), &image, &options).unwrap_err();
+    }
+
+    // a store that stops taking blobs after a while, like a build that got killed
+    struct InterruptedStore {
+        store: oci::FsBlobStore,
+        blobs_left: std::sync::atomic::AtomicUsize,
+    }
+
+    impl oci::BlobStore for InterruptedStore {
+        fn get_blob(&self, digest: &oci::Digest) -> io::Result<Box<dyn compression::Decompressor>> {
+            self.store.get_blob(digest)
+        }
+
+        fn put_blob(&self, digest: &oci::Digest, blob: &mut dyn io::Read) -> io::Result<()> {
+            use std::sync::atomic::Ordering;
+            self.blobs_left
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .map_err(|_| io::Error::new(io::ErrorKind::Other, "interrupted"))?;
+            self.store.put_blob(digest, blob)
+        }
+
+        fn has_blob(&self, digest: &oci::Digest) -> bool {
+            self.store.has_blob(digest)
+        }
+
+        fn delete_blob(&self, digest: &oci::Digest) -> io::Result<()> {
+            self.store.delete_blob(digest)
+        }
+
+        fn list_blobs(&self) -> io::Result<Vec<(oci::Digest, u64)>> {
+            self.store.list_blobs()
+        }
+
+        fn get_index(&self) -> Result<oci::Index> {
+            self.store.get_index()
+        }
+
+        fn put_index(&self, index: &oci::Index) -> Result<()> {
+            self.store.put_index(index)
+        }
+    }
+
+    #[test]
+    fn test_resume_interrupted_build() {
+        let dir = tempdir().unwrap();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs).unwrap();
+        // a chunk each
+        for i in 0..16 {
+            fs::write(rootfs.join(format!("file{:02}", i)), vec![i as u8; 4096]).unwrap();
+        }
+        pin_times(&rootfs);
+        let options = |journal: Option<PathBuf>| BuildOptions {
+            chunking: ChunkingConfig {
+                min: 0,
+                avg: 4096,
+                max: 0,
+                algo: ChunkingAlgorithm::Fixed,
+            },
+            compression: ChunkCompression::Zstd { level: 1 },
+            journal,
+            ..BuildOptions::default()
+        };
+        let journal = dir.path().join("journal");
+
+        let oci_dir = dir.path().join("oci");
+        Image::new(&oci_dir).unwrap();
+        let interrupted = Image::with_store(std::sync::Arc::new(InterruptedStore {
+            store: oci::FsBlobStore::new(&oci_dir),
+            blobs_left: 10.into(),
+        }));
+        build_initial_rootfs_with_options(&rootfs, &interrupted, &options(Some(journal.clone())))
+            .unwrap_err();
+
+        // the chunks that made it are still there, and don't have to be written again
+        let image = Image::open(&oci_dir).unwrap();
+        let (desc, stats) =
+            build_initial_rootfs_with_stats(&rootfs, &image, &options(Some(journal))).unwrap();
+        assert_eq!(stats.chunks_written, 6);
+        assert_eq!(stats.chunks_deduplicated, 10);
+
+        // and it all comes out the same as a build that was never interrupted
+        let fresh = Image::new(&dir.path().join("fresh")).unwrap();
+        let (fresh_desc, _) =
+            build_initial_rootfs_with_stats(&rootfs, &fresh, &options(None)).unwrap();
+        assert_eq!(desc.digest, fresh_desc.digest);
+    }
+
+    #[test]
+    fn test_reproducible_build() {
+        let dir = tempdir().unwrap();
+        let rootfs = dir.path().join("rootfs");
+        // enough directories that hash map ordering would show up in the metadata
+        let rng = Rng::with_seed(0);
+        for i in 0..32 {
+            let d = rootfs.join(format!("dir{}", i));
+            fs::create_dir_all(&d).unwrap();
+            let data = (0..rng.usize(..8192))
+                .map(|_| rng.u8(..))
+                .collect::<Vec<u8>>();
+            fs::write(d.join("file"), data).unwrap();
+        }
+        fs::hard_link(rootfs.join("dir0/file"), rootfs.join("link")).unwrap();
+        pin_times(&rootfs);
+
+        let build = |name: &str| {
+            let oci_dir = dir.path().join(name);
+            let image = Image::new(&oci_dir).unwrap();
+            let desc = build_initial_rootfs(&rootfs, &image).unwrap();
+            image.add_tag("test".to_string(), desc.clone()).unwrap();
+            let mut blobs = fs::read_dir(image.blob_path().unwrap())
+                .unwrap()
+                .map(|e| e.unwrap().file_name())
+                .collect::<Vec<_>>();
+            blobs.sort();
+            (desc, fs::read(oci_dir.join("index.json")).unwrap(), blobs)
+        };
+        let (desc1, index1, blobs1) = build("oci1");
+        let (desc2, index2, blobs2) = build("oci2");
+        assert_eq!(desc1.digest, desc2.digest);
+        assert_eq!(index1, index2);
+        assert_eq!(blobs1, blobs2);
+    }
+
+    #[test]
+    fn test_build_progress() {
+        let dir = tempdir().unwrap();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(rootfs.join("d")).unwrap();
+        fs::write(rootfs.join("d/a"), "a".repeat(4096) + &"b".repeat(4096)).unwrap();
+        fs::write(rootfs.join("b"), "c".repeat(4096)).unwrap();
+        fs::hard_link(rootfs.join("b"), rootfs.join("c")).unwrap();
+
+        let reports = Rc::new(RefCell::new(Vec::new()));
+        let recorded = reports.clone();
+        let options = BuildOptions {
+            chunking: ChunkingConfig {
+                min: 0,
+                avg: 4096,
+                max: 0,
+                algo: ChunkingAlgorithm::Fixed,
+            },
+            progress: Some(Box::new(move |p| recorded.borrow_mut().push(*p))),
+            ..BuildOptions::default()
+        };
+        let image = Image::new(&dir.path().join("oci")).unwrap();
+        build_initial_rootfs_with_options(&rootfs, &image, &options).unwrap();
+
+        // one report per entry (the root, d, d/a, b and c), and one at the end
+        let reports = reports.borrow();
+        assert_eq!(reports.len(), 6);
+        for (i, report) in reports[..5].iter().enumerate() {
+            assert_eq!(report.entries, i as u64 + 1);
+        }
+        assert!(reports
+            .windows(2)
+            .all(|w| w[0].bytes_chunked <= w[1].bytes_chunked
+                && w[0].blobs_written <= w[1].blobs_written));
+        // the hard link's content isn't chunked again
+        assert_eq!(
+            reports[5],
+            BuildProgress {
+                entries: 5,
+                bytes_chunked: 3 * 4096,
+                blobs_written: 3,
+            }
+        );
+    }
+
+    #[test]
+    fn test_skip_errors() {
+        use std::os::unix::fs::{symlink, PermissionsExt};
+
+        let dir = tempdir().unwrap();
+        let image = Image::new(&dir.path().join("oci")).unwrap();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(rootfs.join("locked")).unwrap();
+        fs::write(rootfs.join("a"), b"meshuggah rocks").unwrap();
+        fs::write(rootfs.join("locked/secret"), b"").unwrap();
+        fs::write(rootfs.join("unreadable"), b"").unwrap();
+        symlink("nowhere", rootfs.join("dangling")).unwrap();
+        fs::set_permissions(rootfs.join("locked"), fs::Permissions::from_mode(0o000)).unwrap();
+        fs::set_permissions(rootfs.join("unreadable"), fs::Permissions::from_mode(0o000)).unwrap();
+
+        // nothing's unreadable to root, but a dangling link can't be dereferenced by anyone
+        let mut expected = vec![rootfs.join("dangling")];
+        if !nix::unistd::geteuid().is_root() {
+            expected.push(rootfs.join("locked"));
+            expected.push(rootfs.join("unreadable"));
+        }
+
+        let mut options = BuildOptions {
+            dereference: true,
+            ..BuildOptions::default()
+        };
+        build_initial_rootfs_with_options(&rootfs, &image, &options).unwrap_err();
+
+        let reported = Rc::new(RefCell::new(Vec::new()));
+        let r = reported.clone();
+        options.skip_errors = true;
+        options.on_skip = Some(Box::new(move |s: &SkippedEntry| {
+            r.borrow_mut().push(s.path.clone())
+        }));
+        let (rootfs_desc, stats) =
+            build_initial_rootfs_with_stats(&rootfs, &image, &options).unwrap();
+        let mut skipped = stats
+            .skipped
+            .iter()
+            .map(|s| s.path.clone())
+            .collect::<Vec<_>>();
+        skipped.sort();
+        assert_eq!(skipped, expected);
+        reported.borrow_mut().sort();
+        assert_eq!(*reported.borrow(), expected);
+
+        // and everything else is there
+        let rootfs_blob = Rootfs::open(
+            image
+                .open_compressed_blob::<compression::Noop>(&rootfs_desc.digest)
+                .unwrap(),
+        )
+        .unwrap();
+        let metadata_digest = rootfs_blob.metadatas[0].try_into().unwrap();
+        let mut blob = image
+            .open_metadata_blob::<compression::Noop>(&metadata_digest)
+            .unwrap();
+        let inodes = blob.read_inodes().unwrap();
+        let names = match inodes[0].mode {
+            InodeMode::Dir { offset } => blob
+                .read_dir_list(offset)
+                .unwrap()
+                .entries
+                .into_iter()
+                .map(|de| de.name)
+                .collect::<HashSet<_>>(),
+            _ => panic!("bad inode mode: {:?}", inodes[0].mode),
+        };
+        assert!(names.contains(OsStr::new("a")));
+        assert!(names.contains(OsStr::new("locked")));
+        assert!(!names.contains(OsStr::new("dangling")));
+        assert_eq!(
+            names.contains(OsStr::new("unreadable")),
+            !expected.contains(&rootfs.join("unreadable"))
+        );
+    }
 }
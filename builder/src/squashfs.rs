@@ -0,0 +1,1650 @@
+use std::cmp::min;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::ffi::OsString;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::os::unix::ffi::OsStringExt;
+use std::path::PathBuf;
+use std::vec;
+
+use zstd_seekable::DStream;
+
+use format::{InodeAdditional, InodeMode, Result, Timestamp, Xattr, PERMISSION_BITS};
+use oci::{Descriptor, Image};
+
+use crate::inflate::zlib_decompress;
+use crate::{BuildOptions, BuildStats, Entry, EntryKind, RootfsBuilder};
+
+// everything here is squashfs 4.0, which is what mksquashfs has written since 2009; it's all
+// little endian
+const MAGIC: u32 = 0x7371_7368;
+const SUPERBLOCK_SIZE: usize = 96;
+// metadata (inodes, directories and the tables) is stored in blocks of at most this much, each
+// compressed on its own
+const METADATA_BLOCK_SIZE: usize = 8192;
+const METADATA_UNCOMPRESSED: u16 = 0x8000;
+const DATA_UNCOMPRESSED: u32 = 1 << 24;
+const NO_FRAGMENT: u32 = 0xffff_ffff;
+const NO_XATTRS: u32 = 0xffff_ffff;
+const NO_TABLE: u64 = u64::MAX;
+// the value is somewhere else in the xattr table, which is how mksquashfs stores duplicates
+const XATTR_OUT_OF_LINE: u16 = 0x100;
+
+const GZIP: u16 = 1;
+const ZSTD: u16 = 6;
+
+pub fn build_from_squashfs<R: Read + Seek>(squashfs: R, oci: &Image) -> Result<Descriptor> {
+    build_from_squashfs_with_options(squashfs, oci, &BuildOptions::default())
+}
+
+/// Builds an image from a squashfs image, reading it directly rather than mounting or unpacking
+/// it. Ownership, permissions, mtimes, xattrs, symlinks, device numbers, hard links and holes all
+/// carry over. Only gzip and zstd compressed images can be read.
+pub fn build_from_squashfs_with_options<R: Read + Seek>(
+    squashfs: R,
+    oci: &Image,
+    options: &BuildOptions,
+) -> Result<Descriptor> {
+    build_from_squashfs_with_stats(squashfs, oci, options).map(|(desc, _)| desc)
+}
+
+/// Like build_from_squashfs_with_options(), but also says how well the chunks deduplicated.
+pub fn build_from_squashfs_with_stats<R: Read + Seek>(
+    squashfs: R,
+    oci: &Image,
+    options: &BuildOptions,
+) -> Result<(Descriptor, BuildStats)> {
+    let mut fs = Squashfs::open(squashfs)?;
+    let mut builder = RootfsBuilder::new(oci, options)?;
+    // where each inode with more than one name was first seen
+    let mut links = HashMap::new();
+    // so that a corrupt image can't send us round in circles
+    let mut dirs = HashSet::new();
+
+    // entries get added parents first, and in the order their directories list them
+    let mut todo = vec![(PathBuf::new(), fs.root_inode)];
+    while let Some((path, reference)) = todo.pop() {
+        let inode = fs.inode(reference)?;
+        if path.as_os_str().is_empty() && !matches!(inode.kind, Kind::Dir { .. }) {
+            return Err(corrupt("the root isn't a directory").into());
+        }
+        let xattrs = fs.xattrs(inode.xattrs)?;
+        let mtime = Timestamp {
+            sec: inode.mtime as i64,
+            nsec: 0,
+        };
+        let mut entry = Entry {
+            path,
+            uid: inode.uid,
+            gid: inode.gid,
+            mtime,
+            // squashfs doesn't keep atimes
+            atime: mtime,
+            permissions: inode.permissions & PERMISSION_BITS as u16,
+            kind: EntryKind::Dir,
+            additional: None,
+        };
+
+        if !matches!(inode.kind, Kind::Dir { .. }) {
+            if let Some(first) = links.get(&inode.number) {
+                entry.kind = EntryKind::HardLink(PathBuf::clone(first));
+                builder.add(entry)?;
+                continue;
+            }
+            links.insert(inode.number, entry.path.clone());
+        }
+
+        let mut symlink_target = None;
+        let mut data;
+        match inode.kind {
+            Kind::Dir {
+                block,
+                offset,
+                size,
+            } => {
+                if !dirs.insert(inode.number) {
+                    return Err(corrupt("directory loop").into());
+                }
+                let children = fs.dir(block, offset, size)?;
+                for (name, child) in children.into_iter().rev() {
+                    todo.push((entry.path.join(name), child));
+                }
+            }
+            Kind::File {
+                start,
+                size,
+                blocks,
+                fragment,
+            } => {
+                let (file_data, holes) = fs.file_data(start, size, &blocks, fragment)?;
+                data = file_data;
+                entry.kind = EntryKind::File(&mut data, holes);
+            }
+            Kind::Symlink(target) => {
+                symlink_target = Some(target);
+                entry.kind = EntryKind::Other(InodeMode::Lnk);
+            }
+            Kind::Block(dev) => {
+                let (major, minor) = device(dev);
+                entry.kind = EntryKind::Other(InodeMode::Blk { major, minor });
+            }
+            Kind::Char(dev) => {
+                let (major, minor) = device(dev);
+                entry.kind = EntryKind::Other(InodeMode::Chr { major, minor });
+            }
+            Kind::Fifo => entry.kind = EntryKind::Other(InodeMode::Fifo),
+            Kind::Socket => entry.kind = EntryKind::Other(InodeMode::Sock),
+        }
+
+        if symlink_target.is_some() || !xattrs.is_empty() {
+            entry.additional = Some(InodeAdditional {
+                xattrs,
+                symlink_target,
+                file_digest: None,
+            });
+        }
+        builder.add(entry)?;
+    }
+
+    builder.finish()
+}
+
+fn corrupt(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("corrupt squashfs image: {}", what),
+    )
+}
+
+// the kernel's old_decode_dev()/new_decode_dev(), which is what squashfs stores
+fn device(dev: u32) -> (u64, u64) {
+    let major = (dev >> 8) & 0xfff;
+    let minor = (dev & 0xff) | ((dev >> 12) & 0xfff00);
+    (major as u64, minor as u64)
+}
+
+fn le_u16(r: &mut impl Read) -> io::Result<u16> {
+    let mut buf = [0_u8; 2];
+    r.read_exact(&mut buf)?;
+    Ok(u16::from_le_bytes(buf))
+}
+
+fn le_u32(r: &mut impl Read) -> io::Result<u32> {
+    let mut buf = [0_u8; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn le_u64(r: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0_u8; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn zstd_error(e: zstd_seekable::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, e.to_string())
+}
+
+fn zstd_decompress(data: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+    let mut stream = DStream::new().map_err(zstd_error)?;
+    let mut out = vec![0_u8; limit];
+    let (mut written, mut read) = (0, 0);
+    // DStream doesn't pass on errors, but it stops getting anywhere when it hits one
+    loop {
+        let (w, r) = stream
+            .decompress(&mut out[written..], &data[read..])
+            .map_err(zstd_error)?;
+        if w == 0 && r == 0 {
+            break;
+        }
+        written += w;
+        read += r;
+    }
+    if read < data.len() {
+        return Err(corrupt("bad zstd block"));
+    }
+    out.truncate(written);
+    Ok(out)
+}
+
+enum Kind {
+    // where the directory's listing is in the directory table, and how long it is
+    Dir {
+        block: u32,
+        offset: u16,
+        size: u32,
+    },
+    File {
+        start: u64,
+        size: u64,
+        blocks: Vec<u32>,
+        // which fragment the end of the file is in, and where in it
+        fragment: Option<(u32, u32)>,
+    },
+    Symlink(OsString),
+    Block(u32),
+    Char(u32),
+    Fifo,
+    Socket,
+}
+
+struct Inode {
+    number: u32,
+    permissions: u16,
+    uid: u32,
+    gid: u32,
+    mtime: u32,
+    xattrs: u32,
+    kind: Kind,
+}
+
+struct Squashfs<R> {
+    file: R,
+    compression: u16,
+    block_size: u32,
+    root_inode: u64,
+    inode_table: u64,
+    dir_table: u64,
+    ids: Vec<u32>,
+    // where each fragment block is and its size word
+    fragments: Vec<(u64, u32)>,
+    xattr_table: u64,
+    // where each set of xattrs starts in the xattr table, and how many there are
+    xattr_ids: Vec<(u64, u32)>,
+    // lots of small files share a fragment, one after the other
+    last_fragment: Option<(u32, Vec<u8>)>,
+}
+
+impl<R: Read + Seek> Squashfs<R> {
+    fn open(mut file: R) -> io::Result<Self> {
+        let mut sb = [0_u8; SUPERBLOCK_SIZE];
+        let not_squashfs = || io::Error::new(io::ErrorKind::InvalidData, "not a squashfs image");
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut sb).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => not_squashfs(),
+            _ => e,
+        })?;
+        let u16_at = |off: usize| u16::from_le_bytes([sb[off], sb[off + 1]]);
+        let u32_at = |off: usize| u32::from_le_bytes(sb[off..off + 4].try_into().unwrap());
+        let u64_at = |off: usize| u64::from_le_bytes(sb[off..off + 8].try_into().unwrap());
+
+        if u32_at(0) != MAGIC {
+            return Err(not_squashfs());
+        }
+        let (major, minor) = (u16_at(28), u16_at(30));
+        if (major, minor) != (4, 0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported squashfs version {}.{}", major, minor),
+            ));
+        }
+        let compression = u16_at(20);
+        if compression != GZIP && compression != ZSTD {
+            let name = match compression {
+                2 => "lzma",
+                3 => "lzo",
+                4 => "xz",
+                5 => "lz4",
+                _ => "unknown",
+            };
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "{} compressed squashfs images aren't supported, only gzip and zstd",
+                    name
+                ),
+            ));
+        }
+        let block_size = u32_at(12);
+        if !block_size.is_power_of_two() || !(4096..=1 << 20).contains(&block_size) {
+            return Err(corrupt("bad block size"));
+        }
+
+        let mut fs = Squashfs {
+            file,
+            compression,
+            block_size,
+            root_inode: u64_at(32),
+            inode_table: u64_at(64),
+            dir_table: u64_at(72),
+            ids: Vec::new(),
+            fragments: Vec::new(),
+            xattr_table: 0,
+            xattr_ids: Vec::new(),
+            last_fragment: None,
+        };
+
+        let id_count = u16_at(26) as usize;
+        fs.ids = fs
+            .table(u64_at(48), id_count, 4)?
+            .chunks(4)
+            .map(|id| u32::from_le_bytes(id.try_into().unwrap()))
+            .collect();
+
+        let (fragment_count, fragment_table) = (u32_at(16) as usize, u64_at(80));
+        if fragment_table != NO_TABLE {
+            fs.fragments = fs
+                .table(fragment_table, fragment_count, 16)?
+                .chunks(16)
+                .map(|f| {
+                    (
+                        u64::from_le_bytes(f[..8].try_into().unwrap()),
+                        u32::from_le_bytes(f[8..12].try_into().unwrap()),
+                    )
+                })
+                .collect();
+        }
+
+        let xattr_id_table = u64_at(56);
+        if xattr_id_table != NO_TABLE {
+            fs.file.seek(SeekFrom::Start(xattr_id_table))?;
+            fs.xattr_table = le_u64(&mut fs.file)?;
+            let count = le_u32(&mut fs.file)? as usize;
+            fs.xattr_ids = fs
+                .table(xattr_id_table + 16, count, 16)?
+                .chunks(16)
+                .map(|x| {
+                    (
+                        u64::from_le_bytes(x[..8].try_into().unwrap()),
+                        u32::from_le_bytes(x[8..12].try_into().unwrap()),
+                    )
+                })
+                .collect();
+        }
+
+        Ok(fs)
+    }
+
+    fn decompress(&self, data: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+        match self.compression {
+            GZIP => zlib_decompress(data, limit),
+            _ => zstd_decompress(data, limit),
+        }
+    }
+
+    // the metadata block at pos, and where the one after it starts
+    fn metadata_block(&mut self, pos: u64) -> io::Result<(Vec<u8>, u64)> {
+        self.file.seek(SeekFrom::Start(pos))?;
+        let header = le_u16(&mut self.file)?;
+        let size = (header & !METADATA_UNCOMPRESSED) as usize;
+        let mut data = vec![0_u8; size];
+        self.file.read_exact(&mut data)?;
+        if header & METADATA_UNCOMPRESSED == 0 {
+            data = self.decompress(&data, METADATA_BLOCK_SIZE)?;
+        }
+        Ok((data, pos + 2 + size as u64))
+    }
+
+    // the ids, fragments and xattr ids are each stored as a list of pointers to the metadata
+    // blocks that hold the entries, which is at pos
+    fn table(&mut self, pos: u64, count: usize, entry_size: usize) -> io::Result<Vec<u8>> {
+        let len = count * entry_size;
+        let blocks = (len + METADATA_BLOCK_SIZE - 1) / METADATA_BLOCK_SIZE;
+        self.file.seek(SeekFrom::Start(pos))?;
+        let mut pointers = vec![0_u8; blocks * 8];
+        self.file.read_exact(&mut pointers)?;
+        let mut table = Vec::with_capacity(len);
+        for pointer in pointers.chunks(8) {
+            let (block, _) =
+                self.metadata_block(u64::from_le_bytes(pointer.try_into().unwrap()))?;
+            table.extend(block);
+        }
+        if table.len() < len {
+            return Err(corrupt("table too short"));
+        }
+        table.truncate(len);
+        Ok(table)
+    }
+
+    fn inode(&mut self, reference: u64) -> io::Result<Inode> {
+        let block_size = self.block_size as u64;
+        let start = self
+            .inode_table
+            .checked_add(reference >> 16)
+            .ok_or_else(|| corrupt("bad inode reference"))?;
+        let mut r = Metadata::new(self, start, (reference & 0xffff) as usize)?;
+        let inode_type = le_u16(&mut r)?;
+        let permissions = le_u16(&mut r)?;
+        let uid = le_u16(&mut r)?;
+        let gid = le_u16(&mut r)?;
+        let mtime = le_u32(&mut r)?;
+        let number = le_u32(&mut r)?;
+        let mut xattrs = NO_XATTRS;
+
+        let kind = match inode_type {
+            1 => {
+                let block = le_u32(&mut r)?;
+                let _nlink = le_u32(&mut r)?;
+                let size = le_u16(&mut r)? as u32;
+                let offset = le_u16(&mut r)?;
+                Kind::Dir {
+                    block,
+                    offset,
+                    size,
+                }
+            }
+            8 => {
+                let _nlink = le_u32(&mut r)?;
+                let size = le_u32(&mut r)?;
+                let block = le_u32(&mut r)?;
+                let _parent = le_u32(&mut r)?;
+                let _index_count = le_u16(&mut r)?;
+                let offset = le_u16(&mut r)?;
+                xattrs = le_u32(&mut r)?;
+                Kind::Dir {
+                    block,
+                    offset,
+                    size,
+                }
+            }
+            2 | 9 => {
+                let (start, fragment, fragment_offset, size);
+                if inode_type == 2 {
+                    start = le_u32(&mut r)? as u64;
+                    fragment = le_u32(&mut r)?;
+                    fragment_offset = le_u32(&mut r)?;
+                    size = le_u32(&mut r)? as u64;
+                } else {
+                    start = le_u64(&mut r)?;
+                    size = le_u64(&mut r)?;
+                    let _sparse = le_u64(&mut r)?;
+                    let _nlink = le_u32(&mut r)?;
+                    fragment = le_u32(&mut r)?;
+                    fragment_offset = le_u32(&mut r)?;
+                    xattrs = le_u32(&mut r)?;
+                }
+                // the end of the file is either in a fragment or in a block of its own
+                let (count, fragment) = if fragment == NO_FRAGMENT {
+                    let rounded = size
+                        .checked_add(block_size - 1)
+                        .ok_or_else(|| corrupt("bad file size"))?;
+                    (rounded / block_size, None)
+                } else {
+                    (size / block_size, Some((fragment, fragment_offset)))
+                };
+                let blocks = (0..count)
+                    .map(|_| le_u32(&mut r))
+                    .collect::<io::Result<_>>()?;
+                Kind::File {
+                    start,
+                    size,
+                    blocks,
+                    fragment,
+                }
+            }
+            3 | 10 => {
+                let _nlink = le_u32(&mut r)?;
+                let len = le_u32(&mut r)? as usize;
+                if len > 4096 {
+                    return Err(corrupt("symlink target too long"));
+                }
+                let mut target = vec![0_u8; len];
+                r.read_exact(&mut target)?;
+                if inode_type == 10 {
+                    xattrs = le_u32(&mut r)?;
+                }
+                Kind::Symlink(OsString::from_vec(target))
+            }
+            4 | 5 | 11 | 12 => {
+                let _nlink = le_u32(&mut r)?;
+                let dev = le_u32(&mut r)?;
+                if inode_type > 7 {
+                    xattrs = le_u32(&mut r)?;
+                }
+                if matches!(inode_type, 4 | 11) {
+                    Kind::Block(dev)
+                } else {
+                    Kind::Char(dev)
+                }
+            }
+            6 | 7 | 13 | 14 => {
+                let _nlink = le_u32(&mut r)?;
+                if inode_type > 7 {
+                    xattrs = le_u32(&mut r)?;
+                }
+                if matches!(inode_type, 6 | 13) {
+                    Kind::Fifo
+                } else {
+                    Kind::Socket
+                }
+            }
+            t => return Err(corrupt(&format!("unknown inode type {}", t))),
+        };
+
+        let id = |index: u16| {
+            self.ids
+                .get(index as usize)
+                .copied()
+                .ok_or_else(|| corrupt("bad uid or gid"))
+        };
+        Ok(Inode {
+            number,
+            permissions,
+            uid: id(uid)?,
+            gid: id(gid)?,
+            mtime,
+            xattrs,
+            kind,
+        })
+    }
+
+    // a directory's entries, and where their inodes are
+    fn dir(&mut self, block: u32, offset: u16, size: u32) -> io::Result<Vec<(OsString, u64)>> {
+        // the size counts the . and .. entries, which aren't actually there
+        let mut left = (size as usize).saturating_sub(3);
+        let mut entries = Vec::new();
+        if left == 0 {
+            return Ok(entries);
+        }
+
+        let start = self
+            .dir_table
+            .checked_add(block as u64)
+            .ok_or_else(|| corrupt("bad directory"))?;
+        let mut r = Metadata::new(self, start, offset as usize)?;
+        // entries come in runs whose inodes are all in the same metadata block
+        while left > 0 {
+            left = left
+                .checked_sub(12)
+                .ok_or_else(|| corrupt("bad directory"))?;
+            let count = le_u32(&mut r)? as usize + 1;
+            let inode_block = le_u32(&mut r)? as u64;
+            let _inode_number = le_u32(&mut r)?;
+            for _ in 0..count {
+                left = left
+                    .checked_sub(8)
+                    .ok_or_else(|| corrupt("bad directory"))?;
+                let inode_offset = le_u16(&mut r)? as u64;
+                let _inode_number = le_u16(&mut r)?;
+                let _inode_type = le_u16(&mut r)?;
+                let len = le_u16(&mut r)? as usize + 1;
+                left = left
+                    .checked_sub(len)
+                    .ok_or_else(|| corrupt("bad directory"))?;
+                let mut name = vec![0_u8; len];
+                r.read_exact(&mut name)?;
+                if name == b"." || name == b".." || name.contains(&b'/') {
+                    return Err(corrupt("bad file name"));
+                }
+                entries.push((OsString::from_vec(name), inode_block << 16 | inode_offset));
+            }
+        }
+        Ok(entries)
+    }
+
+    fn xattrs(&mut self, index: u32) -> io::Result<Vec<Xattr>> {
+        if index == NO_XATTRS {
+            return Ok(Vec::new());
+        }
+        let (reference, count) = *self
+            .xattr_ids
+            .get(index as usize)
+            .ok_or_else(|| corrupt("bad xattr index"))?;
+
+        // values stored elsewhere are read once we're done with the keys
+        let mut xattrs = Vec::new();
+        let mut elsewhere = Vec::new();
+        let start = self.xattr_table;
+        let at = |reference: u64| {
+            start
+                .checked_add(reference >> 16)
+                .ok_or_else(|| corrupt("bad xattr reference"))
+        };
+        let mut r = Metadata::new(self, at(reference)?, (reference & 0xffff) as usize)?;
+        for _ in 0..count {
+            let kind = le_u16(&mut r)?;
+            let len = le_u16(&mut r)? as usize;
+            let prefix: &[u8] = match kind & 0xff {
+                0 => b"user.",
+                1 => b"trusted.",
+                2 => b"security.",
+                _ => return Err(corrupt("bad xattr type")),
+            };
+            let mut key = prefix.to_vec();
+            let mut name = vec![0_u8; len];
+            r.read_exact(&mut name)?;
+            key.extend(name);
+
+            let value = if kind & XATTR_OUT_OF_LINE != 0 {
+                if le_u32(&mut r)? != 8 {
+                    return Err(corrupt("bad xattr"));
+                }
+                elsewhere.push((xattrs.len(), le_u64(&mut r)?));
+                Vec::new()
+            } else {
+                xattr_value(&mut r)?
+            };
+            xattrs.push(Xattr {
+                key: OsString::from_vec(key),
+                val: Some(value),
+            });
+        }
+        drop(r);
+
+        for (i, reference) in elsewhere {
+            let mut r = Metadata::new(self, at(reference)?, (reference & 0xffff) as usize)?;
+            xattrs[i].val = Some(xattr_value(&mut r)?);
+        }
+        Ok(xattrs)
+    }
+
+    // the file's contents, less its holes, and where the holes are
+    fn file_data(
+        &mut self,
+        start: u64,
+        size: u64,
+        blocks: &[u32],
+        fragment: Option<(u32, u32)>,
+    ) -> io::Result<(FileData<'_, R>, Vec<Range<u64>>)> {
+        let block_size = self.block_size as u64;
+        let mut pos = start;
+        let mut to_read = Vec::new();
+        let mut holes: Vec<Range<u64>> = Vec::new();
+        for (i, &word) in blocks.iter().enumerate() {
+            let offset = i as u64 * block_size;
+            let len = min(block_size, size - offset);
+            let stored = (word & !DATA_UNCOMPRESSED) as u64;
+            if stored == 0 {
+                match holes.last_mut() {
+                    Some(hole) if hole.end == offset => hole.end += len,
+                    _ => holes.push(offset..offset + len),
+                }
+            } else {
+                to_read.push((pos, word, len as usize));
+                pos = pos
+                    .checked_add(stored)
+                    .ok_or_else(|| corrupt("bad block list"))?;
+            }
+        }
+        let tail = fragment.map(|(index, offset)| {
+            let len = size - blocks.len() as u64 * block_size;
+            (index, offset as usize, len as usize)
+        });
+        let data = FileData {
+            fs: self,
+            blocks: to_read.into_iter(),
+            tail,
+            buf: Vec::new(),
+            pos: 0,
+        };
+        Ok((data, holes))
+    }
+
+    fn data_block(&mut self, pos: u64, word: u32, len: usize) -> io::Result<Vec<u8>> {
+        let mut data = vec![0_u8; (word & !DATA_UNCOMPRESSED) as usize];
+        self.file.seek(SeekFrom::Start(pos))?;
+        self.file.read_exact(&mut data)?;
+        if word & DATA_UNCOMPRESSED == 0 {
+            data = self.decompress(&data, self.block_size as usize)?;
+        }
+        if data.len() != len {
+            return Err(corrupt("data block is the wrong size"));
+        }
+        Ok(data)
+    }
+
+    fn fragment(&mut self, index: u32, offset: usize, len: usize) -> io::Result<Vec<u8>> {
+        if !matches!(&self.last_fragment, Some((i, _)) if *i == index) {
+            let (pos, word) = *self
+                .fragments
+                .get(index as usize)
+                .ok_or_else(|| corrupt("bad fragment index"))?;
+            let mut data = vec![0_u8; (word & !DATA_UNCOMPRESSED) as usize];
+            self.file.seek(SeekFrom::Start(pos))?;
+            self.file.read_exact(&mut data)?;
+            if word & DATA_UNCOMPRESSED == 0 {
+                data = self.decompress(&data, self.block_size as usize)?;
+            }
+            self.last_fragment = Some((index, data));
+        }
+        let (_, data) = self.last_fragment.as_ref().unwrap();
+        data.get(offset..offset + len)
+            .map(|d| d.to_vec())
+            .ok_or_else(|| corrupt("file goes past the end of its fragment"))
+    }
+}
+
+fn xattr_value(r: &mut impl Read) -> io::Result<Vec<u8>> {
+    let len = le_u32(r)? as usize;
+    // the most linux allows
+    if len > 64 * 1024 {
+        return Err(corrupt("xattr value too long"));
+    }
+    let mut value = vec![0_u8; len];
+    r.read_exact(&mut value)?;
+    Ok(value)
+}
+
+// reads metadata as one stream, starting at offset in the block at pos and carrying on through the
+// blocks after it
+struct Metadata<'a, R> {
+    fs: &'a mut Squashfs<R>,
+    next: u64,
+    block: Vec<u8>,
+    pos: usize,
+}
+
+impl<'a, R: Read + Seek> Metadata<'a, R> {
+    fn new(fs: &'a mut Squashfs<R>, pos: u64, offset: usize) -> io::Result<Self> {
+        let (block, next) = fs.metadata_block(pos)?;
+        if offset > block.len() {
+            return Err(corrupt("bad metadata reference"));
+        }
+        Ok(Metadata {
+            fs,
+            next,
+            block,
+            pos: offset,
+        })
+    }
+}
+
+impl<R: Read + Seek> Read for Metadata<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.block.len() {
+            let (block, next) = self.fs.metadata_block(self.next)?;
+            if block.is_empty() {
+                return Err(corrupt("empty metadata block"));
+            }
+            self.block = block;
+            self.next = next;
+            self.pos = 0;
+        }
+        let n = min(buf.len(), self.block.len() - self.pos);
+        buf[..n].copy_from_slice(&self.block[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+struct FileData<'a, R> {
+    fs: &'a mut Squashfs<R>,
+    // the blocks that aren't holes: where each is, its size word and how much of the file it has
+    blocks: vec::IntoIter<(u64, u32, usize)>,
+    // the fragment the end of the file is in, where in it, and how long
+    tail: Option<(u32, usize, usize)>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read + Seek> Read for FileData<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buf.len() {
+            self.buf = if let Some((pos, word, len)) = self.blocks.next() {
+                self.fs.data_block(pos, word, len)?
+            } else if let Some((index, offset, len)) = self.tail.take() {
+                self.fs.fragment(index, offset, len)?
+            } else {
+                return Ok(0);
+            };
+            self.pos = 0;
+        }
+        let n = min(buf.len(), self.buf.len() - self.pos);
+        buf[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::fs;
+    use std::io::Cursor;
+
+    use tar::{Builder, EntryType, Header};
+    use tempfile::tempdir;
+    use zstd_seekable::CStream;
+
+    use format::{DigestAlgorithm, MetadataBlob, Rootfs};
+
+    use super::*;
+    use crate::from_tar::{build_from_tar, PAX_XATTR_PREFIX};
+    use crate::inflate::adler32;
+
+    const BLOCK_SIZE: usize = 4096;
+
+    enum TestKind {
+        Dir,
+        File(Vec<u8>),
+        Symlink(&'static str),
+        Fifo,
+        Char(u32, u32),
+        // another name for an earlier file
+        Link(&'static str),
+    }
+
+    struct TestEntry {
+        // "" for the root
+        path: String,
+        kind: TestKind,
+        uid: u32,
+        gid: u32,
+        mode: u16,
+        mtime: u32,
+        xattrs: Vec<(&'static str, &'static [u8])>,
+    }
+
+    fn entry(path: &str, kind: TestKind) -> TestEntry {
+        TestEntry {
+            path: path.to_string(),
+            kind,
+            uid: 0,
+            gid: 0,
+            mode: 0o755,
+            mtime: 1_626_000_000,
+            xattrs: Vec::new(),
+        }
+    }
+
+    // whether the data ended up compressed. the gzip streams written here are stored blocks, which
+    // never come out smaller, but should still be read as compressed.
+    fn pack(compression: u16, data: &[u8]) -> (Vec<u8>, bool) {
+        let packed = if compression == ZSTD {
+            let mut stream = CStream::new(3).unwrap();
+            let mut out = vec![0_u8; data.len() + 1024];
+            let (mut written, read, _) = stream.compress(&mut out, data).unwrap();
+            assert_eq!(read, data.len());
+            written += stream.end(&mut out[written..]).unwrap();
+            out.truncate(written);
+            out
+        } else {
+            let mut out = vec![0x78, 0x01];
+            let len = data.len() as u16;
+            out.push(1);
+            out.extend(len.to_le_bytes());
+            out.extend((!len).to_le_bytes());
+            out.extend(data);
+            out.extend(adler32(data).to_be_bytes());
+            out
+        };
+        if compression == GZIP || packed.len() < data.len() {
+            (packed, true)
+        } else {
+            (data.to_vec(), false)
+        }
+    }
+
+    // writes metadata blocks, compressed if compression is given, and returns where each one is
+    fn metadata(image: &mut Vec<u8>, stream: &[u8], compression: Option<u16>) -> Vec<u64> {
+        let mut blocks = Vec::new();
+        for block in stream.chunks(METADATA_BLOCK_SIZE) {
+            blocks.push(image.len() as u64);
+            let (packed, compressed) = match compression {
+                Some(compression) => pack(compression, block),
+                None => (block.to_vec(), false),
+            };
+            let header = packed.len() as u16 | if compressed { 0 } else { METADATA_UNCOMPRESSED };
+            image.extend(header.to_le_bytes());
+            image.extend(packed);
+        }
+        blocks
+    }
+
+    // the ids, fragments and xattr ids: compressed metadata blocks and a list of where they are
+    fn table(image: &mut Vec<u8>, stream: &[u8], compression: u16) -> u64 {
+        let blocks = metadata(image, stream, Some(compression));
+        let start = image.len() as u64;
+        for block in blocks {
+            image.extend(block.to_le_bytes());
+        }
+        start
+    }
+
+    // where something at offset in a metadata stream is, given where its blocks are
+    fn reference(blocks: &[u64], offset: usize) -> u64 {
+        (blocks[offset / METADATA_BLOCK_SIZE] - blocks[0]) << 16
+            | (offset % METADATA_BLOCK_SIZE) as u64
+    }
+
+    // the same, for a stream written uncompressed, whose blocks are all the same size
+    fn uncompressed_reference(offset: usize) -> u64 {
+        let block = offset / METADATA_BLOCK_SIZE * (METADATA_BLOCK_SIZE + 2);
+        (block << 16 | (offset % METADATA_BLOCK_SIZE)) as u64
+    }
+
+    // references to inodes and xattrs depend on where the metadata blocks holding them end up, so
+    // those are stored uncompressed to know that up front; everything else is compressed
+    fn squashfs(entries: &[TestEntry], compression: u16) -> Vec<u8> {
+        let mut image = vec![0_u8; SUPERBLOCK_SIZE];
+        let index = |path: &str| entries.iter().position(|e| e.path == path).unwrap();
+        let inode_of = |i: usize| match entries[i].kind {
+            TestKind::Link(target) => index(target),
+            _ => i,
+        };
+        let inodes = (0..entries.len())
+            .filter(|&i| inode_of(i) == i)
+            .collect::<Vec<_>>();
+        let number = |i: usize| inodes.iter().position(|&j| j == inode_of(i)).unwrap() as u32 + 1;
+
+        let mut ids = Vec::new();
+        for e in entries {
+            for id in [e.uid, e.gid] {
+                if !ids.contains(&id) {
+                    ids.push(id);
+                }
+            }
+        }
+        let id = |id: u32| ids.iter().position(|&i| i == id).unwrap() as u16;
+
+        // file data: files of more than a block get their own blocks, smaller ones share fragments
+        let mut files = HashMap::new();
+        let mut fragments = Vec::new();
+        let mut fragment: Vec<u8> = Vec::new();
+        let flush = |image: &mut Vec<u8>, fragments: &mut Vec<(u64, u32)>, fragment: &[u8]| {
+            let (packed, compressed) = pack(compression, fragment);
+            let word = packed.len() as u32 | if compressed { 0 } else { DATA_UNCOMPRESSED };
+            fragments.push((image.len() as u64, word));
+            image.extend(packed);
+        };
+        for &i in &inodes {
+            if let TestKind::File(data) = &entries[i].kind {
+                let start = image.len() as u64;
+                let mut words = Vec::new();
+                let mut tail = None;
+                for block in data.chunks(BLOCK_SIZE) {
+                    if data.len() < BLOCK_SIZE {
+                        if fragment.len() + block.len() > BLOCK_SIZE {
+                            flush(&mut image, &mut fragments, &fragment);
+                            fragment.clear();
+                        }
+                        tail = Some((fragments.len() as u32, fragment.len() as u32));
+                        fragment.extend(block);
+                    } else if block.iter().all(|&b| b == 0) {
+                        words.push(0);
+                    } else {
+                        let (packed, compressed) = pack(compression, block);
+                        words.push(
+                            packed.len() as u32 | if compressed { 0 } else { DATA_UNCOMPRESSED },
+                        );
+                        image.extend(packed);
+                    }
+                }
+                files.insert(i, (start, words, tail));
+            }
+        }
+        if !fragment.is_empty() {
+            flush(&mut image, &mut fragments, &fragment);
+        }
+
+        // the xattrs, with values that were already written pointing back at those, like
+        // mksquashfs does
+        let mut xattr_stream = Vec::new();
+        let mut xattr_ids = Vec::new();
+        let mut values: HashMap<&[u8], usize> = HashMap::new();
+        let mut xattr_index = HashMap::new();
+        for &i in &inodes {
+            if entries[i].xattrs.is_empty() {
+                continue;
+            }
+            xattr_index.insert(i, xattr_ids.len() as u32);
+            let start = xattr_stream.len();
+            for (key, value) in &entries[i].xattrs {
+                let (prefix, name) = key.split_once('.').unwrap();
+                let mut kind = match prefix {
+                    "user" => 0_u16,
+                    "trusted" => 1,
+                    "security" => 2,
+                    _ => unreachable!(),
+                };
+                let earlier = values.get(value).copied();
+                if earlier.is_some() {
+                    kind |= XATTR_OUT_OF_LINE;
+                }
+                xattr_stream.extend(kind.to_le_bytes());
+                xattr_stream.extend((name.len() as u16).to_le_bytes());
+                xattr_stream.extend(name.as_bytes());
+                match earlier {
+                    Some(offset) => {
+                        xattr_stream.extend(8_u32.to_le_bytes());
+                        xattr_stream.extend(uncompressed_reference(offset).to_le_bytes());
+                    }
+                    None => {
+                        values.insert(value, xattr_stream.len());
+                        xattr_stream.extend((value.len() as u32).to_le_bytes());
+                        xattr_stream.extend(*value);
+                    }
+                }
+            }
+            xattr_ids.push((start, entries[i].xattrs.len() as u32));
+        }
+
+        // every inode is a fixed size, whatever's in it, so lay them out with made up directory
+        // references first to find out where they all go
+        let inode_bytes = |i: usize, dirs: &HashMap<usize, (u64, usize)>| {
+            let e = &entries[i];
+            let xattrs = xattr_index.get(&i).copied();
+            let mut out = Vec::new();
+            let mut put = |b: &[u8]| out.extend_from_slice(b);
+            let (basic, extended) = match e.kind {
+                TestKind::Dir => (1_u16, 8_u16),
+                TestKind::File(_) => (2, 9),
+                TestKind::Symlink(_) => (3, 10),
+                TestKind::Char(..) => (5, 12),
+                TestKind::Fifo => (6, 13),
+                TestKind::Link(_) => unreachable!(),
+            };
+            let sparse = files
+                .get(&i)
+                .map_or(false, |(_, words, _)| words.contains(&0));
+            let inode_type = if xattrs.is_some() || sparse {
+                extended
+            } else {
+                basic
+            };
+            put(&inode_type.to_le_bytes());
+            put(&e.mode.to_le_bytes());
+            put(&id(e.uid).to_le_bytes());
+            put(&id(e.gid).to_le_bytes());
+            put(&e.mtime.to_le_bytes());
+            put(&number(i).to_le_bytes());
+            let xattrs = xattrs.unwrap_or(NO_XATTRS);
+            let extended = inode_type == extended;
+            match &e.kind {
+                TestKind::Dir => {
+                    let (block, len) = dirs.get(&i).copied().unwrap_or((0, 0));
+                    let size = len as u32 + 3;
+                    if extended {
+                        put(&2_u32.to_le_bytes());
+                        put(&size.to_le_bytes());
+                        put(&((block >> 16) as u32).to_le_bytes());
+                        put(&1_u32.to_le_bytes());
+                        put(&0_u16.to_le_bytes());
+                        put(&(block as u16).to_le_bytes());
+                        put(&xattrs.to_le_bytes());
+                    } else {
+                        put(&((block >> 16) as u32).to_le_bytes());
+                        put(&2_u32.to_le_bytes());
+                        put(&(size as u16).to_le_bytes());
+                        put(&(block as u16).to_le_bytes());
+                        put(&1_u32.to_le_bytes());
+                    }
+                }
+                TestKind::File(data) => {
+                    let (start, words, tail) = &files[&i];
+                    let (index, offset) = tail.unwrap_or((NO_FRAGMENT, 0));
+                    if extended {
+                        put(&start.to_le_bytes());
+                        put(&(data.len() as u64).to_le_bytes());
+                        put(&0_u64.to_le_bytes());
+                        put(&1_u32.to_le_bytes());
+                        put(&index.to_le_bytes());
+                        put(&offset.to_le_bytes());
+                        put(&xattrs.to_le_bytes());
+                    } else {
+                        put(&(*start as u32).to_le_bytes());
+                        put(&index.to_le_bytes());
+                        put(&offset.to_le_bytes());
+                        put(&(data.len() as u32).to_le_bytes());
+                    }
+                    for word in words {
+                        put(&word.to_le_bytes());
+                    }
+                }
+                TestKind::Symlink(target) => {
+                    put(&1_u32.to_le_bytes());
+                    put(&(target.len() as u32).to_le_bytes());
+                    put(target.as_bytes());
+                    if extended {
+                        put(&xattrs.to_le_bytes());
+                    }
+                }
+                TestKind::Char(major, minor) => {
+                    let dev = (minor & 0xff) | (major << 8) | ((minor & !0xff) << 12);
+                    put(&1_u32.to_le_bytes());
+                    put(&dev.to_le_bytes());
+                    if extended {
+                        put(&xattrs.to_le_bytes());
+                    }
+                }
+                TestKind::Fifo => {
+                    put(&1_u32.to_le_bytes());
+                    if extended {
+                        put(&xattrs.to_le_bytes());
+                    }
+                }
+                TestKind::Link(_) => unreachable!(),
+            }
+            out
+        };
+        let mut offsets = HashMap::new();
+        let mut len = 0;
+        for &i in &inodes {
+            offsets.insert(i, len);
+            len += inode_bytes(i, &HashMap::new()).len();
+        }
+        let inode_ref = |i: usize| uncompressed_reference(offsets[&inode_of(i)]);
+
+        // a run of one entry per child, which is allowed, if wasteful
+        let mut dir_stream = Vec::new();
+        let mut listings = Vec::new();
+        for &i in &inodes {
+            if !matches!(entries[i].kind, TestKind::Dir) {
+                continue;
+            }
+            let start = dir_stream.len();
+            let parent = entries[i].path.as_str();
+            for (j, child) in entries.iter().enumerate() {
+                let name = match child.path.rsplit_once('/') {
+                    Some((p, name)) if p == parent => name,
+                    None if parent.is_empty() && !child.path.is_empty() => &child.path,
+                    _ => continue,
+                };
+                let child_type: u16 = match entries[inode_of(j)].kind {
+                    TestKind::Dir => 1,
+                    TestKind::File(_) => 2,
+                    TestKind::Symlink(_) => 3,
+                    TestKind::Char(..) => 5,
+                    TestKind::Fifo => 6,
+                    TestKind::Link(_) => unreachable!(),
+                };
+                let reference = inode_ref(j);
+                dir_stream.extend(0_u32.to_le_bytes());
+                dir_stream.extend(((reference >> 16) as u32).to_le_bytes());
+                dir_stream.extend(number(j).to_le_bytes());
+                dir_stream.extend((reference as u16).to_le_bytes());
+                dir_stream.extend(0_u16.to_le_bytes());
+                dir_stream.extend(child_type.to_le_bytes());
+                dir_stream.extend((name.len() as u16 - 1).to_le_bytes());
+                dir_stream.extend(name.as_bytes());
+            }
+            listings.push((i, start, dir_stream.len() - start));
+        }
+
+        let inode_table = image.len() as u64;
+        let dir_table =
+            inode_table + (len + (len + METADATA_BLOCK_SIZE - 1) / METADATA_BLOCK_SIZE * 2) as u64;
+        let mut dir_image = Vec::new();
+        let dir_blocks = metadata(&mut dir_image, &dir_stream, Some(compression));
+        let dirs = listings
+            .iter()
+            .map(|&(i, start, len)| {
+                // empty directories don't have a listing to point at
+                let reference = if len == 0 {
+                    0
+                } else {
+                    reference(&dir_blocks, start)
+                };
+                (i, (reference, len))
+            })
+            .collect();
+        let mut inode_stream = Vec::new();
+        for &i in &inodes {
+            inode_stream.extend(inode_bytes(i, &dirs));
+        }
+        assert_eq!(inode_stream.len(), len);
+        metadata(&mut image, &inode_stream, None);
+        assert_eq!(image.len() as u64, dir_table);
+        image.extend(dir_image);
+
+        let fragment_stream = fragments
+            .iter()
+            .flat_map(|(start, word)| {
+                let mut entry = start.to_le_bytes().to_vec();
+                entry.extend(word.to_le_bytes());
+                entry.extend(0_u32.to_le_bytes());
+                entry
+            })
+            .collect::<Vec<_>>();
+        let fragment_table = table(&mut image, &fragment_stream, compression);
+        let id_stream = ids
+            .iter()
+            .flat_map(|id| id.to_le_bytes())
+            .collect::<Vec<_>>();
+        let id_table = table(&mut image, &id_stream, compression);
+
+        let xattr_table = image.len() as u64;
+        metadata(&mut image, &xattr_stream, None);
+        let xattr_id_stream = xattr_ids
+            .iter()
+            .flat_map(|&(start, count)| {
+                let mut entry = uncompressed_reference(start).to_le_bytes().to_vec();
+                entry.extend(count.to_le_bytes());
+                entry.extend(0_u32.to_le_bytes());
+                entry
+            })
+            .collect::<Vec<_>>();
+        let xattr_id_blocks = metadata(&mut image, &xattr_id_stream, Some(compression));
+        let xattr_id_table = image.len() as u64;
+        image.extend(xattr_table.to_le_bytes());
+        image.extend((xattr_ids.len() as u32).to_le_bytes());
+        image.extend(0_u32.to_le_bytes());
+        for block in xattr_id_blocks {
+            image.extend(block.to_le_bytes());
+        }
+
+        let sb = &mut image[..SUPERBLOCK_SIZE];
+        let mut put = |off: usize, b: &[u8]| sb[off..off + b.len()].copy_from_slice(b);
+        put(0, &MAGIC.to_le_bytes());
+        put(4, &(inodes.len() as u32).to_le_bytes());
+        put(12, &(BLOCK_SIZE as u32).to_le_bytes());
+        put(16, &(fragments.len() as u32).to_le_bytes());
+        put(20, &compression.to_le_bytes());
+        put(22, &12_u16.to_le_bytes());
+        put(26, &(ids.len() as u16).to_le_bytes());
+        put(28, &4_u16.to_le_bytes());
+        put(32, &inode_ref(0).to_le_bytes());
+        put(48, &id_table.to_le_bytes());
+        put(56, &xattr_id_table.to_le_bytes());
+        put(64, &inode_table.to_le_bytes());
+        put(72, &dir_table.to_le_bytes());
+        put(80, &fragment_table.to_le_bytes());
+        put(88, &NO_TABLE.to_le_bytes());
+        let len = image.len() as u64;
+        image[40..48].copy_from_slice(&len.to_le_bytes());
+        image
+    }
+
+    // the same thing as a tar archive, with the xattrs in pax headers
+    fn tar(entries: &[TestEntry]) -> Vec<u8> {
+        let mut tar = Builder::new(Vec::new());
+        for e in entries {
+            if !e.xattrs.is_empty() {
+                let mut records = Vec::new();
+                for (key, value) in &e.xattrs {
+                    let record = [
+                        format!("{}{}=", PAX_XATTR_PREFIX, key).as_bytes(),
+                        value,
+                        b"\n",
+                    ]
+                    .concat();
+                    // the length at the front counts its own digits
+                    let mut len = record.len() + 2;
+                    while len != record.len() + 1 + len.to_string().len() {
+                        len = record.len() + 1 + len.to_string().len();
+                    }
+                    records.extend(format!("{} ", len).as_bytes());
+                    records.extend(record);
+                }
+                let mut header = Header::new_ustar();
+                header.set_entry_type(EntryType::XHeader);
+                header.set_size(records.len() as u64);
+                tar.append_data(&mut header, "PaxHeader", &*records)
+                    .unwrap();
+            }
+
+            let mut header = Header::new_gnu();
+            header.set_uid(e.uid as u64);
+            header.set_gid(e.gid as u64);
+            header.set_mode(e.mode as u32);
+            header.set_mtime(e.mtime as u64);
+            let (entry_type, data): (_, &[u8]) = match &e.kind {
+                TestKind::Dir => (EntryType::Directory, b""),
+                TestKind::File(data) => (EntryType::Regular, data),
+                TestKind::Symlink(target) => {
+                    header.set_link_name(target).unwrap();
+                    (EntryType::Symlink, b"")
+                }
+                TestKind::Fifo => (EntryType::Fifo, b""),
+                TestKind::Char(major, minor) => {
+                    header.set_device_major(*major).unwrap();
+                    header.set_device_minor(*minor).unwrap();
+                    (EntryType::Char, b"")
+                }
+                TestKind::Link(target) => {
+                    header.set_link_name(target).unwrap();
+                    (EntryType::Link, b"")
+                }
+            };
+            header.set_entry_type(entry_type);
+            header.set_size(data.len() as u64);
+            let path = if e.path.is_empty() { "." } else { &e.path };
+            tar.append_data(&mut header, path, data).unwrap();
+        }
+        tar.into_inner().unwrap()
+    }
+
+    // bytes that don't compress
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn fixture() -> Vec<TestEntry> {
+        let mut entries = vec![
+            entry("", TestKind::Dir),
+            entry("bin", TestKind::Dir),
+            TestEntry {
+                uid: 1000,
+                xattrs: vec![("security.capability", b"\x01\x00\x00\x02")],
+                ..entry("bin/sh", TestKind::File(b"meshuggah rocks\n".repeat(800)))
+            },
+            entry("bin/sh-link", TestKind::Link("bin/sh")),
+            entry("dev", TestKind::Dir),
+            TestEntry {
+                mode: 0o666,
+                ..entry("dev/null", TestKind::Char(1, 3))
+            },
+            entry("dev/tty300", TestKind::Char(4, 300)),
+            entry("empty", TestKind::File(Vec::new())),
+            TestEntry {
+                xattrs: vec![("user.dir", b"yes")],
+                ..entry("etc", TestKind::Dir)
+            },
+            TestEntry {
+                uid: 1000,
+                gid: 1001,
+                mode: 0o644,
+                xattrs: vec![("user.a", b"same"), ("trusted.b", b"same")],
+                ..entry("etc/hostname", TestKind::File(b"meshuggah\n".to_vec()))
+            },
+            // too big to share a fragment with the others
+            entry("etc/issue", TestKind::File(vec![b'x'; 4000])),
+            TestEntry {
+                mode: 0o600,
+                xattrs: vec![("user.c", b"same")],
+                ..entry("etc/motd", TestKind::File(b"rocks\n".to_vec()))
+            },
+            entry("etc/passwd", TestKind::Symlink("hostname")),
+            TestEntry {
+                mode: 0o600,
+                mtime: 1,
+                ..entry("fifo", TestKind::Fifo)
+            },
+            entry("many", TestKind::Dir),
+        ];
+        // enough that the inodes and the directory listing each take more than a metadata block
+        for i in 0..400 {
+            let name = Box::leak(format!("many/{:03}", i).into_boxed_str());
+            entries.push(entry(name, TestKind::File(vec![i as u8; i % 7])));
+        }
+        entries.push(entry("noise", TestKind::File(noise(5000))));
+        entries
+    }
+
+    fn rootfs_digest(image: &Image, desc: &Descriptor) -> oci::Digest {
+        let rootfs = Rootfs::open(
+            image
+                .open_compressed_blob::<compression::Noop>(&desc.digest)
+                .unwrap(),
+        )
+        .unwrap();
+        oci::Digest::try_from(rootfs.metadatas[0]).unwrap()
+    }
+
+    #[test]
+    fn test_squashfs_matches_tar() {
+        let entries = fixture();
+        let dir = tempdir().unwrap();
+        let image = Image::new(dir.path()).unwrap();
+        let from_tar = build_from_tar(&*tar(&entries), &image).unwrap();
+        for &compression in &[ZSTD, GZIP] {
+            let squashfs = squashfs(&entries, compression);
+            let desc = build_from_squashfs(Cursor::new(squashfs), &image).unwrap();
+            // the same metadata, and so the same data, as unpacking it and building from that
+            assert_eq!(
+                rootfs_digest(&image, &desc),
+                rootfs_digest(&image, &from_tar)
+            );
+        }
+    }
+
+    #[test]
+    fn test_holes() {
+        let mut data = noise(BLOCK_SIZE);
+        data.extend(vec![0; 2 * BLOCK_SIZE]);
+        data.extend(b"the end");
+        let entries = vec![
+            entry("", TestKind::Dir),
+            entry("sparse", TestKind::File(data)),
+        ];
+        let dir = tempdir().unwrap();
+        let image = Image::new(dir.path()).unwrap();
+        let desc = build_from_squashfs(Cursor::new(squashfs(&entries, ZSTD)), &image).unwrap();
+        let mut blob: MetadataBlob = image
+            .open_metadata_blob::<compression::Noop>(&rootfs_digest(&image, &desc))
+            .unwrap();
+        let file = blob.find_inode(2).unwrap().unwrap();
+        let chunks = match file.mode {
+            InodeMode::Reg { offset } => blob.read_file_chunks(offset).unwrap(),
+            mode => panic!("bad inode mode: {:?}", mode),
+        };
+        assert_eq!(
+            chunks.iter().map(|c| c.len).sum::<u64>(),
+            3 * BLOCK_SIZE as u64 + 7
+        );
+        let holes = chunks
+            .iter()
+            .filter(|c| c.blob.is_none())
+            .map(|c| c.len)
+            .collect::<Vec<_>>();
+        assert_eq!(holes, vec![2 * BLOCK_SIZE as u64]);
+    }
+
+    // a line per entry of what a build came out as, parents first, for comparing against the
+    // tree an image was made from
+    fn sha256(data: &[u8]) -> String {
+        let algorithm = DigestAlgorithm::Sha256;
+        oci::Digest::new(algorithm.digest(data), algorithm).reference()
+    }
+
+    fn listing(image: &Image, desc: &Descriptor) -> Vec<String> {
+        let mut blob: MetadataBlob = image
+            .open_metadata_blob::<compression::Noop>(&rootfs_digest(image, desc))
+            .unwrap();
+        let mut names: HashMap<u64, String> = HashMap::new();
+        let mut lines = Vec::new();
+        let mut todo = vec![("/".to_string(), 1)];
+        while let Some((path, ino)) = todo.pop() {
+            if let Some(first) = names.get(&ino) {
+                lines.push(format!("{} link {}", path, first));
+                continue;
+            }
+            names.insert(ino, path.clone());
+            let inode = blob.find_inode(ino).unwrap().unwrap();
+            let additional = inode
+                .additional
+                .map(|r| blob.read_inode_additional(&r).unwrap())
+                .unwrap_or_default();
+            let kind = match inode.mode {
+                InodeMode::Dir { offset } => {
+                    let dir = blob.read_dir_list(offset).unwrap();
+                    for e in dir.entries.into_iter().rev() {
+                        let name = e.name.to_str().unwrap();
+                        todo.push((format!("{}/{}", path.trim_end_matches('/'), name), e.ino));
+                    }
+                    "dir".to_string()
+                }
+                InodeMode::Reg { offset } => {
+                    let mut data = Vec::new();
+                    for chunk in blob.read_file_chunks(offset).unwrap() {
+                        let start = data.len();
+                        data.resize(start + chunk.len as usize, 0);
+                        if let Some(blob) = chunk.blob {
+                            image.fill_from_chunk(blob, 0, &mut data[start..]).unwrap();
+                        }
+                    }
+                    match data.first() {
+                        Some(&b) if data.len() > 64 && data.iter().all(|&c| c == b) => {
+                            format!("file {:?} x {}", b as char, data.len())
+                        }
+                        // anything else that isn't text is only written down by its digest
+                        _ => String::from_utf8(data.clone())
+                            .map(|text| format!("file {:?}", text))
+                            .unwrap_or_else(|_| {
+                                format!("file {} bytes {}", data.len(), sha256(&data))
+                            }),
+                    }
+                }
+                InodeMode::Lnk => format!(
+                    "symlink {}",
+                    additional.symlink_target.unwrap().to_str().unwrap()
+                ),
+                InodeMode::Blk { major, minor } => format!("block {}:{}", major, minor),
+                InodeMode::Chr { major, minor } => format!("char {}:{}", major, minor),
+                InodeMode::Fifo => "fifo".to_string(),
+                InodeMode::Sock => "socket".to_string(),
+                mode => panic!("bad inode mode: {:?}", mode),
+            };
+            let mut line = format!(
+                "{} {} {:o} {}:{} {}",
+                path, kind, inode.permissions, inode.uid, inode.gid, inode.mtime.sec
+            );
+            for xattr in additional.xattrs {
+                let value = String::from_utf8(xattr.val.unwrap_or_default()).unwrap();
+                line += &format!(" {}={}", xattr.key.to_str().unwrap(), value);
+            }
+            lines.push(line);
+        }
+        lines
+    }
+
+    #[test]
+    fn test_mksquashfs_gzip() {
+        // the test image from the sqsh-rs crate (BSD-2-Clause), made with
+        //   mksquashfs $empty_dir test.sqsh -pf pseudo-definitions.txt -reproducible \
+        //     -all-time 1000 -mkfs-time 2000 -root-uid 22 -root-gid 33 -noappend \
+        //     -Xcompression-level 8
+        // it's gzip, with 128K blocks, and has everything squashfs can hold but sparse files
+        let squashfs = fs::read("fixtures/mksquashfs-gzip.sqsh").unwrap();
+        let dir = tempdir().unwrap();
+        let image = Image::new(dir.path()).unwrap();
+        let desc = build_from_squashfs(Cursor::new(squashfs), &image).unwrap();
+        let expected = vec![
+            "/ dir 700 22:33 1000",
+            "/1MiB.file file 'A' x 1048576 0 0:0 1000",
+            "/broken.link symlink nonexistent 777 0:0 1000",
+            "/deep dir 755 0:0 1000",
+            "/deep/level1 dir 755 0:0 1000",
+            "/deep/level1/level2 dir 755 0:0 1000",
+            "/deep/level1/level2/level3 dir 755 0:0 1000",
+            "/deep/level1/level2/level3/level4 dir 755 0:0 1000",
+            "/deep/level1/level2/level3/level4/level5 dir 755 0:0 1000",
+            "/deep/level1/level2/level3/level4/level5/file file \"deep file\\n\" 400 101:101 1000",
+            "/dev dir 755 0:0 1000",
+            "/dev/block block 1:2 644 0:0 1000",
+            "/dev/char char 3:4 644 0:0 1000",
+            "/empty.file file \"\" 644 0:0 1000 user.empty=xattr-value \
+             user.other=longlonglong value is long long long long long longer long",
+            "/empty_dir dir 755 0:0 1000",
+            "/fifo fifo 644 0:0 1000",
+            "/one.file file \"a\" 644 100:100 1000",
+            "/short.file file \"abc\\n\" 400 100:100 1000",
+            "/short.link symlink short.file 777 0:0 1000",
+            "/socket socket 644 0:0 1000",
+            "/socket2 link /socket",
+            "/subdir dir 755 0:0 1000",
+            "/subdir/one.file file \"a\" 644 100:100 1000",
+            "/subdir/short.file file \"abc\\n\" 444 64000:64000 1000",
+            "/weird dir 755 0:0 1000",
+            "/weird/  dir 755 0:0 1000",
+            "/weird/ /\u{1f62d} dir 755 0:0 1000",
+            "/weird/ /\u{1f62d}/*)(&^%$#@! dir 755 0:0 1000",
+        ];
+        assert_eq!(listing(&image, &desc), expected);
+    }
+
+    #[test]
+    fn test_backhand_zstd() {
+        // made with the backhand crate 0.25 (https://github.com/wcampbell0x2a/backhand), which
+        // shares no code with mksquashfs or with us, as a zstd image with 128K blocks and mtimes
+        // of 1000. big.file is two blocks and a fragment of i * 7 % 251, so the metadata, data
+        // and fragment blocks all get decompressed.
+        let squashfs = fs::read("fixtures/backhand-zstd.sqsh").unwrap();
+        let dir = tempdir().unwrap();
+        let image = Image::new(dir.path()).unwrap();
+        let desc = build_from_squashfs(Cursor::new(squashfs), &image).unwrap();
+        let big = (0..300_000_u32)
+            .map(|i| (i * 7 % 251) as u8)
+            .collect::<Vec<_>>();
+        let expected = vec![
+            // backhand leaves the root's mtime at 0
+            "/ dir 700 22:33 0".to_string(),
+            format!("/big.file file 300000 bytes {} 644 0:0 1000", sha256(&big)),
+            "/dir dir 755 100:100 1000".to_string(),
+            "/dir/char char 1:3 644 0:0 1000".to_string(),
+            "/dir/empty.file file \"\" 644 0:0 1000".to_string(),
+            "/dir/small.file file \"zstd\\n\" 400 100:100 1000".to_string(),
+            "/fifo fifo 644 22:33 1000".to_string(),
+            "/small.link symlink dir/small.file 777 0:0 1000".to_string(),
+        ];
+        assert_eq!(listing(&image, &desc), expected);
+    }
+
+    #[test]
+    fn test_bad_file_size() {
+        let entries = vec![
+            entry("", TestKind::Dir),
+            TestEntry {
+                // so that it gets an extended inode, with a 64 bit size
+                xattrs: vec![("user.a", b"b")],
+                ..entry("file", TestKind::File(vec![7; 3333]))
+            },
+        ];
+        let mut squashfs = squashfs(&entries, ZSTD);
+        let size = 3333_u64.to_le_bytes();
+        let at = squashfs.windows(8).rposition(|w| w == size).unwrap();
+        squashfs[at..at + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        // and no fragment, so that the size gets rounded up to whole blocks
+        squashfs[at + 20..at + 24].copy_from_slice(&NO_FRAGMENT.to_le_bytes());
+        let dir = tempdir().unwrap();
+        let image = Image::new(dir.path()).unwrap();
+        let err = build_from_squashfs(Cursor::new(squashfs), &image).unwrap_err();
+        assert!(err.to_string().contains("bad file size"), "{}", err);
+    }
+
+    #[test]
+    fn test_bad_offsets() {
+        // z's xattrs fill the first xattr block, so d/a's are in the next one, and d/a gets
+        // read first
+        const BIG: [u8; METADATA_BLOCK_SIZE] = [b'x'; METADATA_BLOCK_SIZE];
+        let entries = vec![
+            entry("", TestKind::Dir),
+            entry("d", TestKind::Dir),
+            TestEntry {
+                xattrs: vec![("user.z", &BIG)],
+                ..entry("z", TestKind::File(vec![7; 3333]))
+            },
+            TestEntry {
+                xattrs: vec![("user.a", b"b")],
+                ..entry("d/a", TestKind::Fifo)
+            },
+        ];
+        let squashfs = squashfs(&entries, ZSTD);
+        let dir = tempdir().unwrap();
+        let image = Image::new(dir.path()).unwrap();
+        let u64_at = |at: usize| u64::from_le_bytes(squashfs[at..at + 8].try_into().unwrap());
+        // tables that start so far in that the references into them go past the end of a u64
+        let bad = |patches: &[(usize, &[u8])]| {
+            let mut squashfs = squashfs.clone();
+            for (at, value) in patches {
+                squashfs[*at..*at + value.len()].copy_from_slice(value);
+            }
+            build_from_squashfs(Cursor::new(squashfs), &image)
+                .unwrap_err()
+                .to_string()
+        };
+        let max = u64::MAX.to_le_bytes();
+        let err = bad(&[(64, &max), (32, &(1_u64 << 16).to_le_bytes())]);
+        assert!(err.contains("bad inode reference"), "{}", err);
+        // the root's listing is in the first directory block, so point it at the next one too;
+        // inodes are stored uncompressed, and the block comes after the 16 byte header
+        let root_block = u64_at(64) as usize + 2 + (u64_at(32) & 0xffff) as usize + 16;
+        let err = bad(&[(72, &max), (root_block, &1_u32.to_le_bytes())]);
+        assert!(err.contains("bad directory"), "{}", err);
+        let err = bad(&[(u64_at(56) as usize, &max)]);
+        assert!(err.contains("bad xattr reference"), "{}", err);
+    }
+
+    #[test]
+    fn test_not_supported() {
+        let dir = tempdir().unwrap();
+        let image = Image::new(dir.path()).unwrap();
+        let mut squashfs = squashfs(&[entry("", TestKind::Dir)], ZSTD);
+        // xz
+        squashfs[20] = 4;
+        let err = build_from_squashfs(Cursor::new(&squashfs), &image).unwrap_err();
+        assert!(err.to_string().contains("xz"), "{}", err);
+
+        let err = build_from_squashfs(Cursor::new(vec![0_u8; 4096]), &image).unwrap_err();
+        assert!(err.to_string().contains("not a squashfs image"), "{}", err);
+    }
+}
@@ -1,4 +1,3 @@
-use std::fs;
 use std::io;
 
 mod zstd;
@@ -8,30 +7,52 @@ pub trait Compressor: io::Write {
     fn end(&mut self) -> io::Result<()>;
 }
 
-impl Compressor for fs::File {
-    fn end(&mut self) -> io::Result<()> {
-        Ok(())
-    }
-}
-
 pub trait Decompressor: io::Read + io::Seek + Send {}
 
-impl Decompressor for fs::File {}
+impl<R: io::Read + io::Seek + Send + ?Sized> Decompressor for R {}
 
 pub trait Compression {
-    fn compress(dest: fs::File) -> Box<dyn Compressor>;
-    fn decompress(source: fs::File) -> Box<dyn Decompressor>;
+    fn compress<'a, W: io::Write + 'a>(dest: W) -> Box<dyn Compressor + 'a>;
+    // level is algorithm specific; algorithms without levels can ignore it.
+    fn compress_with_level<'a, W: io::Write + 'a>(dest: W, level: u32) -> Box<dyn Compressor + 'a>;
+    fn decompress<R: Decompressor + 'static>(source: R) -> Box<dyn Decompressor>;
     fn append_extension(media_type: &str) -> String;
 }
 
 pub struct Noop {}
 
+// writes straight through to the destination
+struct NoopCompressor<W>(W);
+
+impl<W: io::Write> io::Write for NoopCompressor<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<W: io::Write> Compressor for NoopCompressor<W> {
+    fn end(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
 impl Compression for Noop {
-    fn compress(dest: fs::File) -> Box<dyn Compressor> {
-        Box::new(dest)
+    fn compress<'a, W: io::Write + 'a>(dest: W) -> Box<dyn Compressor + 'a> {
+        Box::new(NoopCompressor(dest))
+    }
+
+    fn compress_with_level<'a, W: io::Write + 'a>(
+        dest: W,
+        _level: u32,
+    ) -> Box<dyn Compressor + 'a> {
+        Self::compress(dest)
     }
 
-    fn decompress(source: fs::File) -> Box<dyn Decompressor> {
+    fn decompress<R: Decompressor + 'static>(source: R) -> Box<dyn Decompressor> {
         Box::new(source)
     }
 
@@ -43,6 +64,7 @@ impl Compression for Noop {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::fs;
     use tempfile::NamedTempFile;
 
     const TRUTH: &str = "meshuggah rocks";
@@ -1,8 +1,6 @@
 use std::cmp::min;
 use std::convert::TryFrom;
-use std::fs;
 use std::io;
-use std::io::Write;
 
 use zstd_seekable::{Seekable, SeekableCStream};
 
@@ -18,24 +16,28 @@ use crate::{Compression, Compressor, Decompressor};
 // also possible that we want different frame sizes for metadata blobs and file content.
 const FRAME_SIZE: usize = 5 * 1024 * 1024;
 
+// a "pretty high" compression level, since decompression should be nearly the same no matter what
+// compression level. Maybe we should turn this to 22 or whatever the max is...
+pub const DEFAULT_ZSTD_LEVEL: u32 = 17;
+
 fn err_to_io<E: 'static + std::error::Error + Send + Sync>(e: E) -> io::Error {
     io::Error::new(io::ErrorKind::Other, e)
 }
 
-pub struct ZstdCompressor {
-    f: fs::File,
+pub struct ZstdCompressor<W> {
+    f: W,
     stream: SeekableCStream,
     buf: Vec<u8>,
 }
 
-impl Compressor for ZstdCompressor {
+impl<W: io::Write> Compressor for ZstdCompressor<W> {
     fn end(&mut self) -> io::Result<()> {
         let size = self.stream.end_stream(&mut self.buf).map_err(err_to_io)?;
         self.f.write_all(&self.buf[0..size])
     }
 }
 
-impl io::Write for ZstdCompressor {
+impl<W: io::Write> io::Write for ZstdCompressor<W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         // TODO: we could try to consume all the input, but for now we just consume a single block
         let (out_pos, in_pos) = self
@@ -53,15 +55,13 @@ impl io::Write for ZstdCompressor {
     }
 }
 
-pub struct ZstdDecompressor {
-    stream: Seekable<'static, fs::File>,
+pub struct ZstdDecompressor<R: 'static> {
+    stream: Seekable<'static, R>,
     offset: u64,
     uncompressed_length: u64,
 }
 
-impl Decompressor for ZstdDecompressor {}
-
-impl io::Seek for ZstdDecompressor {
+impl<R: 'static> io::Seek for ZstdDecompressor<R> {
     fn seek(&mut self, offset: io::SeekFrom) -> io::Result<u64> {
         match offset {
             io::SeekFrom::Start(s) => {
@@ -85,7 +85,7 @@ impl io::Seek for ZstdDecompressor {
     }
 }
 
-impl io::Read for ZstdDecompressor {
+impl<R: 'static> io::Read for ZstdDecompressor<R> {
     fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
         // decompress() gets angry (ZSTD("Corrupted block detected")) if you pass it a buffer
         // longer than the uncompressable data, so let's be careful to truncate the buffer if it
@@ -103,10 +103,12 @@ impl io::Read for ZstdDecompressor {
 pub struct Zstd {}
 
 impl Compression for Zstd {
-    fn compress(dest: fs::File) -> Box<dyn Compressor> {
-        // a "pretty high" compression level, since decompression should be nearly the same no
-        // matter what compression level. Maybe we should turn this to 22 or whatever the max is...
-        let stream = SeekableCStream::new(17, FRAME_SIZE).unwrap();
+    fn compress<'a, W: io::Write + 'a>(dest: W) -> Box<dyn Compressor + 'a> {
+        Self::compress_with_level(dest, DEFAULT_ZSTD_LEVEL)
+    }
+
+    fn compress_with_level<'a, W: io::Write + 'a>(dest: W, level: u32) -> Box<dyn Compressor + 'a> {
+        let stream = SeekableCStream::new(level as usize, FRAME_SIZE).unwrap();
         Box::new(ZstdCompressor {
             f: dest,
             stream,
@@ -114,7 +116,7 @@ impl Compression for Zstd {
         })
     }
 
-    fn decompress(source: fs::File) -> Box<dyn Decompressor> {
+    fn decompress<R: Decompressor + 'static>(source: R) -> Box<dyn Decompressor> {
         let stream = Seekable::init(Box::new(source)).unwrap();
 
         // zstd-seekable doesn't like it when we pass a buffer past the end of the uncompressed
@@ -0,0 +1,61 @@
+// how long extracting a big file takes with a few --io-buffer sizes, the last of them extract's
+// default for the image. set PUZZLEFS_BENCH_SIZE (in MB) for a smaller file. run with cargo bench
+// -p puzzlefs --bench extract.
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::time::Instant;
+
+use tempfile::tempdir;
+
+#[path = "../tests/helpers.rs"]
+mod helpers;
+use helpers::{noise, puzzlefs};
+
+const SIZE_MB: usize = 1024;
+const BUFFERS: &[Option<&str>] = &[Some("8K"), Some("64K"), Some("1M"), Some("8M"), None];
+const ROUNDS: usize = 3;
+
+fn main() {
+    let size_mb = env::var("PUZZLEFS_BENCH_SIZE")
+        .map(|n| n.parse().unwrap())
+        .unwrap_or(SIZE_MB);
+    let dir = tempdir().unwrap();
+    let rootfs = dir.path().join("rootfs");
+    fs::create_dir_all(&rootfs).unwrap();
+    // not all zeros, so the chunker finds some boundaries
+    fs::write(
+        rootfs.join("big"),
+        noise(0x2545_f491_4f6c_dd1d, size_mb << 20),
+    )
+    .unwrap();
+
+    let oci = dir.path().join("oci");
+    puzzlefs(&[
+        OsStr::new("build"),
+        rootfs.as_os_str(),
+        oci.as_os_str(),
+        OsStr::new("bench"),
+    ]);
+    fs::remove_dir_all(&rootfs).unwrap();
+
+    for _ in 0..ROUNDS {
+        for buffer in BUFFERS {
+            let extracted = dir.path().join("extracted");
+            let mut args = vec![OsStr::new("extract"), OsStr::new("--quiet")];
+            if let Some(buffer) = buffer {
+                args.extend(&[OsStr::new("--io-buffer"), OsStr::new(buffer)]);
+            }
+            args.extend(&[oci.as_os_str(), OsStr::new("bench"), extracted.as_os_str()]);
+            let start = Instant::now();
+            puzzlefs(&args);
+            println!(
+                "{} MB with a {} buffer: {:?}",
+                size_mb,
+                buffer.unwrap_or("default"),
+                start.elapsed()
+            );
+            fs::remove_dir_all(&extracted).unwrap();
+        }
+    }
+}
@@ -1,26 +1,61 @@
+#![feature(backtrace)]
+
 #[macro_use]
 extern crate anyhow;
 extern crate clap;
 extern crate nix;
 
+use std::backtrace::Backtrace;
+use std::cmp::min;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::env;
+use std::ffi::{OsStr, OsString};
 use std::fs;
-use std::io;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Component, Path, PathBuf};
+use std::process::exit;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
 
+use anyhow::Context;
 use clap::Clap;
-use nix::sys::stat::{makedev, mknod, Mode, SFlag};
-use nix::unistd::{mkfifo, symlinkat};
+use indicatif::{HumanBytes, ProgressBar, ProgressStyle};
+use nix::errno::Errno;
+use nix::libc;
+use nix::sys::stat::{makedev, mknod, utimensat, Mode, SFlag, UtimensatFlags};
+use nix::sys::time::TimeSpec;
+use nix::unistd::{fchownat, geteuid, isatty, mkfifo, symlinkat, FchownatFlags, Gid, Uid};
+use rayon::prelude::*;
 use signal_hook::consts::TERM_SIGNALS;
 use signal_hook::iterator::exfiltrator::SignalOnly;
 use signal_hook::iterator::SignalsInfo;
 
-use builder::build_initial_rootfs;
-use oci::Image;
-use reader::{mount, InodeMode, PuzzleFS, WalkPuzzleFS};
+use builder::{
+    build_from_squashfs_with_stats, build_from_tar_with_stats, build_merged_rootfs_with_stats,
+    BaseImage, BuildOptions, BuildStats, ChunkCompression, OnConflict,
+};
+use format::{
+    ChunkingAlgorithm, DigestAlgorithm, FileChunk, Timestamp, WireFormatError, CAPABILITY_XATTR,
+    OPAQUE_WHITEOUT, OVERLAY_OPAQUE_XATTR, WHITEOUT_PREFIX,
+};
+use oci::registry::{Reference, Registry};
+use oci::{
+    collect_garbage, inspect, BlobLayout, ChunkStream, Digest, DiscardBlobStore, Image, Platform,
+};
+use reader::{
+    mount_stack_with_options, session_stack_with_options, unmount, BlobDiff, ImageStats, Inode,
+    InodeMode, MountOption, MountOptions, PuzzleFS, WalkEntry, WalkPuzzleFS,
+};
 
 #[derive(Clap)]
 #[clap(version = "0.1.0", author = "Tycho Andersen <tycho@tycho.pizza>")]
 struct Opts {
+    #[clap(long)]
+    json: bool,
     #[clap(subcommand)]
     subcmd: SubCommand,
 }
@@ -30,27 +65,381 @@ enum SubCommand {
     Build(Build),
     Mount(Mount),
     Extract(Extract),
+    Verify(Verify),
+    Ls(Ls),
+    Push(Push),
+    Pull(Pull),
+    Stats(Stats),
+    DiffBlobs(DiffBlobs),
+    Gc(Gc),
+    Rm(Rm),
+    Inspect(Inspect),
 }
 
 #[derive(Clap)]
+#[clap(override_usage = "puzzlefs build [FLAGS] [OPTIONS] <rootfs>... <oci-dir> <tag>")]
 struct Build {
-    rootfs: String,
-    oci_dir: String,
-    tag: String,
+    // <rootfs>... <oci-dir> <tag>; clap only lets the last positionals take several values
+    #[clap(required = true, min_values = 3, value_name = "rootfs")]
+    rootfs_oci_dir_tag: Vec<String>,
+    #[clap(long)]
+    chunk_size_min: Option<ByteSize>,
+    #[clap(long)]
+    chunk_size_avg: Option<ByteSize>,
+    #[clap(long)]
+    chunk_size_max: Option<ByteSize>,
+    #[clap(long)]
+    chunker: Option<ChunkingAlgorithm>,
+    #[clap(long, possible_values = &["none", "zstd"], default_value = "none")]
+    compression: String,
+    #[clap(long)]
+    compression_level: Option<u32>,
+    #[clap(long)]
+    compress_metadata: bool,
+    #[clap(long, possible_values = &["sha256", "blake3", "sha512"], default_value = "sha256")]
+    digest_algorithm: DigestAlgorithm,
+    #[clap(long)]
+    file_digests: bool,
+    #[clap(long)]
+    sharded_blobs: bool,
+    #[clap(long)]
+    from_tar: bool,
+    #[clap(long, conflicts_with = "from-tar")]
+    from_squashfs: bool,
+    #[clap(long)]
+    base: Option<String>,
+    #[clap(long)]
+    whiteouts: bool,
+    #[clap(long, number_of_values = 1)]
+    exclude: Vec<String>,
+    #[clap(long)]
+    dereference: bool,
+    #[clap(long, possible_values = &["error", "overwrite"], default_value = "error")]
+    on_conflict: String,
+    #[clap(long)]
+    skip_errors: bool,
+    #[clap(long)]
+    platform: Option<Platform>,
+    #[clap(long)]
+    dry_run: bool,
+    #[clap(long)]
+    json: bool,
+    #[clap(long)]
+    quiet: bool,
 }
 
 #[derive(Clap)]
 struct Mount {
     oci_dir: String,
-    tag: String,
-    mountpoint: String,
+    // [tag] mountpoint: clap can't have an optional positional before a required one
+    #[clap(value_names = &["tag", "mountpoint"], required = true, min_values = 1, max_values = 2)]
+    tag_and_mountpoint: Vec<String>,
+    #[clap(long)]
+    cache_size: Option<u64>,
+    #[clap(short, number_of_values = 1)]
+    options: Vec<String>,
+    #[clap(long)]
+    overlay: bool,
+    #[clap(long)]
+    verify_on_read: bool,
+    #[clap(long)]
+    du_physical: bool,
+    #[clap(long, number_of_values = 1)]
+    idmap: Vec<String>,
+    #[clap(long)]
+    mkdir: bool,
+    #[clap(long, requires = "mkdir")]
+    rmdir: bool,
+    #[clap(long)]
+    foreground: bool,
+    #[clap(long)]
+    fallback: Option<String>,
+    #[clap(long)]
+    subdir: Option<String>,
 }
 
 #[derive(Clap)]
 struct Extract {
+    oci_dir: String,
+    #[clap(value_names = &["tag", "extract-dir"], required = true, min_values = 1, max_values = 2)]
+    tag_and_extract_dir: Vec<String>,
+    #[clap(long, possible_values = &["dir", "tar", "overlay"], default_value = "dir")]
+    format: String,
+    #[clap(long)]
+    io_buffer: Option<ByteSize>,
+    #[clap(
+        long,
+        possible_values = &["preserve", "relativize", "reject-escapes"],
+        default_value = "preserve"
+    )]
+    symlink_mode: SymlinkMode,
+    #[clap(long)]
+    quiet: bool,
+}
+
+#[derive(Clap)]
+struct Verify {
+    oci_dir: String,
+    tag: Option<String>,
+    #[clap(long)]
+    files_only: bool,
+    #[clap(long)]
+    quiet: bool,
+}
+
+#[derive(Clap)]
+struct Ls {
+    oci_dir: String,
+    // [tag] [path]: clap can't tell which of the two one value is, see tag_or_path()
+    #[clap(value_names = &["tag", "path"], max_values = 2)]
+    tag_and_path: Vec<String>,
+    #[clap(short, long)]
+    recursive: bool,
+    #[clap(long)]
+    json: bool,
+}
+
+#[derive(Clap)]
+struct Push {
+    oci_dir: String,
+    // [tag] reference: clap can't have an optional positional before a required one
+    #[clap(value_names = &["tag", "reference"], required = true, min_values = 1, max_values = 2)]
+    tag_and_reference: Vec<String>,
+    #[clap(long)]
+    insecure: bool,
+}
+
+#[derive(Clap)]
+struct Stats {
+    oci_dir: String,
+    tag: Option<String>,
+    #[clap(long)]
+    compare: Option<String>,
+    #[clap(long)]
+    histogram: Option<String>,
+}
+
+#[derive(Clap)]
+struct DiffBlobs {
+    oci_dir: String,
+    tag_a: String,
+    tag_b: String,
+    #[clap(short, long)]
+    verbose: bool,
+}
+
+#[derive(Clap)]
+struct Gc {
+    oci_dir: String,
+    #[clap(long)]
+    dry_run: bool,
+}
+
+#[derive(Clap)]
+struct Rm {
+    oci_dir: String,
+    #[clap(required_unless_present = "all", conflicts_with = "all")]
+    tag: Option<String>,
+    #[clap(long)]
+    all: bool,
+}
+
+#[derive(Clap)]
+struct Inspect {
+    oci_dir: String,
+    tag: Option<String>,
+}
+
+#[derive(Clap)]
+struct Pull {
+    reference: String,
     oci_dir: String,
     tag: String,
-    extract_dir: String,
+    #[clap(long)]
+    insecure: bool,
+}
+
+// a number of bytes, optionally in K, M, G or T (powers of 1024) and with a fraction, e.g. 64K or
+// 1.5M
+struct ByteSize(u64);
+
+impl FromStr for ByteSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let bad = |why: &str| format!("bad size {}: {}", s, why);
+        let split = s
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or_else(|| s.len());
+        let (number, suffix) = s.split_at(split);
+        let unit: u128 = match suffix.to_ascii_uppercase().as_str() {
+            "" => 1,
+            "K" => 1 << 10,
+            "M" => 1 << 20,
+            "G" => 1 << 30,
+            "T" => 1 << 40,
+            _ => return Err(bad("the suffix can be K, M, G or T")),
+        };
+        let (whole, fraction) = number.split_once('.').unwrap_or((number, ""));
+        if whole.is_empty() && fraction.is_empty() || fraction.contains('.') {
+            return Err(bad("not a number"));
+        }
+        let in_units = |d: &str| -> std::result::Result<u128, String> {
+            if d.is_empty() {
+                return Ok(0);
+            }
+            d.parse::<u128>()
+                .ok()
+                .and_then(|n| n.checked_mul(unit))
+                .ok_or_else(|| bad("too big"))
+        };
+        // the fraction is worked out exactly, so it has to come to a whole number of bytes
+        let scale = 10_u128
+            .checked_pow(fraction.len() as u32)
+            .ok_or_else(|| bad("too many decimal places"))?;
+        let fraction = in_units(fraction)?;
+        if fraction % scale != 0 {
+            return Err(bad("not a whole number of bytes"));
+        }
+        in_units(whole)?
+            .checked_add(fraction / scale)
+            .and_then(|bytes| u64::try_from(bytes).ok())
+            .map(ByteSize)
+            .ok_or_else(|| bad("too big"))
+    }
+}
+
+// what extract does with symlinks whose targets get out of the extract dir, absolute ones included:
+// write them as they are, point them at where they'd be in the image instead, or refuse them
+#[derive(PartialEq)]
+enum SymlinkMode {
+    Preserve,
+    Relativize,
+    RejectEscapes,
+}
+
+impl FromStr for SymlinkMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "preserve" => Ok(SymlinkMode::Preserve),
+            "relativize" => Ok(SymlinkMode::Relativize),
+            "reject-escapes" => Ok(SymlinkMode::RejectEscapes),
+            _ => Err(format!("unknown symlink mode {}", s)),
+        }
+    }
+}
+
+fn tag_or_default(image: &Image, tag: Option<String>) -> anyhow::Result<String> {
+    match tag {
+        Some(tag) => Ok(tag),
+        None => Ok(image.default_tag()?),
+    }
+}
+
+// the one or two values of an optional tag followed by a path (or for push, a reference)
+fn tag_and_path(image: &Image, mut values: Vec<String>) -> anyhow::Result<(String, String)> {
+    let path = values
+        .pop()
+        .ok_or_else(|| anyhow!("missing path after the tag"))?;
+    Ok((tag_or_default(image, values.pop())?, path))
+}
+
+// the up to two values of an optional tag followed by an optional path, which defaults to the
+// root. a single value is the tag if the image has a tag by that name, and the path otherwise.
+fn tag_or_path(image: &Image, mut values: Vec<String>) -> anyhow::Result<(String, String)> {
+    if values.len() == 1 && image.get_index()?.find_tag(&values[0]).is_some() {
+        values.push("/".to_string());
+    }
+    let path = values.pop().unwrap_or_else(|| "/".to_string());
+    Ok((tag_or_default(image, values.pop())?, path))
+}
+
+// creates `path` along with any of its parents that don't exist, and returns the ones it created,
+// deepest first
+fn create_dirs(path: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut missing = Vec::new();
+    let mut dir = path;
+    while !dir.as_os_str().is_empty() && !dir.exists() {
+        missing.push(dir.to_path_buf());
+        dir = match dir.parent() {
+            Some(parent) => parent,
+            None => break,
+        };
+    }
+    fs::create_dir_all(path)?;
+    Ok(missing)
+}
+
+const FUSE_CONF: &str = "/etc/fuse.conf";
+
+// unprivileged users can only use allow_other if the admin said so in fuse.conf
+fn user_allow_other_enabled(conf: &Path) -> bool {
+    fs::read_to_string(conf)
+        .map(|contents| {
+            contents
+                .lines()
+                .any(|l| l.split('#').next().unwrap_or_default().trim() == "user_allow_other")
+        })
+        .unwrap_or(false)
+}
+
+// writes log messages (fuse logs every request it gets at debug level) to stderr
+struct StderrLogger;
+
+impl log::Log for StderrLogger {
+    fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record<'_>) {
+        if self.enabled(record.metadata()) {
+            eprintln!("{} {}: {}", record.level(), record.target(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+// RUST_LOG is just a level here (e.g. info), debug if it isn't set
+fn init_logging() -> anyhow::Result<()> {
+    let level = match env::var("RUST_LOG") {
+        Ok(level) => level
+            .parse::<log::LevelFilter>()
+            .map_err(|_| anyhow!("bad RUST_LOG {}: expected a log level", level))?,
+        Err(_) => log::LevelFilter::Debug,
+    };
+    log::set_logger(&StderrLogger).map_err(|e| anyhow!("couldn't set up logging: {}", e))?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+// serves the mount on this thread rather than in the background, until a signal or someone else
+// unmounts it
+fn mount_foreground(
+    image: &Image,
+    tags: &[&str],
+    mountpoint: &Path,
+    options: &MountOptions,
+) -> anyhow::Result<()> {
+    init_logging()?;
+    let mut session = session_stack_with_options(image, tags, mountpoint, options)?;
+    let mut signals = SignalsInfo::<SignalOnly>::new(TERM_SIGNALS)?;
+    let handle = signals.handle();
+    let mountpoint = mountpoint.to_path_buf();
+    thread::spawn(move || {
+        if let Some(s) = signals.forever().next() {
+            eprintln!("got signal {:?}, exiting puzzlefs fuse mount", s);
+            // which makes the session stop
+            if let Err(e) = unmount(&mountpoint) {
+                eprintln!("couldn't unmount {}: {}", mountpoint.display(), e);
+            }
+        }
+    });
+    let result = session.run();
+    handle.close();
+    Ok(result?)
 }
 
 fn safe_path(dir: &Path, image_path: &Path) -> anyhow::Result<PathBuf> {
@@ -103,48 +492,900 @@ fn safe_path(dir: &Path, image_path: &Path) -> anyhow::Result<PathBuf> {
     Ok(buf)
 }
 
-fn main() -> anyhow::Result<()> {
+// creating device nodes needs privilege; without it they're skipped rather than failing the whole
+// extract. returns whether the node was created.
+fn make_device(path: &Path, kind: SFlag, dev: u64) -> anyhow::Result<bool> {
+    match mknod(path, kind, Mode::S_IRWXU, dev) {
+        Ok(()) => Ok(true),
+        Err(nix::Error::Sys(Errno::EPERM)) => {
+            eprintln!("skipping device {:#?}, not allowed to create it", path);
+            Ok(false)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn timespec(t: Timestamp) -> TimeSpec {
+    TimeSpec::from(libc::timespec {
+        tv_sec: t.sec,
+        tv_nsec: t.nsec as i64,
+    })
+}
+
+// symlinks get their own times, not their targets'
+fn set_times(path: &Path, mtime: Timestamp, atime: Timestamp) -> anyhow::Result<()> {
+    utimensat(
+        None,
+        path,
+        &timespec(atime),
+        &timespec(mtime),
+        UtimensatFlags::NoFollowSymlink,
+    )?;
+    Ok(())
+}
+
+// the bar goes to stderr, but it's only worth drawing when someone is watching stdout; when that's
+// a pipe or a file this is probably running in a script
+fn progress_bar(quiet: bool) -> ProgressBar {
+    if quiet || !isatty(libc::STDOUT_FILENO).unwrap_or(false) {
+        return ProgressBar::hidden();
+    }
+    let bar = ProgressBar::new_spinner()
+        .with_style(ProgressStyle::default_spinner().template("{spinner} [{elapsed}] {msg}"));
+    bar.enable_steady_tick(100);
+    bar
+}
+
+fn extract_progress(bar: &ProgressBar, files: u64, bytes: u64) {
+    bar.set_message(format!("{} files, {}", files, HumanBytes(bytes)));
+}
+
+// a file's data, read through the ChunkStream everything being extracted shares
+struct StreamedFile<'a, 'b> {
+    stream: &'a mut ChunkStream<'b>,
+    chunks: &'a [FileChunk],
+    // the chunk the next read starts in, and how far into it
+    chunk: usize,
+    offset: u64,
+}
+
+impl<'a, 'b> StreamedFile<'a, 'b> {
+    fn new(stream: &'a mut ChunkStream<'b>, chunks: &'a [FileChunk]) -> StreamedFile<'a, 'b> {
+        StreamedFile {
+            stream,
+            chunks,
+            chunk: 0,
+            offset: 0,
+        }
+    }
+}
+
+impl io::Read for StreamedFile<'_, '_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while let Some(chunk) = self.chunks.get(self.chunk) {
+            if self.offset == chunk.len {
+                self.chunk += 1;
+                self.offset = 0;
+                continue;
+            }
+            let len = min(buf.len() as u64, chunk.len - self.offset) as usize;
+            let n = match chunk.blob {
+                Some(blob) => self
+                    .stream
+                    .fill_from_chunk(blob, self.offset, &mut buf[..len])
+                    .map_err(|e| io::Error::from_raw_os_error(e.to_errno()))?,
+                None => {
+                    buf[..len].iter_mut().for_each(|b| *b = 0);
+                    len
+                }
+            };
+            if n == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "chunk blob is too short",
+                ));
+            }
+            self.offset += n as u64;
+            return Ok(n);
+        }
+        Ok(0)
+    }
+}
+
+// unless --io-buffer says otherwise, extract reads file data the image's average chunk size at a
+// time, so that most reads are of a whole chunk, within these limits
+const MIN_IO_BUFFER: u64 = 64 << 10;
+const MAX_IO_BUFFER: u64 = 8 << 20;
+
+fn io_buffer_size(pfs: &PuzzleFS, io_buffer: Option<ByteSize>) -> anyhow::Result<usize> {
+    let size = match io_buffer {
+        Some(ByteSize(0)) => bail!("--io-buffer can't be 0"),
+        Some(ByteSize(size)) => size,
+        None => pfs
+            .chunking_config()
+            .avg
+            .clamp(MIN_IO_BUFFER, MAX_IO_BUFFER),
+    };
+    Ok(usize::try_from(size)?)
+}
+
+// io::copy(), but through buf rather than the small buffer io::copy() has of its own
+fn copy_through(r: &mut dyn io::Read, w: &mut dyn io::Write, buf: &mut [u8]) -> io::Result<u64> {
+    let mut copied = 0;
+    loop {
+        let n = match r.read(buf) {
+            Ok(0) => return Ok(copied),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        w.write_all(&buf[..n])?;
+        copied += n as u64;
+    }
+}
+
+// writes a file's data out and leaves its holes as holes, so sparse files stay sparse. returns how
+// much data there was.
+fn extract_file(
+    chunks: &[FileChunk],
+    stream: &mut ChunkStream,
+    f: &mut fs::File,
+    buf: &mut [u8],
+) -> io::Result<u64> {
+    let mut offset = 0;
+    let mut bytes = 0;
+    for chunk in chunks {
+        if chunk.blob.is_some() {
+            f.seek(SeekFrom::Start(offset))?;
+            bytes += copy_through(
+                &mut StreamedFile::new(stream, std::slice::from_ref(chunk)),
+                f,
+                buf,
+            )?;
+        }
+        offset += chunk.len;
+    }
+    // nothing gets written after a hole at the end of the file
+    f.set_len(offset)?;
+    Ok(bytes)
+}
+
+// the kernel gives up on a path after following this many symlinks
+const MAX_SYMLINKS: usize = 40;
+
+// checks and rewrites symlink targets for --symlink-mode, looking them up in an image of their own
+// since the one being extracted is busy being walked
+struct Symlinks<'a> {
+    mode: SymlinkMode,
+    pfs: Option<PuzzleFS<'a>>,
+}
+
+impl<'a> Symlinks<'a> {
+    fn new(image: &'a Image, tag: &str, mode: SymlinkMode) -> anyhow::Result<Self> {
+        let pfs = match mode {
+            SymlinkMode::Preserve => None,
+            _ => Some(PuzzleFS::open(image, tag)?),
+        };
+        Ok(Symlinks { mode, pfs })
+    }
+
+    // what to point the symlink at image path link at, given it points at target in the image
+    fn target(&mut self, link: &Path, target: &OsStr) -> anyhow::Result<PathBuf> {
+        let target = Path::new(target);
+        if self.mode == SymlinkMode::Preserve {
+            return Ok(target.to_path_buf());
+        }
+        let dir = link
+            .parent()
+            .unwrap_or_else(|| Path::new("/"))
+            .components()
+            .filter_map(|c| match c {
+                Component::Normal(name) => Some(name.to_owned()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let (resolved, escaped) = self.resolve(&dir, target)?;
+        if !escaped {
+            return Ok(target.to_path_buf());
+        }
+        if self.mode == SymlinkMode::RejectEscapes {
+            bail!(
+                "symlink {:#?} -> {:#?} escapes the extract dir",
+                link,
+                target
+            );
+        }
+        // the way there from the symlink's directory, which in the extract dir is the same as in
+        // the image
+        let common = dir
+            .iter()
+            .zip(resolved.iter())
+            .take_while(|(d, r)| d == r)
+            .count();
+        let mut relative = PathBuf::new();
+        for _ in common..dir.len() {
+            relative.push("..");
+        }
+        relative.extend(&resolved[common..]);
+        if relative.as_os_str().is_empty() {
+            relative.push(".");
+        }
+        Ok(relative)
+    }
+
+    // follows target from the directory dir through the image, as the kernel would if the image
+    // were the root of the filesystem: returns the path from the root it ends up at, and whether
+    // it got there by way of the root's "..", or an absolute path, either of which leaves the
+    // extract dir once it's extracted. like the kernel, ".." is the parent of wherever a symlink
+    // went, not of the symlink, and paths that don't exist are only followed as they're written.
+    fn resolve(
+        &mut self,
+        dir: &[OsString],
+        target: &Path,
+    ) -> anyhow::Result<(Vec<OsString>, bool)> {
+        let pfs = self.pfs.as_mut().unwrap();
+        // the inodes along the way, or None once a path doesn't exist
+        let mut resolved = Vec::new();
+        for name in dir {
+            let parent = resolved.last().map(|(_, ino)| *ino).unwrap_or(Some(1));
+            resolved.push((
+                name.clone(),
+                parent.map(|p| pfs.lookup(p, name)).transpose()?,
+            ));
+        }
+        // what's left to follow, last first; names can't have a / in them, so "/" is the root
+        let mut todo = Vec::new();
+        let push = |todo: &mut Vec<OsString>, path: &Path| {
+            for c in path.components().rev() {
+                match c {
+                    Component::RootDir => todo.push("/".into()),
+                    Component::ParentDir => todo.push("..".into()),
+                    Component::Normal(name) => todo.push(name.to_owned()),
+                    Component::CurDir | Component::Prefix(_) => (),
+                }
+            }
+        };
+        push(&mut todo, target);
+        let (mut escaped, mut followed) = (false, 0);
+        while let Some(name) = todo.pop() {
+            if name == "/" {
+                escaped = true;
+                resolved.clear();
+                continue;
+            }
+            if name == ".." {
+                escaped |= resolved.pop().is_none();
+                continue;
+            }
+            let parent = resolved.last().map(|(_, ino)| *ino).unwrap_or(Some(1));
+            let ino = match parent.map(|p| pfs.lookup(p, &name)) {
+                Some(Ok(ino)) => Some(ino),
+                Some(Err(e)) if e.to_errno() == libc::ENOENT || e.to_errno() == libc::ENOTDIR => {
+                    None
+                }
+                Some(Err(e)) => return Err(e.into()),
+                None => None,
+            };
+            if let Some(ino) = ino {
+                let inode = pfs.find_inode(ino)?;
+                if followed < MAX_SYMLINKS && inode.inode.mode == format::InodeMode::Lnk {
+                    followed += 1;
+                    push(&mut todo, Path::new(inode.symlink_target()?));
+                    continue;
+                }
+            }
+            resolved.push((name, ino));
+        }
+        Ok((
+            resolved.into_iter().map(|(name, _)| name).collect(),
+            escaped,
+        ))
+    }
+}
+
+// tar can't represent everything a puzzlefs image can, but it can do everything a directory extract
+// can, which is what people want this for.
+fn extract_tar<'a, W: io::Write>(
+    image: &Image,
+    pfs: &'a mut PuzzleFS<'a>,
+    out: W,
+    io_buffer: usize,
+    symlinks: &mut Symlinks,
+    bar: &ProgressBar,
+) -> anyhow::Result<()> {
+    let mut walker = WalkPuzzleFS::walk(pfs)?;
+    let mut builder = tar::Builder::new(out);
+    let mut stream = ChunkStream::new(image);
+    // puzzlefs inode to the first path we archived it as, for hard links
+    let mut links = HashMap::new();
+    let (mut files, mut bytes) = (0, 0);
+    walker.try_for_each(|de| -> anyhow::Result<()> {
+        let dir_entry = de?;
+        let path = dir_entry.path.strip_prefix("/")?;
+        if path.as_os_str().is_empty() {
+            return Ok(());
+        }
+        files += 1;
+        extract_progress(bar, files, bytes);
+
+        let inode = &dir_entry.inode;
+        let mut header = tar::Header::new_gnu();
+        header.set_uid(inode.inode.uid as u64);
+        header.set_gid(inode.inode.gid as u64);
+        header.set_mode(inode.inode.permissions as u32);
+        // ustar headers only have room for whole, non-negative seconds
+        header.set_mtime(inode.inode.mtime.sec.max(0) as u64);
+
+        if inode.inode.nlink > 1 && !inode.is_dir() {
+            if let Some(existing) = links.get(&inode.inode.ino) {
+                header.set_entry_type(tar::EntryType::Link);
+                header.set_link_name(existing)?;
+                builder.append_data(&mut header, path, io::empty())?;
+                return Ok(());
+            }
+            links.insert(inode.inode.ino, path.to_path_buf());
+        }
+
+        match inode.mode {
+            InodeMode::File { ref chunks } => {
+                header.set_entry_type(tar::EntryType::Regular);
+                let len = inode.file_len()?;
+                header.set_size(len);
+                bytes += len;
+                // tar copies through a small buffer of its own, but it can at least be fed from a
+                // big one
+                let data =
+                    io::BufReader::with_capacity(io_buffer, StreamedFile::new(&mut stream, chunks));
+                builder.append_data(&mut header, path, data)?;
+                return Ok(());
+            }
+            InodeMode::Dir { .. } => header.set_entry_type(tar::EntryType::Directory),
+            InodeMode::Other => match inode.inode.mode {
+                format::InodeMode::Fifo => header.set_entry_type(tar::EntryType::Fifo),
+                format::InodeMode::Chr { major, minor } => {
+                    header.set_entry_type(tar::EntryType::Char);
+                    header.set_device_major(major as u32)?;
+                    header.set_device_minor(minor as u32)?;
+                }
+                format::InodeMode::Blk { major, minor } => {
+                    header.set_entry_type(tar::EntryType::Block);
+                    header.set_device_major(major as u32)?;
+                    header.set_device_minor(minor as u32)?;
+                }
+                format::InodeMode::Lnk => {
+                    header.set_entry_type(tar::EntryType::Symlink);
+                    let target = symlinks.target(&dir_entry.path, inode.symlink_target()?)?;
+                    header.set_link_name(target)?;
+                }
+                format::InodeMode::Sock | format::InodeMode::Wht => {
+                    eprintln!("skipping {:#?}, tar can't represent it", path);
+                    return Ok(());
+                }
+                _ => bail!("bad inode mode {:#?}", inode.inode.mode),
+            },
+        }
+        header.set_size(0);
+        builder.append_data(&mut header, path, io::empty())?;
+        Ok(())
+    })?;
+    builder.into_inner()?.flush()?;
+    Ok(())
+}
+
+// what build says it did, when it isn't asked for json
+fn print_build_stats(stats: &BuildStats) {
+    println!("chunks written: {}", stats.chunks_written);
+    println!("chunks deduplicated: {}", stats.chunks_deduplicated);
+    println!("blob bytes: {}", stats.blob_bytes);
+    for skipped in &stats.skipped {
+        eprintln!("skipped {}: {}", skipped.path.display(), skipped.error);
+    }
+}
+
+// what build --dry-run found a real build would have written: every blob, as it would be stored
+// (i.e. compressed, if it would be), and how many chunks there are for each one that's written
+fn report_dry_run(image: &Image, stats: &BuildStats, json: bool) -> anyhow::Result<()> {
+    let blobs = image.store().list_blobs()?;
+    let bytes = blobs.iter().map(|(_, size)| size).sum::<u64>();
+    let chunks = stats.chunks_written + stats.chunks_deduplicated;
+    let dedup_ratio = if stats.chunks_written == 0 {
+        1.0
+    } else {
+        chunks as f64 / stats.chunks_written as f64
+    };
+    if json {
+        let mut report = serde_json::to_value(stats)?;
+        report["blobs"] = blobs.len().into();
+        report["bytes"] = bytes.into();
+        report["dedup_ratio"] = dedup_ratio.into();
+        println!("{}", report);
+    } else {
+        println!("blobs: {}", blobs.len());
+        println!("bytes: {}", bytes);
+        println!("dedup ratio: {:.2}", dedup_ratio);
+        print_build_stats(stats);
+    }
+    Ok(())
+}
+
+// where a build of `tag` notes down the blobs it has written, so that if it doesn't finish, building
+// the tag again picks up where it left off
+fn build_journal(oci_dir: &Path, tag: &str) -> PathBuf {
+    // tags may have anything in them, including slashes
+    let name = tag
+        .bytes()
+        .map(|b| match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'.' | b'_' | b'-' => (b as char).to_string(),
+            _ => format!("%{:02x}", b),
+        })
+        .collect::<String>();
+    oci_dir.join(format!("puzzlefs-build-{}.journal", name))
+}
+
+// the ls -l file type character
+fn type_char(inode: &Inode) -> char {
+    match inode.inode.mode {
+        format::InodeMode::Unknown => '?',
+        format::InodeMode::Fifo => 'p',
+        format::InodeMode::Chr { .. } => 'c',
+        format::InodeMode::Dir { .. } => 'd',
+        format::InodeMode::Blk { .. } => 'b',
+        format::InodeMode::Reg { .. } => '-',
+        format::InodeMode::Lnk => 'l',
+        format::InodeMode::Sock => 's',
+        format::InodeMode::Wht => 'w',
+    }
+}
+
+// the ls -l rwxrwxrwx, with setuid, setgid and sticky shown in the x columns like ls does
+fn permissions_string(permissions: u16) -> String {
+    let special = [(0o4000, 's'), (0o2000, 's'), (0o1000, 't')];
+    let mut s = String::new();
+    for (i, &(bit, c)) in special.iter().enumerate() {
+        let rwx = permissions >> (6 - 3 * i) & 0o7;
+        s.push(if rwx & 0o4 != 0 { 'r' } else { '-' });
+        s.push(if rwx & 0o2 != 0 { 'w' } else { '-' });
+        s.push(match (rwx & 0o1 != 0, permissions & bit != 0) {
+            (true, true) => c,
+            (false, true) => c.to_ascii_uppercase(),
+            (true, false) => 'x',
+            (false, false) => '-',
+        });
+    }
+    s
+}
+
+fn ls<'a>(pfs: &'a mut PuzzleFS<'a>, path: &str, ls: &Ls) -> anyhow::Result<()> {
+    // paths in the walk are absolute
+    let path = Path::new("/").join(path);
+    let mut found = false;
+    for de in WalkPuzzleFS::walk(pfs)? {
+        let de = de?;
+        if de.path == path {
+            found = true;
+            // like ls, a directory lists its contents rather than itself
+            if de.inode.is_dir() {
+                continue;
+            }
+        } else if !de.path.starts_with(&path)
+            || !(ls.recursive || de.path.parent() == Some(path.as_path()))
+        {
+            continue;
+        }
+
+        if ls.json {
+            println!("{}", serde_json::to_string(&WalkEntry::from(&de))?);
+            continue;
+        }
+
+        let size = de.inode.file_len().unwrap_or(0);
+        // the full path when listing more than one directory, just the name otherwise
+        let name = if ls.recursive {
+            de.path.as_os_str()
+        } else {
+            de.path.file_name().unwrap_or_else(|| de.path.as_os_str())
+        };
+        let mut line = format!(
+            "{}{} {:>3} {:>5} {:>5} {:>10} {}",
+            type_char(&de.inode),
+            permissions_string(de.inode.inode.permissions),
+            de.inode.inode.nlink,
+            de.inode.inode.uid,
+            de.inode.inode.gid,
+            size,
+            name.to_string_lossy()
+        );
+        if let Ok(target) = de.inode.symlink_target() {
+            line.push_str(&format!(" -> {}", target.to_string_lossy()));
+        }
+        println!("{}", line);
+    }
+    if !found {
+        bail!("{}: no such file or directory", path.display());
+    }
+    Ok(())
+}
+
+// how big the metadata blobs of an image are as stored, and once decompressed
+fn metadata_bytes(image: &Image, tag: &str) -> anyhow::Result<(u64, u64)> {
+    let (mut stored, mut uncompressed) = (0, 0);
+    for md in image.open_rootfs_blob::<compression::Noop>(tag)?.metadatas {
+        let digest = Digest::try_from(md)?;
+        stored += image.open_raw_blob(&digest)?.seek(SeekFrom::End(0))?;
+        uncompressed += if md.compressed {
+            image
+                .open_compressed_blob::<compression::Zstd>(&digest)?
+                .seek(SeekFrom::End(0))?
+        } else {
+            image.open_raw_blob(&digest)?.seek(SeekFrom::End(0))?
+        };
+    }
+    Ok((stored, uncompressed))
+}
+
+// one row per distinct chunk, for plotting how the chunker did
+fn write_histogram(stats: &ImageStats, out: impl Write) -> io::Result<()> {
+    let mut out = io::BufWriter::new(out);
+    writeln!(out, "digest,len,refs")?;
+    for chunk in stats.chunks() {
+        writeln!(
+            out,
+            "{},{},{}",
+            chunk.digest.reference(),
+            chunk.len,
+            chunk.refs
+        )?;
+    }
+    out.flush()
+}
+
+// so scripts can tell what went wrong without picking apart the message; clap exits with 2 when
+// the arguments are wrong
+const EXIT_USAGE: i32 = 2;
+const EXIT_TAG_NOT_FOUND: i32 = 3;
+const EXIT_BLOB_NOT_FOUND: i32 = 4;
+const EXIT_CORRUPT: i32 = 5;
+const EXIT_IO: i32 = 6;
+
+// what kind of error it is, and the exit code for it
+fn error_kind(e: &anyhow::Error) -> (&'static str, i32) {
+    match e.chain().find_map(|e| e.downcast_ref::<WireFormatError>()) {
+        Some(WireFormatError::InvalidChunkingParams(..))
+        | Some(WireFormatError::NotAnImageLayout(..)) => ("usage", EXIT_USAGE),
+        Some(WireFormatError::TagNotFound(..)) => ("tag_not_found", EXIT_TAG_NOT_FOUND),
+        Some(WireFormatError::BlobNotFound(..)) => ("blob_not_found", EXIT_BLOB_NOT_FOUND),
+        Some(WireFormatError::MetadataCorrupt(..))
+        | Some(WireFormatError::DigestMismatch(..))
+        | Some(WireFormatError::FileDigestMismatch(..))
+        | Some(WireFormatError::CBORError(..))
+        | Some(WireFormatError::JSONError(..)) => ("corrupt", EXIT_CORRUPT),
+        Some(WireFormatError::IOError(..)) | Some(WireFormatError::RegistryError(..)) => {
+            ("io", EXIT_IO)
+        }
+        Some(_) => ("other", 1),
+        None if e.chain().any(|e| e.is::<io::Error>()) => ("io", EXIT_IO),
+        None => ("other", 1),
+    }
+}
+
+fn error_json(e: &anyhow::Error) -> serde_json::Value {
+    let (kind, code) = error_kind(e);
+    serde_json::json!({
+        "error": e.to_string(),
+        "causes": e.chain().skip(1).map(|e| e.to_string()).collect::<Vec<_>>(),
+        "kind": kind,
+        "exit_code": code,
+    })
+}
+
+fn main() {
     let opts: Opts = Opts::parse();
+    // errors are json if anything is
+    let json = opts.json
+        || match &opts.subcmd {
+            SubCommand::Build(b) => b.json,
+            SubCommand::Ls(l) => l.json,
+            _ => false,
+        };
+    if let Err(e) = run(opts) {
+        if json {
+            eprintln!("{}", error_json(&e));
+        } else {
+            // the way returning the error from main() would have printed it
+            eprintln!("Error: {:?}", e);
+        }
+        exit(error_kind(&e).1);
+    }
+}
+
+fn run(opts: Opts) -> anyhow::Result<()> {
     match opts.subcmd {
         SubCommand::Build(b) => {
-            let rootfs = Path::new(&b.rootfs);
-            let oci_dir = Path::new(&b.oci_dir);
-            let image = Image::new(oci_dir)?;
-            let desc = build_initial_rootfs(rootfs, &image)?;
-            image.add_tag(b.tag, desc).map_err(|e| e.into())
+            let mut roots = b.rootfs_oci_dir_tag;
+            let (tag, oci_dir) = match (roots.pop(), roots.pop()) {
+                (Some(tag), Some(oci_dir)) if !roots.is_empty() => (tag, oci_dir),
+                _ => bail!("build needs a rootfs, an oci dir and a tag"),
+            };
+            if b.from_tar && roots.len() > 1 {
+                bail!("--from-tar only builds from one tarball");
+            }
+            if b.from_squashfs && roots.len() > 1 {
+                bail!("--from-squashfs only builds from one squashfs image");
+            }
+            let oci_dir = Path::new(&oci_dir);
+            let layout = if b.sharded_blobs {
+                BlobLayout::Sharded
+            } else {
+                BlobLayout::Flat
+            };
+            let image = if b.dry_run {
+                // everything goes through the chunking and deduplication a real build would do,
+                // only to end up in a store that notes what it was given and throws it away
+                Image::with_store(Arc::new(DiscardBlobStore::default()))
+            } else {
+                Image::new_with_layout(oci_dir, layout)?
+            }
+            .with_digest_algorithm(b.digest_algorithm);
+            // the blobs aren't referenced by anything until they're tagged at the very end
+            let _lock = image.store().lock(false)?;
+            let mut options = BuildOptions::default();
+            if let Some(min) = b.chunk_size_min {
+                options.chunking.min = min.0;
+            }
+            if let Some(avg) = b.chunk_size_avg {
+                options.chunking.avg = avg.0;
+            }
+            if let Some(max) = b.chunk_size_max {
+                options.chunking.max = max.0;
+            }
+            if let Some(algo) = b.chunker {
+                options.chunking.algo = algo;
+            }
+            if b.compression == "zstd" {
+                options.compression = ChunkCompression::Zstd {
+                    level: b
+                        .compression_level
+                        .unwrap_or(compression::DEFAULT_ZSTD_LEVEL),
+                };
+            } else if b.compression_level.is_some() {
+                bail!("--compression-level requires --compression=zstd");
+            }
+            options.compress_metadata = b.compress_metadata;
+            options.file_digests = b.file_digests;
+            if let Some(base) = b.base {
+                // the oci dir may well have colons in it, the tag won't; a digest has its own
+                let (oci_dir, tag) = match base.rsplit_once(":@") {
+                    Some((oci_dir, digest)) => (oci_dir, format!("@{}", digest)),
+                    None => base
+                        .rsplit_once(':')
+                        .map(|(oci_dir, tag)| (oci_dir, tag.to_string()))
+                        .ok_or_else(|| {
+                            anyhow!("--base must look like <oci dir>:<tag>, got {}", base)
+                        })?,
+                };
+                options.base = Some(BaseImage {
+                    oci_dir: PathBuf::from(oci_dir),
+                    tag,
+                });
+            }
+            options.whiteouts = b.whiteouts;
+            let from_archive = b.from_tar || b.from_squashfs;
+            if from_archive && !b.exclude.is_empty() {
+                bail!("--exclude only works when building from a directory");
+            }
+            if from_archive && b.dereference {
+                bail!("--dereference only works when building from a directory");
+            }
+            if from_archive && b.skip_errors {
+                bail!("--skip-errors only works when building from a directory");
+            }
+            options.exclude = b.exclude;
+            options.dereference = b.dereference;
+            if b.on_conflict == "overwrite" {
+                options.on_conflict = OnConflict::Overwrite;
+            }
+            options.skip_errors = b.skip_errors;
+            let journal = build_journal(oci_dir, &tag);
+            if !b.dry_run {
+                options.journal = Some(journal.clone());
+            }
+            let bar = progress_bar(b.quiet);
+            let reporter = bar.clone();
+            options.progress = Some(Box::new(move |p| {
+                reporter.set_message(format!(
+                    "{} files, {} chunked, {} blobs written",
+                    p.entries,
+                    HumanBytes(p.bytes_chunked),
+                    p.blobs_written
+                ))
+            }));
+            let (desc, stats) = if b.from_squashfs {
+                // squashfs is read all over the place, so it has to be a file
+                let squashfs = io::BufReader::new(fs::File::open(&roots[0])?);
+                build_from_squashfs_with_stats(squashfs, &image, &options)?
+            } else if !b.from_tar {
+                let roots = roots.iter().map(Path::new).collect::<Vec<_>>();
+                build_merged_rootfs_with_stats(&roots, &image, &options)?
+            } else if roots[0] == "-" {
+                build_from_tar_with_stats(io::stdin().lock(), &image, &options)?
+            } else {
+                build_from_tar_with_stats(fs::File::open(&roots[0])?, &image, &options)?
+            };
+            bar.finish_and_clear();
+            if b.dry_run {
+                return report_dry_run(&image, &stats, b.json);
+            }
+            let mut desc = desc;
+            desc.platform = Some(b.platform.unwrap_or_else(Platform::host));
+            image.add_tag(tag, desc)?;
+            fs::remove_file(journal)?;
+            if b.json {
+                println!("{}", serde_json::to_string(&stats)?);
+            } else {
+                print_build_stats(&stats);
+            }
+            Ok(())
         }
         SubCommand::Mount(m) => {
             // TODO: add --background option?
             let oci_dir = Path::new(&m.oci_dir);
-            let image = Image::new(oci_dir)?;
-            let mountpoint = Path::new(&m.mountpoint);
-            let _bg = mount(&image, &m.tag, mountpoint)?;
-            let mut signals = SignalsInfo::<SignalOnly>::new(TERM_SIGNALS);
-            for s in &mut signals {
-                eprintln!("got signal {:?}, exiting puzzlefs fuse mount", s);
+            let mut image = Image::open(oci_dir)?;
+            if let Some(fallback) = &m.fallback {
+                image = image.with_fallback(Image::open(Path::new(fallback))?.store().clone());
             }
-            // we can return, which will ->drop() _bg and kill the thread.
-            Ok(())
+            let (tag, mountpoint) = tag_and_path(&image, m.tag_and_mountpoint)?;
+            let mountpoint = Path::new(&mountpoint);
+            let mut options = MountOptions::default();
+            if let Some(cache_size) = m.cache_size {
+                options.cache_capacity = cache_size;
+            }
+            for o in &m.options {
+                options.add_options(o).map_err(|e| anyhow!(e))?;
+            }
+            if m.verify_on_read {
+                options.options.push(MountOption::VerifyOnRead);
+            }
+            if m.du_physical {
+                options.options.push(MountOption::DuPhysical);
+            }
+            options.subdir = m.subdir.map(PathBuf::from);
+            for mapping in &m.idmap {
+                let mapping = mapping.parse().map_err(|e: String| anyhow!(e))?;
+                options.options.push(MountOption::IdMap(mapping));
+            }
+            if m.overlay {
+                // overlayfs accesses the lower dir with the credentials of whoever mounted it, not
+                // of whoever is doing the access, so everyone has to be able to get in.
+                options.options.push(MountOption::AllowOther);
+                if !geteuid().is_root() && !user_allow_other_enabled(Path::new(FUSE_CONF)) {
+                    eprintln!(
+                        "hint: --overlay needs user_allow_other in {} when not mounting as root",
+                        FUSE_CONF
+                    );
+                }
+            }
+            if !mountpoint.exists() && !m.mkdir {
+                bail!(
+                    "mountpoint {} doesn't exist; --mkdir creates it",
+                    mountpoint.display()
+                );
+            }
+            let created = if m.mkdir {
+                create_dirs(mountpoint)?
+            } else {
+                Vec::new()
+            };
+            // later tags are stacked on top of earlier ones
+            let tags = tag.split(',').collect::<Vec<_>>();
+            let result = if m.foreground {
+                mount_foreground(&image, &tags, mountpoint, &options)
+            } else {
+                let mounted = mount_stack_with_options(&image, &tags, mountpoint, &options)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|bg| {
+                        let mut signals = SignalsInfo::<SignalOnly>::new(TERM_SIGNALS)?;
+                        if let Some(s) = signals.forever().next() {
+                            eprintln!("got signal {:?}, exiting puzzlefs fuse mount", s);
+                        }
+                        Ok(bg)
+                    });
+                // dropping the session unmounts it, which has to happen before the mountpoint can
+                // go
+                mounted.map(drop)
+            };
+            if m.rmdir {
+                for dir in created {
+                    if let Err(e) = fs::remove_dir(&dir) {
+                        eprintln!("couldn't remove {}: {}", dir.display(), e);
+                    }
+                }
+            }
+            result
         }
         SubCommand::Extract(e) => {
             let oci_dir = Path::new(&e.oci_dir);
-            let image = Image::new(oci_dir)?;
-            let dir = Path::new(&e.extract_dir);
+            let image = Image::open(oci_dir)?;
+            let (tag, extract_dir) = tag_and_path(&image, e.tag_and_extract_dir)?;
+            let mut pfs = PuzzleFS::open(&image, &tag)?;
+            let io_buffer = io_buffer_size(&pfs, e.io_buffer)?;
+            let mut symlinks = Symlinks::new(&image, &tag, e.symlink_mode)?;
+            let bar = progress_bar(e.quiet);
+            if e.format == "tar" {
+                let result = if extract_dir == "-" {
+                    extract_tar(
+                        &image,
+                        &mut pfs,
+                        io::stdout().lock(),
+                        io_buffer,
+                        &mut symlinks,
+                        &bar,
+                    )
+                } else {
+                    let out = fs::File::create(&extract_dir)?;
+                    extract_tar(&image, &mut pfs, out, io_buffer, &mut symlinks, &bar)
+                };
+                bar.finish_and_clear();
+                return result;
+            }
+            // an overlayfs style layer of a layered image, with whiteouts as .wh. files
+            let overlay = e.format == "overlay";
+            let dir = Path::new(&extract_dir);
             fs::create_dir_all(dir)?;
-            let mut pfs = PuzzleFS::open(&image, &e.tag)?;
             let mut walker = WalkPuzzleFS::walk(&mut pfs)?;
+            let mut stream = ChunkStream::new(&image);
+            let mut buf = vec![0_u8; io_buffer];
+            // puzzlefs inode to the first path we extracted it to, for recreating hard links
+            let mut links = HashMap::new();
+            // extracting things into a directory changes its mtime (and may not be allowed at all
+            // once it has its own permissions), so directories get their times and permissions
+            // once everything else is done
+            let mut dirs = Vec::new();
+            let (mut files, mut bytes) = (0, 0);
             walker.try_for_each(|de| -> anyhow::Result<()> {
                 let dir_entry = de?;
-                let path = safe_path(dir, &dir_entry.path)?;
-                // TODO: real logging :)
-                eprintln!("extracting {:#?}", path);
+                let mut path = safe_path(dir, &dir_entry.path)?;
+                if dir_entry.inode.is_whiteout() {
+                    // a whiteout deletes something from a lower layer; without one there's
+                    // nothing to do
+                    if !overlay {
+                        return Ok(());
+                    }
+                    let mut name = OsString::from(WHITEOUT_PREFIX);
+                    name.push(path.file_name().unwrap_or_default());
+                    path.set_file_name(name);
+                }
+                files += 1;
+                extract_progress(&bar, files, bytes);
+                if dir_entry.inode.inode.nlink > 1 && !dir_entry.inode.is_dir() {
+                    if let Some(existing) = links.get(&dir_entry.inode.inode.ino) {
+                        fs::hard_link(existing, &path)?;
+                        return Ok(());
+                    }
+                    links.insert(dir_entry.inode.inode.ino, path.clone());
+                }
                 match dir_entry.inode.mode {
-                    InodeMode::File { .. } => {
-                        let mut reader = dir_entry.open()?;
-                        let mut f = fs::File::create(path)?;
-                        io::copy(&mut reader, &mut f)?;
+                    InodeMode::File { ref chunks } => {
+                        let mut f = fs::File::create(&path)?;
+                        bytes += extract_file(chunks, &mut stream, &mut f, &mut buf)?;
+                    }
+                    InodeMode::Dir { .. } => {
+                        fs::create_dir_all(&path)?;
+                        if overlay && dir_entry.inode.is_opaque() {
+                            fs::File::create(path.join(OPAQUE_WHITEOUT))?;
+                            // what overlayfs itself looks for; only root can set trusted.
+                            // xattrs, but the marker file is enough for anything unpacking
+                            // the layer the OCI way
+                            match xattr::set(&path, OVERLAY_OPAQUE_XATTR, b"y") {
+                                Err(e) if e.raw_os_error() == Some(libc::EPERM) => {
+                                    eprintln!(
+                                        "not marking {:#?} opaque for overlayfs, not allowed to",
+                                        path
+                                    );
+                                }
+                                result => result?,
+                            }
+                        }
                     }
-                    InodeMode::Dir { .. } => fs::create_dir_all(path)?,
                     InodeMode::Other => {
                         match dir_entry.inode.inode.mode {
                             // TODO: fix all the hard coded modes when we have modes
@@ -152,20 +1393,26 @@ fn main() -> anyhow::Result<()> {
                                 mkfifo(&path, Mode::S_IRWXU)?;
                             }
                             format::InodeMode::Chr { major, minor } => {
-                                mknod(&path, SFlag::S_IFCHR, Mode::S_IRWXU, makedev(major, minor))?;
+                                if !make_device(&path, SFlag::S_IFCHR, makedev(major, minor))? {
+                                    return Ok(());
+                                }
                             }
                             format::InodeMode::Blk { major, minor } => {
-                                mknod(&path, SFlag::S_IFBLK, Mode::S_IRWXU, makedev(major, minor))?;
+                                if !make_device(&path, SFlag::S_IFBLK, makedev(major, minor))? {
+                                    return Ok(());
+                                }
                             }
                             format::InodeMode::Lnk => {
-                                let target = dir_entry.inode.symlink_target()?;
+                                let target = symlinks
+                                    .target(&dir_entry.path, dir_entry.inode.symlink_target()?)?;
                                 symlinkat(target.as_os_str(), None, &path)?;
                             }
                             format::InodeMode::Sock => {
-                                todo!();
+                                // nothing is listening, but the socket file itself can exist
+                                mknod(&path, SFlag::S_IFSOCK, Mode::S_IRWXU, 0)?;
                             }
                             format::InodeMode::Wht => {
-                                todo!();
+                                fs::File::create(&path)?;
                             }
                             _ => {
                                 bail!("bad inode mode {:#?}", dir_entry.inode.inode.mode)
@@ -173,8 +1420,259 @@ fn main() -> anyhow::Result<()> {
                         }
                     }
                 }
+                // only root can give files away; everyone else gets their own files, like tar.
+                // this comes before xattrs since chown() clears security.capability.
+                if geteuid().is_root() {
+                    fchownat(
+                        None,
+                        &path,
+                        Some(Uid::from_raw(dir_entry.inode.inode.uid)),
+                        Some(Gid::from_raw(dir_entry.inode.inode.gid)),
+                        FchownatFlags::NoFollowSymlink,
+                    )?;
+                }
+                if let Some(additional) = &dir_entry.inode.additional {
+                    for x in &additional.xattrs {
+                        match xattr::set(&path, &x.key, x.val.as_deref().unwrap_or_default()) {
+                            // the file works without its capabilities, just not as well (e.g.
+                            // ping without cap_net_raw), so that's no reason to give up
+                            Err(e)
+                                if x.key == CAPABILITY_XATTR
+                                    && e.raw_os_error() == Some(libc::EPERM) =>
+                            {
+                                eprintln!(
+                                    "not setting the capabilities of {:#?}, not allowed to",
+                                    path
+                                );
+                            }
+                            result => result?,
+                        }
+                    }
+                }
+                let permissions =
+                    fs::Permissions::from_mode(dir_entry.inode.inode.permissions as u32);
+                if dir_entry.inode.is_dir() {
+                    dirs.push((
+                        path,
+                        permissions,
+                        dir_entry.inode.inode.mtime,
+                        dir_entry.inode.inode.atime,
+                    ));
+                } else {
+                    // chmod() would follow a symlink, and their permissions don't mean anything
+                    // anyway. this comes after the xattrs, which need the file to be writable.
+                    if dir_entry.inode.inode.mode != format::InodeMode::Lnk {
+                        fs::set_permissions(&path, permissions)?;
+                    }
+                    set_times(
+                        &path,
+                        dir_entry.inode.inode.mtime,
+                        dir_entry.inode.inode.atime,
+                    )?;
+                }
+                Ok(())
+            })?;
+            bar.finish_and_clear();
+            // deepest first, so that a directory nobody can search doesn't stop its
+            // subdirectories from getting theirs
+            for (path, permissions, mtime, atime) in dirs.into_iter().rev() {
+                fs::set_permissions(&path, permissions)?;
+                set_times(&path, mtime, atime)?;
+            }
+            Ok(())
+        }
+        SubCommand::Verify(v) => {
+            let oci_dir = Path::new(&v.oci_dir);
+            let image = Image::open(oci_dir)?;
+            let mut pfs = PuzzleFS::open(&image, &tag_or_default(&image, v.tag)?)?;
+            let mut walker = WalkPuzzleFS::walk(&mut pfs)?;
+            // lots of files share chunks, no need to hash them more than once
+            let mut seen = HashSet::new();
+            let mut blobs = Vec::new();
+            let (mut files, mut unchecked) = (0, 0);
+            walker.try_for_each(|de| -> anyhow::Result<()> {
+                let dir_entry = de?;
+                if let InodeMode::File { chunks } = &dir_entry.inode.mode {
+                    // the chunk lists are already in memory, so these are cheap
+                    files += 1;
+                    if !dir_entry
+                        .inode
+                        .verify_file_digest()
+                        .with_context(|| format!("{:#?}: bad chunk list", dir_entry.path))?
+                    {
+                        unchecked += 1;
+                    }
+                    for blob in chunks.iter().filter_map(|c| c.blob) {
+                        if seen.insert(Digest::try_from(blob)?.underlying()) {
+                            blobs.push((dir_entry.path.clone(), blob));
+                        }
+                    }
+                }
                 Ok(())
             })?;
+            if v.files_only {
+                if !v.quiet {
+                    println!(
+                        "verified {} files, {} without a stored digest",
+                        files - unchecked,
+                        unchecked
+                    );
+                }
+                return Ok(());
+            }
+
+            let bar = progress_bar(v.quiet);
+            let (done, done_bytes) = (AtomicU64::new(0), AtomicU64::new(0));
+            // hashing is spread over rayon's threads, but results come back in walk order, so the
+            // error that's reported is always the first one, whichever thread finds it first
+            let results = blobs
+                .par_iter()
+                .map(|(path, blob)| -> anyhow::Result<u64> {
+                    let bytes = image.verify_blob(*blob).with_context(|| {
+                        format!("{:#?}: bad blob {}", path, Digest::try_from(*blob).unwrap())
+                    })?;
+                    let done = done.fetch_add(1, Ordering::Relaxed) + 1;
+                    let done_bytes = done_bytes.fetch_add(bytes, Ordering::Relaxed) + bytes;
+                    bar.set_message(format!(
+                        "{}/{} blobs, {} verified",
+                        done,
+                        blobs.len(),
+                        HumanBytes(done_bytes)
+                    ));
+                    Ok(bytes)
+                })
+                .collect::<Vec<_>>();
+            bar.finish_and_clear();
+            let mut total = 0;
+            for bytes in results {
+                total += bytes?;
+            }
+            if !v.quiet {
+                println!("verified {} blobs, {}", blobs.len(), HumanBytes(total));
+            }
+            Ok(())
+        }
+        SubCommand::Ls(l) => {
+            let oci_dir = Path::new(&l.oci_dir);
+            let image = Image::open(oci_dir)?;
+            let (tag, path) = tag_or_path(&image, l.tag_and_path.clone())?;
+            let mut pfs = PuzzleFS::open(&image, &tag)?;
+            ls(&mut pfs, &path, &l)
+        }
+        SubCommand::Push(p) => {
+            let oci_dir = Path::new(&p.oci_dir);
+            let image = Image::open(oci_dir)?;
+            let (tag, reference) = tag_and_path(&image, p.tag_and_reference)?;
+            let reference = reference.parse::<Reference>().map_err(|e| anyhow!(e))?;
+            let registry = Registry::new(reference, p.insecure);
+            registry.push(&image, &tag)?;
+            Ok(())
+        }
+        SubCommand::Pull(p) => {
+            let oci_dir = Path::new(&p.oci_dir);
+            let image = Image::new(oci_dir)?;
+            let reference = p.reference.parse::<Reference>().map_err(|e| anyhow!(e))?;
+            let registry = Registry::new(reference, p.insecure);
+            registry.pull(&image, &p.tag)?;
+            Ok(())
+        }
+        SubCommand::Stats(s) => {
+            let oci_dir = Path::new(&s.oci_dir);
+            let image = Image::open(oci_dir)?;
+            let tag = tag_or_default(&image, s.tag)?;
+            let mut pfs = PuzzleFS::open(&image, &tag)?;
+            let stats = ImageStats::new(&mut pfs)?;
+            println!("logical bytes: {}", stats.logical_bytes);
+            let (stored, uncompressed) = metadata_bytes(&image, &tag)?;
+            println!("metadata bytes: {} ({} uncompressed)", stored, uncompressed);
+            println!("unique bytes: {}", stats.unique_bytes());
+            println!("dedup ratio: {:.2}", stats.dedup_ratio());
+            println!("chunks: {}", stats.chunk_count());
+            if let Some(sizes) = stats.chunk_sizes() {
+                println!(
+                    "chunk sizes: min {} avg {} max {} p50 {} p90 {}",
+                    sizes.min, sizes.avg, sizes.max, sizes.p50, sizes.p90
+                );
+            }
+            // everything in the oci dir, not just what the tag uses
+            println!("blobs in the image: {}", image.blob_count()?);
+            println!("blob bytes in the image: {}", image.total_blob_bytes()?);
+            if let Some(histogram) = &s.histogram {
+                write_histogram(&stats, fs::File::create(histogram)?)?;
+            }
+            if let Some(other) = &s.compare {
+                let mut other_pfs = PuzzleFS::open(&image, other)?;
+                let other_stats = ImageStats::new(&mut other_pfs)?;
+                println!(
+                    "shared with {}: {} bytes",
+                    other,
+                    stats.shared_bytes(&other_stats)
+                );
+            }
+            Ok(())
+        }
+        SubCommand::DiffBlobs(d) => {
+            let oci_dir = Path::new(&d.oci_dir);
+            let image = Image::open(oci_dir)?;
+            let mut pfs_a = PuzzleFS::open(&image, &d.tag_a)?;
+            let stats_a = ImageStats::new(&mut pfs_a)?;
+            let mut pfs_b = PuzzleFS::open(&image, &d.tag_b)?;
+            let stats_b = ImageStats::new(&mut pfs_b)?;
+            let diff = stats_a.diff(&stats_b);
+            let summary = |what: &str, blobs: &[(Digest, u64)]| {
+                println!(
+                    "{}: {} blobs, {} bytes",
+                    what,
+                    blobs.len(),
+                    BlobDiff::bytes(blobs)
+                )
+            };
+            summary("shared", &diff.shared);
+            summary(&format!("only in {}", d.tag_a), &diff.only_ours);
+            summary(&format!("only in {}", d.tag_b), &diff.only_theirs);
+            if d.verbose {
+                for (digest, _) in diff.shared.iter() {
+                    println!("{}", digest);
+                }
+            }
+            Ok(())
+        }
+        SubCommand::Gc(g) => {
+            let oci_dir = Path::new(&g.oci_dir);
+            let image = Image::open(oci_dir)?;
+            let garbage = collect_garbage(&image, g.dry_run)?;
+            let verb = if g.dry_run { "would remove" } else { "removed" };
+            for (digest, size) in garbage.iter() {
+                println!("{} {} ({} bytes)", verb, digest, size);
+            }
+            let reclaimed = garbage.iter().map(|(_, size)| size).sum::<u64>();
+            if g.dry_run {
+                println!("would reclaim {} bytes", reclaimed);
+            } else {
+                println!("reclaimed {} bytes", reclaimed);
+            }
+            Ok(())
+        }
+        SubCommand::Rm(r) => {
+            let oci_dir = Path::new(&r.oci_dir);
+            let image = Image::open(oci_dir)?;
+            // only the tags go, their blobs are left for gc
+            if r.all {
+                let _lock = image.store().lock_index()?;
+                let mut index = image.get_index()?;
+                index.manifests.clear();
+                image.put_index(&index)?;
+            } else if let Some(tag) = r.tag {
+                if !image.remove_tag(&tag)? {
+                    return Err(WireFormatError::TagNotFound(tag, Backtrace::capture()).into());
+                }
+            }
+            Ok(())
+        }
+        SubCommand::Inspect(i) => {
+            let image = Image::open(Path::new(&i.oci_dir))?;
+            let info = inspect(&image, &tag_or_default(&image, i.tag)?)?;
+            println!("{}", serde_json::to_string_pretty(&info)?);
             Ok(())
         }
     }
@@ -0,0 +1,270 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::process::Command;
+use std::thread::sleep;
+use std::time::Duration;
+
+use assert_cmd::cargo::CommandCargoExt;
+use tempfile::tempdir;
+
+mod helpers;
+use helpers::{puzzlefs, Mounted};
+
+#[test]
+fn build_chunk_size_suffixes() {
+    let dir = tempdir().unwrap();
+    let rootfs = dir.path().join("rootfs");
+    fs::create_dir_all(&rootfs).unwrap();
+    fs::write(rootfs.join("file"), "meshuggah rocks").unwrap();
+    let oci = dir.path().join("oci");
+
+    for (tag, min, avg, max) in &[("one", "64K", "10M", "1G"), ("two", "64k", "1.5M", "2.25m")] {
+        puzzlefs(&[
+            OsStr::new("build"),
+            OsStr::new("--chunk-size-min"),
+            OsStr::new(min),
+            OsStr::new("--chunk-size-avg"),
+            OsStr::new(avg),
+            OsStr::new("--chunk-size-max"),
+            OsStr::new(max),
+            rootfs.as_os_str(),
+            oci.as_os_str(),
+            OsStr::new(tag),
+        ]);
+    }
+    let chunking = |tag| {
+        let output = Command::cargo_bin("puzzlefs")
+            .unwrap()
+            .args(&[OsStr::new("inspect"), oci.as_os_str(), OsStr::new(tag)])
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let info: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+        let size = |name: &str| info["chunking"][name].as_u64().unwrap();
+        (size("min"), size("avg"), size("max"))
+    };
+    assert_eq!(chunking("one"), (64 << 10, 10 << 20, 1 << 30));
+    assert_eq!(chunking("two"), (64 << 10, 3 << 19, 9 << 18));
+
+    for bad in &["10X", "1.1", "0.3K", "M", "1.5.5M", "20000000000000000T"] {
+        let output = Command::cargo_bin("puzzlefs")
+            .unwrap()
+            .args(&[
+                OsStr::new("build"),
+                OsStr::new("--chunk-size-avg"),
+                OsStr::new(bad),
+                rootfs.as_os_str(),
+                oci.as_os_str(),
+                OsStr::new("bad"),
+            ])
+            .output()
+            .unwrap();
+        assert!(!output.status.success(), "{}", bad);
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stderr.contains(&format!("bad size {}", bad)), "{}", stderr);
+    }
+}
+
+#[test]
+fn build_skip_errors() {
+    let dir = tempdir().unwrap();
+    let rootfs = dir.path().join("rootfs");
+    fs::create_dir_all(&rootfs).unwrap();
+    fs::write(rootfs.join("file"), "meshuggah rocks").unwrap();
+    // nothing can follow this one, not even root
+    std::os::unix::fs::symlink("nowhere", rootfs.join("dangling")).unwrap();
+    let oci = dir.path().join("oci");
+
+    let build = |extra: &[&str]| {
+        Command::cargo_bin("puzzlefs")
+            .unwrap()
+            .arg("build")
+            .arg("--dereference")
+            .args(extra)
+            .arg(&rootfs)
+            .arg(&oci)
+            .arg("test")
+            .output()
+            .unwrap()
+    };
+    assert!(!build(&[]).status.success());
+
+    let output = build(&["--skip-errors"]);
+    assert!(output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains(&format!("skipped {}", rootfs.join("dangling").display())),
+        "{}",
+        stderr
+    );
+
+    let output = build(&["--skip-errors", "--json"]);
+    let stats = serde_json::from_slice::<serde_json::Value>(&output.stdout).unwrap();
+    assert_eq!(
+        stats["skipped"][0]["path"],
+        rootfs.join("dangling").to_str().unwrap()
+    );
+
+    let extracted = dir.path().join("extracted");
+    puzzlefs(&[
+        OsStr::new("extract"),
+        oci.as_os_str(),
+        OsStr::new("test"),
+        extracted.as_os_str(),
+    ]);
+    assert_eq!(
+        fs::read(extracted.join("file")).unwrap(),
+        b"meshuggah rocks"
+    );
+    assert!(fs::symlink_metadata(extracted.join("dangling")).is_err());
+}
+
+// mounts tag, waiting for the mount to show up, since there may be nothing in it to wait for
+fn mount(oci: &Path, tag: &str, mountpoint: &Path) -> Mounted {
+    fs::create_dir_all(mountpoint).unwrap();
+    let parent = fs::metadata(mountpoint.parent().unwrap()).unwrap().dev();
+    let mounted = Mounted(
+        Command::cargo_bin("puzzlefs")
+            .unwrap()
+            .args(&[
+                OsStr::new("mount"),
+                oci.as_os_str(),
+                OsStr::new(tag),
+                mountpoint.as_os_str(),
+            ])
+            .spawn()
+            .unwrap(),
+    );
+    for _ in 0..100 {
+        if fs::metadata(mountpoint).unwrap().dev() != parent {
+            break;
+        }
+        sleep(Duration::from_millis(50));
+    }
+    assert_ne!(fs::metadata(mountpoint).unwrap().dev(), parent);
+    mounted
+}
+
+fn names(dir: &Path) -> Vec<String> {
+    let mut names = fs::read_dir(dir)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    names.sort();
+    names
+}
+
+#[test]
+fn build_empty_dir() {
+    let dir = tempdir().unwrap();
+    let rootfs = dir.path().join("rootfs");
+    fs::create_dir_all(&rootfs).unwrap();
+    let oci = dir.path().join("oci");
+    puzzlefs(&[
+        OsStr::new("build"),
+        rootfs.as_os_str(),
+        oci.as_os_str(),
+        OsStr::new("empty"),
+    ]);
+
+    let extracted = dir.path().join("extracted");
+    puzzlefs(&[
+        OsStr::new("extract"),
+        oci.as_os_str(),
+        OsStr::new("empty"),
+        extracted.as_os_str(),
+    ]);
+    assert!(fs::metadata(&extracted).unwrap().is_dir());
+    assert!(names(&extracted).is_empty());
+
+    let mountpoint = dir.path().join("mnt");
+    let _mounted = mount(&oci, "empty", &mountpoint);
+    assert!(names(&mountpoint).is_empty());
+}
+
+#[test]
+fn build_single_file() {
+    let dir = tempdir().unwrap();
+    let file = dir.path().join("lyrics");
+    fs::write(&file, "meshuggah rocks").unwrap();
+    let oci = dir.path().join("oci");
+    puzzlefs(&[
+        OsStr::new("build"),
+        file.as_os_str(),
+        oci.as_os_str(),
+        OsStr::new("single"),
+    ]);
+
+    let extracted = dir.path().join("extracted");
+    puzzlefs(&[
+        OsStr::new("extract"),
+        oci.as_os_str(),
+        OsStr::new("single"),
+        extracted.as_os_str(),
+    ]);
+    assert_eq!(names(&extracted), vec!["lyrics"]);
+    assert_eq!(
+        fs::read_to_string(extracted.join("lyrics")).unwrap(),
+        "meshuggah rocks"
+    );
+
+    let mountpoint = dir.path().join("mnt");
+    let _mounted = mount(&oci, "single", &mountpoint);
+    assert_eq!(names(&mountpoint), vec!["lyrics"]);
+    let md = fs::metadata(mountpoint.join("lyrics")).unwrap();
+    assert_eq!(
+        md.mode(),
+        fs::metadata(&file).unwrap().mode(),
+        "the file keeps its permissions"
+    );
+    assert_eq!(
+        fs::read_to_string(mountpoint.join("lyrics")).unwrap(),
+        "meshuggah rocks"
+    );
+}
+
+#[test]
+fn build_dry_run() {
+    let dir = tempdir().unwrap();
+    let rootfs = dir.path().join("rootfs");
+    fs::create_dir_all(rootfs.join("dir")).unwrap();
+    fs::write(rootfs.join("lyrics"), "meshuggah rocks ".repeat(1000)).unwrap();
+    fs::write(rootfs.join("dir/other"), "something else").unwrap();
+
+    let build = |oci: &Path, extra: &[&str]| {
+        let output = Command::cargo_bin("puzzlefs")
+            .unwrap()
+            .arg("build")
+            .args(extra)
+            .args(&[OsStr::new("--json"), rootfs.as_os_str(), oci.as_os_str()])
+            .arg("test")
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        serde_json::from_slice::<serde_json::Value>(&output.stdout).unwrap()
+    };
+    for compression in &["none", "zstd"] {
+        let oci = dir.path().join(format!("dry-{}", compression));
+        let dry = build(&oci, &["--dry-run", "--compression", compression]);
+        // nothing at all was written
+        assert!(!oci.exists());
+
+        let oci = dir.path().join(format!("real-{}", compression));
+        let real = build(&oci, &["--compression", compression]);
+        let blobs = fs::read_dir(oci.join("blobs/sha256")).unwrap().count();
+        let bytes = fs::read_dir(oci.join("blobs/sha256"))
+            .unwrap()
+            .map(|e| e.unwrap().metadata().unwrap().len())
+            .sum::<u64>();
+        assert_eq!(dry["blobs"], blobs);
+        assert_eq!(dry["bytes"], bytes);
+        assert_eq!(dry["chunks_written"], real["chunks_written"]);
+        assert_eq!(dry["chunks_deduplicated"], real["chunks_deduplicated"]);
+        let chunks = |what: &str| dry[what].as_u64().unwrap() as f64;
+        let ratio =
+            (chunks("chunks_written") + chunks("chunks_deduplicated")) / chunks("chunks_written");
+        assert!((dry["dedup_ratio"].as_f64().unwrap() - ratio).abs() < 1e-9);
+    }
+}
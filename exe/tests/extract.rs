@@ -1,11 +1,22 @@
 use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::fs::{FileExt, FileTypeExt, MetadataExt, PermissionsExt};
+use std::path::Path;
+use std::process::Command;
+use std::thread::sleep;
+use std::time::Duration;
 
 extern crate dir_diff;
 
+use assert_cmd::cargo::CommandCargoExt;
+use nix::libc;
+use nix::sys::stat::{makedev, mknod, utimensat, Mode, SFlag, UtimensatFlags};
+use nix::sys::time::TimeSpec;
+use nix::unistd::{chown, geteuid, mkfifo, Gid, Uid};
 use tempfile::tempdir;
 
 mod helpers;
-use helpers::{get_image, puzzlefs};
+use helpers::{get_image, puzzlefs, Mounted};
 
 #[test]
 fn build_and_extract_is_noop() {
@@ -31,3 +42,881 @@ fn build_and_extract_is_noop() {
     ]);
     assert!(!dir_diff::is_different(ubuntu, extracted).unwrap())
 }
+
+#[test]
+fn build_and_extract_xattrs() {
+    let dir = tempdir().unwrap();
+    let rootfs = dir.path().join("rootfs");
+    fs::create_dir_all(&rootfs).unwrap();
+    let file = rootfs.join("foo");
+    fs::write(&file, b"foo").unwrap();
+    xattr::set(&file, "user.empty", b"").unwrap();
+    xattr::set(&file, "user.binary", &[0, 0xff, 0, 42]).unwrap();
+
+    let oci = dir.path().join("oci");
+    puzzlefs(&[
+        OsStr::new("build"),
+        rootfs.as_os_str(),
+        oci.as_os_str(),
+        OsStr::new("test"),
+    ]);
+
+    let extracted = dir.path().join("extracted");
+    puzzlefs(&[
+        OsStr::new("extract"),
+        oci.as_os_str(),
+        OsStr::new("test"),
+        extracted.as_os_str(),
+    ]);
+
+    let extracted_file = extracted.join("foo");
+    assert_eq!(
+        xattr::get(&extracted_file, "user.empty").unwrap().unwrap(),
+        b""
+    );
+    assert_eq!(
+        xattr::get(&extracted_file, "user.binary").unwrap().unwrap(),
+        &[0, 0xff, 0, 42]
+    );
+}
+
+// a POSIX ACL the way the kernel has it in system.posix_acl_access: a version, then (tag, perm, id)
+// entries sorted by tag. this one is u::rw-,u:1000:r--,g::r--,m::r--,o::---; the entries that
+// aren't for a particular user or group have no id.
+fn acl_xattr() -> Vec<u8> {
+    const ACL_UNDEFINED_ID: u32 = u32::MAX;
+    let entries: [(u16, u16, u32); 5] = [
+        (0x01, 6, ACL_UNDEFINED_ID),
+        (0x02, 4, 1000),
+        (0x04, 4, ACL_UNDEFINED_ID),
+        (0x10, 4, ACL_UNDEFINED_ID),
+        (0x20, 0, ACL_UNDEFINED_ID),
+    ];
+    let mut acl = 2_u32.to_le_bytes().to_vec();
+    for (tag, perm, id) in &entries {
+        acl.extend_from_slice(&tag.to_le_bytes());
+        acl.extend_from_slice(&perm.to_le_bytes());
+        acl.extend_from_slice(&id.to_le_bytes());
+    }
+    acl
+}
+
+#[test]
+fn build_extract_and_mount_acls() {
+    let dir = tempdir().unwrap();
+    let rootfs = dir.path().join("rootfs");
+    fs::create_dir_all(&rootfs).unwrap();
+    let file = rootfs.join("secret");
+    fs::write(&file, b"meshuggah rocks").unwrap();
+    let acl = acl_xattr();
+    // not every filesystem the test might run on has ACLs
+    if xattr::set(&file, "system.posix_acl_access", &acl).is_err() {
+        return;
+    }
+    // the ACL's owner, mask and other entries are the permission bits
+    assert_eq!(fs::metadata(&file).unwrap().mode() & 0o777, 0o640);
+    // and directories can have a default one for what's made in them
+    let subdir = rootfs.join("dir");
+    fs::create_dir(&subdir).unwrap();
+    xattr::set(&subdir, "system.posix_acl_default", &acl).unwrap();
+
+    let oci = dir.path().join("oci");
+    puzzlefs(&[
+        OsStr::new("build"),
+        rootfs.as_os_str(),
+        oci.as_os_str(),
+        OsStr::new("test"),
+    ]);
+
+    let extracted = dir.path().join("extracted");
+    puzzlefs(&[
+        OsStr::new("extract"),
+        oci.as_os_str(),
+        OsStr::new("test"),
+        extracted.as_os_str(),
+    ]);
+    let extracted_file = extracted.join("secret");
+    assert_eq!(
+        xattr::get(&extracted_file, "system.posix_acl_access")
+            .unwrap()
+            .unwrap(),
+        acl
+    );
+    assert_eq!(fs::metadata(&extracted_file).unwrap().mode() & 0o777, 0o640);
+    assert_eq!(
+        xattr::get(extracted.join("dir"), "system.posix_acl_default")
+            .unwrap()
+            .unwrap(),
+        acl
+    );
+
+    let mountpoint = dir.path().join("mnt");
+    fs::create_dir_all(&mountpoint).unwrap();
+    let _mounted = Mounted(
+        Command::cargo_bin("puzzlefs")
+            .unwrap()
+            .args(&[
+                OsStr::new("mount"),
+                oci.as_os_str(),
+                OsStr::new("test"),
+                mountpoint.as_os_str(),
+            ])
+            .spawn()
+            .unwrap(),
+    );
+    let mounted_file = mountpoint.join("secret");
+    for _ in 0..100 {
+        if mounted_file.exists() {
+            break;
+        }
+        sleep(Duration::from_millis(50));
+    }
+    assert_eq!(
+        xattr::get(&mounted_file, "system.posix_acl_access")
+            .unwrap()
+            .unwrap(),
+        acl
+    );
+    assert!(xattr::list(&mounted_file)
+        .unwrap()
+        .any(|name| name == "system.posix_acl_access"));
+    assert_eq!(
+        xattr::get(mountpoint.join("dir"), "system.posix_acl_default")
+            .unwrap()
+            .unwrap(),
+        acl
+    );
+}
+
+#[test]
+fn build_and_extract_capabilities() {
+    let dir = tempdir().unwrap();
+    let rootfs = dir.path().join("rootfs");
+    fs::create_dir_all(&rootfs).unwrap();
+    let file = rootfs.join("ping");
+    fs::write(&file, b"ping").unwrap();
+    fs::set_permissions(&file, fs::Permissions::from_mode(0o755)).unwrap();
+    // setting capabilities needs CAP_SETFCAP. this is a VFS_CAP_REVISION_2 value with
+    // cap_net_raw in the permitted set.
+    const CAPABILITY: [u8; 20] = [
+        1, 0, 0, 2, 0, 0x20, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    ];
+    if xattr::set(&file, "security.capability", &CAPABILITY).is_err() {
+        return;
+    }
+
+    let oci = dir.path().join("oci");
+    puzzlefs(&[
+        OsStr::new("build"),
+        rootfs.as_os_str(),
+        oci.as_os_str(),
+        OsStr::new("test"),
+    ]);
+
+    let extracted = dir.path().join("extracted");
+    puzzlefs(&[
+        OsStr::new("extract"),
+        oci.as_os_str(),
+        OsStr::new("test"),
+        extracted.as_os_str(),
+    ]);
+
+    let extracted_file = extracted.join("ping");
+    assert_eq!(
+        xattr::get(&extracted_file, "security.capability")
+            .unwrap()
+            .unwrap(),
+        &CAPABILITY
+    );
+    assert_eq!(
+        fs::metadata(&extracted_file).unwrap().permissions().mode() & 0o7777,
+        0o755
+    );
+}
+
+#[test]
+fn build_and_extract_empty_and_tiny_files() {
+    let dir = tempdir().unwrap();
+    let oci = dir.path().join("oci");
+    // an image whose only file is empty, and one where a tiny file comes after it
+    for (tag, files) in &[
+        ("empty", &[("empty", &b""[..])][..]),
+        ("tiny", &[("empty", b""), ("one", b"x")]),
+    ] {
+        let rootfs = dir.path().join(tag);
+        fs::create_dir_all(&rootfs).unwrap();
+        for (name, data) in files.iter() {
+            fs::write(rootfs.join(name), data).unwrap();
+        }
+        puzzlefs(&[
+            OsStr::new("build"),
+            rootfs.as_os_str(),
+            oci.as_os_str(),
+            OsStr::new(tag),
+        ]);
+
+        let extracted = dir.path().join(format!("{}-extracted", tag));
+        puzzlefs(&[
+            OsStr::new("extract"),
+            oci.as_os_str(),
+            OsStr::new(tag),
+            extracted.as_os_str(),
+        ]);
+        for (name, data) in files.iter() {
+            let path = extracted.join(name);
+            assert!(fs::symlink_metadata(&path).unwrap().file_type().is_file());
+            assert_eq!(fs::read(&path).unwrap(), *data);
+        }
+    }
+}
+
+#[test]
+fn extract_tar_matches_extract_dir() {
+    let dir = tempdir().unwrap();
+    let rootfs = dir.path().join("rootfs");
+    fs::create_dir_all(rootfs.join("dir/subdir")).unwrap();
+    fs::write(rootfs.join("dir/foo"), b"foo").unwrap();
+    fs::write(
+        rootfs.join("dir/subdir/big"),
+        "meshuggah rocks\n".repeat(100_000),
+    )
+    .unwrap();
+    fs::hard_link(rootfs.join("dir/foo"), rootfs.join("foo-link")).unwrap();
+    std::os::unix::fs::symlink("dir/foo", rootfs.join("foo-symlink")).unwrap();
+
+    let oci = dir.path().join("oci");
+    puzzlefs(&[
+        OsStr::new("build"),
+        rootfs.as_os_str(),
+        oci.as_os_str(),
+        OsStr::new("test"),
+    ]);
+
+    let extracted = dir.path().join("extracted");
+    puzzlefs(&[
+        OsStr::new("extract"),
+        oci.as_os_str(),
+        OsStr::new("test"),
+        extracted.as_os_str(),
+    ]);
+
+    let output = Command::cargo_bin("puzzlefs")
+        .unwrap()
+        .args(&[
+            OsStr::new("extract"),
+            OsStr::new("--format=tar"),
+            oci.as_os_str(),
+            OsStr::new("test"),
+            OsStr::new("-"),
+        ])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let tarball = dir.path().join("test.tar");
+    fs::write(&tarball, output.stdout).unwrap();
+
+    let untarred = dir.path().join("untarred");
+    fs::create_dir_all(&untarred).unwrap();
+    let status = Command::new("tar")
+        .arg("-xf")
+        .arg(&tarball)
+        .arg("-C")
+        .arg(&untarred)
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    assert!(!dir_diff::is_different(&extracted, &untarred).unwrap());
+    assert_eq!(
+        fs::read_link(untarred.join("foo-symlink")).unwrap(),
+        Path::new("dir/foo")
+    );
+    let target = fs::metadata(untarred.join("dir/foo")).unwrap();
+    let link = fs::metadata(untarred.join("foo-link")).unwrap();
+    assert_eq!(target.ino(), link.ino());
+}
+
+#[test]
+fn build_from_tar_and_extract() {
+    let dir = tempdir().unwrap();
+    let rootfs = dir.path().join("rootfs");
+    // long enough that tar needs a GNU long name entry for it
+    let deep = rootfs
+        .join("dir")
+        .join("x".repeat(150))
+        .join("y".repeat(150));
+    fs::create_dir_all(&deep).unwrap();
+    fs::write(deep.join("foo"), b"foo").unwrap();
+    fs::write(rootfs.join("big"), "meshuggah rocks\n".repeat(100_000)).unwrap();
+    fs::hard_link(deep.join("foo"), rootfs.join("foo-link")).unwrap();
+    std::os::unix::fs::symlink("big", rootfs.join("big-symlink")).unwrap();
+
+    let tarball = dir.path().join("rootfs.tar");
+    let status = Command::new("tar")
+        .arg("-cf")
+        .arg(&tarball)
+        .arg("-C")
+        .arg(&rootfs)
+        .arg(".")
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let oci = dir.path().join("oci");
+    puzzlefs(&[
+        OsStr::new("build"),
+        OsStr::new("--from-tar"),
+        tarball.as_os_str(),
+        oci.as_os_str(),
+        OsStr::new("test"),
+    ]);
+
+    let extracted = dir.path().join("extracted");
+    puzzlefs(&[
+        OsStr::new("extract"),
+        oci.as_os_str(),
+        OsStr::new("test"),
+        extracted.as_os_str(),
+    ]);
+
+    assert!(!dir_diff::is_different(&rootfs, &extracted).unwrap());
+    assert_eq!(
+        fs::read_link(extracted.join("big-symlink")).unwrap(),
+        Path::new("big")
+    );
+    let target = fs::metadata(
+        extracted
+            .join("dir")
+            .join("x".repeat(150))
+            .join("y".repeat(150))
+            .join("foo"),
+    )
+    .unwrap();
+    let link = fs::metadata(extracted.join("foo-link")).unwrap();
+    assert_eq!(target.ino(), link.ino());
+}
+
+#[test]
+fn build_and_extract_whiteouts() {
+    let dir = tempdir().unwrap();
+    let rootfs = dir.path().join("rootfs");
+    fs::create_dir_all(rootfs.join("dir")).unwrap();
+    fs::write(rootfs.join(".wh.gone"), b"").unwrap();
+    fs::write(rootfs.join("dir/.wh..wh..opq"), b"").unwrap();
+    fs::write(rootfs.join("dir/file"), b"file").unwrap();
+    let oci = dir.path().join("oci");
+    puzzlefs(&[
+        OsStr::new("build"),
+        OsStr::new("--whiteouts"),
+        rootfs.as_os_str(),
+        oci.as_os_str(),
+        OsStr::new("test"),
+    ]);
+
+    // as a layer, the whiteouts come back the way they went in
+    let layer = dir.path().join("layer");
+    puzzlefs(&[
+        OsStr::new("extract"),
+        OsStr::new("--format"),
+        OsStr::new("overlay"),
+        oci.as_os_str(),
+        OsStr::new("test"),
+        layer.as_os_str(),
+    ]);
+    assert!(!dir_diff::is_different(&rootfs, &layer).unwrap());
+
+    // on their own there's nothing for them to delete
+    let extracted = dir.path().join("extracted");
+    puzzlefs(&[
+        OsStr::new("extract"),
+        oci.as_os_str(),
+        OsStr::new("test"),
+        extracted.as_os_str(),
+    ]);
+    assert!(!extracted.join("gone").exists());
+    assert!(!extracted.join(".wh.gone").exists());
+    assert!(!extracted.join("dir/.wh..wh..opq").exists());
+    assert_eq!(fs::read(extracted.join("dir/file")).unwrap(), b"file");
+}
+
+#[test]
+fn extract_overlay_opaque_xattr() {
+    // only root can set trusted. xattrs
+    if !geteuid().is_root() {
+        return;
+    }
+
+    let dir = tempdir().unwrap();
+    let rootfs = dir.path().join("rootfs");
+    fs::create_dir_all(rootfs.join("opaque")).unwrap();
+    fs::create_dir_all(rootfs.join("merged")).unwrap();
+    fs::write(rootfs.join("opaque/.wh..wh..opq"), b"").unwrap();
+    fs::write(rootfs.join("merged/file"), b"file").unwrap();
+    let oci = dir.path().join("oci");
+    puzzlefs(&[
+        OsStr::new("build"),
+        OsStr::new("--whiteouts"),
+        rootfs.as_os_str(),
+        oci.as_os_str(),
+        OsStr::new("test"),
+    ]);
+
+    let layer = dir.path().join("layer");
+    puzzlefs(&[
+        OsStr::new("extract"),
+        OsStr::new("--format"),
+        OsStr::new("overlay"),
+        oci.as_os_str(),
+        OsStr::new("test"),
+        layer.as_os_str(),
+    ]);
+    assert_eq!(
+        xattr::get(layer.join("opaque"), "trusted.overlay.opaque").unwrap(),
+        Some(b"y".to_vec())
+    );
+    assert_eq!(
+        xattr::get(layer.join("merged"), "trusted.overlay.opaque").unwrap(),
+        None
+    );
+
+    // a plain extract isn't a layer of anything
+    let extracted = dir.path().join("extracted");
+    puzzlefs(&[
+        OsStr::new("extract"),
+        oci.as_os_str(),
+        OsStr::new("test"),
+        extracted.as_os_str(),
+    ]);
+    assert_eq!(
+        xattr::get(extracted.join("opaque"), "trusted.overlay.opaque").unwrap(),
+        None
+    );
+}
+
+#[test]
+fn build_and_extract_timestamps() {
+    let dir = tempdir().unwrap();
+    let rootfs = dir.path().join("rootfs");
+    fs::create_dir_all(rootfs.join("dir")).unwrap();
+    fs::write(rootfs.join("dir/file"), b"file").unwrap();
+    std::os::unix::fs::symlink("dir/file", rootfs.join("link")).unwrap();
+
+    // distinct times with nanoseconds, so nothing gets mixed up or rounded. the atimes are
+    // later than now, so that relatime doesn't bump them when the build reads the files.
+    let times = [
+        ("dir/file", (1_000_000_000, 123_456_789), (4_000_000_000, 1)),
+        ("dir", (1_100_000_000, 1), (4_100_000_000, 2)),
+        ("link", (1_200_000_000, 999_999_999), (4_200_000_000, 3)),
+    ];
+    let timespec = |(sec, nsec)| {
+        TimeSpec::from(libc::timespec {
+            tv_sec: sec,
+            tv_nsec: nsec,
+        })
+    };
+    for (path, mtime, atime) in times.iter() {
+        utimensat(
+            None,
+            &rootfs.join(path),
+            &timespec(*atime),
+            &timespec(*mtime),
+            UtimensatFlags::NoFollowSymlink,
+        )
+        .unwrap();
+    }
+
+    let oci = dir.path().join("oci");
+    puzzlefs(&[
+        OsStr::new("build"),
+        rootfs.as_os_str(),
+        oci.as_os_str(),
+        OsStr::new("test"),
+    ]);
+    let extracted = dir.path().join("extracted");
+    puzzlefs(&[
+        OsStr::new("extract"),
+        oci.as_os_str(),
+        OsStr::new("test"),
+        extracted.as_os_str(),
+    ]);
+
+    for (path, mtime, atime) in times.iter() {
+        let md = fs::symlink_metadata(extracted.join(path)).unwrap();
+        assert_eq!((md.mtime(), md.mtime_nsec()), *mtime, "{}", path);
+        assert_eq!((md.atime(), md.atime_nsec()), *atime, "{}", path);
+    }
+}
+
+#[test]
+fn build_and_extract_devices() {
+    // making device nodes and giving files away needs privilege
+    if !geteuid().is_root() {
+        return;
+    }
+
+    let dir = tempdir().unwrap();
+    let rootfs = dir.path().join("rootfs");
+    fs::create_dir_all(&rootfs).unwrap();
+    mknod(
+        &rootfs.join("blk"),
+        SFlag::S_IFBLK,
+        Mode::S_IRUSR,
+        makedev(7, 200),
+    )
+    .unwrap();
+    mkfifo(&rootfs.join("fifo"), Mode::S_IRUSR).unwrap();
+    mknod(&rootfs.join("sock"), SFlag::S_IFSOCK, Mode::S_IRUSR, 0).unwrap();
+    fs::write(rootfs.join("owned"), b"owned").unwrap();
+    for name in &["blk", "owned"] {
+        chown(
+            &rootfs.join(name),
+            Some(Uid::from_raw(1000)),
+            Some(Gid::from_raw(2000)),
+        )
+        .unwrap();
+    }
+
+    let oci = dir.path().join("oci");
+    puzzlefs(&[
+        OsStr::new("build"),
+        rootfs.as_os_str(),
+        oci.as_os_str(),
+        OsStr::new("test"),
+    ]);
+    let extracted = dir.path().join("extracted");
+    puzzlefs(&[
+        OsStr::new("extract"),
+        oci.as_os_str(),
+        OsStr::new("test"),
+        extracted.as_os_str(),
+    ]);
+
+    let blk = fs::metadata(extracted.join("blk")).unwrap();
+    assert!(blk.file_type().is_block_device());
+    assert_eq!(blk.rdev(), makedev(7, 200));
+    assert_eq!((blk.uid(), blk.gid()), (1000, 2000));
+    assert!(fs::metadata(extracted.join("fifo"))
+        .unwrap()
+        .file_type()
+        .is_fifo());
+    assert!(fs::metadata(extracted.join("sock"))
+        .unwrap()
+        .file_type()
+        .is_socket());
+    let owned = fs::metadata(extracted.join("owned")).unwrap();
+    assert_eq!((owned.uid(), owned.gid()), (1000, 2000));
+}
+
+#[test]
+fn extract_keeps_holes() {
+    let dir = tempdir().unwrap();
+    let rootfs = dir.path().join("rootfs");
+    fs::create_dir_all(&rootfs).unwrap();
+    let f = fs::File::create(rootfs.join("sparse")).unwrap();
+    f.set_len(1 << 30).unwrap();
+    f.write_at(b"meshuggah", 1 << 20).unwrap();
+
+    let oci = dir.path().join("oci");
+    puzzlefs(&[
+        OsStr::new("build"),
+        rootfs.as_os_str(),
+        oci.as_os_str(),
+        OsStr::new("test"),
+    ]);
+    let extracted = dir.path().join("extracted");
+    puzzlefs(&[
+        OsStr::new("extract"),
+        oci.as_os_str(),
+        OsStr::new("test"),
+        extracted.as_os_str(),
+    ]);
+
+    let extracted_file = extracted.join("sparse");
+    let md = fs::metadata(&extracted_file).unwrap();
+    assert_eq!(md.len(), 1 << 30);
+    // st_blocks is in 512 byte units; a file full of zeros would be a gigabyte
+    assert!(md.blocks() * 512 < 1024 * 1024, "{} blocks", md.blocks());
+    let mut buf = [0_u8; 9];
+    fs::File::open(&extracted_file)
+        .unwrap()
+        .read_exact_at(&mut buf, 1 << 20)
+        .unwrap();
+    assert_eq!(&buf, b"meshuggah");
+}
+
+#[test]
+fn extract_with_any_io_buffer() {
+    let dir = tempdir().unwrap();
+    let rootfs = dir.path().join("rootfs");
+    fs::create_dir_all(&rootfs).unwrap();
+    // several chunks, the last of them short, and a hole in the middle
+    let data = (0..100_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    fs::write(rootfs.join("file"), &data).unwrap();
+    let f = fs::File::create(rootfs.join("sparse")).unwrap();
+    f.set_len(1 << 20).unwrap();
+    f.write_at(b"meshuggah", 0).unwrap();
+    f.write_at(b"meshuggah", (1 << 20) - 9).unwrap();
+    fs::write(rootfs.join("empty"), b"").unwrap();
+
+    let oci = dir.path().join("oci");
+    puzzlefs(&[
+        OsStr::new("build"),
+        OsStr::new("--chunker"),
+        OsStr::new("fixed"),
+        OsStr::new("--chunk-size-avg"),
+        OsStr::new("4096"),
+        rootfs.as_os_str(),
+        oci.as_os_str(),
+        OsStr::new("test"),
+    ]);
+
+    // smaller than, not a multiple of, the same as and bigger than the chunk size, and the default
+    for size in &[Some("1"), Some("4097"), Some("4096"), Some("1M"), None] {
+        let extracted = dir.path().join(format!("extracted-{:?}", size));
+        let mut args = vec![OsStr::new("extract")];
+        if let Some(size) = size {
+            args.extend(&[OsStr::new("--io-buffer"), OsStr::new(size)]);
+        }
+        args.extend(&[oci.as_os_str(), OsStr::new("test"), extracted.as_os_str()]);
+        puzzlefs(&args);
+        assert!(
+            !dir_diff::is_different(&rootfs, &extracted).unwrap(),
+            "{:?}",
+            size
+        );
+
+        let mut args = vec![OsStr::new("extract"), OsStr::new("--format=tar")];
+        if let Some(size) = size {
+            args.extend(&[OsStr::new("--io-buffer"), OsStr::new(size)]);
+        }
+        args.extend(&[oci.as_os_str(), OsStr::new("test"), OsStr::new("-")]);
+        let output = Command::cargo_bin("puzzlefs")
+            .unwrap()
+            .args(&args)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let tarball = dir.path().join(format!("{:?}.tar", size));
+        fs::write(&tarball, output.stdout).unwrap();
+        let untarred = dir.path().join(format!("untarred-{:?}", size));
+        fs::create_dir_all(&untarred).unwrap();
+        let status = Command::new("tar")
+            .arg("-xf")
+            .arg(&tarball)
+            .arg("-C")
+            .arg(&untarred)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        assert!(
+            !dir_diff::is_different(&rootfs, &untarred).unwrap(),
+            "{:?}",
+            size
+        );
+    }
+
+    let status = Command::cargo_bin("puzzlefs")
+        .unwrap()
+        .args(&[
+            OsStr::new("extract"),
+            OsStr::new("--io-buffer"),
+            OsStr::new("0"),
+            oci.as_os_str(),
+            OsStr::new("test"),
+            dir.path().join("zero").as_os_str(),
+        ])
+        .status()
+        .unwrap();
+    assert!(!status.success());
+}
+
+#[test]
+fn extract_symlink_modes() {
+    let dir = tempdir().unwrap();
+    let oci = dir.path().join("oci");
+    let inside = dir.path().join("inside");
+    fs::create_dir_all(inside.join("sub")).unwrap();
+    fs::create_dir_all(inside.join("x/y")).unwrap();
+    fs::write(inside.join("file"), b"meshuggah").unwrap();
+    std::os::unix::fs::symlink("../file", inside.join("sub/ok")).unwrap();
+    std::os::unix::fs::symlink("../..", inside.join("x/y/up")).unwrap();
+    let escaping = dir.path().join("escaping");
+    fs::create_dir_all(escaping.join("sub")).unwrap();
+    fs::create_dir_all(escaping.join("x/y")).unwrap();
+    std::os::unix::fs::symlink("../../../etc/passwd", escaping.join("sub/evil")).unwrap();
+    std::os::unix::fs::symlink("/etc/passwd", escaping.join("abs")).unwrap();
+    std::os::unix::fs::symlink("../..", escaping.join("x/y/up")).unwrap();
+    // only out once x/y/up has been followed back to the root
+    std::os::unix::fs::symlink("x/y/up/..", escaping.join("sneaky")).unwrap();
+    for (rootfs, tag) in &[(&inside, "inside"), (&escaping, "escaping")] {
+        puzzlefs(&[
+            OsStr::new("build"),
+            rootfs.as_os_str(),
+            oci.as_os_str(),
+            OsStr::new(tag),
+        ]);
+    }
+    let extract = |mode: &str, tag: &str| {
+        let extracted = dir.path().join(format!("{}-{}", mode, tag));
+        let output = Command::cargo_bin("puzzlefs")
+            .unwrap()
+            .args(&[
+                OsStr::new("extract"),
+                OsStr::new("--symlink-mode"),
+                OsStr::new(mode),
+                oci.as_os_str(),
+                OsStr::new(tag),
+                extracted.as_os_str(),
+            ])
+            .output()
+            .unwrap();
+        (output, extracted)
+    };
+    let target = |path: &Path| fs::read_link(path).unwrap().into_os_string();
+
+    for mode in &["preserve", "relativize", "reject-escapes"] {
+        let (output, extracted) = extract(mode, "inside");
+        assert!(output.status.success(), "{}", mode);
+        assert_eq!(target(&extracted.join("sub/ok")), "../file");
+        assert_eq!(target(&extracted.join("x/y/up")), "../..");
+    }
+
+    let (output, extracted) = extract("preserve", "escaping");
+    assert!(output.status.success());
+    assert_eq!(target(&extracted.join("sub/evil")), "../../../etc/passwd");
+    assert_eq!(target(&extracted.join("abs")), "/etc/passwd");
+
+    let (output, extracted) = extract("relativize", "escaping");
+    assert!(output.status.success());
+    assert_eq!(target(&extracted.join("sub/evil")), "../etc/passwd");
+    assert_eq!(target(&extracted.join("abs")), "etc/passwd");
+    assert_eq!(target(&extracted.join("x/y/up")), "../..");
+    assert_eq!(target(&extracted.join("sneaky")), ".");
+
+    let (output, extracted) = extract("reject-escapes", "escaping");
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("escapes the extract dir"));
+    assert!(fs::symlink_metadata(extracted.join("sub/evil")).is_err());
+}
+
+#[test]
+fn extract_keeps_permissions() {
+    let dir = tempdir().unwrap();
+    let rootfs = dir.path().join("rootfs");
+    fs::create_dir_all(rootfs.join("locked")).unwrap();
+    let modes = [
+        ("private", 0o600),
+        ("setuid", 0o4755),
+        ("locked/readonly", 0o444),
+        ("locked", 0o500),
+    ];
+    for (name, _) in &modes[..3] {
+        fs::write(rootfs.join(name), b"foo").unwrap();
+    }
+    for (name, mode) in &modes {
+        fs::set_permissions(rootfs.join(name), fs::Permissions::from_mode(*mode)).unwrap();
+    }
+
+    let oci = dir.path().join("oci");
+    puzzlefs(&[
+        OsStr::new("build"),
+        rootfs.as_os_str(),
+        oci.as_os_str(),
+        OsStr::new("test"),
+    ]);
+    let extracted = dir.path().join("extracted");
+    puzzlefs(&[
+        OsStr::new("extract"),
+        oci.as_os_str(),
+        OsStr::new("test"),
+        extracted.as_os_str(),
+    ]);
+
+    for (name, mode) in &modes {
+        let md = fs::metadata(extracted.join(name)).unwrap();
+        assert_eq!(md.mode() & 0o7777, *mode, "{}", name);
+    }
+}
+
+#[test]
+fn build_merges_roots() {
+    let dir = tempdir().unwrap();
+    let lower = dir.path().join("lower");
+    let upper = dir.path().join("upper");
+    fs::create_dir_all(lower.join("etc")).unwrap();
+    fs::create_dir_all(upper.join("etc")).unwrap();
+    fs::write(lower.join("etc/hostname"), b"lower").unwrap();
+    fs::write(lower.join("etc/hosts"), b"127.0.0.1 localhost").unwrap();
+    fs::write(lower.join("bin"), b"lower").unwrap();
+    fs::write(upper.join("etc/hostname"), b"upper").unwrap();
+    fs::write(upper.join("etc/motd"), b"meshuggah rocks").unwrap();
+
+    let oci = dir.path().join("oci");
+    puzzlefs(&[
+        OsStr::new("build"),
+        lower.as_os_str(),
+        upper.as_os_str(),
+        oci.as_os_str(),
+        OsStr::new("test"),
+    ]);
+    let extracted = dir.path().join("extracted");
+    puzzlefs(&[
+        OsStr::new("extract"),
+        oci.as_os_str(),
+        OsStr::new("test"),
+        extracted.as_os_str(),
+    ]);
+    assert_eq!(fs::read(extracted.join("etc/hostname")).unwrap(), b"upper");
+    assert_eq!(
+        fs::read(extracted.join("etc/hosts")).unwrap(),
+        b"127.0.0.1 localhost"
+    );
+    assert_eq!(
+        fs::read(extracted.join("etc/motd")).unwrap(),
+        b"meshuggah rocks"
+    );
+    assert_eq!(fs::read(extracted.join("bin")).unwrap(), b"lower");
+
+    // a file can't shadow a directory
+    fs::create_dir_all(upper.join("bin")).unwrap();
+    let output = Command::cargo_bin("puzzlefs")
+        .unwrap()
+        .args(&[
+            OsStr::new("build"),
+            lower.as_os_str(),
+            upper.as_os_str(),
+            oci.as_os_str(),
+            OsStr::new("conflict"),
+        ])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("/bin is a file in") && stderr.contains("but a directory in"),
+        "{}",
+        stderr
+    );
+
+    // unless the later one is asked to win, which for a directory takes what was in it too
+    fs::create_dir_all(lower.join("lib")).unwrap();
+    fs::write(lower.join("lib/libc.so"), b"lower").unwrap();
+    fs::write(upper.join("lib"), b"upper").unwrap();
+    puzzlefs(&[
+        OsStr::new("build"),
+        OsStr::new("--on-conflict=overwrite"),
+        lower.as_os_str(),
+        upper.as_os_str(),
+        oci.as_os_str(),
+        OsStr::new("overwrite"),
+    ]);
+    let extracted = dir.path().join("overwritten");
+    puzzlefs(&[
+        OsStr::new("extract"),
+        oci.as_os_str(),
+        OsStr::new("overwrite"),
+        extracted.as_os_str(),
+    ]);
+    assert!(extracted.join("bin").is_dir());
+    assert_eq!(fs::read(extracted.join("lib")).unwrap(), b"upper");
+}
@@ -0,0 +1,90 @@
+// on its own, since a child's peak memory use starts out as whatever its parent's was when it was
+// spawned, and other tests building images in the same process would push that up
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::mem::MaybeUninit;
+use std::os::unix::fs::FileExt;
+use std::process::Command;
+
+use assert_cmd::cargo::CommandCargoExt;
+use nix::libc;
+use tempfile::tempdir;
+
+mod helpers;
+use helpers::Noise;
+
+// a sparse file with a few runs of data spread through it, so that it's big without taking long
+// to build or much space on disk
+const FILE_SIZE: u64 = 2 * 1024 * 1024 * 1024;
+const EXTENTS: u64 = 8;
+const EXTENT_SIZE: u64 = 8 * 1024 * 1024;
+
+// the same however big the file is, and no more than its data; the chunks and extract's buffers
+// should come to a lot less than this
+const MAX_RSS: u64 = 64 * 1024 * 1024;
+
+// runs puzzlefs, and returns the most memory it used, in bytes
+fn puzzlefs_max_rss(args: &[&str]) -> u64 {
+    let child = Command::cargo_bin("puzzlefs")
+        .unwrap()
+        .args(args)
+        .spawn()
+        .unwrap();
+    let mut status = 0;
+    let mut usage = MaybeUninit::<libc::rusage>::uninit();
+    let pid = unsafe { libc::wait4(child.id() as i32, &mut status, 0, usage.as_mut_ptr()) };
+    assert_eq!(pid, child.id() as i32);
+    assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+    unsafe { usage.assume_init() }.ru_maxrss as u64 * 1024
+}
+
+#[test]
+fn extract_big_file_in_bounded_memory() {
+    let dir = tempdir().unwrap();
+    let rootfs = dir.path().join("rootfs");
+    fs::create_dir_all(&rootfs).unwrap();
+    let mut f = fs::File::create(rootfs.join("big")).unwrap();
+    f.set_len(FILE_SIZE).unwrap();
+    for i in 0..EXTENTS {
+        f.seek(SeekFrom::Start(i * (FILE_SIZE / EXTENTS))).unwrap();
+        io::copy(&mut Noise(42 + i).take(EXTENT_SIZE), &mut f).unwrap();
+    }
+    drop(f);
+
+    // compressed chunks can't be read straight out of the blob files. the default chunks are
+    // tens of megabytes each, bigger than a file's worth of reads should ever hold on to.
+    let oci = dir.path().join("oci");
+    let (rootfs, oci) = (rootfs.to_str().unwrap(), oci.to_str().unwrap());
+    puzzlefs_max_rss(&[
+        "build",
+        "--compression",
+        "zstd",
+        "--compression-level",
+        "1",
+        rootfs,
+        oci,
+        "test",
+    ]);
+
+    let extracted = dir.path().join("extracted");
+    let rss = puzzlefs_max_rss(&[
+        "extract",
+        "--quiet",
+        oci,
+        "test",
+        extracted.to_str().unwrap(),
+    ]);
+    let (original, extracted) = (
+        fs::File::open(dir.path().join("rootfs/big")).unwrap(),
+        fs::File::open(extracted.join("big")).unwrap(),
+    );
+    assert_eq!(extracted.metadata().unwrap().len(), FILE_SIZE);
+    let (mut want, mut got) = (vec![0; EXTENT_SIZE as usize], vec![0; EXTENT_SIZE as usize]);
+    for i in 0..EXTENTS {
+        let offset = i * (FILE_SIZE / EXTENTS);
+        original.read_exact_at(&mut want, offset).unwrap();
+        extracted.read_exact_at(&mut got, offset).unwrap();
+        assert!(want == got, "extent {} differs", i);
+    }
+    assert!(rss < MAX_RSS, "extract used {} bytes", rss);
+}
@@ -0,0 +1,102 @@
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use assert_cmd::cargo::CommandCargoExt;
+use tempfile::tempdir;
+
+use oci::Image;
+
+mod helpers;
+use helpers::puzzlefs;
+
+// every blob in the image, and how big it is
+fn blobs(oci: &Path) -> BTreeMap<String, u64> {
+    fs::read_dir(oci.join("blobs/sha256"))
+        .unwrap()
+        .map(|e| {
+            let e = e.unwrap();
+            let name = e.file_name().into_string().unwrap();
+            (name, e.metadata().unwrap().len())
+        })
+        .collect()
+}
+
+fn gc(oci: &Path, dry_run: bool) -> String {
+    let mut args = vec![OsStr::new("gc"), oci.as_os_str()];
+    if dry_run {
+        args.push(OsStr::new("--dry-run"));
+    }
+    let output = Command::cargo_bin("puzzlefs")
+        .unwrap()
+        .args(&args)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn gc_removes_only_unreferenced_blobs() {
+    let dir = tempdir().unwrap();
+    let oci = dir.path().join("oci");
+    let shared = (0..4096).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    let mut after = Vec::new();
+    for tag in &["one", "two"] {
+        let rootfs = dir.path().join(tag);
+        fs::create_dir_all(&rootfs).unwrap();
+        fs::write(rootfs.join("shared"), &shared).unwrap();
+        fs::write(rootfs.join("mine"), tag.repeat(1024)).unwrap();
+        puzzlefs(&[
+            OsStr::new("build"),
+            OsStr::new("--chunker"),
+            OsStr::new("fixed"),
+            OsStr::new("--chunk-size-avg"),
+            OsStr::new("4096"),
+            rootfs.as_os_str(),
+            oci.as_os_str(),
+            OsStr::new(tag),
+        ]);
+        after.push(blobs(&oci));
+    }
+    let (one, both) = (&after[0], &after[1]);
+    let only_two = both
+        .iter()
+        .filter(|(name, _)| !one.contains_key(*name))
+        .collect::<Vec<_>>();
+    // two's rootfs, its metadata and its own chunk; the shared chunk stays
+    assert_eq!(only_two.len(), 3);
+    let reclaimed = only_two.iter().map(|(_, size)| *size).sum::<u64>();
+
+    // with everything still tagged there's nothing to do
+    let stdout = gc(&oci, false);
+    assert_eq!(stdout, "reclaimed 0 bytes\n");
+    assert_eq!(&blobs(&oci), both);
+
+    assert!(Image::open(&oci).unwrap().remove_tag("two").unwrap());
+    let stdout = gc(&oci, true);
+    for (name, _) in only_two.iter() {
+        assert!(
+            stdout.contains(&format!("would remove {}", name)),
+            "{}",
+            stdout
+        );
+    }
+    assert!(
+        stdout.contains(&format!("would reclaim {} bytes\n", reclaimed)),
+        "{}",
+        stdout
+    );
+    assert_eq!(&blobs(&oci), both);
+
+    let stdout = gc(&oci, false);
+    assert!(
+        stdout.contains(&format!("reclaimed {} bytes\n", reclaimed)),
+        "{}",
+        stdout
+    );
+    assert_eq!(&blobs(&oci), one);
+    puzzlefs(&[OsStr::new("verify"), oci.as_os_str(), OsStr::new("one")]);
+}
@@ -1,9 +1,14 @@
+// each integration test binary only uses some of these
+#![allow(dead_code)]
+
 use std::ffi::OsStr;
-use std::io;
+use std::io::{self, Read};
 use std::path::Path;
-use std::process::Command;
+use std::process::{Child, Command};
 
 use assert_cmd::cargo::CommandCargoExt;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
 
 pub fn get_image<P: AsRef<Path>>(to_dir: P) -> io::Result<()> {
     let image = "ubuntu";
@@ -22,3 +27,36 @@ where
     let mut cmd = Command::cargo_bin("puzzlefs").unwrap();
     assert!(cmd.args(args).status().unwrap().success());
 }
+
+// make sure the mount goes away even if the test fails, otherwise the test run hangs
+pub struct Mounted(pub Child);
+
+impl Drop for Mounted {
+    fn drop(&mut self) {
+        let _ = kill(Pid::from_raw(self.0.id() as i32), Signal::SIGTERM);
+        let _ = self.0.wait();
+    }
+}
+
+// data that doesn't deduplicate or compress, a xorshift from the seed, without having to hold it
+// all in memory
+pub struct Noise(pub u64);
+
+impl Read for Noise {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        for b in buf.iter_mut() {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            *b = self.0 as u8;
+        }
+        Ok(buf.len())
+    }
+}
+
+// len bytes of Noise
+pub fn noise(seed: u64, len: usize) -> Vec<u8> {
+    let mut data = vec![0; len];
+    Noise(seed).read_exact(&mut data).unwrap();
+    data
+}
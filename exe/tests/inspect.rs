@@ -0,0 +1,119 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::process::Command;
+
+use assert_cmd::cargo::CommandCargoExt;
+use tempfile::tempdir;
+
+mod helpers;
+use helpers::puzzlefs;
+
+#[test]
+fn inspect_counts_blobs() {
+    let dir = tempdir().unwrap();
+    let rootfs = dir.path().join("rootfs");
+    fs::create_dir_all(&rootfs).unwrap();
+    // three distinct chunks, one of which the second file shares
+    let data = (0..3 * 4096).map(|i| (i / 4096) as u8).collect::<Vec<_>>();
+    fs::write(rootfs.join("a"), &data).unwrap();
+    fs::write(rootfs.join("b"), &data[..4096]).unwrap();
+    let oci = dir.path().join("oci");
+    puzzlefs(&[
+        OsStr::new("build"),
+        OsStr::new("--chunker"),
+        OsStr::new("fixed"),
+        OsStr::new("--chunk-size-avg"),
+        OsStr::new("4096"),
+        rootfs.as_os_str(),
+        oci.as_os_str(),
+        OsStr::new("test"),
+    ]);
+
+    let output = Command::cargo_bin("puzzlefs")
+        .unwrap()
+        .args(&[OsStr::new("inspect"), oci.as_os_str(), OsStr::new("test")])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let info: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+
+    // the rootfs, its one metadata blob and the chunks; nothing else was ever written
+    assert_eq!(info["blob_count"], 5);
+    let blobs = fs::read_dir(oci.join("blobs/sha256"))
+        .unwrap()
+        .map(|e| e.unwrap().metadata().unwrap().len())
+        .collect::<Vec<_>>();
+    assert_eq!(blobs.len(), 5);
+    assert_eq!(info["total_size"], blobs.iter().sum::<u64>());
+    assert_eq!(info["chunking"]["algo"], "Fixed");
+    assert_eq!(info["chunking"]["avg"], 4096);
+    assert_eq!(info["version"], 2);
+    assert_eq!(info["config"]["metadatas"].as_array().unwrap().len(), 1);
+    let index: serde_json::Value =
+        serde_json::from_slice(&fs::read(oci.join("index.json")).unwrap()).unwrap();
+    assert_eq!(info["manifest"], index["manifests"][0]);
+}
+
+fn inspect_platform(oci: &std::path::Path) -> serde_json::Value {
+    let output = Command::cargo_bin("puzzlefs")
+        .unwrap()
+        .args(&[OsStr::new("inspect"), oci.as_os_str(), OsStr::new("test")])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let info: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let index: serde_json::Value =
+        serde_json::from_slice(&fs::read(oci.join("index.json")).unwrap()).unwrap();
+    assert_eq!(
+        info["manifest"]["platform"],
+        index["manifests"][0]["platform"]
+    );
+    info["manifest"]["platform"].clone()
+}
+
+#[test]
+fn inspect_reports_platform() {
+    let dir = tempdir().unwrap();
+    let rootfs = dir.path().join("rootfs");
+    fs::create_dir_all(&rootfs).unwrap();
+    let oci = dir.path().join("oci");
+    puzzlefs(&[
+        OsStr::new("build"),
+        rootfs.as_os_str(),
+        oci.as_os_str(),
+        OsStr::new("test"),
+    ]);
+    let platform = inspect_platform(&oci);
+    assert_eq!(platform["os"], std::env::consts::OS);
+    assert!(platform["architecture"].is_string());
+    assert!(platform.get("variant").is_none());
+
+    let oci = dir.path().join("arm");
+    puzzlefs(&[
+        OsStr::new("build"),
+        OsStr::new("--platform"),
+        OsStr::new("linux/arm/v7"),
+        rootfs.as_os_str(),
+        oci.as_os_str(),
+        OsStr::new("test"),
+    ]);
+    let platform = inspect_platform(&oci);
+    assert_eq!(platform["os"], "linux");
+    assert_eq!(platform["architecture"], "arm");
+    assert_eq!(platform["variant"], "v7");
+
+    let status = Command::cargo_bin("puzzlefs")
+        .unwrap()
+        .args(&[
+            OsStr::new("build"),
+            OsStr::new("--platform"),
+            OsStr::new("arm64"),
+            rootfs.as_os_str(),
+            oci.as_os_str(),
+            OsStr::new("test"),
+        ])
+        .output()
+        .unwrap()
+        .status;
+    assert!(!status.success());
+}
@@ -0,0 +1,173 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::Command;
+
+use assert_cmd::cargo::CommandCargoExt;
+use tempfile::tempdir;
+
+mod helpers;
+use helpers::puzzlefs;
+
+fn build(dir: &Path) -> std::path::PathBuf {
+    let rootfs = dir.join("rootfs");
+    fs::create_dir_all(rootfs.join("dir/subdir")).unwrap();
+    fs::write(rootfs.join("dir/foo"), b"foo").unwrap();
+    fs::set_permissions(rootfs.join("dir/foo"), fs::Permissions::from_mode(0o4750)).unwrap();
+    fs::write(rootfs.join("dir/subdir/bar"), b"barbar").unwrap();
+    std::os::unix::fs::symlink("dir/foo", rootfs.join("foo-symlink")).unwrap();
+
+    let oci = dir.join("oci");
+    puzzlefs(&[
+        OsStr::new("build"),
+        rootfs.as_os_str(),
+        oci.as_os_str(),
+        OsStr::new("test"),
+    ]);
+    oci
+}
+
+fn ls(oci: &Path, args: &[&str]) -> Vec<String> {
+    let output = Command::cargo_bin("puzzlefs")
+        .unwrap()
+        .arg("ls")
+        .arg(oci)
+        .arg("test")
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|l| l.to_string())
+        .collect()
+}
+
+#[test]
+fn ls_lists_a_directory() {
+    let dir = tempdir().unwrap();
+    let oci = build(dir.path());
+
+    let lines = ls(&oci, &[]);
+    assert_eq!(lines.len(), 2, "{:?}", lines);
+    assert!(lines[0].starts_with('d'), "{}", lines[0]);
+    assert!(lines[0].ends_with(" dir"), "{}", lines[0]);
+    assert!(lines[1].starts_with('l'), "{}", lines[1]);
+    assert!(
+        lines[1].ends_with(" foo-symlink -> dir/foo"),
+        "{}",
+        lines[1]
+    );
+
+    let lines = ls(&oci, &["dir"]);
+    assert_eq!(lines.len(), 2, "{:?}", lines);
+    let fields = lines[0].split_whitespace().collect::<Vec<_>>();
+    assert_eq!(fields[0], "-rwsr-x---");
+    // nlink, uid, gid, size, name
+    assert_eq!(fields[1], "1");
+    assert_eq!(fields[4], "3");
+    assert_eq!(fields[5], "foo");
+    assert!(lines[1].ends_with(" subdir"), "{}", lines[1]);
+
+    // a file lists itself
+    let lines = ls(&oci, &["/dir/foo"]);
+    assert_eq!(lines.len(), 1, "{:?}", lines);
+    assert!(lines[0].ends_with(" foo"), "{}", lines[0]);
+}
+
+#[test]
+fn ls_recursive() {
+    let dir = tempdir().unwrap();
+    let oci = build(dir.path());
+
+    let lines = ls(&oci, &["--recursive", "dir"]);
+    let paths = lines
+        .iter()
+        .map(|l| l.split_whitespace().last().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(paths, vec!["/dir/foo", "/dir/subdir", "/dir/subdir/bar"]);
+}
+
+#[test]
+fn ls_json() {
+    let dir = tempdir().unwrap();
+    let oci = build(dir.path());
+
+    let lines = ls(&oci, &["--json", "-r"]);
+    let entries = lines
+        .iter()
+        .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(entries.len(), 5);
+    let bar = entries
+        .iter()
+        .find(|e| e["path"] == "/dir/subdir/bar")
+        .unwrap();
+    assert_eq!(bar["mode"], "file");
+    assert_eq!(bar["size"], 6);
+    assert_eq!(bar["nlink"], 1);
+    let symlink = entries
+        .iter()
+        .find(|e| e["path"] == "/foo-symlink")
+        .unwrap();
+    assert_eq!(symlink["mode"], "symlink");
+    assert_eq!(symlink["symlink_target"], "dir/foo");
+    let subdir = entries.iter().find(|e| e["path"] == "/dir/subdir").unwrap();
+    assert_eq!(subdir["mode"], "dir");
+    assert_eq!(subdir["nlink"], 2);
+}
+
+#[test]
+fn ls_missing_path() {
+    let dir = tempdir().unwrap();
+    let oci = build(dir.path());
+
+    let output = Command::cargo_bin("puzzlefs")
+        .unwrap()
+        .args(&[
+            OsStr::new("ls"),
+            oci.as_os_str(),
+            OsStr::new("test"),
+            OsStr::new("/nope"),
+        ])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("/nope: no such file or directory"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn ls_default_tag() {
+    let dir = tempdir().unwrap();
+    let oci = build(dir.path());
+
+    // "test" is the only tag, so it's the one without one; a value that isn't a tag is a path
+    let ls_untagged = |args: &[&str]| {
+        let output = Command::cargo_bin("puzzlefs")
+            .unwrap()
+            .arg("ls")
+            .arg(&oci)
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", args);
+        String::from_utf8(output.stdout).unwrap()
+    };
+    let root = ls(&oci, &[]).join("\n") + "\n";
+    assert_eq!(ls_untagged(&[]), root);
+    assert_eq!(ls_untagged(&["test"]), root);
+    let subdir = ls(&oci, &["dir"]).join("\n") + "\n";
+    assert_eq!(ls_untagged(&["dir"]), subdir);
+    assert_eq!(ls_untagged(&["/dir"]), subdir);
+}
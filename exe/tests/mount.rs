@@ -0,0 +1,849 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::fs::{DirEntryExt, FileTypeExt, MetadataExt};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread::sleep;
+use std::time::Duration;
+
+use assert_cmd::cargo::CommandCargoExt;
+use format::DigestAlgorithm;
+use nix::mount::{mount, umount, MsFlags};
+use nix::sys::signal::{kill, Signal};
+use nix::sys::stat::{makedev, mknod, Mode, SFlag};
+use nix::unistd::{chown, geteuid, mkfifo, Gid, Pid, Uid};
+use oci::Image;
+use tempfile::tempdir;
+
+mod helpers;
+use helpers::{puzzlefs, Mounted};
+
+#[test]
+fn mount_rejects_unknown_options() {
+    let dir = tempdir().unwrap();
+    let rootfs = dir.path().join("rootfs");
+    fs::create_dir_all(&rootfs).unwrap();
+    fs::write(rootfs.join("foo"), b"foo").unwrap();
+    let oci = dir.path().join("oci");
+    puzzlefs(&[
+        OsStr::new("build"),
+        rootfs.as_os_str(),
+        oci.as_os_str(),
+        OsStr::new("test"),
+    ]);
+
+    let mountpoint = dir.path().join("mnt");
+    fs::create_dir_all(&mountpoint).unwrap();
+    let output = Command::cargo_bin("puzzlefs")
+        .unwrap()
+        .args(&[
+            OsStr::new("mount"),
+            OsStr::new("-o"),
+            OsStr::new("ro,bogus"),
+            oci.as_os_str(),
+            OsStr::new("test"),
+            mountpoint.as_os_str(),
+        ])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("unknown mount option bogus"), "{}", stderr);
+    assert!(stderr.contains("allow_other"), "{}", stderr);
+}
+
+#[test]
+fn overlay_on_puzzlefs() {
+    // mounting overlayfs needs privilege
+    if !geteuid().is_root() {
+        return;
+    }
+
+    let dir = tempdir().unwrap();
+    let rootfs = dir.path().join("rootfs");
+    fs::create_dir_all(rootfs.join("dir")).unwrap();
+    fs::write(rootfs.join("dir/foo"), b"foo").unwrap();
+    let oci = dir.path().join("oci");
+    puzzlefs(&[
+        OsStr::new("build"),
+        rootfs.as_os_str(),
+        oci.as_os_str(),
+        OsStr::new("test"),
+    ]);
+
+    let lower = dir.path().join("lower");
+    fs::create_dir_all(&lower).unwrap();
+    let mut mounted = Mounted(
+        Command::cargo_bin("puzzlefs")
+            .unwrap()
+            .args(&[
+                OsStr::new("mount"),
+                OsStr::new("--overlay"),
+                oci.as_os_str(),
+                OsStr::new("test"),
+                lower.as_os_str(),
+            ])
+            .spawn()
+            .unwrap(),
+    );
+    for _ in 0..100 {
+        if lower.join("dir").exists() {
+            break;
+        }
+        sleep(Duration::from_millis(50));
+    }
+
+    let upper = dir.path().join("upper");
+    let work = dir.path().join("work");
+    let merged = dir.path().join("merged");
+    for d in &[&upper, &work, &merged] {
+        fs::create_dir_all(d).unwrap();
+    }
+    let data = format!(
+        "lowerdir={},upperdir={},workdir={}",
+        lower.display(),
+        upper.display(),
+        work.display()
+    );
+    mount(
+        Some("overlay"),
+        &merged,
+        Some("overlay"),
+        MsFlags::empty(),
+        Some(data.as_str()),
+    )
+    .unwrap();
+
+    fs::write(merged.join("bar"), b"bar").unwrap();
+    let mut names = fs::read_dir(&merged)
+        .unwrap()
+        .map(|e| e.unwrap().file_name())
+        .collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(names, vec!["bar", "dir"]);
+    assert_eq!(fs::read(merged.join("dir/foo")).unwrap(), b"foo");
+
+    umount(&merged).unwrap();
+    kill(Pid::from_raw(mounted.0.id() as i32), Signal::SIGTERM).unwrap();
+    assert!(mounted.0.wait().unwrap().success());
+}
+
+#[test]
+fn mount_reports_devices_and_owners() {
+    // making device nodes and giving files away needs privilege
+    if !geteuid().is_root() {
+        return;
+    }
+
+    let dir = tempdir().unwrap();
+    let rootfs = dir.path().join("rootfs");
+    fs::create_dir_all(&rootfs).unwrap();
+    mknod(
+        &rootfs.join("blk"),
+        SFlag::S_IFBLK,
+        Mode::S_IRUSR,
+        makedev(7, 200),
+    )
+    .unwrap();
+    chown(
+        &rootfs.join("blk"),
+        Some(Uid::from_raw(1000)),
+        Some(Gid::from_raw(2000)),
+    )
+    .unwrap();
+    mkfifo(&rootfs.join("fifo"), Mode::S_IRUSR).unwrap();
+    let oci = dir.path().join("oci");
+    puzzlefs(&[
+        OsStr::new("build"),
+        rootfs.as_os_str(),
+        oci.as_os_str(),
+        OsStr::new("test"),
+    ]);
+
+    let mountpoint = dir.path().join("mnt");
+    fs::create_dir_all(&mountpoint).unwrap();
+    let _mounted = Mounted(
+        Command::cargo_bin("puzzlefs")
+            .unwrap()
+            .args(&[
+                OsStr::new("mount"),
+                oci.as_os_str(),
+                OsStr::new("test"),
+                mountpoint.as_os_str(),
+            ])
+            .spawn()
+            .unwrap(),
+    );
+    for _ in 0..100 {
+        if mountpoint.join("blk").exists() {
+            break;
+        }
+        sleep(Duration::from_millis(50));
+    }
+
+    let blk = fs::symlink_metadata(mountpoint.join("blk")).unwrap();
+    assert!(blk.file_type().is_block_device());
+    assert_eq!(blk.rdev(), makedev(7, 200));
+    assert_eq!((blk.uid(), blk.gid()), (1000, 2000));
+    assert!(fs::symlink_metadata(mountpoint.join("fifo"))
+        .unwrap()
+        .file_type()
+        .is_fifo());
+}
+
+#[test]
+fn overlay_stack_hides_whiteouts() {
+    // mounting overlayfs needs privilege
+    if !geteuid().is_root() {
+        return;
+    }
+
+    let dir = tempdir().unwrap();
+    let oci = dir.path().join("oci");
+    let lower_rootfs = dir.path().join("lower_rootfs");
+    fs::create_dir_all(lower_rootfs.join("dir")).unwrap();
+    fs::write(lower_rootfs.join("foo"), b"foo").unwrap();
+    fs::write(lower_rootfs.join("keep"), b"keep").unwrap();
+    fs::write(lower_rootfs.join("dir/old"), b"old").unwrap();
+    puzzlefs(&[
+        OsStr::new("build"),
+        lower_rootfs.as_os_str(),
+        oci.as_os_str(),
+        OsStr::new("lower"),
+    ]);
+
+    // the upper layer deletes foo and replaces everything in dir
+    let upper_rootfs = dir.path().join("upper_rootfs");
+    fs::create_dir_all(upper_rootfs.join("dir")).unwrap();
+    fs::write(upper_rootfs.join(".wh.foo"), b"").unwrap();
+    fs::write(upper_rootfs.join("dir/.wh..wh..opq"), b"").unwrap();
+    fs::write(upper_rootfs.join("dir/new"), b"new").unwrap();
+    puzzlefs(&[
+        OsStr::new("build"),
+        OsStr::new("--whiteouts"),
+        upper_rootfs.as_os_str(),
+        oci.as_os_str(),
+        OsStr::new("upper"),
+    ]);
+
+    let mut mounts = Vec::new();
+    for (tag, marker) in &[("lower", "foo"), ("upper", "dir/new")] {
+        let mountpoint = dir.path().join(tag);
+        fs::create_dir_all(&mountpoint).unwrap();
+        mounts.push(Mounted(
+            Command::cargo_bin("puzzlefs")
+                .unwrap()
+                .args(&[
+                    OsStr::new("mount"),
+                    OsStr::new("--overlay"),
+                    oci.as_os_str(),
+                    OsStr::new(tag),
+                    mountpoint.as_os_str(),
+                ])
+                .spawn()
+                .unwrap(),
+        ));
+        for _ in 0..100 {
+            if mountpoint.join(marker).exists() {
+                break;
+            }
+            sleep(Duration::from_millis(50));
+        }
+    }
+
+    // on its own, the upper layer shows its whiteouts the way overlayfs expects them
+    let whiteout = fs::symlink_metadata(dir.path().join("upper/foo")).unwrap();
+    assert!(whiteout.file_type().is_char_device());
+    assert_eq!(whiteout.rdev(), 0);
+
+    let merged = dir.path().join("merged");
+    fs::create_dir_all(&merged).unwrap();
+    let data = format!(
+        "lowerdir={}:{}",
+        dir.path().join("upper").display(),
+        dir.path().join("lower").display()
+    );
+    mount(
+        Some("overlay"),
+        &merged,
+        Some("overlay"),
+        MsFlags::empty(),
+        Some(data.as_str()),
+    )
+    .unwrap();
+
+    let list = |path| {
+        let mut names = fs::read_dir(path)
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    };
+    let root = list(merged.clone());
+    let dir_names = list(merged.join("dir"));
+    umount(&merged).unwrap();
+    assert_eq!(root, vec!["dir", "keep"]);
+    assert_eq!(dir_names, vec!["new"]);
+
+    for mut mounted in mounts {
+        kill(Pid::from_raw(mounted.0.id() as i32), Signal::SIGTERM).unwrap();
+        assert!(mounted.0.wait().unwrap().success());
+    }
+}
+
+#[test]
+fn mount_stacked_tags() {
+    let dir = tempdir().unwrap();
+    let oci = dir.path().join("oci");
+    for (tag, files) in &[
+        ("lower", &["foo", "gone", "dir/old"][..]),
+        ("upper", &["foo", ".wh.gone", "dir/new"][..]),
+    ] {
+        let rootfs = dir.path().join(format!("{}_rootfs", tag));
+        fs::create_dir_all(rootfs.join("dir")).unwrap();
+        for f in files.iter() {
+            fs::write(rootfs.join(f), tag).unwrap();
+        }
+        puzzlefs(&[
+            OsStr::new("build"),
+            OsStr::new("--whiteouts"),
+            rootfs.as_os_str(),
+            oci.as_os_str(),
+            OsStr::new(tag),
+        ]);
+    }
+
+    let mountpoint = dir.path().join("mnt");
+    fs::create_dir_all(&mountpoint).unwrap();
+    let _mounted = Mounted(
+        Command::cargo_bin("puzzlefs")
+            .unwrap()
+            .args(&[
+                OsStr::new("mount"),
+                oci.as_os_str(),
+                OsStr::new("lower,upper"),
+                mountpoint.as_os_str(),
+            ])
+            .spawn()
+            .unwrap(),
+    );
+    for _ in 0..100 {
+        if mountpoint.join("dir").exists() {
+            break;
+        }
+        sleep(Duration::from_millis(50));
+    }
+
+    let list = |path: &Path| {
+        let mut entries = fs::read_dir(path)
+            .unwrap()
+            .map(|e| {
+                let e = e.unwrap();
+                (e.file_name().into_string().unwrap(), e.ino())
+            })
+            .collect::<Vec<_>>();
+        entries.sort();
+        entries
+    };
+    let root = list(&mountpoint);
+    let names = root.iter().map(|(n, _)| n.as_str()).collect::<Vec<_>>();
+    assert_eq!(names, vec!["dir", "foo"]);
+    let sub = list(&mountpoint.join("dir"));
+    let names = sub.iter().map(|(n, _)| n.as_str()).collect::<Vec<_>>();
+    assert_eq!(names, vec!["new", "old"]);
+    assert_eq!(fs::read(mountpoint.join("foo")).unwrap(), b"upper");
+    assert_eq!(fs::read(mountpoint.join("dir/old")).unwrap(), b"lower");
+    assert_eq!(fs::read(mountpoint.join("dir/new")).unwrap(), b"upper");
+
+    // the same inode numbers whether they come from readdir or stat, and no two alike
+    let mut inos = Vec::new();
+    for (path, ino) in root.iter().map(|(n, ino)| (mountpoint.join(n), ino)).chain(
+        sub.iter()
+            .map(|(n, ino)| (mountpoint.join("dir").join(n), ino)),
+    ) {
+        assert_eq!(fs::metadata(&path).unwrap().ino(), *ino, "{:?}", path);
+        inos.push(*ino);
+    }
+    inos.push(fs::metadata(&mountpoint).unwrap().ino());
+    inos.sort_unstable();
+    inos.dedup();
+    assert_eq!(inos.len(), root.len() + sub.len() + 1);
+}
+
+#[test]
+fn mount_by_digest() {
+    let dir = tempdir().unwrap();
+    let rootfs = dir.path().join("rootfs");
+    fs::create_dir_all(&rootfs).unwrap();
+    fs::write(rootfs.join("foo"), b"foo").unwrap();
+    let oci = dir.path().join("oci");
+    puzzlefs(&[
+        OsStr::new("build"),
+        rootfs.as_os_str(),
+        oci.as_os_str(),
+        OsStr::new("test"),
+    ]);
+    let index = Image::open(&oci).unwrap().get_index().unwrap();
+    let reference = format!("@sha256:{}", index.find_tag("test").unwrap().digest);
+
+    let mountpoint = dir.path().join("mnt");
+    fs::create_dir_all(&mountpoint).unwrap();
+    let _mounted = Mounted(
+        Command::cargo_bin("puzzlefs")
+            .unwrap()
+            .args(&[
+                OsStr::new("mount"),
+                oci.as_os_str(),
+                OsStr::new(&reference),
+                mountpoint.as_os_str(),
+            ])
+            .spawn()
+            .unwrap(),
+    );
+    for _ in 0..100 {
+        if mountpoint.join("foo").exists() {
+            break;
+        }
+        sleep(Duration::from_millis(50));
+    }
+    assert_eq!(fs::read(mountpoint.join("foo")).unwrap(), b"foo");
+
+    // the digest keeps working for a base image too
+    let upper = dir.path().join("upper");
+    fs::create_dir_all(&upper).unwrap();
+    fs::write(upper.join("bar"), b"bar").unwrap();
+    puzzlefs(&[
+        OsStr::new("build"),
+        OsStr::new("--base"),
+        OsStr::new(&format!("{}:{}", oci.display(), reference)),
+        upper.as_os_str(),
+        oci.as_os_str(),
+        OsStr::new("upper"),
+    ]);
+}
+
+#[test]
+fn mount_without_tag_uses_latest() {
+    let dir = tempdir().unwrap();
+    let oci = dir.path().join("oci");
+    for (tag, contents) in &[("old", "old"), ("latest", "new")] {
+        let rootfs = dir.path().join(tag);
+        fs::create_dir_all(&rootfs).unwrap();
+        fs::write(rootfs.join("foo"), contents).unwrap();
+        puzzlefs(&[
+            OsStr::new("build"),
+            rootfs.as_os_str(),
+            oci.as_os_str(),
+            OsStr::new(tag),
+        ]);
+    }
+
+    let mountpoint = dir.path().join("mnt");
+    fs::create_dir_all(&mountpoint).unwrap();
+    let _mounted = Mounted(
+        Command::cargo_bin("puzzlefs")
+            .unwrap()
+            .args(&[OsStr::new("mount"), oci.as_os_str(), mountpoint.as_os_str()])
+            .spawn()
+            .unwrap(),
+    );
+    for _ in 0..100 {
+        if mountpoint.join("foo").exists() {
+            break;
+        }
+        sleep(Duration::from_millis(50));
+    }
+    assert_eq!(fs::read(mountpoint.join("foo")).unwrap(), b"new");
+
+    // with no latest there's no telling which of the others was meant
+    let image = Image::open(&oci).unwrap();
+    image.remove_tag("latest").unwrap();
+    let desc = image.get_index().unwrap().find_tag("old").unwrap().clone();
+    image.add_tag("older".to_string(), desc).unwrap();
+    let other = dir.path().join("other");
+    fs::create_dir_all(&other).unwrap();
+    let output = Command::cargo_bin("puzzlefs")
+        .unwrap()
+        .args(&[OsStr::new("mount"), oci.as_os_str(), other.as_os_str()])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("no tag latest"), "{}", stderr);
+    assert!(stderr.contains("old, older"), "{}", stderr);
+}
+
+#[test]
+fn mount_mkdir_rmdir() {
+    let dir = tempdir().unwrap();
+    let rootfs = dir.path().join("rootfs");
+    fs::create_dir_all(&rootfs).unwrap();
+    fs::write(rootfs.join("foo"), b"foo").unwrap();
+    let oci = dir.path().join("oci");
+    puzzlefs(&[
+        OsStr::new("build"),
+        rootfs.as_os_str(),
+        oci.as_os_str(),
+        OsStr::new("test"),
+    ]);
+
+    // without --mkdir a mountpoint that isn't there is an error
+    let mountpoint = dir.path().join("a/b/mnt");
+    let output = Command::cargo_bin("puzzlefs")
+        .unwrap()
+        .args(&[
+            OsStr::new("mount"),
+            oci.as_os_str(),
+            OsStr::new("test"),
+            mountpoint.as_os_str(),
+        ])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("--mkdir"), "{}", stderr);
+
+    for rmdir in &[false, true] {
+        let top = dir.path().join(format!("rmdir-{}", rmdir));
+        let mountpoint = top.join("b/mnt");
+        let mut args = vec![OsStr::new("mount"), OsStr::new("--mkdir")];
+        if *rmdir {
+            args.push(OsStr::new("--rmdir"));
+        }
+        args.extend(&[oci.as_os_str(), OsStr::new("test"), mountpoint.as_os_str()]);
+        let mut child = Command::cargo_bin("puzzlefs")
+            .unwrap()
+            .args(&args)
+            .spawn()
+            .unwrap();
+        for _ in 0..100 {
+            if mountpoint.join("foo").exists() {
+                break;
+            }
+            sleep(Duration::from_millis(50));
+        }
+        assert_eq!(fs::read(mountpoint.join("foo")).unwrap(), b"foo");
+
+        kill(Pid::from_raw(child.id() as i32), Signal::SIGINT).unwrap();
+        assert!(child.wait().unwrap().success());
+        // unmounted either way, and with --rmdir everything --mkdir made is gone again
+        assert!(!mountpoint.join("foo").exists());
+        assert_eq!(mountpoint.exists(), !rmdir);
+        assert_eq!(top.exists(), !rmdir);
+    }
+}
+
+#[test]
+fn mount_foreground() {
+    let dir = tempdir().unwrap();
+    let rootfs = dir.path().join("rootfs");
+    fs::create_dir_all(&rootfs).unwrap();
+    fs::write(rootfs.join("foo"), b"foo").unwrap();
+    let oci = dir.path().join("oci");
+    puzzlefs(&[
+        OsStr::new("build"),
+        rootfs.as_os_str(),
+        oci.as_os_str(),
+        OsStr::new("test"),
+    ]);
+
+    let mountpoint = dir.path().join("mnt");
+    fs::create_dir_all(&mountpoint).unwrap();
+    let child = Command::cargo_bin("puzzlefs")
+        .unwrap()
+        .args(&[
+            OsStr::new("mount"),
+            OsStr::new("--foreground"),
+            oci.as_os_str(),
+            OsStr::new("test"),
+            mountpoint.as_os_str(),
+        ])
+        .env("RUST_LOG", "debug")
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    for _ in 0..100 {
+        if mountpoint.join("foo").exists() {
+            break;
+        }
+        sleep(Duration::from_millis(50));
+    }
+    assert_eq!(fs::metadata(mountpoint.join("foo")).unwrap().len(), 3);
+
+    kill(Pid::from_raw(child.id() as i32), Signal::SIGTERM).unwrap();
+    let output = child.wait_with_output().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(!mountpoint.join("foo").exists());
+    // every request got logged, the stat being a lookup of foo
+    assert!(stderr.contains("LOOKUP"), "{}", stderr);
+    assert!(stderr.contains(r#"name "foo""#), "{}", stderr);
+}
+
+#[test]
+fn mount_sharded_blobs() {
+    let dir = tempdir().unwrap();
+    let rootfs = dir.path().join("rootfs");
+    fs::create_dir_all(&rootfs).unwrap();
+    fs::write(rootfs.join("foo"), b"foo").unwrap();
+    let oci = dir.path().join("oci");
+    puzzlefs(&[
+        OsStr::new("build"),
+        OsStr::new("--sharded-blobs"),
+        rootfs.as_os_str(),
+        oci.as_os_str(),
+        OsStr::new("test"),
+    ]);
+    // nothing but shards in blobs/sha256
+    for entry in fs::read_dir(oci.join("blobs/sha256")).unwrap() {
+        let entry = entry.unwrap();
+        assert!(entry.file_type().unwrap().is_dir());
+        assert_eq!(entry.file_name().len(), 2);
+    }
+
+    let mountpoint = dir.path().join("mnt");
+    fs::create_dir_all(&mountpoint).unwrap();
+    let _mounted = Mounted(
+        Command::cargo_bin("puzzlefs")
+            .unwrap()
+            .args(&[
+                OsStr::new("mount"),
+                oci.as_os_str(),
+                OsStr::new("test"),
+                mountpoint.as_os_str(),
+            ])
+            .spawn()
+            .unwrap(),
+    );
+    for _ in 0..100 {
+        if mountpoint.join("foo").exists() {
+            break;
+        }
+        sleep(Duration::from_millis(50));
+    }
+    assert_eq!(fs::read(mountpoint.join("foo")).unwrap(), b"foo");
+}
+
+#[test]
+fn mount_fallback() {
+    let dir = tempdir().unwrap();
+    let rootfs = dir.path().join("rootfs");
+    fs::create_dir_all(&rootfs).unwrap();
+    fs::write(rootfs.join("foo"), b"foo").unwrap();
+    let oci = dir.path().join("oci");
+    let replica = dir.path().join("replica");
+    puzzlefs(&[
+        OsStr::new("build"),
+        rootfs.as_os_str(),
+        oci.as_os_str(),
+        OsStr::new("test"),
+    ]);
+    // a second build would see the atimes the first one left behind, so copy the image instead
+    assert!(Command::new("cp")
+        .args(&[OsStr::new("-a"), oci.as_os_str(), replica.as_os_str()])
+        .status()
+        .unwrap()
+        .success());
+    // everything but the tags has to come from the replica
+    for entry in fs::read_dir(oci.join("blobs/sha256")).unwrap() {
+        fs::remove_file(entry.unwrap().path()).unwrap();
+    }
+
+    let mountpoint = dir.path().join("mnt");
+    fs::create_dir_all(&mountpoint).unwrap();
+    let _mounted = Mounted(
+        Command::cargo_bin("puzzlefs")
+            .unwrap()
+            .args(&[
+                OsStr::new("mount"),
+                OsStr::new("--fallback"),
+                replica.as_os_str(),
+                oci.as_os_str(),
+                OsStr::new("test"),
+                mountpoint.as_os_str(),
+            ])
+            .spawn()
+            .unwrap(),
+    );
+    for _ in 0..100 {
+        if mountpoint.join("foo").exists() {
+            break;
+        }
+        sleep(Duration::from_millis(50));
+    }
+    assert_eq!(fs::read(mountpoint.join("foo")).unwrap(), b"foo");
+    assert!(fs::read_dir(oci.join("blobs/sha256")).unwrap().count() > 0);
+}
+
+#[test]
+fn mount_missing_tag_exit_code() {
+    let dir = tempdir().unwrap();
+    let rootfs = dir.path().join("rootfs");
+    fs::create_dir_all(&rootfs).unwrap();
+    fs::write(rootfs.join("foo"), b"foo").unwrap();
+    let oci = dir.path().join("oci");
+    puzzlefs(&[
+        OsStr::new("build"),
+        rootfs.as_os_str(),
+        oci.as_os_str(),
+        OsStr::new("test"),
+    ]);
+    let mountpoint = dir.path().join("mnt");
+    fs::create_dir_all(&mountpoint).unwrap();
+
+    let mount = |json: bool| {
+        let mut args = Vec::new();
+        if json {
+            args.push(OsStr::new("--json"));
+        }
+        args.extend(&[
+            OsStr::new("mount"),
+            oci.as_os_str(),
+            OsStr::new("nope"),
+            mountpoint.as_os_str(),
+        ]);
+        Command::cargo_bin("puzzlefs")
+            .unwrap()
+            .args(&args)
+            .output()
+            .unwrap()
+    };
+    let output = mount(false);
+    assert_eq!(output.status.code(), Some(3));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("no tag nope"), "{}", stderr);
+
+    let output = mount(true);
+    assert_eq!(output.status.code(), Some(3));
+    let error: serde_json::Value = serde_json::from_slice(&output.stderr).unwrap();
+    assert_eq!(error["kind"], "tag_not_found");
+    assert_eq!(error["exit_code"], 3);
+    assert!(error["error"].as_str().unwrap().contains("no tag nope"));
+}
+
+#[test]
+fn mount_subdir() {
+    let dir = tempdir().unwrap();
+    let rootfs = dir.path().join("rootfs");
+    fs::create_dir_all(rootfs.join("usr/bin")).unwrap();
+    fs::create_dir_all(rootfs.join("usr/lib")).unwrap();
+    fs::write(rootfs.join("usr/bin/sh"), b"sh").unwrap();
+    fs::write(rootfs.join("usr/README"), b"readme").unwrap();
+    fs::create_dir_all(rootfs.join("etc")).unwrap();
+    fs::write(rootfs.join("etc/passwd"), b"root").unwrap();
+    let oci = dir.path().join("oci");
+    puzzlefs(&[
+        OsStr::new("build"),
+        rootfs.as_os_str(),
+        oci.as_os_str(),
+        OsStr::new("test"),
+    ]);
+
+    let mountpoint = dir.path().join("mnt");
+    fs::create_dir_all(&mountpoint).unwrap();
+    let _mounted = Mounted(
+        Command::cargo_bin("puzzlefs")
+            .unwrap()
+            .args(&[
+                OsStr::new("mount"),
+                OsStr::new("--subdir"),
+                OsStr::new("/usr"),
+                oci.as_os_str(),
+                OsStr::new("test"),
+                mountpoint.as_os_str(),
+            ])
+            .spawn()
+            .unwrap(),
+    );
+    for _ in 0..100 {
+        if mountpoint.join("README").exists() {
+            break;
+        }
+        sleep(Duration::from_millis(50));
+    }
+    let names = |dir: &Path| {
+        let mut names = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    };
+    assert_eq!(names(&mountpoint), names(&rootfs.join("usr")));
+    assert_eq!(fs::read(mountpoint.join("bin/sh")).unwrap(), b"sh");
+    // and nothing outside /usr is to be found
+    assert!(!mountpoint.join("etc").exists());
+    assert!(!mountpoint.join("usr").exists());
+}
+
+fn mount_image_hashed_with(algorithm: DigestAlgorithm) {
+    let dir = tempdir().unwrap();
+    let rootfs = dir.path().join("rootfs");
+    fs::create_dir_all(rootfs.join("dir")).unwrap();
+    let lyrics = "meshuggah rocks\n".repeat(1024);
+    fs::write(rootfs.join("dir/lyrics"), &lyrics).unwrap();
+    let oci = dir.path().join("oci");
+    puzzlefs(&[
+        OsStr::new("build"),
+        OsStr::new("--digest-algorithm"),
+        OsStr::new(algorithm.name()),
+        rootfs.as_os_str(),
+        oci.as_os_str(),
+        OsStr::new("test"),
+    ]);
+
+    let index: serde_json::Value =
+        serde_json::from_slice(&fs::read(oci.join("index.json")).unwrap()).unwrap();
+    assert!(index["manifests"][0]["digest"]
+        .as_str()
+        .unwrap()
+        .starts_with(&format!("{}:", algorithm)));
+    // nothing was hashed with sha256, and every blob is named after its own kind of hash
+    assert_eq!(fs::read_dir(oci.join("blobs/sha256")).unwrap().count(), 0);
+    let blobs = fs::read_dir(oci.join("blobs").join(algorithm.name()))
+        .unwrap()
+        .map(|e| e.unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(blobs.len(), 3);
+    for blob in blobs {
+        let digest = algorithm.digest(&fs::read(blob.path()).unwrap());
+        assert_eq!(blob.file_name().to_str().unwrap(), hex::encode(digest));
+    }
+    puzzlefs(&[OsStr::new("verify"), oci.as_os_str(), OsStr::new("test")]);
+
+    let mountpoint = dir.path().join("mnt");
+    fs::create_dir_all(&mountpoint).unwrap();
+    let _mounted = Mounted(
+        Command::cargo_bin("puzzlefs")
+            .unwrap()
+            .args(&[
+                OsStr::new("mount"),
+                oci.as_os_str(),
+                OsStr::new("test"),
+                mountpoint.as_os_str(),
+            ])
+            .spawn()
+            .unwrap(),
+    );
+    for _ in 0..100 {
+        if mountpoint.join("dir").exists() {
+            break;
+        }
+        sleep(Duration::from_millis(50));
+    }
+    assert_eq!(
+        fs::read_to_string(mountpoint.join("dir/lyrics")).unwrap(),
+        lyrics
+    );
+}
+
+#[test]
+fn mount_blake3_image() {
+    mount_image_hashed_with(DigestAlgorithm::Blake3);
+}
+
+#[test]
+fn mount_sha512_image() {
+    mount_image_hashed_with(DigestAlgorithm::Sha512);
+}
@@ -0,0 +1,243 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread::sleep;
+use std::time::Duration;
+
+use assert_cmd::cargo::CommandCargoExt;
+use tempfile::tempdir;
+
+mod helpers;
+use helpers::{puzzlefs, Mounted};
+
+mod registry;
+use registry::FakeRegistry;
+
+// builds an image and pushes it to the registry as test:latest
+fn push_image(dir: &Path, registry: &FakeRegistry) -> PathBuf {
+    let rootfs = dir.join("rootfs");
+    fs::create_dir_all(rootfs.join("dir")).unwrap();
+    fs::write(rootfs.join("foo"), b"foo").unwrap();
+    let bar = (0..1024 * 1024)
+        .map(|i| (i % 251) as u8)
+        .collect::<Vec<_>>();
+    fs::write(rootfs.join("dir/bar"), bar).unwrap();
+    let oci = dir.join("oci");
+    puzzlefs(&[
+        OsStr::new("build"),
+        OsStr::new("--compression"),
+        OsStr::new("zstd"),
+        rootfs.as_os_str(),
+        oci.as_os_str(),
+        OsStr::new("test"),
+    ]);
+    puzzlefs(&[
+        OsStr::new("push"),
+        oci.as_os_str(),
+        OsStr::new("test"),
+        OsStr::new(&format!("{}/test", registry.addr)),
+    ]);
+    rootfs
+}
+
+fn pull(registry: &FakeRegistry, oci: &Path) {
+    puzzlefs(&[
+        OsStr::new("pull"),
+        OsStr::new(&format!("{}/test", registry.addr)),
+        oci.as_os_str(),
+        OsStr::new("pulled"),
+    ]);
+}
+
+fn blob_gets(registry: &FakeRegistry) -> Vec<String> {
+    let state = registry.state.lock().unwrap();
+    state
+        .requests
+        .iter()
+        .filter(|r| r.starts_with("GET") && r.contains("/blobs/"))
+        .cloned()
+        .collect()
+}
+
+// writes the partial download of the biggest blob an earlier pull would have left behind, as
+// `partial` of the blob, and returns the blob's hex digest and contents
+fn write_partial(
+    registry: &FakeRegistry,
+    oci: &Path,
+    partial: impl Fn(&[u8]) -> Vec<u8>,
+) -> (String, Vec<u8>) {
+    let (digest, blob) = {
+        let state = registry.state.lock().unwrap();
+        let (digest, blob) = state.blobs.iter().max_by_key(|(_, b)| b.len()).unwrap();
+        (digest.clone(), blob.clone())
+    };
+    let blobs = oci.join("blobs/sha256");
+    fs::create_dir_all(&blobs).unwrap();
+    let hex = digest.strip_prefix("sha256:").unwrap().to_string();
+    fs::write(blobs.join(format!("{}.partial", hex)), partial(&blob)).unwrap();
+    (hex, blob)
+}
+
+#[test]
+fn pull_and_mount() {
+    let dir = tempdir().unwrap();
+    let registry = FakeRegistry::start(false);
+    let rootfs = push_image(dir.path(), &registry);
+
+    let oci = dir.path().join("pulled");
+    pull(&registry, &oci);
+    puzzlefs(&[OsStr::new("verify"), oci.as_os_str(), OsStr::new("pulled")]);
+
+    let mountpoint = dir.path().join("mnt");
+    fs::create_dir_all(&mountpoint).unwrap();
+    let _mounted = Mounted(
+        Command::cargo_bin("puzzlefs")
+            .unwrap()
+            .args(&[
+                OsStr::new("mount"),
+                oci.as_os_str(),
+                OsStr::new("pulled"),
+                mountpoint.as_os_str(),
+            ])
+            .spawn()
+            .unwrap(),
+    );
+    for _ in 0..100 {
+        if mountpoint.join("dir").exists() {
+            break;
+        }
+        sleep(Duration::from_millis(50));
+    }
+    assert_eq!(fs::read(mountpoint.join("foo")).unwrap(), b"foo");
+    assert_eq!(
+        fs::read(mountpoint.join("dir/bar")).unwrap(),
+        fs::read(rootfs.join("dir/bar")).unwrap()
+    );
+}
+
+#[test]
+fn pull_resumes_and_skips_blobs() {
+    let dir = tempdir().unwrap();
+    let registry = FakeRegistry::start(false);
+    push_image(dir.path(), &registry);
+
+    // pretend an earlier pull got halfway through the biggest blob
+    let oci = dir.path().join("pulled");
+    let (hex, blob) = write_partial(&registry, &oci, |blob| blob[..blob.len() / 2].to_vec());
+    let blobs = oci.join("blobs/sha256");
+
+    registry.state.lock().unwrap().requests.clear();
+    pull(&registry, &oci);
+    let gets = blob_gets(&registry);
+    let resumed = format!("bytes={}-", blob.len() / 2);
+    assert!(
+        gets.iter()
+            .any(|r| r.contains(&hex) && r.ends_with(&resumed)),
+        "{:?}",
+        gets
+    );
+    assert!(!blobs.join(format!("{}.partial", hex)).exists());
+    puzzlefs(&[OsStr::new("verify"), oci.as_os_str(), OsStr::new("pulled")]);
+
+    // everything is there now, so pulling again only fetches the manifest
+    registry.state.lock().unwrap().requests.clear();
+    pull(&registry, &oci);
+    assert_eq!(blob_gets(&registry), Vec::<String>::new());
+}
+
+#[test]
+fn pull_checks_partial_blobs() {
+    let dir = tempdir().unwrap();
+    let registry = FakeRegistry::start(false);
+    push_image(dir.path(), &registry);
+    let blob_gets_of = |hex: &str| -> Vec<String> {
+        let gets = blob_gets(&registry);
+        registry.state.lock().unwrap().requests.clear();
+        gets.into_iter().filter(|r| r.contains(hex)).collect()
+    };
+
+    // all of it was downloaded, but not moved into place
+    let oci = dir.path().join("complete");
+    let (hex, _) = write_partial(&registry, &oci, |blob| blob.to_vec());
+    registry.state.lock().unwrap().requests.clear();
+    pull(&registry, &oci);
+    assert_eq!(blob_gets_of(&hex), Vec::<String>::new());
+    puzzlefs(&[OsStr::new("verify"), oci.as_os_str(), OsStr::new("pulled")]);
+
+    // something as long as the blob, but not it: the registry has nothing after that, and the
+    // whole blob is fetched again
+    let oci = dir.path().join("junk");
+    let (hex, blob) = write_partial(&registry, &oci, |blob| vec![0; blob.len()]);
+    registry.state.lock().unwrap().requests.clear();
+    pull(&registry, &oci);
+    let gets = blob_gets_of(&hex);
+    assert_eq!(gets.len(), 2, "{:?}", gets);
+    assert!(gets[0].ends_with(&format!("bytes={}-", blob.len())));
+    assert!(!gets[1].contains("bytes="));
+    puzzlefs(&[OsStr::new("verify"), oci.as_os_str(), OsStr::new("pulled")]);
+
+    // the registry answers with a range starting before where the partial ends; appending that
+    // would corrupt it, so the whole blob is fetched again
+    registry.state.lock().unwrap().range_slack = 17;
+    let oci = dir.path().join("slack");
+    let (hex, blob) = write_partial(&registry, &oci, |blob| blob[..blob.len() / 2].to_vec());
+    registry.state.lock().unwrap().requests.clear();
+    pull(&registry, &oci);
+    let gets = blob_gets_of(&hex);
+    assert_eq!(gets.len(), 2, "{:?}", gets);
+    assert!(gets[0].ends_with(&format!("bytes={}-", blob.len() / 2)));
+    assert!(!gets[1].contains("bytes="));
+    puzzlefs(&[OsStr::new("verify"), oci.as_os_str(), OsStr::new("pulled")]);
+}
+
+#[test]
+fn pull_blake3_image() {
+    let dir = tempdir().unwrap();
+    let registry = FakeRegistry::start(false);
+    let rootfs = dir.path().join("rootfs");
+    fs::create_dir_all(&rootfs).unwrap();
+    fs::write(rootfs.join("foo"), b"foo").unwrap();
+    let oci = dir.path().join("oci");
+    puzzlefs(&[
+        OsStr::new("build"),
+        OsStr::new("--digest-algorithm"),
+        OsStr::new("blake3"),
+        rootfs.as_os_str(),
+        oci.as_os_str(),
+        OsStr::new("test"),
+    ]);
+    puzzlefs(&[
+        OsStr::new("push"),
+        oci.as_os_str(),
+        OsStr::new("test"),
+        OsStr::new(&format!("{}/test", registry.addr)),
+    ]);
+
+    // the registry gets sha256 digests of every layer, and the blake3 ones are kept alongside
+    let manifest = registry.manifest("test", "latest").unwrap();
+    for layer in manifest["layers"].as_array().unwrap() {
+        assert!(layer["digest"].as_str().unwrap().starts_with("sha256:"));
+        let local = layer["annotations"]["org.puzzlefs.blob.digest"]
+            .as_str()
+            .unwrap();
+        let local = local.strip_prefix("blake3:").unwrap();
+        assert!(oci.join("blobs/blake3").join(local).exists());
+    }
+
+    let pulled = dir.path().join("pulled");
+    pull(&registry, &pulled);
+    puzzlefs(&[
+        OsStr::new("verify"),
+        pulled.as_os_str(),
+        OsStr::new("pulled"),
+    ]);
+    let index: serde_json::Value =
+        serde_json::from_slice(&fs::read(pulled.join("index.json")).unwrap()).unwrap();
+    let original: serde_json::Value =
+        serde_json::from_slice(&fs::read(oci.join("index.json")).unwrap()).unwrap();
+    assert_eq!(
+        index["manifests"][0]["digest"],
+        original["manifests"][0]["digest"]
+    );
+}
@@ -0,0 +1,178 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use assert_cmd::cargo::CommandCargoExt;
+use tempfile::tempdir;
+
+mod helpers;
+use helpers::puzzlefs;
+
+mod registry;
+use registry::{FakeRegistry, CREDENTIALS};
+
+fn build_image(dir: &Path, compression: &str) -> PathBuf {
+    let rootfs = dir.join("rootfs");
+    fs::create_dir_all(rootfs.join("dir")).unwrap();
+    fs::write(rootfs.join("foo"), b"foo").unwrap();
+    fs::write(rootfs.join("dir/bar"), vec![7_u8; 1024 * 1024]).unwrap();
+    let oci = dir.join("oci");
+    puzzlefs(&[
+        OsStr::new("build"),
+        OsStr::new("--compression"),
+        OsStr::new(compression),
+        rootfs.as_os_str(),
+        oci.as_os_str(),
+        OsStr::new("test"),
+    ]);
+    oci
+}
+
+#[test]
+fn push_to_registry() {
+    let dir = tempdir().unwrap();
+    let oci = build_image(dir.path(), "zstd");
+    let registry = FakeRegistry::start(false);
+
+    let reference = format!("{}/puzzlefs/test:v1", registry.addr);
+    puzzlefs(&[
+        OsStr::new("push"),
+        oci.as_os_str(),
+        OsStr::new("test"),
+        OsStr::new(&reference),
+    ]);
+
+    let manifest = registry.manifest("puzzlefs/test", "v1").unwrap();
+    assert_eq!(manifest["schemaVersion"], 2);
+    let layers = manifest["layers"].as_array().unwrap();
+    assert_eq!(
+        layers[0]["mediaType"],
+        "application/vnd.puzzlefs.image.rootfs.v1"
+    );
+    assert_eq!(
+        layers[1]["mediaType"],
+        "application/vnd.puzzlefs.image.inodes.v1"
+    );
+
+    // every layer made it, under the hash of its bytes, and compressed chunks remember their name
+    let state = registry.state.lock().unwrap();
+    for layer in layers {
+        let digest = layer["digest"].as_str().unwrap();
+        let blob = &state.blobs[digest];
+        assert_eq!(layer["size"], blob.len());
+        if layer["mediaType"].as_str().unwrap().ends_with("+zstd") {
+            let local = layer["annotations"]["org.puzzlefs.blob.digest"]
+                .as_str()
+                .unwrap();
+            let local = local.strip_prefix("sha256:").unwrap();
+            assert_eq!(
+                fs::read(oci.join("blobs/sha256").join(local)).unwrap(),
+                *blob
+            );
+        }
+    }
+    // the config says which platform the image was built for
+    let config = &state.blobs[manifest["config"]["digest"].as_str().unwrap()];
+    let config: serde_json::Value = serde_json::from_slice(config).unwrap();
+    assert_eq!(config["os"], std::env::consts::OS);
+    assert!(config["architecture"].is_string());
+    assert_eq!(
+        manifest["config"]["size"],
+        serde_json::to_vec(&config).unwrap().len()
+    );
+    drop(state);
+
+    // pushing again only checks that the blobs are there
+    registry.state.lock().unwrap().requests.clear();
+    puzzlefs(&[
+        OsStr::new("push"),
+        oci.as_os_str(),
+        OsStr::new("test"),
+        OsStr::new(&reference),
+    ]);
+    let state = registry.state.lock().unwrap();
+    assert!(
+        !state.requests.iter().any(|r| r.starts_with("POST")),
+        "{:?}",
+        state.requests
+    );
+}
+
+#[test]
+fn push_default_tag() {
+    let dir = tempdir().unwrap();
+    let oci = build_image(dir.path(), "none");
+    let registry = FakeRegistry::start(false);
+
+    // "test" is the image's only tag
+    let reference = format!("{}/puzzlefs/test:v1", registry.addr);
+    puzzlefs(&[OsStr::new("push"), oci.as_os_str(), OsStr::new(&reference)]);
+    let manifest = registry.manifest("puzzlefs/test", "v1").unwrap();
+    assert_eq!(manifest["schemaVersion"], 2);
+}
+
+#[test]
+fn push_with_token_auth() {
+    let dir = tempdir().unwrap();
+    let oci = build_image(dir.path(), "none");
+    let registry = FakeRegistry::start(true);
+    let reference = format!("{}/test", registry.addr);
+
+    // without credentials the registry won't hand out a token
+    let docker_config = dir.path().join("docker");
+    fs::create_dir_all(&docker_config).unwrap();
+    let output = Command::cargo_bin("puzzlefs")
+        .unwrap()
+        .env("DOCKER_CONFIG", &docker_config)
+        .args(&[
+            OsStr::new("push"),
+            oci.as_os_str(),
+            OsStr::new("test"),
+            OsStr::new(&reference),
+        ])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(registry.manifest("test", "latest").is_none());
+
+    fs::write(
+        docker_config.join("config.json"),
+        format!(
+            "{{\"auths\": {{\"{}\": {{\"auth\": \"{}\"}}}}}}",
+            registry.addr, CREDENTIALS
+        ),
+    )
+    .unwrap();
+    let status = Command::cargo_bin("puzzlefs")
+        .unwrap()
+        .env("DOCKER_CONFIG", &docker_config)
+        .args(&[
+            OsStr::new("push"),
+            oci.as_os_str(),
+            OsStr::new("test"),
+            OsStr::new(&reference),
+        ])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert!(registry.manifest("test", "latest").is_some());
+}
+
+// against a real registry, e.g. docker run -d -p 5000:5000 registry:2 and
+// PUZZLEFS_TEST_REGISTRY=localhost:5000
+#[test]
+fn push_to_distribution_registry() {
+    let registry = match std::env::var("PUZZLEFS_TEST_REGISTRY") {
+        Ok(registry) => registry,
+        Err(_) => return,
+    };
+    let dir = tempdir().unwrap();
+    let oci = build_image(dir.path(), "zstd");
+    puzzlefs(&[
+        OsStr::new("push"),
+        oci.as_os_str(),
+        OsStr::new("test"),
+        OsStr::new(&format!("{}/puzzlefs/test:push", registry)),
+    ]);
+}
@@ -0,0 +1,293 @@
+// a just-enough in-process implementation of the OCI distribution API, so pushing and pulling can
+// be tested without docker. each integration test binary only uses some of this.
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use sha2::{Digest, Sha256};
+
+// base64 of user:pass
+pub const CREDENTIALS: &str = "dXNlcjpwYXNz";
+const TOKEN: &str = "sekrit";
+
+#[derive(Default)]
+pub struct State {
+    pub blobs: HashMap<String, Vec<u8>>,
+    pub manifests: HashMap<String, Vec<u8>>,
+    uploads: HashMap<String, Vec<u8>>,
+    next_upload: u64,
+    // ranged GETs are served from this many bytes before the asked for start, like a proxy that
+    // rounds ranges down to its own block size
+    pub range_slack: usize,
+    // every request as "METHOD path [range]", in the order they arrived
+    pub requests: Vec<String>,
+}
+
+pub struct FakeRegistry {
+    pub addr: String,
+    pub state: Arc<Mutex<State>>,
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: HashMap<String, String>,
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn new(status: u16) -> Response {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    fn header(mut self, name: &str, value: &str) -> Response {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    fn body(mut self, body: Vec<u8>) -> Response {
+        self.body = body;
+        self
+    }
+}
+
+impl FakeRegistry {
+    /// Starts a registry on some free port on localhost. If `auth` is set, every request has to
+    /// carry a bearer token, which it hands out in exchange for `CREDENTIALS`.
+    pub fn start(auth: bool) -> FakeRegistry {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let state = Arc::new(Mutex::new(State::default()));
+        let registry = FakeRegistry {
+            addr: addr.clone(),
+            state: state.clone(),
+        };
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                let state = state.clone();
+                let addr = addr.clone();
+                thread::spawn(move || serve(stream, &state, &addr, auth));
+            }
+        });
+        registry
+    }
+
+    pub fn manifest(&self, repository: &str, tag: &str) -> Option<serde_json::Value> {
+        let state = self.state.lock().unwrap();
+        let manifest = state.manifests.get(&format!("{}:{}", repository, tag))?;
+        Some(serde_json::from_slice(manifest).unwrap())
+    }
+}
+
+fn serve(stream: TcpStream, state: &Mutex<State>, addr: &str, auth: bool) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let request = match read_request(&mut reader) {
+        Some(request) => request,
+        None => return,
+    };
+    let head = request.method == "HEAD";
+    let response = handle(request, state, addr, auth);
+    write_response(stream, response, head);
+}
+
+fn read_request<R: BufRead>(reader: &mut R) -> Option<Request> {
+    let mut line = String::new();
+    reader.read_line(&mut line).ok()?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?.to_string();
+    let target = parts.next()?.to_string();
+
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line.split_once(':')?;
+        headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+    }
+
+    let len = headers
+        .get("content-length")
+        .map(|l| l.parse().unwrap())
+        .unwrap_or(0);
+    let mut body = vec![0_u8; len];
+    reader.read_exact(&mut body).ok()?;
+
+    let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+    let query = query
+        .split('&')
+        .filter_map(|kv| kv.split_once('='))
+        .map(|(k, v)| (k.to_string(), v.replace("%3A", ":").replace("%2F", "/")))
+        .collect();
+    Some(Request {
+        method,
+        path: path.to_string(),
+        query,
+        headers,
+        body,
+    })
+}
+
+fn write_response(mut stream: TcpStream, response: Response, head: bool) {
+    let mut out = format!("HTTP/1.1 {} Fake\r\nConnection: close\r\n", response.status);
+    if !response
+        .headers
+        .iter()
+        .any(|(h, _)| h.eq_ignore_ascii_case("content-length"))
+    {
+        out.push_str(&format!("Content-Length: {}\r\n", response.body.len()));
+    }
+    for (name, value) in response.headers {
+        out.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    out.push_str("\r\n");
+    let _ = stream.write_all(out.as_bytes());
+    if !head {
+        let _ = stream.write_all(&response.body);
+    }
+}
+
+fn sha256(data: &[u8]) -> String {
+    format!("sha256:{}", hex::encode(Sha256::digest(data)))
+}
+
+fn handle(request: Request, state: &Mutex<State>, addr: &str, auth: bool) -> Response {
+    let mut state = state.lock().unwrap();
+    let mut logged = format!("{} {}", request.method, request.path);
+    if let Some(range) = request.headers.get("range") {
+        logged = format!("{} {}", logged, range);
+    }
+    state.requests.push(logged);
+
+    if request.path == "/token" {
+        return match request.headers.get("authorization") {
+            Some(a) if *a == format!("Basic {}", CREDENTIALS) => {
+                Response::new(200).body(format!("{{\"token\": \"{}\"}}", TOKEN).into_bytes())
+            }
+            _ => Response::new(401),
+        };
+    }
+
+    if auth && request.headers.get("authorization") != Some(&format!("Bearer {}", TOKEN)) {
+        let challenge = format!("Bearer realm=\"http://{}/token\",service=\"fake\"", addr);
+        return Response::new(401).header("WWW-Authenticate", &challenge);
+    }
+
+    if request.path == "/v2/" {
+        return Response::new(200);
+    }
+    let path = match request.path.strip_prefix("/v2/") {
+        Some(path) => path,
+        None => return Response::new(404),
+    };
+
+    if let Some((repository, id)) = path.split_once("/blobs/uploads/") {
+        return match (request.method.as_str(), id) {
+            ("POST", "") => {
+                state.next_upload += 1;
+                let id = state.next_upload.to_string();
+                state.uploads.insert(id.clone(), Vec::new());
+                Response::new(202).header(
+                    "Location",
+                    &format!("/v2/{}/blobs/uploads/{}", repository, id),
+                )
+            }
+            ("PATCH", id) => {
+                let upload = match state.uploads.get_mut(id) {
+                    Some(upload) => upload,
+                    None => return Response::new(404),
+                };
+                upload.extend_from_slice(&request.body);
+                let range = format!("0-{}", upload.len() as i64 - 1);
+                Response::new(202)
+                    .header(
+                        "Location",
+                        &format!("/v2/{}/blobs/uploads/{}", repository, id),
+                    )
+                    .header("Range", &range)
+            }
+            ("PUT", id) => {
+                let mut upload = match state.uploads.remove(id) {
+                    Some(upload) => upload,
+                    None => return Response::new(404),
+                };
+                upload.extend_from_slice(&request.body);
+                let digest = sha256(&upload);
+                if request.query.get("digest") != Some(&digest) {
+                    return Response::new(400).body(b"DIGEST_INVALID".to_vec());
+                }
+                state.blobs.insert(digest.clone(), upload);
+                Response::new(201).header("Docker-Content-Digest", &digest)
+            }
+            _ => Response::new(405),
+        };
+    }
+
+    if let Some((_, digest)) = path.split_once("/blobs/") {
+        let blob = match state.blobs.get(digest) {
+            Some(blob) => blob,
+            None => return Response::new(404),
+        };
+        let start = request
+            .headers
+            .get("range")
+            .and_then(|r| r.strip_prefix("bytes="))
+            .and_then(|r| r.trim_end_matches('-').parse::<usize>().ok());
+        return match (request.method.as_str(), start) {
+            ("HEAD", _) => Response::new(200).header("Content-Length", &blob.len().to_string()),
+            ("GET", Some(start)) if start >= blob.len() => {
+                Response::new(416).header("Content-Range", &format!("bytes */{}", blob.len()))
+            }
+            ("GET", Some(start)) => {
+                let start = start.saturating_sub(state.range_slack);
+                Response::new(206)
+                    .header(
+                        "Content-Range",
+                        &format!("bytes {}-{}/{}", start, blob.len() - 1, blob.len()),
+                    )
+                    .body(blob[start..].to_vec())
+            }
+            ("GET", _) => Response::new(200).body(blob.clone()),
+            _ => Response::new(405),
+        };
+    }
+
+    if let Some((repository, tag)) = path.split_once("/manifests/") {
+        let key = format!("{}:{}", repository, tag);
+        return match request.method.as_str() {
+            "PUT" => {
+                state.manifests.insert(key, request.body);
+                Response::new(201)
+            }
+            "GET" | "HEAD" => match state.manifests.get(&key) {
+                Some(manifest) => Response::new(200)
+                    .header("Content-Type", "application/vnd.oci.image.manifest.v1+json")
+                    .header("Docker-Content-Digest", &sha256(manifest))
+                    .body(manifest.clone()),
+                None => Response::new(404),
+            },
+            _ => Response::new(405),
+        };
+    }
+
+    Response::new(404)
+}
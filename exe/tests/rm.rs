@@ -0,0 +1,80 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::process::Command;
+use std::thread::sleep;
+use std::time::Duration;
+
+use assert_cmd::cargo::CommandCargoExt;
+use tempfile::tempdir;
+
+mod helpers;
+use helpers::{puzzlefs, Mounted};
+
+#[test]
+fn rm_leaves_other_tags_and_blobs() {
+    let dir = tempdir().unwrap();
+    let oci = dir.path().join("oci");
+    for tag in &["one", "two"] {
+        let rootfs = dir.path().join(tag);
+        fs::create_dir_all(&rootfs).unwrap();
+        fs::write(rootfs.join("foo"), tag).unwrap();
+        puzzlefs(&[
+            OsStr::new("build"),
+            rootfs.as_os_str(),
+            oci.as_os_str(),
+            OsStr::new(tag),
+        ]);
+    }
+    let blobs = || fs::read_dir(oci.join("blobs/sha256")).unwrap().count();
+    let before = blobs();
+
+    puzzlefs(&[OsStr::new("rm"), oci.as_os_str(), OsStr::new("two")]);
+    assert_eq!(blobs(), before);
+    let verify = |tag| {
+        Command::cargo_bin("puzzlefs")
+            .unwrap()
+            .args(&[OsStr::new("verify"), oci.as_os_str(), OsStr::new(tag)])
+            .output()
+            .unwrap()
+            .status
+            .success()
+    };
+    assert!(!verify("two"));
+
+    // it's gone, so there's nothing to remove the second time
+    let output = Command::cargo_bin("puzzlefs")
+        .unwrap()
+        .args(&[OsStr::new("rm"), oci.as_os_str(), OsStr::new("two")])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("no tag two"), "{}", stderr);
+
+    let mountpoint = dir.path().join("mnt");
+    fs::create_dir_all(&mountpoint).unwrap();
+    let mounted = Mounted(
+        Command::cargo_bin("puzzlefs")
+            .unwrap()
+            .args(&[
+                OsStr::new("mount"),
+                oci.as_os_str(),
+                OsStr::new("one"),
+                mountpoint.as_os_str(),
+            ])
+            .spawn()
+            .unwrap(),
+    );
+    for _ in 0..100 {
+        if mountpoint.join("foo").exists() {
+            break;
+        }
+        sleep(Duration::from_millis(50));
+    }
+    assert_eq!(fs::read(mountpoint.join("foo")).unwrap(), b"one");
+    drop(mounted);
+
+    puzzlefs(&[OsStr::new("rm"), OsStr::new("--all"), oci.as_os_str()]);
+    assert!(!verify("one"));
+    assert_eq!(blobs(), before);
+}
@@ -0,0 +1,232 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::process::Command;
+
+use assert_cmd::cargo::CommandCargoExt;
+use tempfile::tempdir;
+
+mod helpers;
+use helpers::puzzlefs;
+
+#[test]
+fn stats_reports_dedup() {
+    let dir = tempdir().unwrap();
+    let oci = dir.path().join("oci");
+    let data = (0..16 * 4096).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    for (tag, copies) in &[("one", 1), ("two", 2)] {
+        let rootfs = dir.path().join(tag);
+        fs::create_dir_all(&rootfs).unwrap();
+        for i in 0..*copies {
+            fs::write(rootfs.join(format!("copy{}", i)), &data).unwrap();
+        }
+        puzzlefs(&[
+            OsStr::new("build"),
+            OsStr::new("--chunker"),
+            OsStr::new("fixed"),
+            OsStr::new("--chunk-size-avg"),
+            OsStr::new("4096"),
+            rootfs.as_os_str(),
+            oci.as_os_str(),
+            OsStr::new(tag),
+        ]);
+    }
+
+    let output = Command::cargo_bin("puzzlefs")
+        .unwrap()
+        .args(&[
+            OsStr::new("stats"),
+            oci.as_os_str(),
+            OsStr::new("two"),
+            OsStr::new("--compare"),
+            OsStr::new("one"),
+        ])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("logical bytes: 131072\n"), "{}", stdout);
+    assert!(stdout.contains("unique bytes: 65536\n"), "{}", stdout);
+    assert!(stdout.contains("dedup ratio: 2.00\n"), "{}", stdout);
+    assert!(stdout.contains("chunks: 16\n"), "{}", stdout);
+    assert!(
+        stdout.contains("shared with one: 65536 bytes\n"),
+        "{}",
+        stdout
+    );
+    let blobs = fs::read_dir(oci.join("blobs/sha256"))
+        .unwrap()
+        .map(|ent| ent.unwrap().metadata().unwrap().len())
+        .collect::<Vec<_>>();
+    assert!(
+        stdout.contains(&format!("blobs in the image: {}\n", blobs.len())),
+        "{}",
+        stdout
+    );
+    assert!(
+        stdout.contains(&format!(
+            "blob bytes in the image: {}\n",
+            blobs.iter().sum::<u64>()
+        )),
+        "{}",
+        stdout
+    );
+}
+
+#[test]
+fn stats_histogram() {
+    let dir = tempdir().unwrap();
+    let rootfs = dir.path().join("rootfs");
+    fs::create_dir_all(&rootfs).unwrap();
+    let data = (0..16 * 4096).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    fs::write(rootfs.join("a"), &data).unwrap();
+    fs::write(rootfs.join("b"), &data).unwrap();
+    fs::write(rootfs.join("c"), "meshuggah rocks").unwrap();
+    let oci = dir.path().join("oci");
+    puzzlefs(&[
+        OsStr::new("build"),
+        OsStr::new("--chunker"),
+        OsStr::new("fixed"),
+        OsStr::new("--chunk-size-avg"),
+        OsStr::new("4096"),
+        rootfs.as_os_str(),
+        oci.as_os_str(),
+        OsStr::new("test"),
+    ]);
+
+    let histogram = dir.path().join("chunks.csv");
+    puzzlefs(&[
+        OsStr::new("stats"),
+        oci.as_os_str(),
+        OsStr::new("test"),
+        OsStr::new("--histogram"),
+        histogram.as_os_str(),
+    ]);
+    let csv = fs::read_to_string(&histogram).unwrap();
+    let mut lines = csv.lines();
+    assert_eq!(lines.next(), Some("digest,len,refs"));
+    let rows = lines
+        .map(|l| l.split(',').collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    assert_eq!(rows.len(), 17);
+    let mut refs = rows.iter().map(|r| r[2]).collect::<Vec<_>>();
+    refs.sort_unstable();
+    refs.dedup();
+    assert_eq!(refs, vec!["1", "2"]);
+    assert!(rows.iter().any(|r| r[1] == "15"));
+}
+
+#[test]
+fn build_reports_dedup() {
+    let dir = tempdir().unwrap();
+    let rootfs = dir.path().join("rootfs");
+    fs::create_dir_all(rootfs.join("dir")).unwrap();
+    // 64 distinct chunks, twice
+    let data = (0..64 * 65536).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    fs::write(rootfs.join("big"), &data).unwrap();
+    fs::write(rootfs.join("dir/copy"), &data).unwrap();
+
+    let oci = dir.path().join("oci");
+    let output = Command::cargo_bin("puzzlefs")
+        .unwrap()
+        .args(&[
+            OsStr::new("build"),
+            OsStr::new("--chunker"),
+            OsStr::new("fixed"),
+            OsStr::new("--chunk-size-avg"),
+            OsStr::new("65536"),
+            OsStr::new("--json"),
+            rootfs.as_os_str(),
+            oci.as_os_str(),
+            OsStr::new("test"),
+        ])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stats = serde_json::from_slice::<serde_json::Value>(&output.stdout).unwrap();
+    assert_eq!(stats["chunks_written"], 64);
+    assert_eq!(stats["chunks_deduplicated"], 64);
+    assert_eq!(stats["blob_bytes"], data.len());
+}
+
+#[test]
+fn diff_blobs_between_versions() {
+    let dir = tempdir().unwrap();
+    let oci = dir.path().join("oci");
+    let base = (0..16 * 4096).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    for (tag, extra) in &[("v1", b'1'), ("v2", b'2')] {
+        let rootfs = dir.path().join(tag);
+        fs::create_dir_all(&rootfs).unwrap();
+        fs::write(rootfs.join("base"), &base).unwrap();
+        fs::write(rootfs.join("version"), vec![*extra; 2 * 4096]).unwrap();
+        puzzlefs(&[
+            OsStr::new("build"),
+            OsStr::new("--chunker"),
+            OsStr::new("fixed"),
+            OsStr::new("--chunk-size-avg"),
+            OsStr::new("4096"),
+            rootfs.as_os_str(),
+            oci.as_os_str(),
+            OsStr::new(tag),
+        ]);
+    }
+
+    let output = Command::cargo_bin("puzzlefs")
+        .unwrap()
+        .args(&[
+            OsStr::new("diff-blobs"),
+            OsStr::new("--verbose"),
+            oci.as_os_str(),
+            OsStr::new("v1"),
+            OsStr::new("v2"),
+        ])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let mut lines = stdout.lines();
+    assert_eq!(lines.next(), Some("shared: 16 blobs, 65536 bytes"));
+    // the version files are a single repeated chunk each
+    assert_eq!(lines.next(), Some("only in v1: 1 blobs, 4096 bytes"));
+    assert_eq!(lines.next(), Some("only in v2: 1 blobs, 4096 bytes"));
+    let shared = lines.collect::<Vec<_>>();
+    assert_eq!(shared.len(), 16);
+    for digest in shared {
+        assert!(oci.join("blobs/sha256").join(digest).exists(), "{}", digest);
+    }
+}
+
+#[test]
+fn stats_compressed_metadata() {
+    let dir = tempdir().unwrap();
+    let rootfs = dir.path().join("rootfs");
+    fs::create_dir_all(&rootfs).unwrap();
+    for i in 0..500 {
+        fs::write(rootfs.join(format!("file-{}", i)), i.to_string()).unwrap();
+    }
+    let oci = dir.path().join("oci");
+    puzzlefs(&[
+        OsStr::new("build"),
+        OsStr::new("--compress-metadata"),
+        rootfs.as_os_str(),
+        oci.as_os_str(),
+        OsStr::new("test"),
+    ]);
+
+    let output = Command::cargo_bin("puzzlefs")
+        .unwrap()
+        .args(&[OsStr::new("stats"), oci.as_os_str(), OsStr::new("test")])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let line = stdout
+        .lines()
+        .find_map(|l| l.strip_prefix("metadata bytes: "))
+        .unwrap();
+    let sizes = line
+        .trim_end_matches(" uncompressed)")
+        .split(" (")
+        .map(|n| n.parse::<u64>().unwrap())
+        .collect::<Vec<_>>();
+    assert!(sizes[0] < sizes[1], "{}", stdout);
+}
@@ -0,0 +1,169 @@
+use std::ffi::OsStr;
+use std::fs;
+use std::process::Command;
+
+use assert_cmd::cargo::CommandCargoExt;
+use sha2::{Digest, Sha256};
+use tempfile::tempdir;
+
+mod helpers;
+use helpers::puzzlefs;
+
+#[test]
+fn verify_detects_corruption() {
+    let dir = tempdir().unwrap();
+    let rootfs = dir.path().join("rootfs");
+    fs::create_dir_all(&rootfs).unwrap();
+    fs::write(rootfs.join("lyrics"), "meshuggah rocks\n".repeat(1024)).unwrap();
+
+    let oci = dir.path().join("oci");
+    puzzlefs(&[
+        OsStr::new("build"),
+        rootfs.as_os_str(),
+        oci.as_os_str(),
+        OsStr::new("test"),
+    ]);
+    puzzlefs(&[OsStr::new("verify"), oci.as_os_str(), OsStr::new("test")]);
+
+    // the only chunk blob is the one with the file contents in it
+    const DIGEST: &str = "e76a4ac8d1ca749d0abc8f48a7b4808e325a6638607c2494a7d6ae6abb509e6e";
+    let blob = oci.join("blobs/sha256").join(DIGEST);
+    let mut contents = fs::read(&blob).unwrap();
+    contents[0] ^= 0xff;
+    fs::write(&blob, contents).unwrap();
+
+    let output = Command::cargo_bin("puzzlefs")
+        .unwrap()
+        .args(&[OsStr::new("verify"), oci.as_os_str(), OsStr::new("test")])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(5));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("/lyrics"), "{}", stderr);
+    assert!(stderr.contains(DIGEST), "{}", stderr);
+}
+
+#[test]
+fn verify_exit_codes() {
+    let dir = tempdir().unwrap();
+    let rootfs = dir.path().join("rootfs");
+    fs::create_dir_all(&rootfs).unwrap();
+    fs::write(rootfs.join("lyrics"), "meshuggah rocks\n".repeat(1024)).unwrap();
+    let oci = dir.path().join("oci");
+    puzzlefs(&[
+        OsStr::new("build"),
+        rootfs.as_os_str(),
+        oci.as_os_str(),
+        OsStr::new("test"),
+    ]);
+
+    let verify = |tag: &str| {
+        Command::cargo_bin("puzzlefs")
+            .unwrap()
+            .args(&[OsStr::new("verify"), oci.as_os_str(), OsStr::new(tag)])
+            .output()
+            .unwrap()
+    };
+    let output = verify("nope");
+    assert_eq!(output.status.code(), Some(3));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("no tag nope"), "{}", stderr);
+
+    const DIGEST: &str = "e76a4ac8d1ca749d0abc8f48a7b4808e325a6638607c2494a7d6ae6abb509e6e";
+    fs::remove_file(oci.join("blobs/sha256").join(DIGEST)).unwrap();
+    let output = verify("test");
+    assert_eq!(output.status.code(), Some(4));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains(DIGEST), "{}", stderr);
+}
+
+#[test]
+fn verify_in_parallel() {
+    let dir = tempdir().unwrap();
+    let rootfs = dir.path().join("rootfs");
+    fs::create_dir_all(&rootfs).unwrap();
+    // files exactly a chunk long, so each has a blob of its own
+    let contents = (0..32)
+        .map(|i| format!("meshuggah rocks {:02}\n", i).repeat(4096 / 19 + 1))
+        .map(|s| s.as_bytes()[..4096].to_vec())
+        .collect::<Vec<_>>();
+    for (i, data) in contents.iter().enumerate() {
+        fs::write(rootfs.join(format!("f{:02}", i)), data).unwrap();
+    }
+    let oci = dir.path().join("oci");
+    puzzlefs(&[
+        OsStr::new("build"),
+        OsStr::new("--chunker"),
+        OsStr::new("fixed"),
+        OsStr::new("--chunk-size-avg"),
+        OsStr::new("4K"),
+        rootfs.as_os_str(),
+        oci.as_os_str(),
+        OsStr::new("test"),
+    ]);
+    let verify = || {
+        Command::cargo_bin("puzzlefs")
+            .unwrap()
+            .args(&[OsStr::new("verify"), oci.as_os_str(), OsStr::new("test")])
+            .output()
+            .unwrap()
+    };
+    let output = verify();
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("verified 32 blobs"), "{}", stdout);
+
+    // whichever thread gets to its bad blob first, the one reported is the first in the image
+    for i in &[5, 25] {
+        let blob = oci
+            .join("blobs/sha256")
+            .join(hex::encode(Sha256::digest(&contents[*i])));
+        let mut data = fs::read(&blob).unwrap();
+        data[0] ^= 0xff;
+        fs::write(&blob, data).unwrap();
+    }
+    for _ in 0..5 {
+        let output = verify();
+        assert_eq!(output.status.code(), Some(5));
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("/f05"), "{}", stderr);
+        assert!(!stderr.contains("/f25"), "{}", stderr);
+    }
+}
+
+#[test]
+fn verify_files_only() {
+    let dir = tempdir().unwrap();
+    let rootfs = dir.path().join("rootfs");
+    fs::create_dir_all(&rootfs).unwrap();
+    fs::write(rootfs.join("lyrics"), "meshuggah rocks\n".repeat(1024)).unwrap();
+    let oci = dir.path().join("oci");
+    puzzlefs(&[
+        OsStr::new("build"),
+        OsStr::new("--file-digests"),
+        rootfs.as_os_str(),
+        oci.as_os_str(),
+        OsStr::new("test"),
+    ]);
+    let verify = || {
+        let output = Command::cargo_bin("puzzlefs")
+            .unwrap()
+            .args(&[
+                OsStr::new("verify"),
+                OsStr::new("--files-only"),
+                oci.as_os_str(),
+                OsStr::new("test"),
+            ])
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+    let stdout = verify();
+    assert!(stdout.contains("verified 1 files"), "{}", stdout);
+
+    // the chunk blobs aren't read at all
+    const DIGEST: &str = "e76a4ac8d1ca749d0abc8f48a7b4808e325a6638607c2494a7d6ae6abb509e6e";
+    fs::remove_file(oci.join("blobs/sha256").join(DIGEST)).unwrap();
+    verify();
+}
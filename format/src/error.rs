@@ -22,6 +22,24 @@ pub enum WireFormatError {
     InvalidImageSchema(i32, Backtrace),
     #[error("invalid image version: {0}")]
     InvalidImageVersion(String, Backtrace),
+    #[error("{0} is not an OCI image layout: {1}")]
+    NotAnImageLayout(String, String, Backtrace),
+    #[error("unsupported format version {0}: this puzzlefs only reads version {}, so the image needs building again with it (or reading with the puzzlefs that built it)", crate::FORMAT_VERSION)]
+    UnsupportedFormatVersion(u64, Backtrace),
+    #[error("invalid chunking parameters: {0}")]
+    InvalidChunkingParams(String, Backtrace),
+    #[error("blob digest mismatch: expected {0}, got {1}")]
+    DigestMismatch(String, String, Backtrace),
+    #[error("chunk list digest mismatch: expected {0}, got {1}")]
+    FileDigestMismatch(String, String, Backtrace),
+    #[error("registry error: {0}")]
+    RegistryError(String, Backtrace),
+    #[error("no blob {0} in the image; it may need pulling again")]
+    BlobNotFound(String, Backtrace),
+    #[error("no tag {0} in the image")]
+    TagNotFound(String, Backtrace),
+    #[error("corrupt metadata: {0}; the image needs rebuilding or pulling again")]
+    MetadataCorrupt(#[source] serde_cbor::Error, Backtrace),
     #[error("fs error: {0}")]
     IOError(#[from] io::Error, Backtrace),
     #[error("deserialization error (cbor): {0}")]
@@ -38,6 +56,15 @@ impl WireFormatError {
             WireFormatError::ValueMissing(..) => Errno::ENOENT as c_int,
             WireFormatError::InvalidImageSchema(..) => Errno::EINVAL as c_int,
             WireFormatError::InvalidImageVersion(..) => Errno::EINVAL as c_int,
+            WireFormatError::NotAnImageLayout(..) => Errno::EINVAL as c_int,
+            WireFormatError::UnsupportedFormatVersion(..) => Errno::EINVAL as c_int,
+            WireFormatError::InvalidChunkingParams(..) => Errno::EINVAL as c_int,
+            WireFormatError::DigestMismatch(..) => Errno::EIO as c_int,
+            WireFormatError::FileDigestMismatch(..) => Errno::EIO as c_int,
+            WireFormatError::RegistryError(..) => Errno::EIO as c_int,
+            WireFormatError::BlobNotFound(..) => Errno::ENOENT as c_int,
+            WireFormatError::TagNotFound(..) => Errno::ENOENT as c_int,
+            WireFormatError::MetadataCorrupt(..) => Errno::EIO as c_int,
             WireFormatError::IOError(ioe, ..) => {
                 ioe.raw_os_error().unwrap_or(Errno::EINVAL as i32) as c_int
             }
@@ -4,18 +4,22 @@ extern crate xattr;
 use std::backtrace::Backtrace;
 use std::convert::TryInto;
 use std::ffi::OsString;
+use std::fmt;
 use std::fs;
 use std::io;
 use std::io::{Read, Seek};
 use std::mem;
+use std::ops::Deref;
 use std::os::unix::fs::{FileTypeExt, MetadataExt};
 use std::path::Path;
+use std::str::FromStr;
 use std::vec::Vec;
 
 use nix::sys::stat;
 use serde::de::Error as SerdeError;
 use serde::de::Visitor;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256, Sha512};
 
 use compression::{Compression, Decompressor};
 
@@ -47,67 +51,329 @@ fn read_one<'a, T: Deserialize<'a>, R: Read>(r: R) -> Result<T> {
     // hack, we create a streaming deserializer for the type we're about to read, and then only
     // read one value.
     let mut iter = serde_cbor::Deserializer::from_reader(r).into_iter::<T>();
-    let v = iter.next().transpose()?;
+    // whatever is in a metadata blob was put there by serializing it, so anything that doesn't
+    // deserialize has been damaged since
+    let v = iter
+        .next()
+        .transpose()
+        .map_err(|e| WireFormatError::MetadataCorrupt(e, Backtrace::capture()))?;
     v.ok_or_else(|| WireFormatError::ValueMissing(Backtrace::capture()))
 }
 
+/// The version of the image format this writes, and the only one it can read. Images built before
+/// the rootfs recorded a version are version 0; version 1 images were laid out like neither those
+/// nor these.
+pub const FORMAT_VERSION: u64 = 2;
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Rootfs {
+    pub version: u64,
     pub metadatas: Vec<BlobRef>,
+    pub chunking: ChunkingConfig,
+}
+
+// just the version of a rootfs, which any version of it deserializes to. images without one are
+// version 0
+#[derive(Deserialize)]
+struct RootfsVersion {
+    #[serde(default)]
+    version: u64,
+}
+
+// the parameters the image's file content was chunked with, so that builds can be reproduced and
+// audited.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct ChunkingConfig {
+    pub min: u64,
+    pub avg: u64,
+    pub max: u64,
+    pub algo: ChunkingAlgorithm,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ChunkingAlgorithm {
+    FastCDC,
+    // fixed size chunks of ChunkingConfig.avg bytes
+    Fixed,
+}
+
+impl FromStr for ChunkingAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "fastcdc" => Ok(ChunkingAlgorithm::FastCDC),
+            "fixed" => Ok(ChunkingAlgorithm::Fixed),
+            _ => Err(format!(
+                "unknown chunking algorithm {}, expected fastcdc or fixed",
+                s
+            )),
+        }
+    }
 }
 
 impl Rootfs {
-    pub fn open<R: Read>(f: R) -> Result<Rootfs> {
-        read_one(f)
+    pub fn open<R: Read>(mut f: R) -> Result<Rootfs> {
+        let mut buf = Vec::new();
+        f.read_to_end(&mut buf)?;
+        // the blob refs and inodes of other versions are laid out differently, so the rest of the
+        // rootfs (let alone what it points to) would only fail to deserialize as corrupt
+        let RootfsVersion { version } = read_one(&buf[..])?;
+        if version != FORMAT_VERSION {
+            return Err(WireFormatError::UnsupportedFormatVersion(
+                version,
+                Backtrace::capture(),
+            ));
+        }
+        read_one(&buf[..])
+    }
+}
+
+/// What blobs are hashed with to get their digests, picked when the image is built.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DigestAlgorithm {
+    Sha256,
+    Blake3,
+    Sha512,
+}
+
+impl Default for DigestAlgorithm {
+    fn default() -> Self {
+        DigestAlgorithm::Sha256
+    }
+}
+
+impl DigestAlgorithm {
+    /// How digests hashed this way are prefixed, e.g. `sha256` for `sha256:<hex>`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            DigestAlgorithm::Sha256 => "sha256",
+            DigestAlgorithm::Blake3 => "blake3",
+            DigestAlgorithm::Sha512 => "sha512",
+        }
+    }
+
+    /// How many bytes its digests are.
+    pub fn size(&self) -> usize {
+        match self {
+            DigestAlgorithm::Sha256 | DigestAlgorithm::Blake3 => 32,
+            DigestAlgorithm::Sha512 => 64,
+        }
+    }
+
+    pub fn hasher(&self) -> Hasher {
+        match self {
+            DigestAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            DigestAlgorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+            DigestAlgorithm::Sha512 => Hasher::Sha512(Sha512::new()),
+        }
+    }
+
+    pub fn digest(&self, data: &[u8]) -> RawDigest {
+        let mut hasher = self.hasher();
+        hasher.update(data);
+        hasher.finalize()
+    }
+}
+
+impl fmt::Display for DigestAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for DigestAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "sha256" => Ok(DigestAlgorithm::Sha256),
+            "blake3" => Ok(DigestAlgorithm::Blake3),
+            "sha512" => Ok(DigestAlgorithm::Sha512),
+            _ => Err(format!("unknown digest algorithm {}", s)),
+        }
+    }
+}
+
+pub const MAX_DIGEST_SIZE: usize = 64;
+
+/// The bytes of a digest, as many as its algorithm makes.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct RawDigest {
+    len: u8,
+    bytes: [u8; MAX_DIGEST_SIZE],
+}
+
+impl RawDigest {
+    pub fn new(digest: &[u8]) -> RawDigest {
+        assert!(
+            digest.len() <= MAX_DIGEST_SIZE,
+            "{} byte digest",
+            digest.len()
+        );
+        let mut bytes = [0; MAX_DIGEST_SIZE];
+        bytes[..digest.len()].copy_from_slice(digest);
+        RawDigest {
+            len: digest.len() as u8,
+            bytes,
+        }
+    }
+}
+
+impl Deref for RawDigest {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+}
+
+impl AsRef<[u8]> for RawDigest {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl From<[u8; 32]> for RawDigest {
+    fn from(digest: [u8; 32]) -> Self {
+        RawDigest::new(&digest)
+    }
+}
+
+impl fmt::Debug for RawDigest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&hex::encode(&**self))
+    }
+}
+
+/// Hashes something with one of the DigestAlgorithms.
+pub enum Hasher {
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+    Sha512(Sha512),
+}
+
+impl Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(h) => h.update(data),
+            Hasher::Blake3(h) => {
+                h.update(data);
+            }
+            Hasher::Sha512(h) => h.update(data),
+        }
+    }
+
+    pub fn finalize(self) -> RawDigest {
+        match self {
+            Hasher::Sha256(h) => RawDigest::new(&h.finalize()),
+            Hasher::Blake3(h) => RawDigest::new(h.finalize().as_bytes()),
+            Hasher::Sha512(h) => RawDigest::new(&h.finalize()),
+        }
+    }
+}
+
+impl io::Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
     }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum BlobRefKind {
     Local,
-    Other { digest: [u8; 32] },
+    Other {
+        digest: RawDigest,
+        algorithm: DigestAlgorithm,
+    },
 }
 
-const BLOB_REF_SIZE: usize = 1 /* mode */ + 32 /* digest */ + 8 /* offset */;
+// what a sha256 ref takes, and so what inodes make room for; theirs only ever point into their
+// own metadata blob. local refs keep the room for a digest they've always had, so only sha512 refs
+// are any longer.
+const BLOB_REF_SIZE: usize = 1 /* mode */ + 32 /* digest */ + 8 /* offset */ + 1 /* compressed */;
 
 // TODO: should this be an ociv1 digest and include size and media type?
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct BlobRef {
     pub offset: u64,
     pub kind: BlobRefKind,
+    // whether the referenced blob is zstd compressed; offset is relative to the uncompressed data
+    pub compressed: bool,
 }
 
 impl BlobRef {
-    fn fixed_length_serialize(&self, state: &mut [u8; BLOB_REF_SIZE]) {
+    fn wire_size(&self) -> usize {
+        match self.kind {
+            BlobRefKind::Local => BLOB_REF_SIZE,
+            BlobRefKind::Other { algorithm, .. } => BLOB_REF_SIZE - 32 + algorithm.size(),
+        }
+    }
+
+    fn serialize_into(&self, state: &mut [u8]) {
         state[0..8].copy_from_slice(&self.offset.to_le_bytes());
         match self.kind {
             BlobRefKind::Local => state[8] = 0,
-            BlobRefKind::Other { ref digest } => {
-                state[8] = 1;
-                state[9..41].copy_from_slice(digest);
+            // sha256 came first, and every image before there was a choice used it
+            BlobRefKind::Other {
+                ref digest,
+                algorithm,
+            } => {
+                state[8] = match algorithm {
+                    DigestAlgorithm::Sha256 => 1,
+                    DigestAlgorithm::Blake3 => 2,
+                    DigestAlgorithm::Sha512 => 3,
+                };
+                state[9..9 + digest.len()].copy_from_slice(digest);
             }
         };
+        state[self.wire_size() - 1] = self.compressed as u8;
     }
 
-    fn fixed_length_deserialize<E: SerdeError>(
-        state: &[u8; BLOB_REF_SIZE],
-    ) -> std::result::Result<BlobRef, E> {
+    fn deserialize_from<E: SerdeError>(state: &[u8]) -> std::result::Result<BlobRef, E> {
+        let bad_length = || SerdeError::invalid_length(state.len(), &"the bytes of a BlobRef");
+        if state.len() < 9 {
+            return Err(bad_length());
+        }
         let offset = u64::from_le_bytes(state[0..8].try_into().unwrap());
 
-        let kind = match state[8] {
-            0 => BlobRefKind::Local,
-            1 => BlobRefKind::Other {
-                digest: state[9..41].try_into().unwrap(),
-            },
+        let algorithm = match state[8] {
+            0 => None,
+            1 => Some(DigestAlgorithm::Sha256),
+            2 => Some(DigestAlgorithm::Blake3),
+            3 => Some(DigestAlgorithm::Sha512),
             _ => {
                 return Err(SerdeError::custom(format!(
                     "bad blob ref kind {}",
-                    state[0]
+                    state[8]
                 )))
             }
         };
+        let digest_size = algorithm.map_or(32, |a| a.size());
+        if state.len() != 9 + digest_size + 1 {
+            return Err(bad_length());
+        }
+        let kind = match algorithm {
+            None => BlobRefKind::Local,
+            Some(algorithm) => BlobRefKind::Other {
+                digest: RawDigest::new(&state[9..9 + digest_size]),
+                algorithm,
+            },
+        };
+
+        let compressed = state[9 + digest_size] != 0;
 
-        Ok(BlobRef { offset, kind })
+        Ok(BlobRef {
+            offset,
+            kind,
+            compressed,
+        })
     }
 }
 
@@ -116,8 +382,8 @@ impl Serialize for BlobRef {
     where
         S: Serializer,
     {
-        let mut state: [u8; BLOB_REF_SIZE] = [0; BLOB_REF_SIZE];
-        self.fixed_length_serialize(&mut state);
+        let mut state = vec![0; self.wire_size()];
+        self.serialize_into(&mut state);
         serializer.serialize_bytes(&state)
     }
 }
@@ -133,17 +399,14 @@ impl<'de> Deserialize<'de> for BlobRef {
             type Value = BlobRef;
 
             fn expecting(&self, formatter: &mut ::core::fmt::Formatter<'_>) -> ::core::fmt::Result {
-                formatter.write_fmt(format_args!("expected {} bytes for BlobRef", BLOB_REF_SIZE))
+                formatter.write_str("the bytes of a BlobRef")
             }
 
             fn visit_bytes<E>(self, v: &[u8]) -> std::result::Result<BlobRef, E>
             where
                 E: SerdeError,
             {
-                let state: [u8; BLOB_REF_SIZE] = v
-                    .try_into()
-                    .map_err(|_| SerdeError::invalid_length(v.len(), &self))?;
-                BlobRef::fixed_length_deserialize(&state)
+                BlobRef::deserialize_from(v)
             }
         }
 
@@ -165,6 +428,8 @@ pub struct DirEnt {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DirList {
     // TODO: flags instead?
+    // whether a lower layer's entries for this directory show through; an opaque directory
+    // doesn't look below.
     pub look_below: bool,
     pub entries: Vec<DirEnt>,
 }
@@ -176,10 +441,64 @@ pub struct FileChunkList {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct FileChunk {
-    pub blob: BlobRef,
+    // None for a hole in a sparse file: len bytes of zeros that aren't stored anywhere
+    pub blob: Option<BlobRef>,
     pub len: u64,
 }
 
+/// A digest of which bytes of which blobs a file is made of, which changes if its chunk list does.
+/// The blobs have digests of their own, so this and them together stand for the file's content,
+/// without anything having to be read to check it.
+pub fn chunk_list_digest(chunks: &[FileChunk]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    for chunk in chunks {
+        hasher.update(chunk.len.to_le_bytes());
+        match chunk.blob {
+            None => hasher.update([0]),
+            Some(BlobRef {
+                kind: BlobRefKind::Local,
+                offset,
+                ..
+            }) => {
+                hasher.update([1]);
+                hasher.update(offset.to_le_bytes());
+            }
+            Some(BlobRef {
+                kind: BlobRefKind::Other { digest, .. },
+                offset,
+                ..
+            }) => {
+                hasher.update([2]);
+                hasher.update(digest);
+                hasher.update(offset.to_le_bytes());
+            }
+        }
+    }
+    hasher.finalize().into()
+}
+
+/// Checks that a chunk list's digest is `expected`.
+pub fn verify_chunk_list(chunks: &[FileChunk], expected: &[u8; 32]) -> Result<()> {
+    let actual = chunk_list_digest(chunks);
+    if actual != *expected {
+        return Err(WireFormatError::FileDigestMismatch(
+            hex::encode(expected),
+            hex::encode(actual),
+            Backtrace::capture(),
+        ));
+    }
+    Ok(())
+}
+
+/// How OCI layers mark a deleted file: an empty file named `.wh.<name>` next to where it was.
+pub const WHITEOUT_PREFIX: &str = ".wh.";
+/// An OCI layer directory containing this hides everything lower layers have in it.
+pub const OPAQUE_WHITEOUT: &str = ".wh..wh..opq";
+/// How overlayfs marks an opaque directory; its whiteouts are 0/0 character devices instead.
+pub const OVERLAY_OPAQUE_XATTR: &str = "trusted.overlay.opaque";
+/// A file's capabilities, e.g. ping's cap_net_raw. Setting them needs CAP_SETFCAP.
+pub const CAPABILITY_XATTR: &str = "security.capability";
+
 const INODE_MODE_SIZE: usize = 1 /* mode */ + mem::size_of::<u64>() * 2 /* major/minor/offset */;
 
 // InodeMode needs to have custom serialization because inodes must be a fixed size.
@@ -196,17 +515,94 @@ pub enum InodeMode {
     Wht,
 }
 
+impl InodeMode {
+    /// The mode of anything that isn't a directory or a regular file; those need to know where
+    /// their dir list or chunk list is.
+    pub fn new_other(md: &fs::Metadata) -> io::Result<Self> {
+        let file_type = md.file_type();
+        let mode = if file_type.is_fifo() {
+            InodeMode::Fifo
+        } else if file_type.is_char_device() {
+            let major = stat::major(md.rdev());
+            let minor = stat::minor(md.rdev());
+            InodeMode::Chr { major, minor }
+        } else if file_type.is_dir() {
+            return Err(io::Error::new(io::ErrorKind::Other, "is a dir"));
+        } else if file_type.is_block_device() {
+            let major = stat::major(md.rdev());
+            let minor = stat::minor(md.rdev());
+            InodeMode::Blk { major, minor }
+        } else if file_type.is_file() {
+            return Err(io::Error::new(io::ErrorKind::Other, "is a file"));
+        } else if file_type.is_symlink() {
+            InodeMode::Lnk
+        } else if file_type.is_socket() {
+            InodeMode::Sock
+        } else {
+            InodeMode::Unknown
+        };
+        Ok(mode)
+    }
+}
+
 pub type Ino = u64;
 
-const INODE_SIZE: usize = mem::size_of::<Ino>() + INODE_MODE_SIZE + mem::size_of::<u64>() + mem::size_of::<u64>() + 1 /* Option<BlobRef> */ + BLOB_REF_SIZE;
+/// A point in time, as seconds and nanoseconds since the epoch, like a struct timespec.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Timestamp {
+    pub sec: i64,
+    pub nsec: u32,
+}
+
+const TIMESTAMP_SIZE: usize = mem::size_of::<i64>() + mem::size_of::<u32>();
+
+impl Timestamp {
+    pub fn mtime(md: &fs::Metadata) -> Self {
+        Timestamp {
+            sec: md.mtime(),
+            nsec: md.mtime_nsec() as u32,
+        }
+    }
+
+    pub fn atime(md: &fs::Metadata) -> Self {
+        Timestamp {
+            sec: md.atime(),
+            nsec: md.atime_nsec() as u32,
+        }
+    }
+
+    fn fixed_length_serialize(&self, state: &mut [u8; TIMESTAMP_SIZE]) {
+        state[0..8].copy_from_slice(&self.sec.to_le_bytes());
+        state[8..12].copy_from_slice(&self.nsec.to_le_bytes());
+    }
+
+    fn fixed_length_deserialize(state: &[u8; TIMESTAMP_SIZE]) -> Self {
+        Timestamp {
+            sec: i64::from_le_bytes(state[0..8].try_into().unwrap()),
+            nsec: u32::from_le_bytes(state[8..12].try_into().unwrap()),
+        }
+    }
+}
+
+const INODE_SIZE: usize = mem::size_of::<Ino>() + INODE_MODE_SIZE + mem::size_of::<u64>() + mem::size_of::<u64>() + 1 /* Option<BlobRef> */ + BLOB_REF_SIZE + mem::size_of::<u32>() /* nlink */ + 2 * TIMESTAMP_SIZE /* mtime, atime */ + mem::size_of::<u16>() /* permissions */;
+
+// nlink lives after the additional BlobRef, then the timestamps and the permissions
+const INODE_NLINK_OFFSET: usize = 35 + BLOB_REF_SIZE;
+const INODE_MTIME_OFFSET: usize = INODE_NLINK_OFFSET + mem::size_of::<u32>();
+const INODE_ATIME_OFFSET: usize = INODE_MTIME_OFFSET + TIMESTAMP_SIZE;
+const INODE_PERMISSIONS_OFFSET: usize = INODE_ATIME_OFFSET + TIMESTAMP_SIZE;
+
+/// The permission bits of a mode (including setuid, setgid and sticky), i.e. everything but the
+/// file type.
+pub const PERMISSION_BITS: u32 = 0o7777;
 
 pub const fn cbor_size_of_list_header(size: usize) -> usize {
     match size {
         0..=23 => 1,
         24..=255 => 2,
         256..=65535 => 3,
-        65536..=4294967295 => 4,
-        _ => 8,
+        65536..=4294967295 => 5,
+        _ => 9,
     }
 }
 
@@ -218,7 +614,12 @@ pub struct Inode {
     pub mode: InodeMode,
     pub uid: u32,
     pub gid: u32,
+    pub nlink: u32,
+    pub mtime: Timestamp,
+    pub atime: Timestamp,
     pub additional: Option<BlobRef>,
+    // st_mode's PERMISSION_BITS
+    pub permissions: u16,
 }
 
 impl Serialize for Inode {
@@ -260,12 +661,30 @@ impl Serialize for Inode {
         state[25..29].copy_from_slice(&self.uid.to_le_bytes());
         state[29..33].copy_from_slice(&self.gid.to_le_bytes());
         if let Some(additional) = self.additional {
+            if additional.wire_size() != BLOB_REF_SIZE {
+                return Err(serde::ser::Error::custom(
+                    "an inode's additional ref doesn't fit in it",
+                ));
+            }
             state[34] = 1;
-            additional
-                .fixed_length_serialize((&mut state[35..35 + BLOB_REF_SIZE]).try_into().unwrap());
+            additional.serialize_into(&mut state[35..35 + BLOB_REF_SIZE]);
         } else {
             state[34] = 0;
         }
+        state[INODE_NLINK_OFFSET..INODE_NLINK_OFFSET + 4]
+            .copy_from_slice(&self.nlink.to_le_bytes());
+        self.mtime.fixed_length_serialize(
+            (&mut state[INODE_MTIME_OFFSET..INODE_MTIME_OFFSET + TIMESTAMP_SIZE])
+                .try_into()
+                .unwrap(),
+        );
+        self.atime.fixed_length_serialize(
+            (&mut state[INODE_ATIME_OFFSET..INODE_ATIME_OFFSET + TIMESTAMP_SIZE])
+                .try_into()
+                .unwrap(),
+        );
+        state[INODE_PERMISSIONS_OFFSET..INODE_PERMISSIONS_OFFSET + 2]
+            .copy_from_slice(&self.permissions.to_le_bytes());
         serializer.serialize_bytes(&state)
     }
 }
@@ -325,9 +744,7 @@ impl<'de> Deserialize<'de> for Inode {
                 };
 
                 let additional = if state[34] > 0 {
-                    Some(BlobRef::fixed_length_deserialize(
-                        state[35..35 + BLOB_REF_SIZE].try_into().unwrap(),
-                    )?)
+                    Some(BlobRef::deserialize_from(&state[35..35 + BLOB_REF_SIZE])?)
                 } else {
                     None
                 };
@@ -339,7 +756,27 @@ impl<'de> Deserialize<'de> for Inode {
                     mode,
                     uid: u32::from_le_bytes(state[25..29].try_into().unwrap()),
                     gid: u32::from_le_bytes(state[29..33].try_into().unwrap()),
+                    nlink: u32::from_le_bytes(
+                        state[INODE_NLINK_OFFSET..INODE_NLINK_OFFSET + 4]
+                            .try_into()
+                            .unwrap(),
+                    ),
+                    mtime: Timestamp::fixed_length_deserialize(
+                        state[INODE_MTIME_OFFSET..INODE_MTIME_OFFSET + TIMESTAMP_SIZE]
+                            .try_into()
+                            .unwrap(),
+                    ),
+                    atime: Timestamp::fixed_length_deserialize(
+                        state[INODE_ATIME_OFFSET..INODE_ATIME_OFFSET + TIMESTAMP_SIZE]
+                            .try_into()
+                            .unwrap(),
+                    ),
                     additional,
+                    permissions: u16::from_le_bytes(
+                        state[INODE_PERMISSIONS_OFFSET..INODE_PERMISSIONS_OFFSET + 2]
+                            .try_into()
+                            .unwrap(),
+                    ),
                 })
             }
         }
@@ -384,50 +821,56 @@ impl Inode {
     }
 
     pub fn new_other(ino: Ino, md: &fs::Metadata, additional: Option<BlobRef>) -> io::Result<Self> {
-        let file_type = md.file_type();
-        let mode = if file_type.is_fifo() {
-            InodeMode::Fifo
-        } else if file_type.is_char_device() {
-            let major = stat::major(md.rdev());
-            let minor = stat::minor(md.rdev());
-            InodeMode::Chr { major, minor }
-        } else if file_type.is_dir() {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("{} is a dir", ino),
-            ));
-        } else if file_type.is_block_device() {
-            let major = stat::major(md.rdev());
-            let minor = stat::minor(md.rdev());
-            InodeMode::Blk { major, minor }
-        } else if file_type.is_file() {
-            return Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("{} is a file", ino),
-            ));
-        } else if file_type.is_symlink() {
-            InodeMode::Lnk
-        } else if file_type.is_socket() {
-            InodeMode::Sock
-        } else {
-            InodeMode::Unknown
-        };
-
+        let mode = InodeMode::new_other(md)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{}: {}", ino, e)))?;
         Ok(Self::new_inode(ino, md, mode, additional))
     }
 
-    fn new_inode(
+    /// For when the inode's metadata doesn't come from a file on the host, e.g. when building
+    /// from a tar archive.
+    pub fn new(
         ino: Ino,
-        md: &fs::Metadata,
         mode: InodeMode,
+        uid: u32,
+        gid: u32,
+        mtime: Timestamp,
+        atime: Timestamp,
         additional: Option<BlobRef>,
     ) -> Self {
         Inode {
             ino,
             mode,
-            uid: md.uid(),
-            gid: md.gid(),
+            uid,
+            gid,
+            // the builder knows how many times this inode is referenced in the image (the host's
+            // count may include links outside of the rootfs), so it fixes this up later.
+            nlink: 1,
+            mtime,
+            atime,
             additional,
+            // what everything looked like before images had permissions; callers that know
+            // better set them
+            permissions: 0o644,
+        }
+    }
+
+    fn new_inode(
+        ino: Ino,
+        md: &fs::Metadata,
+        mode: InodeMode,
+        additional: Option<BlobRef>,
+    ) -> Self {
+        Inode {
+            permissions: (md.mode() & PERMISSION_BITS) as u16,
+            ..Self::new(
+                ino,
+                mode,
+                md.uid(),
+                md.gid(),
+                Timestamp::mtime(md),
+                Timestamp::atime(md),
+                additional,
+            )
         }
     }
 
@@ -445,6 +888,61 @@ impl Inode {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_rootfs_versions() {
+        let blob = BlobRef {
+            offset: 0,
+            kind: BlobRefKind::Other {
+                digest: RawDigest::from([1; 32]),
+                algorithm: DigestAlgorithm::Sha256,
+            },
+            compressed: false,
+        };
+        let chunking = ChunkingConfig {
+            min: 0,
+            avg: 4096,
+            max: 0,
+            algo: ChunkingAlgorithm::Fixed,
+        };
+        let current = serde_cbor::to_vec(&Rootfs {
+            version: FORMAT_VERSION,
+            metadatas: vec![blob],
+            chunking,
+        })
+        .unwrap();
+        let rootfs = Rootfs::open(&current[..]).unwrap();
+        assert_eq!(rootfs.version, FORMAT_VERSION);
+        assert_eq!(rootfs.metadatas, vec![blob]);
+        assert_eq!(rootfs.chunking, chunking);
+
+        // no version at all is version 0, which is refused before anything else in it is read
+        #[derive(Serialize)]
+        struct RootfsV0 {
+            metadatas: Vec<u8>,
+        }
+        let old = serde_cbor::to_vec(&RootfsV0 {
+            metadatas: vec![1; 41],
+        })
+        .unwrap();
+        assert!(matches!(
+            Rootfs::open(&old[..]),
+            Err(WireFormatError::UnsupportedFormatVersion(0, _))
+        ));
+
+        for version in &[1, FORMAT_VERSION + 1] {
+            let other = serde_cbor::to_vec(&Rootfs {
+                version: *version,
+                metadatas: vec![blob],
+                chunking,
+            })
+            .unwrap();
+            assert!(matches!(
+                Rootfs::open(&other[..]),
+                Err(WireFormatError::UnsupportedFormatVersion(v, _)) if v == *version
+            ));
+        }
+    }
+
     #[test]
     fn test_inode_is_constant_serialized_size() {
         // TODO: this is the sort of think quickcheck is perfect for...
@@ -454,21 +952,33 @@ mod tests {
                 mode: InodeMode::Unknown,
                 uid: 0,
                 gid: 0,
+                nlink: 1,
+                mtime: Timestamp::default(),
+                atime: Timestamp::default(),
                 additional: None,
+                permissions: 0o644,
             },
             Inode {
                 ino: 0,
                 mode: InodeMode::Lnk,
                 uid: 0,
                 gid: 0,
+                nlink: 1,
+                mtime: Timestamp::default(),
+                atime: Timestamp::default(),
                 additional: None,
+                permissions: 0o777,
             },
             Inode {
                 ino: 0,
                 mode: InodeMode::Reg { offset: 64 },
                 uid: 0,
                 gid: 0,
+                nlink: 1,
+                mtime: Timestamp::default(),
+                atime: Timestamp::default(),
                 additional: None,
+                permissions: 0o4755,
             },
             Inode {
                 ino: 65343,
@@ -478,17 +988,32 @@ mod tests {
                 },
                 uid: 10,
                 gid: 10000,
+                nlink: 2,
+                mtime: Timestamp {
+                    sec: -1,
+                    nsec: 999_999_999,
+                },
+                atime: Timestamp {
+                    sec: 1_626_000_000,
+                    nsec: 1,
+                },
                 additional: None,
+                permissions: 0o600,
             },
             Inode {
                 ino: 0,
                 mode: InodeMode::Lnk,
                 uid: 0,
                 gid: 0,
+                nlink: 1,
+                mtime: Timestamp::default(),
+                atime: Timestamp::default(),
                 additional: Some(BlobRef {
                     offset: 42,
                     kind: BlobRefKind::Local,
+                    compressed: false,
                 }),
+                permissions: 0o777,
             },
         ];
 
@@ -501,11 +1026,11 @@ mod tests {
     }
 
     fn blobref_roundtrip(original: BlobRef) {
-        let mut serialized = [0_u8; BLOB_REF_SIZE];
-        original.fixed_length_serialize(&mut serialized);
+        let mut serialized = vec![0_u8; original.wire_size()];
+        original.serialize_into(&mut serialized);
         // we lie here and say this is a serde_cbor error, even though it really doesn't matter...
         let deserialized =
-            BlobRef::fixed_length_deserialize::<serde_cbor::error::Error>(&serialized).unwrap();
+            BlobRef::deserialize_from::<serde_cbor::error::Error>(&serialized).unwrap();
         assert_eq!(original, deserialized);
     }
 
@@ -514,6 +1039,7 @@ mod tests {
         let local = BlobRef {
             offset: 42,
             kind: BlobRefKind::Local,
+            compressed: false,
         };
         blobref_roundtrip(local)
     }
@@ -523,18 +1049,195 @@ mod tests {
         let mut digest = [0_u8; 32];
         digest[0] = 0;
         digest[31] = 31;
+        let digest = RawDigest::from(digest);
         let other = BlobRef {
             offset: 42,
-            kind: BlobRefKind::Other { digest },
+            kind: BlobRefKind::Other {
+                digest,
+                algorithm: DigestAlgorithm::Sha256,
+            },
+            compressed: false,
+        };
+        blobref_roundtrip(other);
+
+        let compressed = BlobRef {
+            offset: 42,
+            kind: BlobRefKind::Other {
+                digest,
+                algorithm: DigestAlgorithm::Sha256,
+            },
+            compressed: true,
+        };
+        blobref_roundtrip(compressed);
+
+        // what sha256 refs have always looked like on the wire
+        let mut wire = [0_u8; BLOB_REF_SIZE];
+        compressed.serialize_into(&mut wire);
+        assert_eq!(wire[8], 1);
+        assert_eq!(wire[9..41], *digest);
+        assert_eq!(wire[41], 1);
+
+        let blake3 = BlobRef {
+            offset: 42,
+            kind: BlobRefKind::Other {
+                digest,
+                algorithm: DigestAlgorithm::Blake3,
+            },
+            compressed: false,
         };
-        blobref_roundtrip(other)
+        blobref_roundtrip(blake3);
+        blake3.serialize_into(&mut wire);
+        wire[8] = 4;
+        BlobRef::deserialize_from::<serde_cbor::error::Error>(&wire).unwrap_err();
+    }
+
+    #[test]
+    fn test_sha512_blobref_serialization() {
+        let digest = DigestAlgorithm::Sha512.digest(b"meshuggah");
+        assert_eq!(digest.len(), 64);
+        let sha512 = BlobRef {
+            offset: 42,
+            kind: BlobRefKind::Other {
+                digest,
+                algorithm: DigestAlgorithm::Sha512,
+            },
+            compressed: true,
+        };
+        blobref_roundtrip(sha512);
+        let wire = serde_cbor::to_vec(&sha512).unwrap();
+        assert_eq!(serde_cbor::from_slice::<BlobRef>(&wire).unwrap(), sha512);
+
+        // the digest's length goes with its algorithm
+        let mut wire = vec![0_u8; sha512.wire_size()];
+        sha512.serialize_into(&mut wire);
+        wire[8] = 1;
+        BlobRef::deserialize_from::<serde_cbor::error::Error>(&wire).unwrap_err();
+
+        // and inodes only have room for 32 bytes of it
+        let inode = Inode {
+            ino: 1,
+            mode: InodeMode::Lnk,
+            uid: 0,
+            gid: 0,
+            nlink: 1,
+            mtime: Timestamp::default(),
+            atime: Timestamp::default(),
+            additional: Some(sha512),
+            permissions: 0o777,
+        };
+        inode.to_wire().unwrap_err();
+    }
+
+    #[test]
+    fn test_file_chunks_before_holes() {
+        // images from before sparse files had a BlobRef in every chunk
+        #[derive(Serialize)]
+        struct OldFileChunk {
+            blob: BlobRef,
+            len: u64,
+        }
+        let blob = BlobRef {
+            offset: 42,
+            kind: BlobRefKind::Other {
+                digest: RawDigest::from([7; 32]),
+                algorithm: DigestAlgorithm::Sha256,
+            },
+            compressed: true,
+        };
+        let wire = serde_cbor::to_vec(&OldFileChunk { blob, len: 4096 }).unwrap();
+        let chunk = serde_cbor::from_slice::<FileChunk>(&wire).unwrap();
+        assert_eq!(chunk.blob, Some(blob));
+        assert_eq!(chunk.len, 4096);
+
+        let wire = serde_cbor::to_vec(&FileChunk {
+            blob: None,
+            len: 1 << 30,
+        })
+        .unwrap();
+        let hole = serde_cbor::from_slice::<FileChunk>(&wire).unwrap();
+        assert_eq!(hole.blob, None);
+        assert_eq!(hole.len, 1 << 30);
+    }
+
+    #[test]
+    fn test_list_header_size() {
+        // null is one byte, so whatever else there is is the header
+        for &len in &[0, 23, 24, 255, 256, 65535, 65536] {
+            let wire = serde_cbor::to_vec(&vec![(); len]).unwrap();
+            assert_eq!(cbor_size_of_list_header(len), wire.len() - len, "{}", len);
+        }
+    }
+
+    #[test]
+    fn test_find_inode_in_big_table() {
+        // enough that the array's length takes four bytes to write down
+        let inodes = (1..=70_000)
+            .map(|ino| {
+                Inode::new(
+                    ino * 2,
+                    InodeMode::Fifo,
+                    0,
+                    0,
+                    Timestamp::default(),
+                    Timestamp::default(),
+                    None,
+                )
+            })
+            .collect::<Vec<_>>();
+        let wire = serde_cbor::to_vec(&inodes).unwrap();
+        let mut md = MetadataBlob::new::<compression::Noop, _>(io::Cursor::new(wire));
+        for &ino in &[2, 4, 70_000, 139_998, 140_000] {
+            assert_eq!(md.find_inode(ino).unwrap().unwrap().ino, ino);
+        }
+        for &ino in &[0, 1, 70_001, 140_002] {
+            assert!(md.find_inode(ino).unwrap().is_none(), "{}", ino);
+        }
+        assert_eq!(md.read_inodes().unwrap().len(), inodes.len());
+    }
+
+    #[test]
+    fn test_corrupt_metadata() {
+        let mut md = MetadataBlob::new::<compression::Noop, _>(io::Cursor::new(vec![0xff; 64]));
+        match md.read_inodes() {
+            Err(WireFormatError::MetadataCorrupt(..)) => {}
+            r => panic!("expected corrupt metadata, got {:?}", r.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_digest_algorithms() {
+        let abc = |algorithm: DigestAlgorithm| {
+            let mut hasher = algorithm.hasher();
+            hasher.update(b"a");
+            hasher.update(b"bc");
+            let digest = hasher.finalize();
+            assert_eq!(digest.len(), algorithm.size());
+            assert_eq!(digest, algorithm.digest(b"abc"));
+            hex::encode(&*digest)
+        };
+        assert_eq!(
+            abc(DigestAlgorithm::Sha256),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            abc(DigestAlgorithm::Blake3),
+            "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+        );
+        assert_eq!(
+            abc(DigestAlgorithm::Sha512),
+            "ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a\
+             2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f"
+        );
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default)]
 pub struct InodeAdditional {
     pub xattrs: Vec<Xattr>,
     pub symlink_target: Option<OsString>,
+    /// For files, the chunk_list_digest() of their chunk list, if the build was asked to store it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_digest: Option<[u8; 32]>,
 }
 
 impl InodeAdditional {
@@ -552,12 +1255,16 @@ impl InodeAdditional {
             Ok(Some(InodeAdditional {
                 xattrs,
                 symlink_target,
+                file_digest: None,
             }))
         }
     }
 
     fn get_xattrs(p: &Path) -> io::Result<Vec<Xattr>> {
-        xattr::list(p)?
+        // POSIX ACLs come along too, as system.posix_acl_access and system.posix_acl_default in
+        // the kernel's binary encoding, which is what setxattr() and getxattr() over a mount take
+        // and give back.
+        let mut xattrs = xattr::list(p)?
             .map(|xa| {
                 let value = xattr::get(p, &xa)?;
                 Ok(Xattr {
@@ -565,7 +1272,10 @@ impl InodeAdditional {
                     val: value,
                 })
             })
-            .collect()
+            .collect::<io::Result<Vec<Xattr>>>()?;
+        // listxattr() order is up to the filesystem, keep images reproducible
+        xattrs.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(xattrs)
     }
 }
 
@@ -577,11 +1287,16 @@ pub struct Xattr {
 
 pub struct MetadataBlob {
     f: Box<dyn Decompressor>,
+    // how many inodes there are and where they start, once something has looked
+    inode_table: Option<(u64, u64)>,
 }
 
 impl MetadataBlob {
-    pub fn new<C: Compression>(f: fs::File) -> MetadataBlob {
-        MetadataBlob { f: Box::new(f) }
+    pub fn new<C: Compression, R: Decompressor + 'static>(f: R) -> MetadataBlob {
+        MetadataBlob {
+            f: Box::new(f),
+            inode_table: None,
+        }
     }
 
     pub fn seek_ref(&mut self, r: &BlobRef) -> Result<u64> {
@@ -609,22 +1324,56 @@ impl MetadataBlob {
         read_one(&mut self.f)
     }
 
-    pub fn find_inode(&mut self, ino: Ino) -> Result<Option<Inode>> {
+    // the inodes are a cbor array at the start of the blob; its header says how many there are and
+    // where the first one starts, so nothing else has to be decoded to find one
+    fn inode_table(&mut self) -> Result<(u64, u64)> {
+        if let Some(table) = self.inode_table {
+            return Ok(table);
+        }
         self.f.seek(io::SeekFrom::Start(0))?;
-        // TODO: if we stick with serde and the fixed length encoding, we should not read the whole
-        // thing into memory before we binary search it...
-        let inodes = read_one::<Vec<Inode>, _>(&mut self.f)?;
-        let inode_count = inodes.len() as u64;
+        let mut initial = [0_u8; 1];
+        self.f.read_exact(&mut initial)?;
+        // major type 4 is an array, the low bits its length or how many bytes that takes
+        let extra = match initial[0] {
+            0x80..=0x97 => 0,
+            0x98 => 1,
+            0x99 => 2,
+            0x9a => 4,
+            0x9b => 8,
+            b => {
+                return Err(WireFormatError::MetadataCorrupt(
+                    SerdeError::custom(format!("inodes start with {:#x}", b)),
+                    Backtrace::capture(),
+                ))
+            }
+        };
+        let count = if extra == 0 {
+            (initial[0] & 0x1f) as u64
+        } else {
+            let mut len = [0_u8; 8];
+            self.f.read_exact(&mut len[8 - extra..])?;
+            u64::from_be_bytes(len)
+        };
+        let table = (count, 1 + extra as u64);
+        self.inode_table = Some(table);
+        Ok(table)
+    }
+
+    pub fn find_inode(&mut self, ino: Ino) -> Result<Option<Inode>> {
+        let (inode_count, start) = self.inode_table()?;
+        if inode_count == 0 {
+            return Ok(None);
+        }
         let mut left = 0;
-        let mut right = inode_count;
+        // the last index, not one past it: reading there would be reading whatever comes after
+        // the inodes
+        let mut right = inode_count - 1;
 
         while left <= right {
             let mid = left + (right - left) / 2;
 
-            self.f.seek(io::SeekFrom::Start(
-                (mid * INODE_WIRE_SIZE as u64)
-                    + cbor_size_of_list_header(inode_count as usize) as u64,
-            ))?;
+            self.f
+                .seek(io::SeekFrom::Start(start + mid * INODE_WIRE_SIZE as u64))?;
             let i = read_one::<Inode, _>(&mut self.f)?;
             if i.ino == ino {
                 return Ok(Some(i));
@@ -645,6 +1394,7 @@ impl MetadataBlob {
     }
 
     pub fn read_inodes(&mut self) -> Result<Vec<Inode>> {
+        self.f.seek(io::SeekFrom::Start(0))?;
         read_one(&mut self.f)
     }
 }
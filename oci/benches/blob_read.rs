@@ -0,0 +1,49 @@
+// how fast a big uncompressed blob can be read a fuse-sized piece at a time, through a mapping vs.
+// through the file. run with cargo bench -p oci.
+use std::sync::Arc;
+use std::time::Instant;
+
+use tempfile::tempdir;
+
+use format::BlobRef;
+use oci::{media_types, FsBlobStore, Image};
+
+const BLOB_SIZE: usize = 500 * 1024 * 1024;
+// the most the kernel asks a fuse filesystem for at once by default
+const READ_SIZE: usize = 128 * 1024;
+const ROUNDS: usize = 3;
+
+// returns MB/s
+fn read_all(image: &Image, blob: BlobRef) -> f64 {
+    let mut buf = vec![0_u8; READ_SIZE];
+    let start = Instant::now();
+    let mut offset = 0;
+    loop {
+        let n = image.fill_from_chunk(blob, offset, &mut buf).unwrap();
+        if n == 0 {
+            break;
+        }
+        offset += n as u64;
+    }
+    assert_eq!(offset, BLOB_SIZE as u64);
+    BLOB_SIZE as f64 / (1024.0 * 1024.0) / start.elapsed().as_secs_f64()
+}
+
+fn main() {
+    let dir = tempdir().unwrap();
+    let mapped = Image::new(dir.path()).unwrap();
+    let data = (0..BLOB_SIZE).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    let desc = mapped
+        .put_blob::<_, compression::Noop, media_types::Chunk>(data.as_slice())
+        .unwrap();
+    drop(data);
+    let blob = desc.digest.blob_ref(false);
+    let buffered = Image::with_store(Arc::new(FsBlobStore::buffered(dir.path())));
+
+    // the first pass pulls the blob into the page cache, so neither gets to benefit from that
+    read_all(&buffered, blob);
+    for _ in 0..ROUNDS {
+        println!("buffered: {:.0} MB/s", read_all(&buffered, blob));
+        println!("mmap:     {:.0} MB/s", read_all(&mapped, blob));
+    }
+}
@@ -1,7 +1,8 @@
 use std::backtrace::Backtrace;
-use std::collections::HashMap;
-use std::convert::{TryFrom, TryInto};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
 use std::fmt;
+use std::str::FromStr;
 
 extern crate hex;
 
@@ -9,25 +10,85 @@ use serde::de::Error as SerdeError;
 use serde::de::Visitor;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
-use format::{BlobRef, BlobRefKind, WireFormatError};
+use format::{BlobRef, BlobRefKind, DigestAlgorithm, RawDigest, WireFormatError};
 
-const SHA256_BLOCK_SIZE: usize = 32;
 const NAME_ANNOTATION: &str = "org.opencontainers.image.ref.name";
 
-#[derive(Debug, Clone, PartialEq)]
-pub struct Digest([u8; SHA256_BLOCK_SIZE]);
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Digest {
+    digest: RawDigest,
+    algorithm: DigestAlgorithm,
+}
 
 impl Digest {
-    pub fn underlying(&self) -> [u8; SHA256_BLOCK_SIZE] {
-        let mut dest = [0_u8; SHA256_BLOCK_SIZE];
-        dest.copy_from_slice(&self.0);
-        dest
+    pub fn new(digest: RawDigest, algorithm: DigestAlgorithm) -> Digest {
+        assert_eq!(digest.len(), algorithm.size(), "{} digest", algorithm);
+        Digest { digest, algorithm }
+    }
+
+    pub fn underlying(&self) -> RawDigest {
+        self.digest
+    }
+
+    pub fn algorithm(&self) -> DigestAlgorithm {
+        self.algorithm
+    }
+
+    /// `<algorithm>:<hex>`, the way the OCI spec writes digests; Display is just the hex.
+    pub fn reference(&self) -> String {
+        format!("{}:{}", self.algorithm, self)
+    }
+
+    /// Whether `data` is what this is a digest of.
+    pub fn matches(&self, data: &[u8]) -> bool {
+        self.algorithm.digest(data) == self.digest
+    }
+
+    // a BlobRef to the start of this blob
+    pub fn blob_ref(&self, compressed: bool) -> BlobRef {
+        BlobRef {
+            offset: 0,
+            kind: BlobRefKind::Other {
+                digest: self.digest,
+                algorithm: self.algorithm,
+            },
+            compressed,
+        }
+    }
+}
+
+/// A sha256 digest, which is what everything was before there was a choice.
+impl From<[u8; 32]> for Digest {
+    fn from(digest: [u8; 32]) -> Self {
+        Digest::new(digest.into(), DigestAlgorithm::Sha256)
     }
 }
 
 impl fmt::Display for Digest {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", hex::encode(&self.0))
+        write!(f, "{}", hex::encode(&self.digest))
+    }
+}
+
+/// Parses `sha256:<hex>`, `blake3:<hex>` or `sha512:<hex>`, the way digests are written in OCI
+/// images.
+impl FromStr for Digest {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(':').collect();
+        if parts.len() != 2 {
+            return Err(format!("bad digest {}", s));
+        }
+
+        let algorithm = parts[0]
+            .parse::<DigestAlgorithm>()
+            .map_err(|_| format!("unknown digest type {}", parts[0]))?;
+        let buf = hex::decode(parts[1]).map_err(|e| e.to_string())?;
+        if buf.len() != algorithm.size() {
+            return Err(format!("invalid {} block length {}", algorithm, buf.len()));
+        }
+        Ok(Digest::new(RawDigest::new(&buf), algorithm))
     }
 }
 
@@ -36,18 +97,14 @@ impl Serialize for Digest {
     where
         S: Serializer,
     {
-        let val = format!("sha256:{}", hex::encode(&self.0));
-        serializer.serialize_str(&val)
+        serializer.serialize_str(&self.reference())
     }
 }
 
 impl TryFrom<BlobRef> for Digest {
     type Error = WireFormatError;
     fn try_from(v: BlobRef) -> std::result::Result<Self, Self::Error> {
-        match v.kind {
-            BlobRefKind::Other { digest } => Ok(Digest(digest)),
-            BlobRefKind::Local => Err(WireFormatError::LocalRefError(Backtrace::capture())),
-        }
+        Digest::try_from(&v)
     }
 }
 
@@ -55,7 +112,7 @@ impl TryFrom<&BlobRef> for Digest {
     type Error = WireFormatError;
     fn try_from(v: &BlobRef) -> std::result::Result<Self, Self::Error> {
         match v.kind {
-            BlobRefKind::Other { digest } => Ok(Digest(digest)),
+            BlobRefKind::Other { digest, algorithm } => Ok(Digest::new(digest, algorithm)),
             BlobRefKind::Local => Err(WireFormatError::LocalRefError(Backtrace::capture())),
         }
     }
@@ -72,34 +129,14 @@ impl<'de> Deserialize<'de> for Digest {
             type Value = Digest;
 
             fn expecting(&self, formatter: &mut ::core::fmt::Formatter<'_>) -> ::core::fmt::Result {
-                formatter.write_fmt(format_args!("expected 'sha256:<hex encoded hash>'"))
+                formatter.write_fmt(format_args!("expected '<algorithm>:<hex encoded hash>'"))
             }
 
             fn visit_str<E>(self, s: &str) -> Result<Self::Value, E>
             where
                 E: SerdeError,
             {
-                let parts: Vec<&str> = s.split(':').collect();
-                if parts.len() != 2 {
-                    return Err(SerdeError::custom(format!("bad digest {}", s)));
-                }
-
-                match parts[0] {
-                    "sha256" => {
-                        let buf =
-                            hex::decode(parts[1]).map_err(|e| SerdeError::custom(e.to_string()))?;
-
-                        let len = buf.len();
-                        let digest: [u8; SHA256_BLOCK_SIZE] = buf.try_into().map_err(|_| {
-                            SerdeError::custom(format!("invalid sha256 block length {}", len))
-                        })?;
-                        Ok(Digest(digest))
-                    }
-                    _ => Err(SerdeError::custom(format!(
-                        "unknown digest type {}",
-                        parts[0]
-                    ))),
-                }
+                s.parse().map_err(SerdeError::custom)
             }
         }
 
@@ -107,21 +144,87 @@ impl<'de> Deserialize<'de> for Digest {
     }
 }
 
+/// The OS and architecture an image was built for, named the way OCI (and so Go) names them.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Platform {
+    pub os: String,
+    pub architecture: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+}
+
+impl Platform {
+    /// The platform this program is running on.
+    pub fn host() -> Platform {
+        let little = cfg!(target_endian = "little");
+        let architecture = match std::env::consts::ARCH {
+            "x86" => "386",
+            "x86_64" => "amd64",
+            "aarch64" => "arm64",
+            "powerpc" => "ppc",
+            "powerpc64" if little => "ppc64le",
+            "powerpc64" => "ppc64",
+            "mips" if little => "mipsle",
+            "mips64" if little => "mips64le",
+            arch => arch,
+        };
+        Platform {
+            os: std::env::consts::OS.to_string(),
+            architecture: architecture.to_string(),
+            variant: None,
+        }
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.os, self.architecture)?;
+        if let Some(variant) = &self.variant {
+            write!(f, "/{}", variant)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses `os/architecture[/variant]`, e.g. `linux/arm64` or `linux/arm/v7`.
+impl FromStr for Platform {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split('/').collect();
+        if parts.len() < 2 || parts.len() > 3 || parts.iter().any(|p| p.is_empty()) {
+            return Err(format!(
+                "bad platform {}, expected os/architecture[/variant]",
+                s
+            ));
+        }
+        Ok(Platform {
+            os: parts[0].to_string(),
+            architecture: parts[1].to_string(),
+            variant: parts.get(2).map(|v| v.to_string()),
+        })
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Descriptor {
     pub digest: Digest,
     pub size: u64,
     pub media_type: String,
-    pub annotations: HashMap<String, String>,
+    pub annotations: BTreeMap<String, String>,
+    // older images don't record one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform: Option<Platform>,
 }
 
 impl Descriptor {
-    pub fn new(digest: [u8; 32], size: u64, media_type: String) -> Descriptor {
+    pub fn new(digest: Digest, size: u64, media_type: String) -> Descriptor {
         Descriptor {
-            digest: Digest(digest),
+            digest,
             size,
             media_type,
-            annotations: HashMap::new(),
+            annotations: BTreeMap::new(),
+            platform: None,
         }
     }
 
@@ -133,3 +236,28 @@ impl Descriptor {
         self.annotations.get(NAME_ANNOTATION)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_platform() {
+        let platform: Platform = "linux/arm/v7".parse().unwrap();
+        assert_eq!(platform.os, "linux");
+        assert_eq!(platform.architecture, "arm");
+        assert_eq!(platform.variant.as_deref(), Some("v7"));
+        assert_eq!(platform.to_string(), "linux/arm/v7");
+        assert_eq!(
+            "linux/amd64".parse::<Platform>().unwrap(),
+            Platform {
+                os: "linux".to_string(),
+                architecture: "amd64".to_string(),
+                variant: None,
+            }
+        );
+        for bad in &["linux", "linux/", "/amd64", "linux/arm/v7/extra"] {
+            assert!(bad.parse::<Platform>().is_err(), "{}", bad);
+        }
+    }
+}
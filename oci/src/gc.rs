@@ -0,0 +1,97 @@
+use std::collections::HashSet;
+use std::io;
+
+use format::{Result, WireFormatError};
+
+use crate::descriptor::Digest;
+use crate::index::Index;
+use crate::Image;
+
+/// Deletes every blob no tag refers to, or with `dry_run` only finds them, and returns them and
+/// their sizes. Builds and pulls write their blobs before they tag them, so this refuses to run
+/// while one of them holds the store's lock.
+pub fn collect_garbage(image: &Image, dry_run: bool) -> Result<Vec<(Digest, u64)>> {
+    let _lock = image.store().lock(true).map_err(|e| match e.kind() {
+        io::ErrorKind::WouldBlock => WireFormatError::from(io::Error::new(
+            e.kind(),
+            "the image is being written to, try again later",
+        )),
+        _ => e.into(),
+    })?;
+
+    let mut reachable = HashSet::new();
+    // no index just means nothing was ever tagged, but one we can't read mustn't look like that
+    let index = match image.get_index() {
+        Err(WireFormatError::IOError(e, _)) if e.kind() == io::ErrorKind::NotFound => {
+            Index::default()
+        }
+        index => index?,
+    };
+    for desc in index.manifests.iter() {
+        reachable.extend(image.rootfs_blobs(&desc.digest)?);
+    }
+
+    let mut garbage = image
+        .store()
+        .list_blobs()?
+        .into_iter()
+        .filter(|(digest, _)| !reachable.contains(digest))
+        .collect::<Vec<_>>();
+    garbage.sort_by_key(|(digest, _)| digest.underlying());
+    if !dry_run {
+        for (digest, _) in garbage.iter() {
+            image.store().delete_blob(digest)?;
+        }
+    }
+    Ok(garbage)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::{media_types, MemBlobStore};
+
+    #[test]
+    fn test_gc_untagged_blobs() {
+        let image = Image::with_store(Arc::new(MemBlobStore::default()));
+        // an image that was never tagged has nothing to keep
+        let desc = image
+            .put_blob::<_, compression::Noop, media_types::Chunk>("meshuggah rocks".as_bytes())
+            .unwrap();
+        assert_eq!(
+            collect_garbage(&image, true).unwrap(),
+            vec![(desc.digest.clone(), 15)]
+        );
+        assert!(image.store().has_blob(&desc.digest));
+        collect_garbage(&image, false).unwrap();
+        assert!(!image.store().has_blob(&desc.digest));
+    }
+
+    #[test]
+    fn test_gc_stays_out_of_the_way() {
+        let dir = tempdir().unwrap();
+        let image = Image::new(dir.path()).unwrap();
+        let desc = image
+            .put_blob::<_, compression::Noop, media_types::Chunk>("meshuggah rocks".as_bytes())
+            .unwrap();
+
+        let lock = image.store().lock(false).unwrap();
+        collect_garbage(&image, false).unwrap_err();
+        assert!(image.store().has_blob(&desc.digest));
+        drop(lock);
+        collect_garbage(&image, false).unwrap();
+        assert!(!image.store().has_blob(&desc.digest));
+
+        // an index that can't be read doesn't mean nothing is tagged
+        let desc = image
+            .put_blob::<_, compression::Noop, media_types::Chunk>("meshuggah rocks".as_bytes())
+            .unwrap();
+        std::fs::write(dir.path().join(crate::index::PATH), b"{").unwrap();
+        collect_garbage(&image, false).unwrap_err();
+        assert!(image.store().has_blob(&desc.digest));
+    }
+}
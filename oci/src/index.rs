@@ -1,9 +1,11 @@
 use std::backtrace::Backtrace;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::fs;
+use std::io;
 use std::path::Path;
 
 use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
 
 extern crate serde_json;
 
@@ -23,7 +25,7 @@ pub struct Index {
     #[serde(rename = "schemaVersion")]
     version: i32,
     pub manifests: Vec<Descriptor>,
-    pub annotations: HashMap<String, String>,
+    pub annotations: BTreeMap<String, String>,
 }
 
 impl Default for Index {
@@ -31,15 +33,18 @@ impl Default for Index {
         Index {
             version: PUZZLEFS_SCHEMA_VERSION,
             manifests: Vec::new(),
-            annotations: HashMap::new(),
+            annotations: BTreeMap::new(),
         }
     }
 }
 
 impl Index {
     pub(crate) fn open(p: &Path) -> Result<Index> {
-        let index_file = fs::File::open(p)?;
-        let index = serde_json::from_reader::<_, Index>(index_file)?;
+        Index::read(fs::File::open(p)?)
+    }
+
+    pub(crate) fn read<R: io::Read>(r: R) -> Result<Index> {
+        let index = serde_json::from_reader::<_, Index>(r)?;
         if index.version != PUZZLEFS_SCHEMA_VERSION {
             Err(WireFormatError::InvalidImageSchema(
                 index.version,
@@ -50,9 +55,17 @@ impl Index {
         }
     }
 
+    // written next to it and renamed over it, so that readers, and whatever's left after a
+    // crash, only ever see a whole index
     pub(crate) fn write(&self, p: &Path) -> Result<()> {
-        let index_file = fs::File::create(p)?;
-        serde_json::to_writer(index_file, &self)?;
+        let dir = p
+            .parent()
+            .filter(|d| !d.as_os_str().is_empty())
+            .unwrap_or_else(|| Path::new("."));
+        let tmp = NamedTempFile::new_in(dir)?;
+        serde_json::to_writer(tmp.as_file(), &self)?;
+        tmp.as_file().sync_all()?;
+        tmp.persist(p).map_err(|e| e.error)?;
         Ok(())
     }
 
@@ -66,6 +79,9 @@ impl Index {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
     use tempfile::tempdir;
 
     #[test]
@@ -75,4 +91,33 @@ mod tests {
         i.write(&dir.path().join(PATH)).unwrap();
         Index::open(&dir.path().join(PATH)).unwrap();
     }
+
+    #[test]
+    fn test_readers_only_see_whole_indexes() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join(PATH);
+        let mut i = Index::default();
+        i.write(&path).unwrap();
+        // big enough that writing it takes more than one write()
+        for n in 0..1000 {
+            i.annotations
+                .insert(format!("key{}", n), "value".repeat(100));
+        }
+        let done = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let (path, done) = (path.clone(), done.clone());
+            thread::spawn(move || {
+                for _ in 0..100 {
+                    i.write(&path).unwrap();
+                }
+                done.store(true, Ordering::SeqCst);
+            })
+        };
+        while !done.load(Ordering::SeqCst) {
+            Index::open(&path).unwrap();
+        }
+        writer.join().unwrap();
+        // and nothing's left lying around
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
 }
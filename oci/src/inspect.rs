@@ -0,0 +1,75 @@
+use std::convert::TryFrom;
+use std::io::{self, Seek};
+
+use serde::Serialize;
+
+use format::{ChunkingConfig, Result, Rootfs};
+
+use crate::descriptor::{Descriptor, Digest};
+use crate::media_types::{self, MediaType};
+use crate::registry::image_blobs;
+use crate::Image;
+
+/// How an image is put together, as far as can be told without mounting it.
+#[derive(Debug, Serialize)]
+pub struct ImageInfo {
+    /// What the reference points to: the image's rootfs blob.
+    pub manifest: Descriptor,
+    /// The format version the image was built with.
+    pub version: u64,
+    pub config: RootfsConfig,
+    pub chunking: ChunkingConfig,
+    /// Every blob the image is made of, the rootfs and metadata blobs included.
+    pub blob_count: usize,
+    /// The size of those blobs, as stored.
+    pub total_size: u64,
+}
+
+/// What's in the rootfs blob.
+#[derive(Debug, Serialize)]
+pub struct RootfsConfig {
+    pub metadatas: Vec<Digest>,
+}
+
+fn stored_size(image: &Image, digest: &Digest) -> Result<u64> {
+    Ok(image.open_raw_blob(digest)?.seek(io::SeekFrom::End(0))?)
+}
+
+/// Describes the image `reference` names, which can be a tag or a digest; see Image::resolve().
+pub fn inspect(image: &Image, reference: &str) -> Result<ImageInfo> {
+    let digest = image.resolve(reference)?;
+    let blobs = image_blobs(image, &digest)?;
+    let mut total_size = 0;
+    for blob in blobs.iter() {
+        total_size += stored_size(image, &blob.digest)?;
+    }
+
+    // a digest doesn't have an entry in the index, so make up the one it would have
+    let tagged = image
+        .get_index()
+        .ok()
+        .and_then(|index| index.find_tag(reference).cloned());
+    let manifest = match tagged {
+        Some(desc) => desc,
+        None => Descriptor::new(
+            digest.clone(),
+            stored_size(image, &digest)?,
+            media_types::Rootfs::name().to_string(),
+        ),
+    };
+
+    let rootfs = Rootfs::open(image.open_compressed_blob::<compression::Noop>(&digest)?)?;
+    let metadatas = rootfs
+        .metadatas
+        .iter()
+        .map(Digest::try_from)
+        .collect::<Result<Vec<_>>>()?;
+    Ok(ImageInfo {
+        manifest,
+        version: rootfs.version,
+        config: RootfsConfig { metadatas },
+        chunking: rootfs.chunking,
+        blob_count: blobs.len(),
+        total_size,
+    })
+}
@@ -3,28 +3,46 @@
 extern crate hex;
 
 use std::backtrace::Backtrace;
+use std::cmp::min;
+use std::collections::HashSet;
 use std::convert::TryFrom;
 use std::fs;
 use std::io;
 use std::io::{Read, Seek};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
+use memmap2::Mmap;
 use serde::{Deserialize, Serialize};
-use sha2::{Digest as Sha2Digest, Sha256};
 use tee::TeeReader;
-use tempfile::NamedTempFile;
 
 use compression::{Compression, Decompressor};
-use format::{MetadataBlob, Result, Rootfs, WireFormatError};
+use format::{DigestAlgorithm, MetadataBlob, Result, Rootfs, WireFormatError};
 
 mod descriptor;
-pub use descriptor::{Descriptor, Digest};
+pub use descriptor::{Descriptor, Digest, Platform};
+
+mod gc;
+pub use gc::collect_garbage;
 
 mod index;
 pub use index::Index;
 
+mod inspect;
+pub use inspect::{inspect, ImageInfo, RootfsConfig};
+
 pub mod media_types;
 
+pub mod registry;
+
+mod store;
+pub use store::{
+    BlobLayout, BlobStore, BlobWriter, DiscardBlobStore, FsBlobStore, MemBlobStore, StoreLock,
+};
+
+mod stream;
+pub use stream::ChunkStream;
+
 // this is a string, probably intended to be a real version format (though the spec doesn't say
 // anything) so let's just say "puzzlefs-dev" for now since the format is in flux.
 const PUZZLEFS_IMAGE_LAYOUT_VERSION: &str = "puzzlefs-dev";
@@ -35,114 +53,527 @@ const IMAGE_LAYOUT_PATH: &str = "oci-layout";
 struct OCILayout {
     #[serde(rename = "imageLayoutVersion")]
     version: String,
+    // puzzlefs' own, and only written for sharded blobs, which tools that follow the spec
+    // wouldn't find anyway
+    #[serde(
+        rename = "puzzlefsBlobLayout",
+        default,
+        skip_serializing_if = "is_flat"
+    )]
+    blob_layout: BlobLayout,
+}
+
+fn is_flat(layout: &BlobLayout) -> bool {
+    *layout == BlobLayout::Flat
+}
+
+fn not_an_image(oci_dir: &Path, why: &str) -> WireFormatError {
+    WireFormatError::NotAnImageLayout(
+        oci_dir.display().to_string(),
+        why.to_string(),
+        Backtrace::capture(),
+    )
+}
+
+fn read_layout(oci_dir: &Path) -> Result<OCILayout> {
+    if !oci_dir.exists() {
+        return Err(not_an_image(oci_dir, "no such directory"));
+    }
+    if !oci_dir.is_dir() {
+        return Err(not_an_image(oci_dir, "not a directory"));
+    }
+    let layout_file = match fs::File::open(oci_dir.join(IMAGE_LAYOUT_PATH)) {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(not_an_image(oci_dir, "no oci-layout file"))
+        }
+        Err(e) => return Err(e.into()),
+    };
+    serde_json::from_reader(layout_file)
+        .map_err(|e| not_an_image(oci_dir, &format!("bad oci-layout file: {}", e)))
 }
 
-pub struct Image<'a> {
-    oci_dir: &'a Path,
+/// The tag images are opened by when they aren't told which one.
+pub const DEFAULT_TAG: &str = "latest";
+
+#[derive(Clone)]
+pub struct Image {
+    store: Arc<dyn BlobStore>,
+    // where blobs that are missing from the store, or turn out to be corrupt, get fetched from
+    fallback: Option<Arc<dyn BlobStore>>,
+    // what new blobs are hashed with; blobs that are already there say what they were hashed with
+    digest_algorithm: DigestAlgorithm,
 }
 
-impl<'a> Image<'a> {
-    pub fn new(oci_dir: &'a Path) -> Result<Self> {
-        let image = Image { oci_dir };
-        fs::create_dir_all(image.blob_path())?;
+impl Image {
+    /// Creates an OCI layout at `oci_dir` (or opens the one that's already there, e.g. to build
+    /// another tag into it). The path is made absolute first, so what it refers to doesn't change
+    /// if the working directory does later.
+    pub fn new(oci_dir: &Path) -> Result<Self> {
+        Image::new_with_layout(oci_dir, BlobLayout::default())
+    }
+
+    /// Like new(), but a new image's blobs are laid out as `blob_layout` says. An image that's
+    /// already there keeps the layout it has, which open() finds out from its oci-layout file.
+    pub fn new_with_layout(oci_dir: &Path, blob_layout: BlobLayout) -> Result<Self> {
+        if oci_dir.exists() && !oci_dir.is_dir() {
+            return Err(not_an_image(oci_dir, "not a directory"));
+        }
+        // something that isn't ours shouldn't be written over
+        let layout_path = oci_dir.join(IMAGE_LAYOUT_PATH);
+        let blob_layout = if layout_path.exists() {
+            read_layout(oci_dir)?.blob_layout
+        } else {
+            blob_layout
+        };
+        fs::create_dir_all(oci_dir.join(store::BLOBS_PATH))?;
+        let oci_dir = fs::canonicalize(oci_dir)?;
         let layout_file = fs::File::create(oci_dir.join(IMAGE_LAYOUT_PATH))?;
         let layout = OCILayout {
             version: PUZZLEFS_IMAGE_LAYOUT_VERSION.to_string(),
+            blob_layout,
         };
         serde_json::to_writer(layout_file, &layout)?;
-        Ok(Image { oci_dir })
+        Ok(Image::with_store(Arc::new(FsBlobStore::with_layout(
+            &oci_dir,
+            blob_layout,
+        ))))
     }
 
-    pub fn open(oci_dir: &'a Path) -> Result<Self> {
-        let layout_file = fs::File::open(oci_dir.join(IMAGE_LAYOUT_PATH))?;
-        let layout = serde_json::from_reader::<_, OCILayout>(layout_file)?;
+    /// Opens the OCI layout at `oci_dir`, which has to exist already. Like new(), the path is made
+    /// absolute first.
+    pub fn open(oci_dir: &Path) -> Result<Self> {
+        let layout = read_layout(oci_dir)?;
+        let oci_dir = fs::canonicalize(oci_dir)?;
         if layout.version != PUZZLEFS_IMAGE_LAYOUT_VERSION {
             Err(WireFormatError::InvalidImageVersion(
                 layout.version,
                 Backtrace::capture(),
             ))
         } else {
-            Ok(Image { oci_dir })
+            Ok(Image::with_store(Arc::new(FsBlobStore::with_layout(
+                &oci_dir,
+                layout.blob_layout,
+            ))))
+        }
+    }
+
+    /// An image whose blobs live wherever `store` keeps them, rather than in an OCI layout on
+    /// disk.
+    pub fn with_store(store: Arc<dyn BlobStore>) -> Self {
+        Image {
+            store,
+            fallback: None,
+            digest_algorithm: DigestAlgorithm::default(),
+        }
+    }
+
+    /// Fetches blobs that the image's own store is missing, or that fail verify_blob(), from
+    /// `fallback` (e.g. a replica of the image), and keeps them in the image's store from then on.
+    /// They're only kept if they match their digest.
+    pub fn with_fallback(self, fallback: Arc<dyn BlobStore>) -> Self {
+        Image {
+            fallback: Some(fallback),
+            ..self
         }
     }
 
-    pub fn blob_path(&self) -> PathBuf {
-        self.oci_dir.join("blobs/sha256")
+    /// Hashes the blobs written from now on with `algorithm`, rather than with sha256. Reading
+    /// doesn't care, every digest says how it was made.
+    pub fn with_digest_algorithm(self, algorithm: DigestAlgorithm) -> Self {
+        Image {
+            digest_algorithm: algorithm,
+            ..self
+        }
+    }
+
+    pub fn digest_algorithm(&self) -> DigestAlgorithm {
+        self.digest_algorithm
+    }
+
+    pub fn store(&self) -> &Arc<dyn BlobStore> {
+        &self.store
+    }
+
+    /// Where the blobs are on the local filesystem, if they are on it at all.
+    pub fn blob_path(&self) -> Option<PathBuf> {
+        self.store.blob_path()
+    }
+
+    /// The directory the image is in, if it's an OCI layout on the local filesystem.
+    pub fn oci_dir(&self) -> Option<PathBuf> {
+        self.store.oci_dir()
+    }
+
+    /// The file the blob `digest` is (or would be) in, if the blobs are on the local filesystem.
+    pub fn blob_file(&self, digest: &Digest) -> Option<PathBuf> {
+        self.store.blob_file(digest)
+    }
+
+    /// How many blobs there are, whether or not anything refers to them.
+    pub fn blob_count(&self) -> Result<usize> {
+        Ok(self.store.list_blobs()?.len())
+    }
+
+    /// The size of every blob, as stored.
+    pub fn total_blob_bytes(&self) -> Result<u64> {
+        Ok(self.store.list_blobs()?.iter().map(|(_, size)| size).sum())
     }
 
     pub fn put_blob<R: io::Read, C: Compression, MT: media_types::MediaType>(
         &self,
         buf: R,
     ) -> Result<Descriptor> {
-        let tmp = NamedTempFile::new_in(self.oci_dir)?;
-        let mut compressed = C::compress(tmp.reopen()?);
-        let mut hasher = Sha256::new();
+        self.write_blob::<_, C, MT>(buf, None)
+    }
+
+    pub fn put_blob_with_level<R: io::Read, C: Compression, MT: media_types::MediaType>(
+        &self,
+        buf: R,
+        level: u32,
+    ) -> Result<Descriptor> {
+        self.write_blob::<_, C, MT>(buf, Some(level))
+    }
 
+    // note that the digest and size are of the uncompressed content
+    fn write_blob<R: io::Read, C: Compression, MT: media_types::MediaType>(
+        &self,
+        buf: R,
+        level: Option<u32>,
+    ) -> Result<Descriptor> {
+        let mut hasher = self.digest_algorithm.hasher();
+        let mut blob = self.store.blob_writer()?;
+
+        let mut compressed = match level {
+            Some(level) => C::compress_with_level(&mut blob, level),
+            None => C::compress(&mut blob),
+        };
         let mut t = TeeReader::new(buf, &mut hasher);
         let size = io::copy(&mut t, &mut compressed)?;
+        compressed.end()?;
+        drop(compressed);
 
-        let digest = hasher.finalize();
+        let digest = Digest::new(hasher.finalize(), self.digest_algorithm);
         let media_type = C::append_extension(MT::name());
-        let descriptor = Descriptor::new(digest.into(), size, media_type);
+        let descriptor = Descriptor::new(digest, size, media_type);
 
-        tmp.persist(self.blob_path().join(descriptor.digest.to_string()))
-            .map_err(|e| e.error)?;
+        blob.commit(&descriptor.digest)?;
         Ok(descriptor)
     }
 
-    fn open_raw_blob(&self, digest: &Digest) -> io::Result<fs::File> {
-        fs::File::open(self.blob_path().join(digest.to_string()))
+    pub fn open_raw_blob(&self, digest: &Digest) -> Result<Box<dyn Decompressor>> {
+        self.store.get_blob(digest).map_err(|e| {
+            if e.kind() == io::ErrorKind::NotFound {
+                WireFormatError::BlobNotFound(digest.reference(), Backtrace::capture())
+            } else {
+                e.into()
+            }
+        })
+    }
+
+    // like open_raw_blob(), but a blob that isn't there gets fetched from the fallback; checking
+    // what it fetched needs to know how the blob is compressed
+    fn open_or_fetch_blob<C: Compression>(&self, digest: &Digest) -> Result<Box<dyn Decompressor>> {
+        match self.open_raw_blob(digest) {
+            Err(WireFormatError::BlobNotFound(..)) if self.fallback.is_some() => {
+                self.fetch_blob::<C>(digest)?;
+                self.open_raw_blob(digest)
+            }
+            result => result,
+        }
+    }
+
+    fn fetch_blob<C: Compression>(&self, digest: &Digest) -> Result<()> {
+        let fallback = match &self.fallback {
+            Some(fallback) => fallback,
+            None => return Ok(()),
+        };
+        let mut raw = Vec::new();
+        fallback
+            .get_blob(digest)
+            .map_err(|e| {
+                if e.kind() == io::ErrorKind::NotFound {
+                    WireFormatError::BlobNotFound(digest.reference(), Backtrace::capture())
+                } else {
+                    e.into()
+                }
+            })?
+            .read_to_end(&mut raw)?;
+        let raw: Arc<[u8]> = raw.into();
+        // the fallback is no more trustworthy than the store it stands in for
+        let mut hasher = digest.algorithm().hasher();
+        io::copy(
+            &mut C::decompress(io::Cursor::new(raw.clone())),
+            &mut hasher,
+        )?;
+        let actual = hasher.finalize();
+        if actual != digest.underlying() {
+            return Err(WireFormatError::DigestMismatch(
+                digest.to_string(),
+                hex::encode(actual),
+                Backtrace::capture(),
+            ));
+        }
+        self.store.put_blob(digest, &mut &*raw)?;
+        Ok(())
     }
 
     pub fn open_compressed_blob<C: Compression>(
         &self,
         digest: &Digest,
-    ) -> io::Result<Box<dyn Decompressor>> {
-        let f = self.open_raw_blob(digest)?;
+    ) -> Result<Box<dyn Decompressor>> {
+        let f = self.open_or_fetch_blob::<C>(digest)?;
         Ok(C::decompress(f))
     }
 
-    pub fn open_metadata_blob<C: Compression>(&self, digest: &Digest) -> io::Result<MetadataBlob> {
-        let f = self.open_raw_blob(digest)?;
-        Ok(MetadataBlob::new::<C>(f))
+    pub fn open_metadata_blob<C: Compression>(&self, digest: &Digest) -> Result<MetadataBlob> {
+        let f = self.open_or_fetch_blob::<C>(digest)?;
+        Ok(MetadataBlob::new::<C, _>(f))
+    }
+
+    /// Opens one of the metadata blobs a rootfs lists, however it's stored.
+    pub fn open_rootfs_metadata(&self, md: &format::BlobRef) -> Result<MetadataBlob> {
+        let digest = Digest::try_from(md)?;
+        if !md.compressed {
+            return self.open_metadata_blob::<compression::Noop>(&digest);
+        }
+        // reading metadata seeks all over the place, and every seek into a zstd blob starts
+        // decompressing over from the beginning of a frame, so decompress it all up front
+        let mut buf = Vec::new();
+        self.open_compressed_blob::<compression::Zstd>(&digest)?
+            .read_to_end(&mut buf)?;
+        Ok(MetadataBlob::new::<compression::Noop, _>(io::Cursor::new(
+            buf,
+        )))
+    }
+
+    /// Finds the rootfs blob `reference` names: either a tag, or `@sha256:<hex>` (`@blake3:<hex>`
+    /// for images built with blake3) for the blob with that digest, tagged or not. Digests never
+    /// change what they point at, so they pin an image in a way tags can't.
+    pub fn resolve(&self, reference: &str) -> Result<Digest> {
+        if let Some(digest) = reference.strip_prefix('@') {
+            let digest = digest
+                .parse::<Digest>()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            if !self.store.has_blob(&digest) {
+                return Err(WireFormatError::BlobNotFound(
+                    digest.reference(),
+                    Backtrace::capture(),
+                ));
+            }
+            return Ok(digest);
+        }
+        let index = self.get_index()?;
+        let desc = index.find_tag(reference).ok_or_else(|| {
+            WireFormatError::TagNotFound(reference.to_string(), Backtrace::capture())
+        })?;
+        Ok(desc.digest.clone())
+    }
+
+    /// Every blob `reference` (see resolve()) needs: its rootfs blob, the metadata blobs and the
+    /// chunk blobs of all its files.
+    pub fn referenced_blobs(&self, reference: &str) -> Result<HashSet<Digest>> {
+        self.rootfs_blobs(&self.resolve(reference)?)
     }
 
-    pub fn open_rootfs_blob<C: Compression>(&self, tag: &str) -> Result<Rootfs> {
+    // like referenced_blobs(), for a rootfs blob that's already been resolved
+    pub(crate) fn rootfs_blobs(&self, rootfs_digest: &Digest) -> Result<HashSet<Digest>> {
+        Ok(registry::image_blobs(self, rootfs_digest)?
+            .into_iter()
+            .map(|blob| blob.digest)
+            .collect())
+    }
+
+    /// The tag to use when none is given: DEFAULT_TAG if there is one, or else the only tag there
+    /// is. With several tags and none of them DEFAULT_TAG, it's anyone's guess which was meant, so
+    /// that's an error.
+    pub fn default_tag(&self) -> Result<String> {
         let index = self.get_index()?;
-        let desc = index
-            .find_tag(tag)
-            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no tag {}", tag)))?;
-        let rootfs = Rootfs::open(self.open_compressed_blob::<C>(&desc.digest)?)?;
+        if index.find_tag(DEFAULT_TAG).is_some() {
+            return Ok(DEFAULT_TAG.to_string());
+        }
+        let mut tags = index
+            .manifests
+            .iter()
+            .filter_map(|d| d.get_name())
+            .collect::<Vec<_>>();
+        tags.sort();
+        tags.dedup();
+        match tags.as_slice() {
+            [] => Err(io::Error::new(io::ErrorKind::NotFound, "no tags").into()),
+            [tag] => Ok(tag.to_string()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "no tag {}, and several others to choose from: {}",
+                    DEFAULT_TAG,
+                    tags.iter()
+                        .map(|t| t.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            )
+            .into()),
+        }
+    }
+
+    /// Opens the rootfs `reference` names; see resolve().
+    pub fn open_rootfs_blob<C: Compression>(&self, reference: &str) -> Result<Rootfs> {
+        let digest = self.resolve(reference)?;
+        let rootfs = Rootfs::open(self.open_compressed_blob::<C>(&digest)?)?;
         Ok(rootfs)
     }
 
+    // chunk digests are of the uncompressed content, so compressed chunks get decompressed
+    fn open_chunk_blob(&self, chunk: format::BlobRef) -> format::Result<Box<dyn Decompressor>> {
+        let digest = &<Digest>::try_from(chunk)?;
+        if chunk.compressed {
+            Ok(self.open_compressed_blob::<compression::Zstd>(digest)?)
+        } else {
+            Ok(self.open_compressed_blob::<compression::Noop>(digest)?)
+        }
+    }
+
+    // the raw bytes of an uncompressed chunk blob, if the store can map it into memory
+    pub fn map_chunk_blob(&self, chunk: format::BlobRef) -> format::Result<Option<Arc<Mmap>>> {
+        if chunk.compressed {
+            return Ok(None);
+        }
+        Ok(self.store.map_blob(&<Digest>::try_from(chunk)?))
+    }
+
     pub fn fill_from_chunk(
         &self,
         chunk: format::BlobRef,
         addl_offset: u64,
         buf: &mut [u8],
     ) -> format::Result<usize> {
-        let digest = &<Digest>::try_from(chunk)?;
-        let mut blob = self.open_raw_blob(digest)?;
+        if let Some(map) = self.map_chunk_blob(chunk)? {
+            let start = min((chunk.offset + addl_offset) as usize, map.len());
+            let n = min(buf.len(), map.len() - start);
+            buf[..n].copy_from_slice(&map[start..start + n]);
+            return Ok(n);
+        }
+
+        let mut blob = self.open_chunk_blob(chunk)?;
         blob.seek(io::SeekFrom::Start(chunk.offset + addl_offset))?;
-        let n = blob.read(buf)?;
+
+        // a single read() may come up short (e.g. at the end of a compressed frame), so keep going
+        // until we fill the buffer or hit the end of the blob
+        let mut n = 0;
+        while n < buf.len() {
+            let read = blob.read(&mut buf[n..])?;
+            if read == 0 {
+                break;
+            }
+            n += read;
+        }
         Ok(n)
     }
 
+    // reads the whole (uncompressed) blob a chunk lives in
+    pub fn read_chunk_blob(&self, chunk: format::BlobRef) -> format::Result<Vec<u8>> {
+        if let Some(map) = self.map_chunk_blob(chunk)? {
+            return Ok(map.to_vec());
+        }
+        let mut data = Vec::new();
+        self.open_chunk_blob(chunk)?.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    // re-hashes the (uncompressed) content of a blob and checks that it matches its digest; with a
+    // fallback, one that doesn't gets replaced by the fallback's copy. returns how many bytes it
+    // hashed.
+    pub fn verify_blob(&self, blob: format::BlobRef) -> format::Result<u64> {
+        match self.check_blob(blob) {
+            // corrupt compressed blobs tend not to decompress at all, rather than to the wrong thing
+            Err(_) if self.fallback.is_some() => {
+                let digest = <Digest>::try_from(blob)?;
+                // which also gets rid of any mapping of the bad copy. it may have been the
+                // fallback's copy that was bad, in which case there's nothing here to delete.
+                if let Err(e) = self.store.delete_blob(&digest) {
+                    if e.kind() != io::ErrorKind::NotFound {
+                        return Err(e.into());
+                    }
+                }
+                if blob.compressed {
+                    self.fetch_blob::<compression::Zstd>(&digest)?;
+                } else {
+                    self.fetch_blob::<compression::Noop>(&digest)?;
+                }
+                self.check_blob(blob)
+            }
+            result => result,
+        }
+    }
+
+    fn check_blob(&self, blob: format::BlobRef) -> format::Result<u64> {
+        let digest = <Digest>::try_from(blob)?;
+        let mut reader = self.open_chunk_blob(blob)?;
+        let mut hasher = digest.algorithm().hasher();
+        let len = io::copy(&mut reader, &mut hasher)?;
+        let actual = hasher.finalize();
+        if actual != digest.underlying() {
+            return Err(WireFormatError::DigestMismatch(
+                digest.to_string(),
+                hex::encode(actual),
+                Backtrace::capture(),
+            ));
+        }
+        Ok(len)
+    }
+
+    // makes a blob from another image available in this one without rewriting it: a hard link if
+    // both are on the local filesystem and we can, a copy otherwise (e.g. across filesystems)
+    pub fn reuse_blob(&self, from: &Image, digest: &Digest) -> Result<()> {
+        if self.store.has_blob(digest) {
+            return Ok(());
+        }
+        if let (Some(dest), Some(src)) = (self.blob_file(digest), from.blob_file(digest)) {
+            // the two needn't lay their blobs out the same way
+            if let Some(parent) = dest.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            if fs::hard_link(src, dest).is_ok() {
+                return Ok(());
+            }
+        }
+        self.store
+            .put_blob(digest, &mut from.open_raw_blob(digest)?)?;
+        Ok(())
+    }
+
     pub fn get_index(&self) -> Result<Index> {
-        Index::open(&self.oci_dir.join(index::PATH))
+        self.store.get_index()
     }
 
     pub fn put_index(&self, i: &Index) -> Result<()> {
-        i.write(&self.oci_dir.join(index::PATH))
+        self.store.put_index(i)
     }
 
     pub fn add_tag(&self, name: String, mut desc: Descriptor) -> Result<()> {
         desc.set_name(name);
+        let _lock = self.store.lock_index()?;
         let mut index = self.get_index().unwrap_or_default();
         index.manifests.push(desc);
         self.put_index(&index)
     }
+
+    /// Untags `name`; the blobs only it referred to stay around until collect_garbage(). Returns
+    /// whether there was such a tag.
+    pub fn remove_tag(&self, name: &str) -> Result<bool> {
+        let _lock = self.store.lock_index()?;
+        let mut index = self.get_index()?;
+        let before = index.manifests.len();
+        index
+            .manifests
+            .retain(|d| d.get_name().map(|n| n.as_str()) != Some(name));
+        if index.manifests.len() == before {
+            return Ok(false);
+        }
+        self.put_index(&index)?;
+        Ok(true)
+    }
 }
 
 #[cfg(test)]
@@ -161,7 +592,7 @@ mod tests {
         const DIGEST: &str = "3abd5ce0f91f640d88dca1f26b37037b02415927cacec9626d87668a715ec12d";
         assert_eq!(desc.digest.to_string(), DIGEST);
 
-        let md = fs::symlink_metadata(image.blob_path().join(DIGEST)).unwrap();
+        let md = fs::symlink_metadata(image.blob_path().unwrap().join(DIGEST)).unwrap();
         assert!(md.is_file());
     }
 
@@ -172,6 +603,69 @@ mod tests {
         Image::open(dir.path()).unwrap();
     }
 
+    #[test]
+    fn test_relative_and_absolute_paths() {
+        let dir = tempdir().unwrap();
+        let absolute = dir.path().join("oci");
+        // the same directory relative to wherever the test runs, without changing directory
+        // under the other tests
+        let mut relative = PathBuf::new();
+        for _ in std::env::current_dir().unwrap().components().skip(1) {
+            relative.push("..");
+        }
+        relative.push(absolute.strip_prefix("/").unwrap());
+
+        let image = Image::new(&relative).unwrap();
+        let desc = image
+            .put_blob::<_, compression::Noop, media_types::Rootfs>("meshuggah rocks".as_bytes())
+            .unwrap();
+        image.add_tag("test".to_string(), desc.clone()).unwrap();
+        for path in &[&relative, &absolute] {
+            let image = Image::open(path).unwrap();
+            assert_eq!(image.blob_path(), Some(absolute.join("blobs/sha256")));
+            assert_eq!(image.resolve("test").unwrap(), desc.digest);
+        }
+    }
+
+    #[test]
+    fn test_not_an_image() {
+        let dir = tempdir().unwrap();
+        let file = dir.path().join("file");
+        fs::write(&file, "meshuggah rocks").unwrap();
+        fs::write(dir.path().join(IMAGE_LAYOUT_PATH), "not json").unwrap();
+        let empty = dir.path().join("empty");
+        fs::create_dir(&empty).unwrap();
+        let missing = dir.path().join("missing");
+        for (path, why) in &[
+            (&file, "not a directory"),
+            (&dir.path().to_path_buf(), "bad oci-layout file"),
+            (&empty, "no oci-layout file"),
+            (&missing, "no such directory"),
+        ] {
+            match Image::open(path) {
+                Err(e @ WireFormatError::NotAnImageLayout(..)) => {
+                    let msg = e.to_string();
+                    assert!(msg.contains(&*path.to_string_lossy()), "{}", msg);
+                    assert!(msg.contains(why), "{}", msg);
+                }
+                r => panic!(
+                    "expected {:?} not to be an image, got {:?}",
+                    path,
+                    r.map(|_| ())
+                ),
+            }
+        }
+        // and new() doesn't write over them either
+        assert!(Image::new(&file).is_err());
+        assert!(Image::new(dir.path()).is_err());
+        assert_eq!(
+            fs::read_to_string(dir.path().join(IMAGE_LAYOUT_PATH)).unwrap(),
+            "not json"
+        );
+        Image::new(&empty).unwrap();
+        Image::open(&empty).unwrap();
+    }
+
     #[test]
     fn test_put_get_index() {
         let dir = tempdir().unwrap();
@@ -190,6 +684,96 @@ mod tests {
         assert_eq!(index.manifests, index2.manifests);
     }
 
+    #[test]
+    fn test_put_compressed_blob() {
+        let dir = tempdir().unwrap();
+        let image = Image::new(dir.path()).unwrap();
+        let data = "meshuggah rocks ".repeat(1024);
+        let desc = image
+            .put_blob::<_, compression::Zstd, media_types::Chunk>(data.as_bytes())
+            .unwrap();
+
+        // the digest and size describe the uncompressed content
+        assert_eq!(desc.size, data.len() as u64);
+        let md =
+            fs::symlink_metadata(image.blob_path().unwrap().join(desc.digest.to_string())).unwrap();
+        assert!(md.len() < desc.size);
+
+        let chunk = desc.digest.blob_ref(true);
+        let mut buf = vec![0_u8; "meshuggah rocks ".len()];
+        let n = image.fill_from_chunk(chunk, 16 * 1000, &mut buf).unwrap();
+        assert_eq!(n, buf.len());
+        assert_eq!(buf, "meshuggah rocks ".as_bytes());
+    }
+
+    #[test]
+    fn test_verify_blob() {
+        let dir = tempdir().unwrap();
+        let image = Image::new(dir.path()).unwrap();
+        for (i, compressed) in [false, true].iter().enumerate() {
+            let data = format!("meshuggah rocks {}", i).repeat(1024);
+            let desc = if *compressed {
+                image.put_blob::<_, compression::Zstd, media_types::Chunk>(data.as_bytes())
+            } else {
+                image.put_blob::<_, compression::Noop, media_types::Chunk>(data.as_bytes())
+            }
+            .unwrap();
+            let blob = desc.digest.blob_ref(*compressed);
+            assert_eq!(image.verify_blob(blob).unwrap(), data.len() as u64);
+
+            // flip a byte in the middle of the blob
+            let path = image.blob_path().unwrap().join(desc.digest.to_string());
+            let mut contents = fs::read(&path).unwrap();
+            let mid = contents.len() / 2;
+            contents[mid] ^= 0xff;
+            fs::write(&path, contents).unwrap();
+            image.verify_blob(blob).unwrap_err();
+
+            fs::remove_file(&path).unwrap();
+            image.verify_blob(blob).unwrap_err();
+        }
+    }
+
+    #[test]
+    fn test_fallback() {
+        let dir = tempdir().unwrap();
+        let replica = Image::new(&dir.path().join("replica")).unwrap();
+        let image = Image::new(&dir.path().join("oci"))
+            .unwrap()
+            .with_fallback(replica.store().clone());
+        for (i, compressed) in [false, true].iter().enumerate() {
+            let data = format!("meshuggah rocks {}", i).repeat(1024);
+            let desc = if *compressed {
+                replica.put_blob::<_, compression::Zstd, media_types::Chunk>(data.as_bytes())
+            } else {
+                replica.put_blob::<_, compression::Noop, media_types::Chunk>(data.as_bytes())
+            }
+            .unwrap();
+            let blob = desc.digest.blob_ref(*compressed);
+            // missing, so it comes from the replica and stays
+            assert_eq!(image.read_chunk_blob(blob).unwrap(), data.as_bytes());
+            let path = image.blob_path().unwrap().join(desc.digest.to_string());
+            assert!(path.exists());
+
+            // corrupt, so verifying it gets the replica's copy
+            let mut contents = fs::read(&path).unwrap();
+            let mid = contents.len() / 2;
+            contents[mid] ^= 0xff;
+            fs::write(&path, contents).unwrap();
+            image.verify_blob(blob).unwrap();
+            assert_eq!(image.read_chunk_blob(blob).unwrap(), data.as_bytes());
+
+            // a bad copy in the replica doesn't get kept either
+            let replica_path = replica.blob_path().unwrap().join(desc.digest.to_string());
+            let mut contents = fs::read(&replica_path).unwrap();
+            contents[mid] ^= 0xff;
+            fs::write(&replica_path, contents).unwrap();
+            fs::remove_file(&path).unwrap();
+            image.verify_blob(blob).unwrap_err();
+            assert!(!path.exists());
+        }
+    }
+
     #[test]
     fn double_put_ok() {
         let dir = tempdir().unwrap();
@@ -202,4 +786,92 @@ mod tests {
             .unwrap();
         assert_eq!(desc1, desc2);
     }
+
+    #[test]
+    fn test_resolve() {
+        let dir = tempdir().unwrap();
+        let image = Image::new(dir.path()).unwrap();
+        let desc = image
+            .put_blob::<_, compression::Noop, media_types::Rootfs>("meshuggah rocks".as_bytes())
+            .unwrap();
+        image.add_tag("test".to_string(), desc.clone()).unwrap();
+
+        assert_eq!(image.resolve("test").unwrap(), desc.digest);
+        let by_digest = format!("@sha256:{}", desc.digest);
+        assert_eq!(image.resolve(&by_digest).unwrap(), desc.digest);
+        match image.resolve("nope") {
+            Err(WireFormatError::TagNotFound(tag, ..)) => assert_eq!(tag, "nope"),
+            r => panic!("expected a missing tag, got {:?}", r),
+        }
+        // a digest needs no tag, but it does need a blob
+        match image.resolve(&format!("@sha256:{}", "00".repeat(32))) {
+            Err(WireFormatError::BlobNotFound(..)) => {}
+            r => panic!("expected a missing blob, got {:?}", r),
+        }
+        image.resolve("@sha256:beef").unwrap_err();
+        image.resolve("@md5:beef").unwrap_err();
+    }
+
+    #[test]
+    fn test_missing_blob() {
+        let dir = tempdir().unwrap();
+        let image = Image::new(dir.path()).unwrap();
+        let desc = image
+            .put_blob::<_, compression::Noop, media_types::Chunk>("meshuggah rocks".as_bytes())
+            .unwrap();
+        image.open_raw_blob(&desc.digest).unwrap();
+        image.store().delete_blob(&desc.digest).unwrap();
+        match image.open_raw_blob(&desc.digest) {
+            Err(WireFormatError::BlobNotFound(digest, ..)) => {
+                assert_eq!(digest, format!("sha256:{}", desc.digest))
+            }
+            r => panic!("expected a missing blob, got {:?}", r.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_default_tag() {
+        let dir = tempdir().unwrap();
+        let image = Image::new(dir.path()).unwrap();
+        image.default_tag().unwrap_err();
+
+        let desc = image
+            .put_blob::<_, compression::Noop, media_types::Rootfs>("meshuggah rocks".as_bytes())
+            .unwrap();
+        image.add_tag("test".to_string(), desc.clone()).unwrap();
+        assert_eq!(image.default_tag().unwrap(), "test");
+
+        image.add_tag("other".to_string(), desc.clone()).unwrap();
+        let err = image.default_tag().unwrap_err().to_string();
+        assert!(err.contains("other, test"), "{}", err);
+
+        image.add_tag(DEFAULT_TAG.to_string(), desc).unwrap();
+        assert_eq!(image.default_tag().unwrap(), DEFAULT_TAG);
+    }
+
+    #[test]
+    fn test_concurrent_tags() {
+        let dir = tempdir().unwrap();
+        let image = Image::new(dir.path()).unwrap();
+        let desc = image
+            .put_blob::<_, compression::Noop, media_types::Rootfs>("meshuggah rocks".as_bytes())
+            .unwrap();
+        // each as if it were a build of its own, holding the store's shared lock while it tags
+        let taggers = (0..8)
+            .map(|i| {
+                let (path, desc) = (dir.path().to_path_buf(), desc.clone());
+                std::thread::spawn(move || {
+                    let image = Image::open(&path).unwrap();
+                    let _lock = image.store().lock(false).unwrap();
+                    for j in 0..10 {
+                        image.add_tag(format!("{}-{}", i, j), desc.clone()).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for tagger in taggers {
+            tagger.join().unwrap();
+        }
+        assert_eq!(image.get_index().unwrap().manifests.len(), 80);
+    }
 }
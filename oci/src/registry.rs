@@ -0,0 +1,664 @@
+// Moving images between a local OCI layout and a registry that speaks the OCI distribution API.
+//
+// A registry only knows about blobs that a manifest refers to, so pushing synthesizes a manifest
+// whose layers are every blob the tag uses: the rootfs blob first, then the metadata blobs, then
+// the chunks. Registries also insist that a blob's digest is the hash of its bytes, which isn't
+// true for compressed chunks (their digest is of the uncompressed content), so those are pushed
+// under the hash of their bytes with an annotation remembering the name they have locally.
+// Pulling undoes that: blobs are fetched by their registry digest and stored under their local one.
+
+use std::backtrace::Backtrace;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryFrom;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest as Sha2Digest, Sha256};
+
+use compression::Compression;
+use format::{DigestAlgorithm, InodeMode, Result, Rootfs, WireFormatError};
+
+use crate::media_types::{self, MediaType};
+use crate::{Descriptor, Digest, Image};
+
+const DOCKER_HUB: &str = "docker.io";
+// docker hub's API doesn't live at docker.io itself
+const DOCKER_HUB_API: &str = "registry-1.docker.io";
+
+const OCI_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+const PUZZLEFS_CONFIG: &str = "application/vnd.puzzlefs.image.config.v1+json";
+// puzzlefs images don't have any runtime config, but a manifest has to point at one
+const EMPTY_CONFIG: &[u8] = b"{}";
+
+// the local (i.e. uncompressed content) digest of a blob, when it differs from the registry one
+const PUZZLEFS_DIGEST_ANNOTATION: &str = "org.puzzlefs.blob.digest";
+
+// the size of each PATCH when uploading a blob
+const UPLOAD_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+
+// blobs being downloaded live next to the finished ones until they're verified, so an
+// interrupted pull can pick up where it left off
+const PARTIAL_SUFFIX: &str = ".partial";
+
+/// Where an image lives in a registry, e.g. `localhost:5000/puzzlefs/ubuntu:latest`. Like docker,
+/// a reference without a registry host means docker hub, and one without a tag means `latest`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reference {
+    pub registry: String,
+    pub repository: String,
+    pub tag: String,
+}
+
+impl FromStr for Reference {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        // a colon after the last slash is the tag, anything before that is a port
+        let (name, tag) = match s.rfind(':') {
+            Some(i) if !s[i..].contains('/') => (&s[..i], &s[i + 1..]),
+            _ => (s, "latest"),
+        };
+        let (registry, repository) = match name.split_once('/') {
+            Some((host, rest))
+                if host.contains('.') || host.contains(':') || host == "localhost" =>
+            {
+                (host.to_string(), rest.to_string())
+            }
+            _ if !name.contains('/') => (DOCKER_HUB.to_string(), format!("library/{}", name)),
+            _ => (DOCKER_HUB.to_string(), name.to_string()),
+        };
+        if repository.is_empty() || tag.is_empty() {
+            return Err(format!("bad image reference {}", s));
+        }
+        Ok(Reference {
+            registry,
+            repository,
+            tag: tag.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Reference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}:{}", self.registry, self.repository, self.tag)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Manifest {
+    #[serde(rename = "schemaVersion")]
+    schema_version: u32,
+    #[serde(rename = "mediaType")]
+    media_type: String,
+    config: ManifestDescriptor,
+    layers: Vec<ManifestDescriptor>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ManifestDescriptor {
+    #[serde(rename = "mediaType")]
+    media_type: String,
+    digest: Digest,
+    size: u64,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    annotations: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    token: Option<String>,
+    access_token: Option<String>,
+}
+
+// a blob the tag needs, by its name in the local image
+pub(crate) struct LocalBlob {
+    pub(crate) digest: Digest,
+    media_type: String,
+    compressed: bool,
+}
+
+fn registry_error(msg: String) -> WireFormatError {
+    WireFormatError::RegistryError(msg, Backtrace::capture())
+}
+
+/// A client for one repository in a registry.
+pub struct Registry {
+    agent: ureq::Agent,
+    base_url: String,
+    reference: Reference,
+    // base64 encoded user:pass, the way docker stores it
+    credentials: Option<String>,
+    token: Mutex<Option<String>>,
+}
+
+impl Registry {
+    /// Credentials are looked up the way docker does, in `$DOCKER_CONFIG/config.json` or
+    /// `~/.docker/config.json`. Registries on localhost are spoken to over plain http, as are all
+    /// of them if `insecure` is set.
+    pub fn new(reference: Reference, insecure: bool) -> Registry {
+        let host = if reference.registry == DOCKER_HUB {
+            DOCKER_HUB_API
+        } else {
+            &reference.registry
+        };
+        let local = ["localhost", "127.0.0.1", "[::1]"]
+            .iter()
+            .any(|l| host == *l || host.starts_with(&format!("{}:", l)));
+        let scheme = if insecure || local { "http" } else { "https" };
+        Registry {
+            agent: ureq::AgentBuilder::new().build(),
+            base_url: format!("{}://{}", scheme, host),
+            credentials: docker_credentials(&reference.registry),
+            reference,
+            token: Mutex::new(None),
+        }
+    }
+
+    /// Uploads everything `tag` in `image` needs, and then a manifest for it as the reference's
+    /// tag. Blobs the registry already has are skipped.
+    pub fn push(&self, image: &Image, tag: &str) -> Result<()> {
+        let rootfs = image.resolve(tag)?;
+
+        let mut layers = Vec::new();
+        for blob in image_blobs(image, &rootfs)? {
+            image.verify_blob(blob.digest.blob_ref(blob.compressed))?;
+            let mut annotations = BTreeMap::new();
+            // registries only know sha256, and it has to be of the bytes they're given
+            let digest = if blob.compressed || blob.digest.algorithm() != DigestAlgorithm::Sha256 {
+                let mut hasher = Sha256::new();
+                io::copy(&mut image.open_raw_blob(&blob.digest)?, &mut hasher)?;
+                let raw: [u8; 32] = hasher.finalize().into();
+                annotations.insert(
+                    PUZZLEFS_DIGEST_ANNOTATION.to_string(),
+                    blob.digest.reference(),
+                );
+                Digest::from(raw)
+            } else {
+                blob.digest.clone()
+            };
+            let mut raw = image.open_raw_blob(&blob.digest)?;
+            let size = raw.seek(io::SeekFrom::End(0))?;
+            raw.rewind()?;
+            self.put_blob(raw, &digest)?;
+            layers.push(ManifestDescriptor {
+                media_type: blob.media_type,
+                digest,
+                size,
+                annotations,
+            });
+        }
+
+        // the platform goes in the config, where registries and tools look for it
+        let platform = image
+            .get_index()
+            .ok()
+            .and_then(|index| index.find_tag(tag).and_then(|desc| desc.platform.clone()));
+        let config = match platform {
+            Some(platform) => serde_json::to_vec(&platform)?,
+            None => EMPTY_CONFIG.to_vec(),
+        };
+        let config_digest = Digest::from(<[u8; 32]>::from(Sha256::digest(&config)));
+        self.put_blob(config.as_slice(), &config_digest)?;
+        let manifest = Manifest {
+            schema_version: 2,
+            media_type: OCI_MANIFEST.to_string(),
+            config: ManifestDescriptor {
+                media_type: PUZZLEFS_CONFIG.to_string(),
+                digest: config_digest,
+                size: config.len() as u64,
+                annotations: BTreeMap::new(),
+            },
+            layers,
+        };
+
+        let url = self.url(&format!("manifests/{}", self.reference.tag));
+        let body = serde_json::to_vec(&manifest)?;
+        let resp = self.send("PUT", &url, &[("Content-Type", OCI_MANIFEST)], Some(&body))?;
+        expect_status(resp, "PUT", &url, &[201]).map(|_| ())
+    }
+
+    /// Downloads the reference's manifest and every blob it lists that `image` doesn't have yet,
+    /// then tags the rootfs as `tag`. Each blob is checked against its digest before it is put in
+    /// place, and blobs whose download was interrupted are resumed rather than started over.
+    pub fn pull(&self, image: &Image, tag: &str) -> Result<Descriptor> {
+        // interrupted downloads are kept next to the blobs so they can be picked up again
+        let blobs = image
+            .blob_path()
+            .ok_or_else(|| registry_error("can only pull into an image on disk".to_string()))?;
+        // nothing is tagged until the end, so keep collection from deleting it all in the meantime
+        let _lock = image.store().lock(false)?;
+        let url = self.url(&format!("manifests/{}", self.reference.tag));
+        let resp = self.send("GET", &url, &[("Accept", OCI_MANIFEST)], None)?;
+        let manifest = expect_status(resp, "GET", &url, &[200])?.into_json::<Manifest>()?;
+
+        let mut rootfs = None;
+        for layer in manifest.layers.iter() {
+            let local = match layer.annotations.get(PUZZLEFS_DIGEST_ANNOTATION) {
+                Some(d) => serde_json::from_value::<Digest>(serde_json::Value::String(d.clone()))?,
+                None => layer.digest.clone(),
+            };
+            let compressed = layer
+                .media_type
+                .ends_with(&compression::Zstd::append_extension(""));
+            let path = image.blob_file(&local).unwrap();
+            if !path.exists() {
+                self.get_blob(&blobs, &layer.digest)?;
+                fs::create_dir_all(path.parent().unwrap())?;
+                fs::rename(partial_path(&blobs, &layer.digest), &path)?;
+                if local != layer.digest {
+                    // get_blob() checked the bytes it got, this checks what's inside them
+                    let verified = image.verify_blob(local.blob_ref(compressed));
+                    if let Err(e) = verified {
+                        fs::remove_file(&path)?;
+                        return Err(e);
+                    }
+                }
+            }
+            if rootfs.is_none() && layer.media_type == media_types::Rootfs::name() {
+                rootfs = Some(Descriptor::new(
+                    local.clone(),
+                    layer.size,
+                    layer.media_type.clone(),
+                ));
+            }
+        }
+
+        let rootfs = rootfs
+            .ok_or_else(|| registry_error(format!("{} is not a puzzlefs image", self.reference)))?;
+        image.add_tag(tag.to_string(), rootfs.clone())?;
+        Ok(rootfs)
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!(
+            "{}/v2/{}/{}",
+            self.base_url, self.reference.repository, path
+        )
+    }
+
+    fn put_blob<R: Read>(&self, mut blob: R, digest: &Digest) -> Result<()> {
+        let digest = digest.reference();
+        let url = self.url(&format!("blobs/{}", digest));
+        if self.send("HEAD", &url, &[], None)?.status() == 200 {
+            return Ok(());
+        }
+
+        let url = self.url("blobs/uploads/");
+        let resp = self.send("POST", &url, &[], None)?;
+        let mut location = self.location(expect_status(resp, "POST", &url, &[202])?)?;
+
+        let mut buf = vec![0_u8; UPLOAD_CHUNK_SIZE];
+        let mut offset = 0;
+        loop {
+            let n = read_full(&mut blob, &mut buf)?;
+            if n == 0 {
+                break;
+            }
+            let range = format!("{}-{}", offset, offset + n - 1);
+            let headers = [
+                ("Content-Type", "application/octet-stream"),
+                ("Content-Range", range.as_str()),
+            ];
+            let resp = self.send("PATCH", &location, &headers, Some(&buf[..n]))?;
+            location = self.location(expect_status(resp, "PATCH", &location, &[202])?)?;
+            offset += n;
+        }
+
+        let sep = if location.contains('?') { '&' } else { '?' };
+        let url = format!("{}{}digest={}", location, sep, digest);
+        let resp = self.send("PUT", &url, &[], Some(&[]))?;
+        let resp = expect_status(resp, "PUT", &url, &[201])?;
+        match resp.header("Docker-Content-Digest") {
+            Some(d) if d != digest => Err(WireFormatError::DigestMismatch(
+                digest,
+                d.to_string(),
+                Backtrace::capture(),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    // downloads a blob into its partial file, continuing from whatever is already there
+    fn get_blob(&self, blobs: &Path, digest: &Digest) -> Result<()> {
+        let path = partial_path(blobs, digest);
+        let mut partial = fs::OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&path)?;
+        let mut hasher = Sha256::new();
+        let mut have = io::copy(&mut partial, &mut hasher)?;
+        if have > 0 && hasher.clone().finalize()[..] == *digest.underlying() {
+            // an earlier pull got all of it, but stopped before moving it into place
+            return Ok(());
+        }
+
+        let url = self.url(&format!("blobs/{}", digest.reference()));
+        let range = format!("bytes={}-", have);
+        let headers = if have > 0 {
+            vec![("Range", range.as_str())]
+        } else {
+            vec![]
+        };
+        let mut resp = self.send("GET", &url, &headers, None)?;
+        if resp.status() == 416 && have > 0 {
+            // there's nothing after what we have, yet it isn't the blob; it can only be junk
+            partial.set_len(0)?;
+            hasher = Sha256::new();
+            have = 0;
+            resp = self.send("GET", &url, &[], None)?;
+        }
+        let resp = expect_status(resp, "GET", &url, &[200, 206])?;
+        if resp.status() == 206 && content_range_start(&resp) != Some(have) {
+            if have == 0 {
+                return Err(registry_error(format!(
+                    "GET {} sent a range we didn't ask for",
+                    url
+                )));
+            }
+            // not where we left off, so fetch the whole thing instead
+            partial.set_len(0)?;
+            return self.get_blob(blobs, digest);
+        }
+        if resp.status() == 200 && have > 0 {
+            // the registry doesn't do ranges, start over
+            partial.set_len(0)?;
+            hasher = Sha256::new();
+        }
+        let mut body = resp.into_reader();
+        let mut buf = vec![0_u8; 64 * 1024];
+        loop {
+            let n = body.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            partial.write_all(&buf[..n])?;
+        }
+
+        let actual: [u8; 32] = hasher.finalize().into();
+        if actual[..] != *digest.underlying() {
+            fs::remove_file(&path)?;
+            return Err(WireFormatError::DigestMismatch(
+                digest.to_string(),
+                hex::encode(actual),
+                Backtrace::capture(),
+            ));
+        }
+        Ok(())
+    }
+
+    // upload URLs may be relative to the registry
+    fn location(&self, resp: ureq::Response) -> Result<String> {
+        let location = resp
+            .header("Location")
+            .ok_or_else(|| registry_error(format!("no upload location from {}", resp.get_url())))?;
+        if location.starts_with("http://") || location.starts_with("https://") {
+            Ok(location.to_string())
+        } else {
+            Ok(format!("{}{}", self.base_url, location))
+        }
+    }
+
+    fn authorization(&self) -> Option<String> {
+        match &*self.token.lock().unwrap() {
+            Some(token) => Some(format!("Bearer {}", token)),
+            None => self.credentials.as_ref().map(|c| format!("Basic {}", c)),
+        }
+    }
+
+    // sends a request, authenticating and retrying if the registry asks us to. responses with
+    // error statuses are returned like any other, it's up to the caller what they expect.
+    fn send(
+        &self,
+        method: &str,
+        url: &str,
+        headers: &[(&str, &str)],
+        body: Option<&[u8]>,
+    ) -> Result<ureq::Response> {
+        let mut authenticated = false;
+        loop {
+            let mut req = self.agent.request(method, url);
+            for (header, value) in headers {
+                req = req.set(header, value);
+            }
+            if let Some(auth) = self.authorization() {
+                req = req.set("Authorization", &auth);
+            }
+            let result = match body {
+                Some(body) => req.send_bytes(body),
+                None => req.call(),
+            };
+            let resp = match result {
+                Ok(resp) => resp,
+                Err(ureq::Error::Status(_, resp)) => resp,
+                Err(e) => return Err(registry_error(format!("{} {}: {}", method, url, e))),
+            };
+            if resp.status() != 401 || authenticated {
+                return Ok(resp);
+            }
+            self.authenticate(&resp)?;
+            authenticated = true;
+        }
+    }
+
+    // handles a 401's WWW-Authenticate challenge: basic auth just needs the credentials we
+    // already send, bearer auth needs a token from the realm it points at.
+    fn authenticate(&self, resp: &ureq::Response) -> Result<()> {
+        let challenge = resp.header("WWW-Authenticate").unwrap_or("");
+        let (scheme, params) = challenge.split_once(' ').unwrap_or((challenge, ""));
+        if !scheme.eq_ignore_ascii_case("bearer") {
+            return match self.credentials {
+                Some(_) => Ok(()),
+                None => Err(registry_error(format!(
+                    "{} needs credentials, try docker login",
+                    self.reference.registry
+                ))),
+            };
+        }
+
+        let params = parse_challenge(params);
+        let realm = params
+            .get("realm")
+            .ok_or_else(|| registry_error(format!("no realm in challenge {}", challenge)))?;
+        let default_scope = format!("repository:{}:pull,push", self.reference.repository);
+        let mut req = self
+            .agent
+            .get(realm)
+            .query("scope", params.get("scope").unwrap_or(&default_scope));
+        if let Some(service) = params.get("service") {
+            req = req.query("service", service);
+        }
+        if let Some(credentials) = &self.credentials {
+            req = req.set("Authorization", &format!("Basic {}", credentials));
+        }
+        let resp = req
+            .call()
+            .map_err(|e| registry_error(format!("getting a token from {}: {}", realm, e)))?;
+        let token = resp.into_json::<TokenResponse>()?;
+        let token = token
+            .token
+            .or(token.access_token)
+            .ok_or_else(|| registry_error(format!("no token from {}", realm)))?;
+        *self.token.lock().unwrap() = Some(token);
+        Ok(())
+    }
+}
+
+// the first byte of a 206's body, from its "bytes <start>-<end>/<size>" Content-Range
+fn content_range_start(resp: &ureq::Response) -> Option<u64> {
+    let range = resp.header("Content-Range")?.strip_prefix("bytes ")?;
+    range.split('-').next()?.parse().ok()
+}
+
+fn partial_path(blobs: &Path, digest: &Digest) -> PathBuf {
+    blobs.join(format!("{}{}", digest, PARTIAL_SUFFIX))
+}
+
+fn expect_status(
+    resp: ureq::Response,
+    method: &str,
+    url: &str,
+    ok: &[u16],
+) -> Result<ureq::Response> {
+    if ok.contains(&resp.status()) {
+        return Ok(resp);
+    }
+    let status = resp.status();
+    let body = resp.into_string().unwrap_or_default();
+    Err(registry_error(format!(
+        "{} {}: {} {}",
+        method, url, status, body
+    )))
+}
+
+fn read_full<R: Read>(r: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        let read = r.read(&mut buf[n..])?;
+        if read == 0 {
+            break;
+        }
+        n += read;
+    }
+    Ok(n)
+}
+
+// key="value" pairs from a WWW-Authenticate header; values don't contain quotes or commas in
+// practice, so this doesn't try to be a full RFC 7235 parser
+fn parse_challenge(params: &str) -> HashMap<String, String> {
+    let mut result = HashMap::new();
+    let mut rest = params.trim();
+    while let Some((key, after)) = rest.split_once('=') {
+        let (value, after) = match after.strip_prefix('"') {
+            Some(quoted) => quoted.split_once('"').unwrap_or((quoted, "")),
+            None => after.split_once(',').unwrap_or((after, "")),
+        };
+        result.insert(key.trim().to_string(), value.to_string());
+        rest = after.trim_start_matches(|c: char| c == ',' || c.is_whitespace());
+    }
+    result
+}
+
+fn docker_credentials(registry: &str) -> Option<String> {
+    let dir = match env::var_os("DOCKER_CONFIG") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var_os("HOME")?).join(".docker"),
+    };
+    let config: serde_json::Value =
+        serde_json::from_reader(fs::File::open(dir.join("config.json")).ok()?).ok()?;
+    let auths = config.get("auths")?;
+    // docker hub's credentials are filed under its old v1 API URL
+    let keys = if registry == DOCKER_HUB {
+        vec![
+            "https://index.docker.io/v1/".to_string(),
+            DOCKER_HUB.to_string(),
+        ]
+    } else {
+        vec![
+            registry.to_string(),
+            format!("https://{}", registry),
+            format!("http://{}", registry),
+        ]
+    };
+    keys.iter()
+        .find_map(|k| auths.get(k)?.get("auth")?.as_str().map(|a| a.to_string()))
+}
+
+// every blob a tag refers to, the rootfs first
+pub(crate) fn image_blobs(image: &Image, rootfs_digest: &Digest) -> Result<Vec<LocalBlob>> {
+    let mut blobs = vec![LocalBlob {
+        digest: rootfs_digest.clone(),
+        media_type: media_types::Rootfs::name().to_string(),
+        compressed: false,
+    }];
+    let rootfs = Rootfs::open(image.open_compressed_blob::<compression::Noop>(rootfs_digest)?)?;
+    let mut chunks = Vec::new();
+    let mut seen = HashSet::new();
+    for md in rootfs.metadatas.iter() {
+        let mut metadata = image.open_rootfs_metadata(md)?;
+        let media_type = if md.compressed {
+            compression::Zstd::append_extension(media_types::Inodes::name())
+        } else {
+            media_types::Inodes::name().to_string()
+        };
+        blobs.push(LocalBlob {
+            digest: Digest::try_from(md)?,
+            media_type,
+            compressed: md.compressed,
+        });
+        for inode in metadata.read_inodes()? {
+            if let InodeMode::Reg { offset } = inode.mode {
+                // holes aren't stored anywhere
+                for blob in metadata
+                    .read_file_chunks(offset)?
+                    .iter()
+                    .filter_map(|c| c.blob)
+                {
+                    let digest = Digest::try_from(blob)?;
+                    if !seen.insert(digest.underlying()) {
+                        continue;
+                    }
+                    let media_type = if blob.compressed {
+                        compression::Zstd::append_extension(media_types::Chunk::name())
+                    } else {
+                        media_types::Chunk::name().to_string()
+                    };
+                    chunks.push(LocalBlob {
+                        digest,
+                        media_type,
+                        compressed: blob.compressed,
+                    });
+                }
+            }
+        }
+    }
+    blobs.append(&mut chunks);
+    Ok(blobs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_reference() {
+        let r: Reference = "localhost:5000/puzzlefs/ubuntu:20.04".parse().unwrap();
+        assert_eq!(r.registry, "localhost:5000");
+        assert_eq!(r.repository, "puzzlefs/ubuntu");
+        assert_eq!(r.tag, "20.04");
+
+        let r: Reference = "localhost:5000/ubuntu".parse().unwrap();
+        assert_eq!(r.registry, "localhost:5000");
+        assert_eq!(r.repository, "ubuntu");
+        assert_eq!(r.tag, "latest");
+
+        let r: Reference = "ubuntu:latest".parse().unwrap();
+        assert_eq!(r.registry, DOCKER_HUB);
+        assert_eq!(r.repository, "library/ubuntu");
+
+        let r: Reference = "anuvu/puzzlefs".parse().unwrap();
+        assert_eq!(r.registry, DOCKER_HUB);
+        assert_eq!(r.repository, "anuvu/puzzlefs");
+
+        "localhost:5000/".parse::<Reference>().unwrap_err();
+        "ubuntu:".parse::<Reference>().unwrap_err();
+    }
+
+    #[test]
+    fn test_parse_challenge() {
+        let params = parse_challenge(
+            r#"realm="https://auth.docker.io/token",service="registry.docker.io",scope="repository:library/ubuntu:pull""#,
+        );
+        assert_eq!(params["realm"], "https://auth.docker.io/token");
+        assert_eq!(params["service"], "registry.docker.io");
+        assert_eq!(params["scope"], "repository:library/ubuntu:pull");
+    }
+}
@@ -0,0 +1,676 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use memmap2::Mmap;
+use nix::errno::Errno;
+use nix::fcntl::{flock, FlockArg};
+use serde::{Deserialize, Serialize};
+use tempfile::NamedTempFile;
+
+use compression::Decompressor;
+use format::{DigestAlgorithm, RawDigest, Result};
+
+use crate::descriptor::Digest;
+use crate::index::{self, Index};
+
+/// Where an image keeps its blobs, by digest, and the index of its tags. Blobs are stored exactly
+/// as they are handed over, so compressed ones come back compressed; decompressing them is up to
+/// Image.
+pub trait BlobStore: Send + Sync {
+    fn get_blob(&self, digest: &Digest) -> io::Result<Box<dyn Decompressor>>;
+    fn put_blob(&self, digest: &Digest, blob: &mut dyn io::Read) -> io::Result<()>;
+
+    /// Starts a blob whose digest isn't known until all of it has been written, like a compressed
+    /// one, whose digest is of what went into the compressor. Stores that can't do any better
+    /// hold it in memory until it's committed and then put_blob() it.
+    fn blob_writer(&self) -> io::Result<Box<dyn BlobWriter + '_>> {
+        Ok(Box::new(BufferedBlobWriter {
+            store: self,
+            blob: Vec::new(),
+        }))
+    }
+
+    fn has_blob(&self, digest: &Digest) -> bool;
+    fn delete_blob(&self, digest: &Digest) -> io::Result<()>;
+    /// Every blob in the store, and how big it is as stored.
+    fn list_blobs(&self) -> io::Result<Vec<(Digest, u64)>>;
+    fn get_index(&self) -> Result<Index>;
+    fn put_index(&self, index: &Index) -> Result<()>;
+
+    /// Keeps garbage collection from deleting blobs out from under something that is adding new
+    /// ones and hasn't tagged them yet: writers hold the lock shared, collection holds it
+    /// exclusively and gives up rather than wait. It's released when the StoreLock is dropped.
+    fn lock(&self, _exclusive: bool) -> io::Result<StoreLock> {
+        Ok(StoreLock(None))
+    }
+
+    /// Keeps changes to the index, like adding a tag, from undoing each other: anything that
+    /// reads the index to write a changed one back holds this around both, waiting its turn if it
+    /// has to. It's released when the StoreLock is dropped.
+    fn lock_index(&self) -> io::Result<StoreLock> {
+        Ok(StoreLock(None))
+    }
+
+    /// The directory the blobs are files in, for stores that keep them on the local filesystem.
+    fn blob_path(&self) -> Option<PathBuf> {
+        None
+    }
+
+    /// The OCI layout the store is, for stores that are one on the local filesystem.
+    fn oci_dir(&self) -> Option<PathBuf> {
+        None
+    }
+
+    /// The file the blob `digest` is (or would be) in, for stores that keep them on the local
+    /// filesystem.
+    fn blob_file(&self, _digest: &Digest) -> Option<PathBuf> {
+        None
+    }
+
+    /// The raw bytes of a blob mapped into memory, so reads can slice them directly instead of
+    /// copying them through get_blob(). Stores that can't map a blob return None, and it gets read
+    /// the usual way.
+    fn map_blob(&self, _digest: &Digest) -> Option<Arc<Mmap>> {
+        None
+    }
+}
+
+pub struct StoreLock(Option<fs::File>);
+
+/// A blob being written, from BlobStore::blob_writer(). It's only stored once it's committed;
+/// dropping it throws it away.
+pub trait BlobWriter: io::Write {
+    fn commit(self: Box<Self>, digest: &Digest) -> io::Result<()>;
+}
+
+struct BufferedBlobWriter<'a, S: ?Sized> {
+    store: &'a S,
+    blob: Vec<u8>,
+}
+
+impl<S: ?Sized> io::Write for BufferedBlobWriter<'_, S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.blob.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<S: BlobStore + ?Sized> BlobWriter for BufferedBlobWriter<'_, S> {
+    fn commit(self: Box<Self>, digest: &Digest) -> io::Result<()> {
+        self.store.put_blob(digest, &mut self.blob.as_slice())
+    }
+}
+
+struct DiscardBlobWriter<'a> {
+    store: &'a DiscardBlobStore,
+    size: u64,
+}
+
+impl io::Write for DiscardBlobWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.size += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl BlobWriter for DiscardBlobWriter<'_> {
+    fn commit(self: Box<Self>, digest: &Digest) -> io::Result<()> {
+        let mut blobs = self.store.blobs.lock().unwrap();
+        blobs.insert(digest.clone(), self.size);
+        Ok(())
+    }
+}
+
+// a temp file in the image's directory, renamed into place once it's done, so readers never see
+// half of a blob
+struct FsBlobWriter<'a> {
+    store: &'a FsBlobStore,
+    tmp: NamedTempFile,
+}
+
+impl io::Write for FsBlobWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.tmp.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.tmp.flush()
+    }
+}
+
+impl BlobWriter for FsBlobWriter<'_> {
+    fn commit(self: Box<Self>, digest: &Digest) -> io::Result<()> {
+        let path = self.store.path(digest);
+        // only blobs/sha256 is made up front
+        if self.store.layout == BlobLayout::Sharded || digest.algorithm() != DigestAlgorithm::Sha256
+        {
+            fs::create_dir_all(path.parent().unwrap())?;
+        }
+        self.tmp.persist(path).map_err(|e| e.error)?;
+        Ok(())
+    }
+}
+
+// puzzlefs' own, the OCI spec doesn't have anything like them
+const LOCK_PATH: &str = "puzzlefs.lock";
+const INDEX_LOCK_PATH: &str = "puzzlefs.index.lock";
+
+// mappings are kept around since the blobs never change, but there's a limit on how many a process
+// can have (vm.max_map_count), so don't hog them
+const MAX_MAPPED_BLOBS: usize = 1024;
+
+// where sha256 blobs go, which is most of them; the others go next to it, under blobs/<algorithm>
+pub(crate) const BLOBS_PATH: &str = "blobs/sha256";
+
+/// Where in blobs/<algorithm> an FsBlobStore puts each blob.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlobLayout {
+    /// blobs/sha256/<digest>, as the OCI spec has it.
+    Flat,
+    /// blobs/sha256/<first two digits of the digest>/<the rest of it>, so that no directory ends
+    /// up with hundreds of thousands of files in it.
+    Sharded,
+}
+
+impl Default for BlobLayout {
+    fn default() -> Self {
+        BlobLayout::Flat
+    }
+}
+
+/// The OCI image layout: blobs in blobs/sha256 and the tags in index.json.
+pub struct FsBlobStore {
+    oci_dir: PathBuf,
+    layout: BlobLayout,
+    mmap: bool,
+    maps: Mutex<HashMap<RawDigest, Arc<Mmap>>>,
+}
+
+impl FsBlobStore {
+    pub fn new(oci_dir: &Path) -> FsBlobStore {
+        FsBlobStore::with_layout(oci_dir, BlobLayout::Flat)
+    }
+
+    pub fn with_layout(oci_dir: &Path, layout: BlobLayout) -> FsBlobStore {
+        FsBlobStore {
+            oci_dir: oci_dir.to_path_buf(),
+            layout,
+            mmap: true,
+            maps: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// A store that never maps blobs, and always reads them through get_blob().
+    pub fn buffered(oci_dir: &Path) -> FsBlobStore {
+        FsBlobStore {
+            mmap: false,
+            ..FsBlobStore::new(oci_dir)
+        }
+    }
+
+    pub fn oci_dir(&self) -> &Path {
+        &self.oci_dir
+    }
+
+    pub fn layout(&self) -> BlobLayout {
+        self.layout
+    }
+
+    fn path(&self, digest: &Digest) -> PathBuf {
+        let name = digest.to_string();
+        let dir = self.oci_dir.join("blobs").join(digest.algorithm().name());
+        match self.layout {
+            BlobLayout::Flat => dir.join(name),
+            BlobLayout::Sharded => dir.join(&name[..2]).join(&name[2..]),
+        }
+    }
+
+    fn lock_file(&self, name: &str, arg: FlockArg) -> io::Result<StoreLock> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(self.oci_dir.join(name))?;
+        flock(file.as_raw_fd(), arg)
+            .map_err(|e| io::Error::from(e.as_errno().unwrap_or(Errno::EINVAL)))?;
+        Ok(StoreLock(Some(file)))
+    }
+}
+
+// the blobs in `dir` whose names are digests once `prefix` is put in front of them
+fn list_dir(
+    dir: &Path,
+    algorithm: DigestAlgorithm,
+    prefix: &str,
+    blobs: &mut Vec<(Digest, u64)>,
+) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let digest = match name
+            .to_str()
+            .map(|n| format!("{}:{}{}", algorithm, prefix, n).parse())
+        {
+            Some(Ok(digest)) => digest,
+            _ => continue,
+        };
+        blobs.push((digest, entry.metadata()?.len()));
+    }
+    Ok(())
+}
+
+impl BlobStore for FsBlobStore {
+    fn get_blob(&self, digest: &Digest) -> io::Result<Box<dyn Decompressor>> {
+        Ok(Box::new(fs::File::open(self.path(digest))?))
+    }
+
+    fn put_blob(&self, digest: &Digest, blob: &mut dyn io::Read) -> io::Result<()> {
+        let mut writer = self.blob_writer()?;
+        io::copy(blob, &mut writer)?;
+        writer.commit(digest)
+    }
+
+    fn blob_writer(&self) -> io::Result<Box<dyn BlobWriter + '_>> {
+        Ok(Box::new(FsBlobWriter {
+            store: self,
+            tmp: NamedTempFile::new_in(&self.oci_dir)?,
+        }))
+    }
+
+    fn has_blob(&self, digest: &Digest) -> bool {
+        self.path(digest).exists()
+    }
+
+    fn delete_blob(&self, digest: &Digest) -> io::Result<()> {
+        self.maps.lock().unwrap().remove(&digest.underlying());
+        fs::remove_file(self.path(digest))
+    }
+
+    // half finished pulls are kept next to the blobs, with names that aren't digests
+    fn list_blobs(&self) -> io::Result<Vec<(Digest, u64)>> {
+        let mut blobs = Vec::new();
+        for &algorithm in &[
+            DigestAlgorithm::Sha256,
+            DigestAlgorithm::Blake3,
+            DigestAlgorithm::Sha512,
+        ] {
+            let dir = self.oci_dir.join("blobs").join(algorithm.name());
+            // there's only a directory for the others once something has been hashed with them
+            if algorithm != DigestAlgorithm::Sha256 && !dir.exists() {
+                continue;
+            }
+            match self.layout {
+                BlobLayout::Flat => list_dir(&dir, algorithm, "", &mut blobs)?,
+                BlobLayout::Sharded => {
+                    for entry in fs::read_dir(&dir)? {
+                        let entry = entry?;
+                        let name = entry.file_name();
+                        match name.to_str() {
+                            Some(shard) if shard.len() == 2 && entry.file_type()?.is_dir() => {
+                                list_dir(&entry.path(), algorithm, shard, &mut blobs)?
+                            }
+                            _ => continue,
+                        }
+                    }
+                }
+            }
+        }
+        Ok(blobs)
+    }
+
+    fn get_index(&self) -> Result<Index> {
+        Index::open(&self.oci_dir.join(index::PATH))
+    }
+
+    fn put_index(&self, index: &Index) -> Result<()> {
+        index.write(&self.oci_dir.join(index::PATH))
+    }
+
+    fn blob_path(&self) -> Option<PathBuf> {
+        Some(self.oci_dir.join(BLOBS_PATH))
+    }
+
+    fn oci_dir(&self) -> Option<PathBuf> {
+        Some(self.oci_dir.clone())
+    }
+
+    fn blob_file(&self, digest: &Digest) -> Option<PathBuf> {
+        Some(self.path(digest))
+    }
+
+    // other processes build into and collect the same directory, so this is a lock file
+    fn lock(&self, exclusive: bool) -> io::Result<StoreLock> {
+        let arg = if exclusive {
+            FlockArg::LockExclusiveNonblock
+        } else {
+            FlockArg::LockShared
+        };
+        self.lock_file(LOCK_PATH, arg)
+    }
+
+    // a file of its own rather than index.json, which gets replaced rather than written to
+    fn lock_index(&self) -> io::Result<StoreLock> {
+        self.lock_file(INDEX_LOCK_PATH, FlockArg::LockExclusive)
+    }
+
+    fn map_blob(&self, digest: &Digest) -> Option<Arc<Mmap>> {
+        if !self.mmap {
+            return None;
+        }
+        let mut maps = self.maps.lock().unwrap();
+        if let Some(map) = maps.get(&digest.underlying()) {
+            return Some(map.clone());
+        }
+        // some filesystems can't be mapped. blobs are only ever renamed into place, never written
+        // in place, so the mapping can't change under us.
+        let file = fs::File::open(self.path(digest)).ok()?;
+        let map = Arc::new(unsafe { Mmap::map(&file) }.ok()?);
+        if maps.len() >= MAX_MAPPED_BLOBS {
+            maps.clear();
+        }
+        maps.insert(digest.underlying(), map.clone());
+        Some(map)
+    }
+}
+
+/// Keeps everything in memory, e.g. for images that only need to live as long as a test.
+#[derive(Default)]
+pub struct MemBlobStore {
+    blobs: Mutex<HashMap<Digest, Arc<[u8]>>>,
+    index: Mutex<Option<Vec<u8>>>,
+}
+
+impl BlobStore for MemBlobStore {
+    fn get_blob(&self, digest: &Digest) -> io::Result<Box<dyn Decompressor>> {
+        let blobs = self.blobs.lock().unwrap();
+        let blob = blobs.get(digest).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("no blob {}", digest))
+        })?;
+        Ok(Box::new(io::Cursor::new(blob.clone())))
+    }
+
+    fn put_blob(&self, digest: &Digest, blob: &mut dyn io::Read) -> io::Result<()> {
+        let mut data = Vec::new();
+        blob.read_to_end(&mut data)?;
+        self.blobs
+            .lock()
+            .unwrap()
+            .insert(digest.clone(), data.into());
+        Ok(())
+    }
+
+    fn has_blob(&self, digest: &Digest) -> bool {
+        self.blobs.lock().unwrap().contains_key(digest)
+    }
+
+    fn delete_blob(&self, digest: &Digest) -> io::Result<()> {
+        self.blobs
+            .lock()
+            .unwrap()
+            .remove(digest)
+            .map(|_| ())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no blob {}", digest)))
+    }
+
+    fn list_blobs(&self) -> io::Result<Vec<(Digest, u64)>> {
+        let blobs = self.blobs.lock().unwrap();
+        Ok(blobs
+            .iter()
+            .map(|(digest, blob)| (digest.clone(), blob.len() as u64))
+            .collect())
+    }
+
+    fn get_index(&self) -> Result<Index> {
+        match &*self.index.lock().unwrap() {
+            Some(index) => Index::read(index.as_slice()),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "no index").into()),
+        }
+    }
+
+    fn put_index(&self, index: &Index) -> Result<()> {
+        *self.index.lock().unwrap() = Some(serde_json::to_vec(index)?);
+        Ok(())
+    }
+}
+
+/// Remembers which blobs were put in it and how big they were, but not what was in them: for
+/// finding out what a build would write without writing any of it. Getting a blob back fails.
+#[derive(Default)]
+pub struct DiscardBlobStore {
+    blobs: Mutex<HashMap<Digest, u64>>,
+    index: Mutex<Option<Vec<u8>>>,
+}
+
+impl BlobStore for DiscardBlobStore {
+    fn get_blob(&self, digest: &Digest) -> io::Result<Box<dyn Decompressor>> {
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("blob {} was discarded", digest),
+        ))
+    }
+
+    fn put_blob(&self, digest: &Digest, blob: &mut dyn io::Read) -> io::Result<()> {
+        let size = io::copy(blob, &mut io::sink())?;
+        self.blobs.lock().unwrap().insert(digest.clone(), size);
+        Ok(())
+    }
+
+    // only the size is kept, so there's no need to hold on to the rest
+    fn blob_writer(&self) -> io::Result<Box<dyn BlobWriter + '_>> {
+        Ok(Box::new(DiscardBlobWriter {
+            store: self,
+            size: 0,
+        }))
+    }
+
+    fn has_blob(&self, digest: &Digest) -> bool {
+        self.blobs.lock().unwrap().contains_key(digest)
+    }
+
+    fn delete_blob(&self, digest: &Digest) -> io::Result<()> {
+        self.blobs
+            .lock()
+            .unwrap()
+            .remove(digest)
+            .map(|_| ())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no blob {}", digest)))
+    }
+
+    fn list_blobs(&self) -> io::Result<Vec<(Digest, u64)>> {
+        let blobs = self.blobs.lock().unwrap();
+        Ok(blobs
+            .iter()
+            .map(|(digest, size)| (digest.clone(), *size))
+            .collect())
+    }
+
+    fn get_index(&self) -> Result<Index> {
+        match &*self.index.lock().unwrap() {
+            Some(index) => Index::read(index.as_slice()),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "no index").into()),
+        }
+    }
+
+    fn put_index(&self, index: &Index) -> Result<()> {
+        *self.index.lock().unwrap() = Some(serde_json::to_vec(index)?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{media_types, Image};
+    use std::io::Write;
+    use tempfile::tempdir;
+
+    fn chunk(digest: &Digest) -> format::BlobRef {
+        digest.blob_ref(false)
+    }
+
+    #[test]
+    fn test_mmap_matches_buffered() {
+        let dir = tempdir().unwrap();
+        let mapped = Image::new(dir.path()).unwrap();
+        let buffered = Image::with_store(Arc::new(FsBlobStore::buffered(dir.path())));
+        let data = (0..1024 * 1024 + 17)
+            .map(|i| (i * 7 % 251) as u8)
+            .collect::<Vec<_>>();
+        let desc = mapped
+            .put_blob::<_, compression::Noop, media_types::Chunk>(data.as_slice())
+            .unwrap();
+        let blob = chunk(&desc.digest);
+        assert!(mapped.map_chunk_blob(blob).unwrap().is_some());
+        assert!(buffered.map_chunk_blob(blob).unwrap().is_none());
+
+        // odd offsets and sizes, reads that run off the end and ones that start past it
+        for &(offset, len) in &[(0, 4096), (1, 100_000), (999_999, 70_000), (2_000_000, 10)] {
+            let mut ours = vec![0_u8; len];
+            let mut theirs = vec![0_u8; len];
+            let n = mapped.fill_from_chunk(blob, offset, &mut ours).unwrap();
+            assert_eq!(
+                buffered.fill_from_chunk(blob, offset, &mut theirs).unwrap(),
+                n
+            );
+            assert_eq!(ours, theirs);
+        }
+        assert_eq!(mapped.read_chunk_blob(blob).unwrap(), data);
+
+        let desc = mapped
+            .put_blob::<_, compression::Noop, media_types::Chunk>(&[][..])
+            .unwrap();
+        let blob = chunk(&desc.digest);
+        assert_eq!(mapped.fill_from_chunk(blob, 0, &mut [0_u8; 16]).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_sharded_layout() {
+        let dir = tempdir().unwrap();
+        let image = Image::new_with_layout(dir.path(), BlobLayout::Sharded).unwrap();
+        let desc = image
+            .put_blob::<_, compression::Noop, media_types::Chunk>("meshuggah rocks".as_bytes())
+            .unwrap();
+        let name = desc.digest.to_string();
+        let path = dir
+            .path()
+            .join(BLOBS_PATH)
+            .join(&name[..2])
+            .join(&name[2..]);
+        assert!(path.is_file());
+        assert_eq!(image.blob_file(&desc.digest), Some(path));
+        // things that aren't blobs get left alone, like half finished pulls are
+        fs::write(dir.path().join(BLOBS_PATH).join("partial"), "").unwrap();
+        fs::create_dir(dir.path().join(BLOBS_PATH).join("nope")).unwrap();
+        assert_eq!(
+            image.store().list_blobs().unwrap(),
+            vec![(desc.digest.clone(), 15)]
+        );
+        image.add_tag("test".to_string(), desc.clone()).unwrap();
+
+        // opening the image again, or building into it, finds the blobs where they are
+        for image in &[
+            Image::open(dir.path()).unwrap(),
+            Image::new(dir.path()).unwrap(),
+        ] {
+            let resolved = image.resolve("test").unwrap();
+            assert_eq!(
+                image.read_chunk_blob(chunk(&resolved)).unwrap(),
+                b"meshuggah rocks"
+            );
+        }
+
+        // and flat images don't say anything about it, so other tools can read them
+        let flat = tempdir().unwrap();
+        Image::new(flat.path()).unwrap();
+        let layout = fs::read_to_string(flat.path().join("oci-layout")).unwrap();
+        assert!(!layout.contains("puzzlefsBlobLayout"), "{}", layout);
+
+        image.store().delete_blob(&desc.digest).unwrap();
+        assert!(image.store().list_blobs().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_blob_writer() {
+        let dir = tempdir().unwrap();
+        let store = FsBlobStore::new(dir.path());
+        fs::create_dir_all(dir.path().join(BLOBS_PATH)).unwrap();
+        let temp_files = || {
+            fs::read_dir(dir.path())
+                .unwrap()
+                .map(|e| e.unwrap())
+                .filter(|e| e.file_type().unwrap().is_file())
+                .map(|e| e.metadata().unwrap().len())
+                .collect::<Vec<_>>()
+        };
+
+        // what's written goes straight to disk rather than piling up in memory
+        let data = vec![7_u8; 1 << 20];
+        let mut writer = store.blob_writer().unwrap();
+        writer.write_all(&data).unwrap();
+        assert_eq!(temp_files(), vec![data.len() as u64]);
+        let digest = Digest::new(
+            DigestAlgorithm::Sha256.digest(&data),
+            DigestAlgorithm::Sha256,
+        );
+        writer.commit(&digest).unwrap();
+        assert!(temp_files().is_empty());
+        assert_eq!(
+            store.list_blobs().unwrap(),
+            vec![(digest, data.len() as u64)]
+        );
+
+        // and one that's dropped rather than committed is gone
+        let mut writer = store.blob_writer().unwrap();
+        writer.write_all(&data).unwrap();
+        drop(writer);
+        assert!(temp_files().is_empty());
+        assert_eq!(store.list_blobs().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_mem_store() {
+        let image = Image::with_store(Arc::new(MemBlobStore::default()));
+        let data = "meshuggah rocks ".repeat(1024);
+        let desc = image
+            .put_blob::<_, compression::Zstd, media_types::Chunk>(data.as_bytes())
+            .unwrap();
+        assert!(image.store().has_blob(&desc.digest));
+
+        let chunk = desc.digest.blob_ref(true);
+        assert_eq!(image.read_chunk_blob(chunk).unwrap(), data.as_bytes());
+
+        image.get_index().unwrap_err();
+        image.add_tag("test".to_string(), desc.clone()).unwrap();
+        let index = image.get_index().unwrap();
+        assert_eq!(index.find_tag("test").unwrap().digest, desc.digest);
+    }
+
+    #[test]
+    fn test_discard_store() {
+        let image = Image::with_store(Arc::new(DiscardBlobStore::default()));
+        let data = "meshuggah rocks ".repeat(1024);
+        let desc = image
+            .put_blob::<_, compression::Zstd, media_types::Chunk>(data.as_bytes())
+            .unwrap();
+        assert!(image.store().has_blob(&desc.digest));
+        // what was stored is the compressed blob, which is smaller
+        let blobs = image.store().list_blobs().unwrap();
+        assert_eq!(blobs.len(), 1);
+        assert_eq!(blobs[0].0, desc.digest);
+        assert!(blobs[0].1 < desc.size);
+        image
+            .read_chunk_blob(desc.digest.blob_ref(true))
+            .unwrap_err();
+    }
+}
@@ -0,0 +1,106 @@
+use std::convert::TryFrom;
+use std::io::{Read, Seek, SeekFrom};
+
+use compression::Decompressor;
+use format::{BlobRef, RawDigest, Result};
+
+use crate::{Digest, Image};
+
+/// Reads chunks without holding any more of them in memory than the caller's buffer, for going
+/// through a whole image (e.g. to extract it). The blob the last read was from is kept open, so
+/// reads that carry on through a blob don't have to open and decompress it all over again: as long
+/// as they come in the order the blob has its data in, which is the order files were built in,
+/// each blob is only decompressed once.
+pub struct ChunkStream<'a> {
+    image: &'a Image,
+    current: Option<(RawDigest, Box<dyn Decompressor>)>,
+}
+
+impl<'a> ChunkStream<'a> {
+    pub fn new(image: &'a Image) -> ChunkStream<'a> {
+        ChunkStream {
+            image,
+            current: None,
+        }
+    }
+
+    pub fn image(&self) -> &'a Image {
+        self.image
+    }
+
+    /// Like Image::fill_from_chunk().
+    pub fn fill_from_chunk(
+        &mut self,
+        chunk: BlobRef,
+        addl_offset: u64,
+        buf: &mut [u8],
+    ) -> Result<usize> {
+        // mapped blobs can be read anywhere for free
+        if self.image.map_chunk_blob(chunk)?.is_some() {
+            return self.image.fill_from_chunk(chunk, addl_offset, buf);
+        }
+
+        let digest = Digest::try_from(chunk)?.underlying();
+        let blob = match &mut self.current {
+            Some((current, blob)) if *current == digest => blob,
+            current => {
+                &mut current
+                    .insert((digest, self.image.open_chunk_blob(chunk)?))
+                    .1
+            }
+        };
+        // seeking to where the last read left off doesn't cost anything
+        blob.seek(SeekFrom::Start(chunk.offset + addl_offset))?;
+        let mut n = 0;
+        while n < buf.len() {
+            let read = blob.read(&mut buf[n..])?;
+            if read == 0 {
+                break;
+            }
+            n += read;
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::{media_types, FsBlobStore};
+
+    #[test]
+    fn test_chunk_stream() {
+        let dir = tempdir().unwrap();
+        Image::new(dir.path()).unwrap();
+        let image = Image::with_store(Arc::new(FsBlobStore::buffered(dir.path())));
+        let blobs = ["first blob", "the second blob"]
+            .iter()
+            .map(|data| {
+                let desc = image
+                    .put_blob::<_, compression::Zstd, media_types::Chunk>(data.as_bytes())
+                    .unwrap();
+                desc.digest.blob_ref(true)
+            })
+            .collect::<Vec<_>>();
+
+        let mut stream = ChunkStream::new(&image);
+        let mut buf = [0_u8; 5];
+        // on through a blob, over to another one, and back
+        for &(blob, offset, expected) in &[
+            (0, 0, "first"),
+            (0, 5, " blob"),
+            (1, 4, "secon"),
+            (0, 6, "blob"),
+            (0, 1, "irst "),
+        ] {
+            let n = stream
+                .fill_from_chunk(blobs[blob], offset, &mut buf)
+                .unwrap();
+            assert_eq!(&buf[..n], expected.as_bytes());
+        }
+    }
+}
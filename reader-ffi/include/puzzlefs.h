@@ -0,0 +1,76 @@
+/*
+ * Reading puzzlefs images from C. Build the reader-ffi crate and link against the
+ * libreader_ffi.a it makes, along with the libraries it needs: -lfuse -lzstd -lxxhash -lpthread
+ * -ldl -lm, or whatever `cargo rustc -p reader-ffi -- --print native-static-libs` says.
+ *
+ * Every function returns a negative errno if something goes wrong, and 0 (or for puzzlefs_read(),
+ * how many bytes it read) if it doesn't.
+ *
+ * Strings handed in must be NUL terminated, and are only looked at during the call. Paths are
+ * relative to the root of the image, whether or not they start with '/'; ".." isn't allowed.
+ * Buffers are the caller's: nothing here allocates memory the caller has to free, other than the
+ * handle itself.
+ */
+#ifndef PUZZLEFS_H
+#define PUZZLEFS_H
+
+#include <stddef.h>
+#include <stdint.h>
+#include <sys/types.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/*
+ * An open image. It belongs to the caller from puzzlefs_open() until it's handed to
+ * puzzlefs_close(), and must not be used by more than one thread at a time.
+ */
+typedef struct puzzlefs puzzlefs;
+
+struct puzzlefs_stat {
+	uint64_t ino;
+	/* the file type and permission bits, like st_mode */
+	uint32_t mode;
+	uint32_t nlink;
+	uint32_t uid;
+	uint32_t gid;
+	/* 0 for anything but regular files */
+	uint64_t size;
+	uint64_t rdev;
+	int64_t mtime_sec;
+	uint32_t mtime_nsec;
+	int64_t atime_sec;
+	uint32_t atime_nsec;
+};
+
+/* opens tag in the image at oci_dir, and stores a handle for it in *out */
+int puzzlefs_open(const char *oci_dir, const char *tag, puzzlefs **out);
+
+/* closes a handle from puzzlefs_open(); NULL is ignored */
+void puzzlefs_close(puzzlefs *pfs);
+
+/* fills in *st for path */
+int puzzlefs_stat(puzzlefs *pfs, const char *path, struct puzzlefs_stat *st);
+
+/*
+ * Called for every entry of a directory, "." and ".." not included, in order of name. name is only
+ * good until the callback returns. Returning anything but 0 stops puzzlefs_readdir(), which then
+ * returns that.
+ */
+typedef int (*puzzlefs_readdir_fn)(void *arg, const char *name, uint64_t ino);
+
+/* calls fn with arg for every entry of the directory path */
+int puzzlefs_readdir(puzzlefs *pfs, const char *path, puzzlefs_readdir_fn fn, void *arg);
+
+/*
+ * Reads up to len bytes of the file path starting at offset into buf, which must have room for
+ * them. Fewer than len are only read at the end of the file.
+ */
+ssize_t puzzlefs_read(puzzlefs *pfs, const char *path, uint64_t offset, void *buf, size_t len);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* PUZZLEFS_H */
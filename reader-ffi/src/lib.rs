@@ -0,0 +1,240 @@
+// the C interface to reading images; include/puzzlefs.h declares it, and is where the rules about
+// who owns what are written down for C programs.
+use std::cmp::min;
+use std::ffi::{CStr, CString, OsStr};
+use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::ffi::OsStrExt;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::Path;
+use std::slice;
+
+use nix::errno::Errno;
+use nix::libc::{c_char, c_int, c_void, size_t, ssize_t};
+use nix::sys::stat::{makedev, SFlag};
+
+use format::{Result, WireFormatError};
+use oci::Image;
+use reader::PuzzleFS;
+
+/// An open image, `struct puzzlefs` on the C side.
+pub struct PuzzleFSHandle {
+    // borrows image, so it has to be dropped first
+    pfs: PuzzleFS<'static>,
+    _image: Box<Image>,
+}
+
+/// `struct puzzlefs_stat`.
+#[repr(C)]
+pub struct PuzzleFSStat {
+    pub ino: u64,
+    pub mode: u32,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u64,
+    pub rdev: u64,
+    pub mtime_sec: i64,
+    pub mtime_nsec: u32,
+    pub atime_sec: i64,
+    pub atime_nsec: u32,
+}
+
+pub type ReaddirFn = extern "C" fn(arg: *mut c_void, name: *const c_char, ino: u64) -> c_int;
+
+// what the functions hand back to C, which is a negative errno if something went wrong
+trait Ret {
+    fn errno(errno: c_int) -> Self;
+}
+
+impl Ret for c_int {
+    fn errno(errno: c_int) -> Self {
+        -errno
+    }
+}
+
+impl Ret for ssize_t {
+    fn errno(errno: c_int) -> Self {
+        -(errno as ssize_t)
+    }
+}
+
+// runs f, turning errors into negative errnos; panics mustn't unwind into C
+fn ffi_call<T: Ret>(f: impl FnOnce() -> Result<T>) -> T {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(ret)) => ret,
+        Ok(Err(e)) => T::errno(e.to_errno()),
+        Err(_) => T::errno(Errno::EIO as c_int),
+    }
+}
+
+unsafe fn c_str<'a>(s: *const c_char) -> Result<&'a OsStr> {
+    if s.is_null() {
+        return Err(WireFormatError::from_errno(Errno::EINVAL));
+    }
+    Ok(OsStr::from_bytes(CStr::from_ptr(s).to_bytes()))
+}
+
+unsafe fn handle<'a>(pfs: *mut PuzzleFSHandle) -> Result<&'a mut PuzzleFSHandle> {
+    pfs.as_mut()
+        .ok_or_else(|| WireFormatError::from_errno(Errno::EINVAL))
+}
+
+/// Opens `tag` in the image at `oci_dir`, and stores the handle in `out`.
+///
+/// # Safety
+///
+/// `oci_dir` and `tag` must be NUL terminated strings, and `out` must point to somewhere a pointer
+/// can be written.
+#[no_mangle]
+pub unsafe extern "C" fn puzzlefs_open(
+    oci_dir: *const c_char,
+    tag: *const c_char,
+    out: *mut *mut PuzzleFSHandle,
+) -> c_int {
+    ffi_call(|| {
+        if out.is_null() {
+            return Err(WireFormatError::from_errno(Errno::EINVAL));
+        }
+        let image = Box::new(Image::open(Path::new(c_str(oci_dir)?))?);
+        let tag = c_str(tag)?
+            .to_str()
+            .ok_or_else(|| WireFormatError::from_errno(Errno::EINVAL))?;
+        // the image lives on the heap until the handle is dropped, and pfs is dropped before it
+        let image_ref = &*(image.as_ref() as *const Image);
+        let pfs = PuzzleFS::open(image_ref, tag)?;
+        *out = Box::into_raw(Box::new(PuzzleFSHandle { pfs, _image: image }));
+        Ok(0)
+    })
+}
+
+/// Closes a handle puzzlefs_open() gave out. NULL is ignored.
+///
+/// # Safety
+///
+/// `pfs` must have come from puzzlefs_open(), and not be used again.
+#[no_mangle]
+pub unsafe extern "C" fn puzzlefs_close(pfs: *mut PuzzleFSHandle) {
+    if !pfs.is_null() {
+        let _ = catch_unwind(AssertUnwindSafe(|| drop(Box::from_raw(pfs))));
+    }
+}
+
+/// Fills in `st` for `path`.
+///
+/// # Safety
+///
+/// `pfs` must be an open handle, `path` a NUL terminated string and `st` must point to a
+/// `struct puzzlefs_stat`.
+#[no_mangle]
+pub unsafe extern "C" fn puzzlefs_stat(
+    pfs: *mut PuzzleFSHandle,
+    path: *const c_char,
+    st: *mut PuzzleFSStat,
+) -> c_int {
+    ffi_call(|| {
+        let pfs = &mut handle(pfs)?.pfs;
+        let st = st
+            .as_mut()
+            .ok_or_else(|| WireFormatError::from_errno(Errno::EINVAL))?;
+        let ino = pfs.lookup_path(Path::new(c_str(path)?))?;
+        let inode = pfs.find_inode(ino)?;
+        let (kind, rdev) = match inode.inode.mode {
+            format::InodeMode::Reg { .. } => (SFlag::S_IFREG, 0),
+            format::InodeMode::Dir { .. } => (SFlag::S_IFDIR, 0),
+            format::InodeMode::Lnk => (SFlag::S_IFLNK, 0),
+            format::InodeMode::Fifo => (SFlag::S_IFIFO, 0),
+            format::InodeMode::Sock => (SFlag::S_IFSOCK, 0),
+            format::InodeMode::Chr { major, minor } => (SFlag::S_IFCHR, makedev(major, minor)),
+            format::InodeMode::Blk { major, minor } => (SFlag::S_IFBLK, makedev(major, minor)),
+            // what overlayfs makes whiteouts out of
+            format::InodeMode::Wht => (SFlag::S_IFCHR, 0),
+            format::InodeMode::Unknown => return Err(WireFormatError::from_errno(Errno::EINVAL)),
+        };
+        *st = PuzzleFSStat {
+            ino,
+            mode: kind.bits() | inode.inode.permissions as u32,
+            nlink: inode.inode.nlink,
+            uid: inode.inode.uid,
+            gid: inode.inode.gid,
+            size: inode.file_len().unwrap_or(0),
+            rdev,
+            mtime_sec: inode.inode.mtime.sec,
+            mtime_nsec: inode.inode.mtime.nsec,
+            atime_sec: inode.inode.atime.sec,
+            atime_nsec: inode.inode.atime.nsec,
+        };
+        Ok(0)
+    })
+}
+
+/// Calls `f` for every entry of the directory `path`, in order of name.
+///
+/// # Safety
+///
+/// `pfs` must be an open handle and `path` a NUL terminated string; `arg` is only handed on to `f`.
+#[no_mangle]
+pub unsafe extern "C" fn puzzlefs_readdir(
+    pfs: *mut PuzzleFSHandle,
+    path: *const c_char,
+    f: Option<ReaddirFn>,
+    arg: *mut c_void,
+) -> c_int {
+    ffi_call(|| {
+        let pfs = &mut handle(pfs)?.pfs;
+        let f = f.ok_or_else(|| WireFormatError::from_errno(Errno::EINVAL))?;
+        let ino = pfs.lookup_path(Path::new(c_str(path)?))?;
+        let inode = pfs.find_inode(ino)?;
+        for (name, ino) in inode.dir_entries()? {
+            // names can't have NULs in them, there's no way to build an image with one
+            let name = CString::new(name.as_bytes())
+                .map_err(|_| WireFormatError::from_errno(Errno::EINVAL))?;
+            let ret = f(arg, name.as_ptr(), *ino);
+            if ret != 0 {
+                return Ok(ret);
+            }
+        }
+        Ok(0)
+    })
+}
+
+/// Reads up to `len` bytes of the file `path` at `offset` into `buf`, returning how many it read:
+/// fewer than `len` only at the end of the file.
+///
+/// # Safety
+///
+/// `pfs` must be an open handle, `path` a NUL terminated string and `buf` must have room for `len`
+/// bytes.
+#[no_mangle]
+pub unsafe extern "C" fn puzzlefs_read(
+    pfs: *mut PuzzleFSHandle,
+    path: *const c_char,
+    offset: u64,
+    buf: *mut c_void,
+    len: size_t,
+) -> ssize_t {
+    ffi_call(|| {
+        let handle = handle(pfs)?;
+        let pfs = &mut handle.pfs;
+        let ino = pfs.lookup_path(Path::new(c_str(path)?))?;
+        let mut file = pfs.open_file(ino)?;
+        let len = min(len as u64, file.len().saturating_sub(offset));
+        if len == 0 {
+            return Ok(0);
+        }
+        if buf.is_null() {
+            return Err(WireFormatError::from_errno(Errno::EINVAL));
+        }
+        let buf = slice::from_raw_parts_mut(buf as *mut u8, len as usize);
+        file.seek(SeekFrom::Start(offset))?;
+        // a read can stop short of what was asked, e.g. at the end of a chunk
+        let mut filled = 0;
+        while filled < buf.len() {
+            let n = file.read(&mut buf[filled..])?;
+            if n == 0 {
+                break;
+            }
+            filled += n;
+        }
+        Ok(filled as ssize_t)
+    })
+}
@@ -0,0 +1,84 @@
+/* reads an image through the C interface, printing what it finds; see ffi.rs */
+#include <errno.h>
+#include <stdio.h>
+#include <string.h>
+#include <sys/stat.h>
+
+#include "puzzlefs.h"
+
+#define CHECK(expr, want)                                                           \
+	do {                                                                        \
+		long ret = (expr);                                                  \
+		if (ret != (want)) {                                                \
+			fprintf(stderr, "%s: got %ld, wanted %ld\n", #expr, ret,    \
+				(long)(want));                                      \
+			return 1;                                                   \
+		}                                                                   \
+	} while (0)
+
+static int print_entry(void *arg, const char *name, uint64_t ino)
+{
+	int *count = arg;
+
+	(*count)++;
+	printf("entry %s\n", name);
+	return 0;
+}
+
+static int stop(void *arg, const char *name, uint64_t ino)
+{
+	return 42;
+}
+
+int main(int argc, char **argv)
+{
+	puzzlefs *pfs;
+	struct puzzlefs_stat st;
+	char buf[4], content[64] = {};
+	unsigned char big[2 * 4096];
+	uint64_t offset = 0;
+	ssize_t n;
+	int count = 0, i;
+
+	if (argc != 3) {
+		fprintf(stderr, "usage: %s <oci dir> <tag>\n", argv[0]);
+		return 1;
+	}
+
+	CHECK(puzzlefs_open(argv[1], "no such tag", &pfs) < 0, 1);
+	CHECK(puzzlefs_open(argv[1], argv[2], &pfs), 0);
+
+	CHECK(puzzlefs_stat(pfs, "/", &st), 0);
+	CHECK(S_ISDIR(st.mode), 1);
+	CHECK(puzzlefs_stat(pfs, "dir/file", &st), 0);
+	CHECK(S_ISREG(st.mode), 1);
+	printf("size %lu mode %o uid %u\n", (unsigned long)st.size, st.mode & 07777, st.uid);
+	CHECK(puzzlefs_stat(pfs, "missing", &st), -ENOENT);
+	CHECK(puzzlefs_stat(pfs, "dir/../dir", &st), -EINVAL);
+
+	CHECK(puzzlefs_readdir(pfs, "/dir", print_entry, &count), 0);
+	CHECK(count, 2);
+	CHECK(puzzlefs_readdir(pfs, "/dir", stop, NULL), 42);
+	CHECK(puzzlefs_readdir(pfs, "/dir/file", print_entry, &count), -ENOTDIR);
+
+	/* a little at a time, so the offsets get used */
+	while ((n = puzzlefs_read(pfs, "/dir/file", offset, buf, sizeof(buf))) > 0) {
+		memcpy(content + offset, buf, n);
+		offset += n;
+	}
+	CHECK(n, 0);
+	printf("content %s\n", content);
+	CHECK(puzzlefs_read(pfs, "/dir/file", 1000, buf, sizeof(buf)), 0);
+	CHECK(puzzlefs_read(pfs, "/dir", 0, buf, sizeof(buf)), -ENOTDIR);
+
+	/* big is in 4096 byte chunks; one read has to get all of the ones it spans */
+	CHECK(puzzlefs_read(pfs, "/big", 4000, big, sizeof(big)), sizeof(big));
+	for (i = 0; i < sizeof(big); i++)
+		CHECK(big[i], (4000 + i) % 251);
+	/* and stops at the end of the file */
+	CHECK(puzzlefs_read(pfs, "/big", 3 * 4096, big, sizeof(big)), 100);
+	printf("big ok\n");
+
+	puzzlefs_close(pfs);
+	return 0;
+}
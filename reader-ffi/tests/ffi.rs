@@ -0,0 +1,103 @@
+// builds ffi.c against libreader_ffi.a and include/puzzlefs.h, the way a C program would use them,
+// and has it read an image
+use std::env;
+use std::fs;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+use std::process::Command;
+
+use tempfile::tempdir;
+
+use builder::BuildOptions;
+use format::{ChunkingAlgorithm, ChunkingConfig};
+use oci::Image;
+
+#[test]
+fn read_through_ffi() {
+    let dir = tempdir().unwrap();
+    let rootfs = dir.path().join("rootfs");
+    fs::create_dir_all(rootfs.join("dir")).unwrap();
+    fs::write(rootfs.join("dir/file"), b"meshuggah rocks").unwrap();
+    fs::set_permissions(rootfs.join("dir/file"), fs::Permissions::from_mode(0o640)).unwrap();
+    fs::write(rootfs.join("dir/other"), b"").unwrap();
+    // a few chunks' worth, so that reads go across their ends
+    let big = (0..3 * 4096 + 100)
+        .map(|i| (i % 251) as u8)
+        .collect::<Vec<_>>();
+    fs::write(rootfs.join("big"), big).unwrap();
+    let oci_dir = dir.path().join("oci");
+    let image = Image::new(&oci_dir).unwrap();
+    let options = BuildOptions {
+        chunking: ChunkingConfig {
+            min: 0,
+            avg: 4096,
+            max: 0,
+            algo: ChunkingAlgorithm::Fixed,
+        },
+        ..BuildOptions::default()
+    };
+    let desc = builder::build_initial_rootfs_with_options(&rootfs, &image, &options).unwrap();
+    image.add_tag("test".to_string(), desc).unwrap();
+
+    // the library a test gets built against is in target/<profile>/deps with a hash in its name,
+    // so build it the way a C program's build would, which leaves it at
+    // target/<profile>/libreader_ffi.a
+    let profile_dir = env::current_exe()
+        .unwrap()
+        .parent()
+        .unwrap()
+        .parent()
+        .unwrap()
+        .to_path_buf();
+    let mut cargo = Command::new(env!("CARGO"));
+    cargo
+        .args(&["build", "-p", "reader-ffi", "--lib"])
+        .env("CARGO_TARGET_DIR", profile_dir.parent().unwrap());
+    if profile_dir.ends_with("release") {
+        cargo.arg("--release");
+    }
+    assert!(cargo.status().unwrap().success());
+    let lib = profile_dir.join("libreader_ffi.a");
+    let manifest_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let program = dir.path().join("ffi");
+    let status = Command::new("cc")
+        .arg("-Wall")
+        .arg("-I")
+        .arg(manifest_dir.join("include"))
+        .arg(manifest_dir.join("tests/ffi.c"))
+        .arg(&lib)
+        .args(&[
+            "-lfuse",
+            "-lzstd",
+            "-lxxhash",
+            "-lpthread",
+            "-ldl",
+            "-lm",
+            "-o",
+        ])
+        .arg(&program)
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let output = Command::new(&program)
+        .arg(&oci_dir)
+        .arg("test")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "{}{}",
+        stdout,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let uid = fs::metadata(rootfs.join("dir/file")).unwrap().uid();
+    assert_eq!(
+        stdout,
+        format!(
+            "size 15 mode 640 uid {}\nentry file\nentry other\ncontent meshuggah rocks\nbig ok\n",
+            uid
+        )
+    );
+}
@@ -0,0 +1,57 @@
+// how long it takes to look up every entry of a big directory, through the lookup index vs. by
+// scanning the directory's entries each time. run with cargo bench -p reader.
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::time::Instant;
+
+use tempfile::tempdir;
+
+use builder::build_initial_rootfs;
+use oci::Image;
+use reader::PuzzleFS;
+
+const ENTRIES: usize = 10_000;
+const ROUNDS: usize = 3;
+// scanning means decoding the whole directory every time, so only time it on a sample
+const SCAN_SAMPLE: usize = 100;
+
+// returns lookups per second
+fn time<F: FnMut(&OsStr)>(names: &[OsString], mut lookup: F) -> f64 {
+    let start = Instant::now();
+    for name in names {
+        lookup(name);
+    }
+    names.len() as f64 / start.elapsed().as_secs_f64()
+}
+
+fn main() {
+    let dir = tempdir().unwrap();
+    let rootfs = dir.path().join("rootfs");
+    fs::create_dir_all(&rootfs).unwrap();
+    let names = (0..ENTRIES)
+        .map(|i| OsString::from(format!("file-{}", i)))
+        .collect::<Vec<_>>();
+    for name in &names {
+        fs::write(rootfs.join(name), b"").unwrap();
+    }
+    let image = Image::new(&dir.path().join("oci")).unwrap();
+    let desc = build_initial_rootfs(&rootfs, &image).unwrap();
+    image.add_tag("bench".to_string(), desc).unwrap();
+    let mut pfs = PuzzleFS::open(&image, "bench").unwrap();
+
+    let sample = names
+        .iter()
+        .step_by(ENTRIES / SCAN_SAMPLE)
+        .cloned()
+        .collect::<Vec<_>>();
+    for _ in 0..ROUNDS {
+        let scan = time(&sample, |name| {
+            pfs.find_inode(1).unwrap().dir_lookup(name).unwrap();
+        });
+        let index = time(&names, |name| {
+            pfs.lookup(1, name).unwrap();
+        });
+        println!("scan:  {:.0} lookups/s", scan);
+        println!("index: {:.0} lookups/s", index);
+    }
+}
@@ -0,0 +1,67 @@
+// how long it takes to open a synthetic image with a million inodes and get at one file in it,
+// finding inodes in the metadata blob as they're needed, compared to decoding the whole inode
+// table first, which is what finding any inode used to cost. set PUZZLEFS_BENCH_INODES for a
+// smaller image. run with cargo bench -p reader --bench open.
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use tempfile::tempdir;
+
+use builder::build_initial_rootfs;
+use oci::Image;
+use reader::PuzzleFS;
+
+const INODES: usize = 1_000_000;
+const PER_DIR: usize = 1000;
+const ROUNDS: usize = 3;
+
+fn time<F: FnMut()>(mut f: F) -> Duration {
+    let start = Instant::now();
+    f();
+    start.elapsed()
+}
+
+fn main() {
+    let inodes = env::var("PUZZLEFS_BENCH_INODES")
+        .map(|n| n.parse().unwrap())
+        .unwrap_or(INODES);
+    let dir = tempdir().unwrap();
+    let rootfs = dir.path().join("rootfs");
+    let dirs = (inodes + PER_DIR - 1) / PER_DIR;
+    for d in 0..dirs {
+        let sub = rootfs.join(format!("dir-{}", d));
+        fs::create_dir_all(&sub).unwrap();
+        for f in 0..PER_DIR.min(inodes - d * PER_DIR) {
+            fs::write(sub.join(format!("file-{}", f)), b"").unwrap();
+        }
+    }
+    let image = Image::new(&dir.path().join("oci")).unwrap();
+    let start = Instant::now();
+    let desc = build_initial_rootfs(&rootfs, &image).unwrap();
+    image.add_tag("bench".to_string(), desc).unwrap();
+    println!("built {} inodes in {:?}", inodes, start.elapsed());
+    // the files aren't needed any more, and there are a lot of them
+    fs::remove_dir_all(&rootfs).unwrap();
+
+    let last = Path::new(&format!("dir-{}", dirs - 1)).join("file-0");
+    for _ in 0..ROUNDS {
+        let eager = time(|| {
+            let rootfs = image
+                .open_rootfs_blob::<compression::Noop>("bench")
+                .unwrap();
+            for md in rootfs.metadatas.iter() {
+                let mut blob = image.open_rootfs_metadata(md).unwrap();
+                assert!(!blob.read_inodes().unwrap().is_empty());
+            }
+        });
+        let lazy = time(|| {
+            let mut pfs = PuzzleFS::open(&image, "bench").unwrap();
+            let ino = pfs.lookup_path(&last).unwrap();
+            pfs.find_inode(ino).unwrap();
+        });
+        println!("decoding every inode: {:?}", eager);
+        println!("open and look up one file: {:?}", lazy);
+    }
+}
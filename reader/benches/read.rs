@@ -0,0 +1,139 @@
+// how fast a file can be read through PuzzleFile, sequentially and at random offsets, with the
+// chunks it needs already in the chunk cache, with them not there yet, and with a cache too small
+// to hold them. the data is generated from a fixed seed, so every run reads the same image. run
+// with cargo bench -p reader.
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::time::Instant;
+
+use tempfile::tempdir;
+
+use builder::{build_initial_rootfs_with_options, BuildOptions, ChunkCompression};
+use oci::Image;
+use reader::{PuzzleFS, PuzzleFile, DEFAULT_CACHE_CAPACITY};
+
+const FILE_SIZE: usize = 32 * 1024 * 1024;
+const SEQUENTIAL_READ_SIZE: usize = 128 * 1024;
+const RANDOM_READ_SIZE: usize = 4096;
+const RANDOM_READS: usize = 500;
+const ROUNDS: usize = 3;
+// a few chunks' worth, so random reads mostly miss
+const SMALL_CACHE: u64 = 512 * 1024;
+
+// words picked by a xorshift, so zstd has something to do but the data isn't all the same
+fn dataset() -> Vec<u8> {
+    const WORDS: &[&str] = &["meshuggah ", "rocks ", "puzzle ", "fs ", "chunk ", "\n"];
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    let mut data = Vec::with_capacity(FILE_SIZE + 16);
+    while data.len() < FILE_SIZE {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        data.extend_from_slice(WORDS[(state % WORDS.len() as u64) as usize].as_bytes());
+    }
+    data.truncate(FILE_SIZE);
+    data
+}
+
+fn offsets() -> Vec<u64> {
+    let mut state = 0x9e37_79b9_7f4a_7c15_u64;
+    (0..RANDOM_READS)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state % (FILE_SIZE - RANDOM_READ_SIZE) as u64
+        })
+        .collect()
+}
+
+// returns MB/s
+fn sequential(f: &mut PuzzleFile) -> f64 {
+    let mut buf = vec![0_u8; SEQUENTIAL_READ_SIZE];
+    f.seek(SeekFrom::Start(0)).unwrap();
+    let start = Instant::now();
+    let mut total = 0;
+    loop {
+        let n = f.read(&mut buf).unwrap();
+        if n == 0 {
+            break;
+        }
+        total += n;
+    }
+    assert_eq!(total, FILE_SIZE);
+    total as f64 / (1024.0 * 1024.0) / start.elapsed().as_secs_f64()
+}
+
+fn random(f: &mut PuzzleFile, offsets: &[u64]) -> f64 {
+    let mut buf = vec![0_u8; RANDOM_READ_SIZE];
+    let start = Instant::now();
+    for &offset in offsets {
+        f.seek(SeekFrom::Start(offset)).unwrap();
+        f.read_exact(&mut buf).unwrap();
+    }
+    (offsets.len() * RANDOM_READ_SIZE) as f64 / (1024.0 * 1024.0) / start.elapsed().as_secs_f64()
+}
+
+fn main() {
+    let dir = tempdir().unwrap();
+    let rootfs = dir.path().join("rootfs");
+    fs::create_dir_all(&rootfs).unwrap();
+    fs::write(rootfs.join("file"), dataset()).unwrap();
+    let offsets = offsets();
+
+    // uncompressed blobs are mapped rather than cached, so for them the cache shouldn't matter
+    for (name, compression) in &[
+        ("uncompressed", ChunkCompression::None),
+        ("zstd", ChunkCompression::Zstd { level: 3 }),
+    ] {
+        let image = Image::new(&dir.path().join(name)).unwrap();
+        let options = BuildOptions {
+            compression: *compression,
+            ..BuildOptions::default()
+        };
+        let desc = build_initial_rootfs_with_options(&rootfs, &image, &options).unwrap();
+        image.add_tag("bench".to_string(), desc).unwrap();
+
+        // (what's measured, cache capacity, whether the file is read through once first, whether
+        // the reads are random)
+        let cases: &[(&str, u64, bool, bool)] = &[
+            (
+                "sequential, cold cache",
+                DEFAULT_CACHE_CAPACITY,
+                false,
+                false,
+            ),
+            (
+                "sequential, warm cache",
+                DEFAULT_CACHE_CAPACITY,
+                true,
+                false,
+            ),
+            ("random, cold cache", DEFAULT_CACHE_CAPACITY, false, true),
+            ("random, warm cache", DEFAULT_CACHE_CAPACITY, true, true),
+            ("random, small cache", SMALL_CACHE, true, true),
+            ("random, no cache", 0, false, true),
+        ];
+        for &(case, capacity, warm, is_random) in cases {
+            let mut best = 0.0_f64;
+            for _ in 0..ROUNDS {
+                // nothing else has the image open, so this starts with an empty cache
+                let mut pfs =
+                    PuzzleFS::open_with_cache_capacity(&image, "bench", capacity).unwrap();
+                let ino = pfs.lookup(1, OsStr::new("file")).unwrap();
+                let mut f = pfs.open_file(ino).unwrap();
+                if warm {
+                    sequential(&mut f);
+                }
+                let speed = if is_random {
+                    random(&mut f, &offsets)
+                } else {
+                    sequential(&mut f)
+                };
+                best = best.max(speed);
+            }
+            println!("{} chunks, {}: {:.1} MB/s", name, case, best);
+        }
+    }
+}
@@ -0,0 +1,114 @@
+// how fast a big file can be read from start to finish through a mount when every chunk takes a
+// while to fetch (e.g. from a store across the network), with and without fetching chunks ahead of
+// the reads. run with cargo bench -p reader.
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::Arc;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use tempfile::tempdir;
+
+use builder::{build_initial_rootfs_with_options, BuildOptions};
+use compression::Decompressor;
+use format::{ChunkingAlgorithm, ChunkingConfig};
+use oci::{BlobStore, Digest, FsBlobStore, Image, Index};
+use reader::{mount_with_options, MountOption, MountOptions, DEFAULT_READAHEAD};
+
+const CHUNK_SIZE: u64 = 1024 * 1024;
+const FILE_SIZE: usize = 64 * CHUNK_SIZE as usize;
+const LATENCY: Duration = Duration::from_millis(20);
+// the most the kernel asks a fuse filesystem for at once by default
+const READ_SIZE: usize = 128 * 1024;
+
+// blobs that can't be mapped, and take LATENCY to start reading
+struct SlowStore(FsBlobStore);
+
+impl BlobStore for SlowStore {
+    fn get_blob(&self, digest: &Digest) -> io::Result<Box<dyn Decompressor>> {
+        sleep(LATENCY);
+        self.0.get_blob(digest)
+    }
+
+    fn put_blob(&self, digest: &Digest, blob: &mut dyn io::Read) -> io::Result<()> {
+        self.0.put_blob(digest, blob)
+    }
+
+    fn has_blob(&self, digest: &Digest) -> bool {
+        self.0.has_blob(digest)
+    }
+
+    fn delete_blob(&self, digest: &Digest) -> io::Result<()> {
+        self.0.delete_blob(digest)
+    }
+
+    fn list_blobs(&self) -> io::Result<Vec<(Digest, u64)>> {
+        self.0.list_blobs()
+    }
+
+    fn get_index(&self) -> format::Result<Index> {
+        self.0.get_index()
+    }
+
+    fn put_index(&self, index: &Index) -> format::Result<()> {
+        self.0.put_index(index)
+    }
+}
+
+// returns MB/s
+fn read_file(path: &Path) -> f64 {
+    let mut f = fs::File::open(path).unwrap();
+    let mut buf = vec![0_u8; READ_SIZE];
+    let mut total = 0;
+    let start = Instant::now();
+    loop {
+        let n = f.read(&mut buf).unwrap();
+        if n == 0 {
+            break;
+        }
+        total += n;
+    }
+    assert_eq!(total, FILE_SIZE);
+    FILE_SIZE as f64 / (1024.0 * 1024.0) / start.elapsed().as_secs_f64()
+}
+
+fn main() {
+    let dir = tempdir().unwrap();
+    let rootfs = dir.path().join("rootfs");
+    fs::create_dir_all(&rootfs).unwrap();
+    let data = (0..FILE_SIZE)
+        .map(|i| (i / 4096 % 251) as u8)
+        .collect::<Vec<_>>();
+    fs::write(rootfs.join("file"), data).unwrap();
+
+    let options = BuildOptions {
+        chunking: ChunkingConfig {
+            min: 0,
+            avg: CHUNK_SIZE,
+            max: 0,
+            algo: ChunkingAlgorithm::Fixed,
+        },
+        ..BuildOptions::default()
+    };
+    for &readahead in &[0, DEFAULT_READAHEAD] {
+        // a fresh image each time, so nothing is cached from the last round
+        let oci_dir = dir.path().join(format!("oci-{}", readahead));
+        Image::new(&oci_dir).unwrap();
+        let image = Image::with_store(Arc::new(SlowStore(FsBlobStore::buffered(&oci_dir))));
+        let desc = build_initial_rootfs_with_options(&rootfs, &image, &options).unwrap();
+        image.add_tag("bench".to_string(), desc).unwrap();
+
+        let mountpoint = tempdir().unwrap();
+        let mount_options = MountOptions {
+            options: vec![MountOption::Readahead(readahead)],
+            ..MountOptions::default()
+        };
+        let _bg = mount_with_options(&image, "bench", mountpoint.path(), &mount_options).unwrap();
+        println!(
+            "readahead={}: {:.0} MB/s",
+            readahead,
+            read_file(&mountpoint.path().join("file"))
+        );
+    }
+}
@@ -0,0 +1,269 @@
+use std::cmp::min;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use tokio::task::JoinError;
+
+use format::{BlobRef, RawDigest, Result, WireFormatError};
+use oci::{ChunkStream, Digest, Image};
+
+use crate::cache::ChunkCache;
+use crate::puzzlefs::{chunk_reads, ChunkRead, Inode, PuzzleFS};
+
+pub type ChunkFuture = Pin<Box<dyn Future<Output = Result<Vec<u8>>> + Send>>;
+
+/// Somewhere chunk blobs can be fetched from without tying up a thread for each one, e.g. a
+/// network backed store.
+pub trait AsyncChunkStore: Send + Sync {
+    /// Fetches the whole (uncompressed) blob `chunk` lives in.
+    fn fetch(&self, chunk: BlobRef) -> ChunkFuture;
+}
+
+/// The chunks of an image, read on tokio's blocking thread pool. Reads spanning several chunks
+/// still get to decompress them all at once.
+pub struct OciChunkStore {
+    image: Image,
+}
+
+impl OciChunkStore {
+    pub fn new(image: &Image) -> OciChunkStore {
+        OciChunkStore {
+            image: image.clone(),
+        }
+    }
+}
+
+impl AsyncChunkStore for OciChunkStore {
+    fn fetch(&self, chunk: BlobRef) -> ChunkFuture {
+        let image = self.image.clone();
+        Box::pin(async move {
+            tokio::task::spawn_blocking(move || image.read_chunk_blob(chunk))
+                .await
+                .map_err(join_error)?
+        })
+    }
+}
+
+fn join_error(e: JoinError) -> WireFormatError {
+    io::Error::new(io::ErrorKind::Other, e).into()
+}
+
+impl<'a> PuzzleFS<'a> {
+    /// Reads part of a file like a PuzzleFile would, except that all the chunks the read needs
+    /// are fetched from `store` at the same time. This has to run on a tokio runtime.
+    pub async fn read_async(
+        &self,
+        store: &Arc<dyn AsyncChunkStore>,
+        inode: &Inode,
+        offset: u64,
+        data: &mut [u8],
+    ) -> Result<usize> {
+        file_read(
+            self.oci,
+            store,
+            None,
+            &self.cache,
+            inode,
+            offset as usize,
+            data,
+        )
+        .await
+    }
+}
+
+// the bytes of a chunk blob, wherever they came from
+type BlobData = Arc<dyn AsRef<[u8]> + Send + Sync>;
+
+// with a stream from `oci`, reads that only need part of a compressed chunk go through it like
+// the synchronous file_read() does, decompressing only as far as they need, and only whole chunks
+// get fetched from `store`
+pub(crate) async fn file_read(
+    oci: &Image,
+    store: &Arc<dyn AsyncChunkStore>,
+    mut stream: Option<&mut ChunkStream<'_>>,
+    cache: &ChunkCache,
+    inode: &Inode,
+    offset: usize,
+    data: &mut [u8],
+) -> Result<usize> {
+    let reads = chunk_reads(inode, offset, data.len())?;
+    let streamed = |read: &ChunkRead, stream: &Option<&mut ChunkStream>| {
+        stream.is_some() && read.partial && read.blob.map_or(false, |b| b.compressed)
+    };
+
+    // start fetching everything that isn't cached (or mapped) before waiting for any of it. a file
+    // can use the same blob more than once, but it only needs fetching once.
+    let mut blobs: HashMap<RawDigest, BlobData> = HashMap::new();
+    let mut fetches = HashMap::new();
+    for blob in reads
+        .iter()
+        .filter(|r| !streamed(r, &stream))
+        .filter_map(|r| r.blob)
+    {
+        let digest = Digest::try_from(blob)?.underlying();
+        if blobs.contains_key(&digest) || fetches.contains_key(&digest) {
+            continue;
+        }
+        if let Some(map) = oci.map_chunk_blob(blob)? {
+            blobs.insert(digest, map);
+            continue;
+        }
+        match cache.get(&digest) {
+            Some(blob) => {
+                blobs.insert(digest, blob);
+            }
+            None => {
+                fetches.insert(digest, tokio::spawn(store.fetch(blob)));
+            }
+        }
+    }
+    for (digest, fetch) in fetches {
+        let blob = Arc::new(fetch.await.map_err(join_error)??);
+        cache.insert(digest, blob.clone());
+        blobs.insert(digest, blob);
+    }
+
+    let mut buf_offset = 0;
+    for read in reads {
+        let chunk = match read.blob {
+            Some(chunk) => chunk,
+            None => {
+                data[read.buf.clone()].iter_mut().for_each(|b| *b = 0);
+                buf_offset += read.buf.len();
+                continue;
+            }
+        };
+        let n = if streamed(&read, &stream) {
+            let stream = stream.as_deref_mut().unwrap();
+            cache.fill_part_from_chunk(
+                stream,
+                chunk,
+                read.addl_offset,
+                &mut data[read.buf.clone()],
+            )?
+        } else {
+            let blob = (*blobs[&Digest::try_from(chunk)?.underlying()]).as_ref();
+            let start = min((chunk.offset + read.addl_offset) as usize, blob.len());
+            let n = min(read.buf.len(), blob.len() - start);
+            data[read.buf.start..read.buf.start + n].copy_from_slice(&blob[start..start + n]);
+            n
+        };
+        buf_offset += n;
+        if n < read.buf.len() {
+            // the blob is shorter than its chunk claims; don't read the next chunk into the hole
+            break;
+        }
+    }
+    Ok(buf_offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use tempfile::tempdir;
+
+    use builder::{build_initial_rootfs_with_options, BuildOptions};
+    use format::{ChunkingAlgorithm, ChunkingConfig};
+    use oci::FsBlobStore;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Fetches {
+        total: AtomicUsize,
+        in_flight: AtomicUsize,
+        max_in_flight: AtomicUsize,
+    }
+
+    // serves chunks from an image the way a far away store would: slowly
+    struct SlowStore {
+        image: Image,
+        fetches: Arc<Fetches>,
+    }
+
+    impl AsyncChunkStore for SlowStore {
+        fn fetch(&self, chunk: BlobRef) -> ChunkFuture {
+            let image = self.image.clone();
+            let fetches = self.fetches.clone();
+            Box::pin(async move {
+                fetches.total.fetch_add(1, Ordering::SeqCst);
+                let now = fetches.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                fetches.max_in_flight.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                fetches.in_flight.fetch_sub(1, Ordering::SeqCst);
+                image.read_chunk_blob(chunk)
+            })
+        }
+    }
+
+    #[test]
+    fn test_read_fetches_chunks_concurrently() {
+        let dir = tempdir().unwrap();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs).unwrap();
+        // 64 distinct chunks
+        let data = (0..64 * 4096)
+            .map(|i| (i * 7 % 251) as u8)
+            .collect::<Vec<_>>();
+        fs::write(rootfs.join("big"), &data).unwrap();
+
+        let oci_dir = dir.path().join("oci");
+        Image::new(&oci_dir).unwrap();
+        // uncompressed blobs would otherwise be mapped rather than fetched
+        let image = Image::with_store(Arc::new(FsBlobStore::buffered(&oci_dir)));
+        let options = BuildOptions {
+            chunking: ChunkingConfig {
+                min: 0,
+                avg: 4096,
+                max: 0,
+                algo: ChunkingAlgorithm::Fixed,
+            },
+            ..BuildOptions::default()
+        };
+        let desc = build_initial_rootfs_with_options(&rootfs, &image, &options).unwrap();
+        image.add_tag("test".to_string(), desc).unwrap();
+        let mut pfs = PuzzleFS::open(&image, "test").unwrap();
+        let inode = pfs.find_inode(2).unwrap();
+
+        let fetches = Arc::new(Fetches::default());
+        let store: Arc<dyn AsyncChunkStore> = Arc::new(SlowStore {
+            image: image.clone(),
+            fetches: fetches.clone(),
+        });
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+
+        let mut buf = vec![0_u8; data.len()];
+        let n = runtime
+            .block_on(pfs.read_async(&store, &inode, 0, &mut buf))
+            .unwrap();
+        assert_eq!(n, data.len());
+        assert_eq!(buf, data);
+        assert_eq!(fetches.total.load(Ordering::SeqCst), 64);
+        assert!(fetches.max_in_flight.load(Ordering::SeqCst) > 1);
+
+        // everything is cached now, and reads that don't line up with chunks work too
+        let mut buf = vec![0_u8; 10000];
+        let n = runtime
+            .block_on(pfs.read_async(&store, &inode, 4000, &mut buf))
+            .unwrap();
+        assert_eq!(n, buf.len());
+        assert_eq!(buf, &data[4000..14000]);
+        assert_eq!(fetches.total.load(Ordering::SeqCst), 64);
+
+        // short at EOF
+        let n = runtime
+            .block_on(pfs.read_async(&store, &inode, data.len() as u64 - 10, &mut buf))
+            .unwrap();
+        assert_eq!(n, 10);
+    }
+}
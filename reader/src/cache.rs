@@ -0,0 +1,455 @@
+use std::cmp::min;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryFrom;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, Weak};
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use format::{BlobRef, RawDigest, Result};
+use oci::{ChunkStream, Digest, Image};
+
+// the same file (or the same chunk in different files) tends to get read over and over again, so
+// by default keep a decent amount of it around.
+pub const DEFAULT_CACHE_CAPACITY: u64 = 256 * 1024 * 1024;
+
+/// An LRU cache of decompressed chunk blobs, keyed by digest. A hit doesn't touch the image at all,
+/// which in particular means we don't re-decompress zstd chunks on every read.
+pub struct ChunkCache {
+    capacity: u64,
+    lru: Mutex<Lru>,
+    // signalled whenever a fetch someone may be waiting for is done
+    fetched: Condvar,
+    // the chunks verify() found to match their digests
+    verified: Mutex<HashSet<RawDigest>>,
+    counters: Counters,
+}
+
+/// How well a chunk cache has been doing since it was made. Blobs that are mapped rather than
+/// cached don't count, since the cache has nothing to do with reading them.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// How many chunks were dropped to make room for others.
+    pub evictions: u64,
+    /// How much chunk data (decompressed) had to be read from the image, including what was fetched
+    /// ahead of time.
+    pub bytes_fetched: u64,
+}
+
+// reads can come from any number of threads at once, without taking the lock for all of them
+#[derive(Default)]
+struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+    bytes_fetched: AtomicU64,
+}
+
+// which image a shared cache's chunks come from: images on disk are the same image whichever
+// Image they were opened with, other stores only if it's the very same store
+#[derive(PartialEq, Eq, Hash)]
+enum ImageKey {
+    Dir(PathBuf),
+    Store(usize),
+}
+
+// the caches of everything that has an image open, by image and capacity, so opening it again can
+// share
+type SharedCaches = HashMap<(ImageKey, u64), Weak<ChunkCache>>;
+static SHARED: Lazy<Mutex<SharedCaches>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Default)]
+struct Lru {
+    // each blob with when it was last used
+    blobs: HashMap<RawDigest, (Arc<Vec<u8>>, u64)>,
+    // the blobs by when they were last used, least recently used first; a Vec or VecDeque would
+    // need a linear search (under the lock) to move a blob to the back on every hit
+    order: BTreeMap<u64, RawDigest>,
+    // counts uses, for the above
+    clock: u64,
+    size: u64,
+    // the blobs that are being fetched ahead of time, which get() waits for instead of having them
+    // read a second time
+    pending: HashSet<RawDigest>,
+}
+
+impl ChunkCache {
+    /// A cache holding at most `capacity` bytes of chunk data; zero disables caching.
+    pub fn new(capacity: u64) -> ChunkCache {
+        ChunkCache {
+            capacity,
+            lru: Mutex::new(Lru::default()),
+            fetched: Condvar::new(),
+            verified: Mutex::new(HashSet::new()),
+            counters: Counters::default(),
+        }
+    }
+
+    /// The cache of whatever else has `image` open with the same capacity, e.g. another mount of
+    /// it, or a new one if there isn't anything. Chunks are keyed by their digest, so everything
+    /// reading from the same blobs can make use of each other's reads.
+    pub fn shared(image: &Image, capacity: u64) -> Arc<ChunkCache> {
+        let image = match image.blob_path() {
+            Some(path) => ImageKey::Dir(path),
+            None => ImageKey::Store(Arc::as_ptr(image.store()) as *const u8 as usize),
+        };
+        let key = (image, capacity);
+        let mut shared = SHARED.lock().unwrap();
+        shared.retain(|_, cache| cache.strong_count() > 0);
+        if let Some(cache) = shared.get(&key).and_then(Weak::upgrade) {
+            return cache;
+        }
+        let cache = Arc::new(ChunkCache::new(capacity));
+        shared.insert(key, Arc::downgrade(&cache));
+        cache
+    }
+
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    /// Counts of what the cache has done so far. A shared cache counts the reads of everything
+    /// sharing it.
+    pub fn stats(&self) -> CacheStats {
+        let c = &self.counters;
+        CacheStats {
+            hits: c.hits.load(Ordering::Relaxed),
+            misses: c.misses.load(Ordering::Relaxed),
+            evictions: c.evictions.load(Ordering::Relaxed),
+            bytes_fetched: c.bytes_fetched.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Like Image::fill_from_chunk(), but serves the data from the cache if it can.
+    pub fn fill_from_chunk(
+        &self,
+        oci: &Image,
+        chunk: BlobRef,
+        addl_offset: u64,
+        buf: &mut [u8],
+    ) -> Result<usize> {
+        // blobs the image can map are served straight from the page cache, no need to keep a copy
+        if oci.map_chunk_blob(chunk)?.is_some() {
+            return oci.fill_from_chunk(chunk, addl_offset, buf);
+        }
+        if self.capacity == 0 {
+            self.counters.misses.fetch_add(1, Ordering::Relaxed);
+            let n = oci.fill_from_chunk(chunk, addl_offset, buf)?;
+            self.counters
+                .bytes_fetched
+                .fetch_add(n as u64, Ordering::Relaxed);
+            return Ok(n);
+        }
+
+        let digest = Digest::try_from(chunk)?.underlying();
+        let data = match self.get(&digest) {
+            Some(data) => data,
+            None => {
+                let data = Arc::new(oci.read_chunk_blob(chunk)?);
+                self.insert(digest, data.clone());
+                data
+            }
+        };
+
+        Ok(copy_out(&data, chunk.offset + addl_offset, buf))
+    }
+
+    /// Like fill_from_chunk(), for reads that only need part of a chunk. A compressed chunk that
+    /// isn't cached is decompressed through `stream` only as far as the read needs, rather than
+    /// all of it into the cache, and reading on through it with the same stream carries on from
+    /// where this read stopped.
+    pub fn fill_part_from_chunk(
+        &self,
+        stream: &mut ChunkStream,
+        chunk: BlobRef,
+        addl_offset: u64,
+        buf: &mut [u8],
+    ) -> Result<usize> {
+        if !chunk.compressed {
+            return self.fill_from_chunk(stream.image(), chunk, addl_offset, buf);
+        }
+        let digest = Digest::try_from(chunk)?.underlying();
+        if let Some(data) = self.get(&digest) {
+            return Ok(copy_out(&data, chunk.offset + addl_offset, buf));
+        }
+        let n = stream.fill_from_chunk(chunk, addl_offset, buf)?;
+        self.counters
+            .bytes_fetched
+            .fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+
+    /// Checks that a chunk's blob hashes to its digest, unless it already did: the blobs of an
+    /// image never change, so each one only needs checking the first time it's read.
+    pub fn verify(&self, oci: &Image, chunk: BlobRef) -> Result<()> {
+        let digest = Digest::try_from(chunk)?.underlying();
+        if self.verified.lock().unwrap().contains(&digest) {
+            return Ok(());
+        }
+        oci.verify_blob(chunk)?;
+        self.verified.lock().unwrap().insert(digest);
+        Ok(())
+    }
+
+    pub(crate) fn get(&self, digest: &RawDigest) -> Option<Arc<Vec<u8>>> {
+        let mut lru = self.lru.lock().unwrap();
+        while lru.pending.contains(digest) {
+            lru = self.fetched.wait(lru).unwrap();
+        }
+        let lru = &mut *lru;
+        let (data, used) = match lru.blobs.get_mut(digest) {
+            Some(entry) => entry,
+            None => {
+                self.counters.misses.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+        };
+        self.counters.hits.fetch_add(1, Ordering::Relaxed);
+        lru.order.remove(used);
+        lru.clock += 1;
+        *used = lru.clock;
+        lru.order.insert(lru.clock, *digest);
+        Some(data.clone())
+    }
+
+    /// Says that `digest` is about to be fetched into the cache, so get() should wait for it.
+    /// Returns false if it is already cached or on its way, in which case there's nothing to do.
+    pub(crate) fn start_fetch(&self, digest: RawDigest) -> bool {
+        let mut lru = self.lru.lock().unwrap();
+        !lru.blobs.contains_key(&digest) && lru.pending.insert(digest)
+    }
+
+    /// Ends a fetch start_fetch() announced; `data` is None if it failed.
+    pub(crate) fn finish_fetch(&self, digest: RawDigest, data: Option<Arc<Vec<u8>>>) {
+        let mut lru = self.lru.lock().unwrap();
+        lru.pending.remove(&digest);
+        if let Some(data) = data {
+            self.insert_locked(&mut lru, digest, data);
+        }
+        self.fetched.notify_all();
+    }
+
+    pub(crate) fn insert(&self, digest: RawDigest, data: Arc<Vec<u8>>) {
+        let mut lru = self.lru.lock().unwrap();
+        self.insert_locked(&mut lru, digest, data)
+    }
+
+    fn insert_locked(&self, lru: &mut Lru, digest: RawDigest, data: Arc<Vec<u8>>) {
+        let len = data.len() as u64;
+        self.counters
+            .bytes_fetched
+            .fetch_add(len, Ordering::Relaxed);
+        if len > self.capacity {
+            return;
+        }
+
+        // someone else may have raced us to read it
+        if lru.blobs.contains_key(&digest) {
+            return;
+        }
+        while lru.size + len > self.capacity {
+            let (used, oldest) = match lru.order.iter().next() {
+                Some((&used, &oldest)) => (used, oldest),
+                None => break,
+            };
+            lru.order.remove(&used);
+            if let Some((evicted, _)) = lru.blobs.remove(&oldest) {
+                lru.size -= evicted.len() as u64;
+                self.counters.evictions.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        lru.size += len;
+        lru.clock += 1;
+        lru.blobs.insert(digest, (data, lru.clock));
+        lru.order.insert(lru.clock, digest);
+    }
+}
+
+// copies what there is of a cached blob from offset on into buf
+fn copy_out(data: &[u8], offset: u64, buf: &mut [u8]) -> usize {
+    let start = min(offset as usize, data.len());
+    let n = min(buf.len(), data.len() - start);
+    buf[..n].copy_from_slice(&data[start..start + n]);
+    n
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use tempfile::tempdir;
+
+    use oci::{media_types, FsBlobStore};
+
+    use super::*;
+
+    // blobs that can be mapped skip the cache, so don't let them be
+    fn buffered(oci_dir: &Path) -> Image {
+        Image::new(oci_dir).unwrap();
+        Image::with_store(Arc::new(FsBlobStore::buffered(oci_dir)))
+    }
+
+    fn put(image: &Image, data: &str) -> BlobRef {
+        let desc = image
+            .put_blob::<_, compression::Noop, media_types::Chunk>(data.as_bytes())
+            .unwrap();
+        desc.digest.blob_ref(false)
+    }
+
+    fn remove(image: &Image, blob: BlobRef) {
+        let digest = Digest::try_from(blob).unwrap();
+        fs::remove_file(image.blob_path().unwrap().join(digest.to_string())).unwrap();
+    }
+
+    #[test]
+    fn test_hits_skip_the_image() {
+        let dir = tempdir().unwrap();
+        let image = buffered(dir.path());
+        let blob = put(&image, "meshuggah rocks");
+        let cache = ChunkCache::new(1024);
+
+        let mut buf = [0_u8; 5];
+        assert_eq!(
+            cache.fill_from_chunk(&image, blob, 10, &mut buf).unwrap(),
+            5
+        );
+        assert_eq!(&buf, b"rocks");
+
+        // the blob is gone, but we don't need it any more
+        remove(&image, blob);
+        let mut buf = [0_u8; 100];
+        assert_eq!(
+            cache.fill_from_chunk(&image, blob, 0, &mut buf).unwrap(),
+            15
+        );
+        assert_eq!(&buf[..15], b"meshuggah rocks");
+        assert_eq!(
+            cache.fill_from_chunk(&image, blob, 15, &mut buf).unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let dir = tempdir().unwrap();
+        let image = buffered(dir.path());
+        let first = put(&image, "first blob");
+        let second = put(&image, "second blob");
+        let third = put(&image, "third blob!");
+        let too_big = put(&image, &"x".repeat(64));
+
+        // room for two of them
+        let cache = ChunkCache::new(25);
+        let mut buf = [0_u8; 64];
+        cache.fill_from_chunk(&image, first, 0, &mut buf).unwrap();
+        cache.fill_from_chunk(&image, second, 0, &mut buf).unwrap();
+        // make second the least recently used one
+        cache.fill_from_chunk(&image, first, 0, &mut buf).unwrap();
+        cache.fill_from_chunk(&image, third, 0, &mut buf).unwrap();
+        // bigger than the whole cache, so it doesn't evict anything
+        cache.fill_from_chunk(&image, too_big, 0, &mut buf).unwrap();
+
+        remove(&image, first);
+        remove(&image, second);
+        remove(&image, third);
+        remove(&image, too_big);
+        cache.fill_from_chunk(&image, first, 0, &mut buf).unwrap();
+        cache.fill_from_chunk(&image, third, 0, &mut buf).unwrap();
+        cache
+            .fill_from_chunk(&image, second, 0, &mut buf)
+            .unwrap_err();
+        cache
+            .fill_from_chunk(&image, too_big, 0, &mut buf)
+            .unwrap_err();
+
+        // only third had to make room, by pushing out second; the reads that failed still missed
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 3,
+                misses: 6,
+                evictions: 1,
+                bytes_fetched: 10 + 11 + 11 + 64,
+            }
+        );
+    }
+
+    #[test]
+    fn test_lru_order_with_many_blobs() {
+        let digest = |i: u32| RawDigest::new(&i.to_le_bytes());
+        let cache = ChunkCache::new(1000);
+        for i in 0..1000 {
+            cache.insert(digest(i), Arc::new(vec![0]));
+        }
+        // use the even ones again, so the odd ones are the ones that make room for more
+        for i in (0..1000).step_by(2) {
+            assert!(cache.get(&digest(i)).is_some());
+        }
+        for i in 1000..1500 {
+            cache.insert(digest(i), Arc::new(vec![0]));
+        }
+        for i in 0..1500 {
+            assert_eq!(
+                cache.get(&digest(i)).is_some(),
+                i >= 1000 || i % 2 == 0,
+                "{}",
+                i
+            );
+        }
+        assert_eq!(cache.stats().evictions, 500);
+    }
+
+    #[test]
+    fn test_zero_capacity_caches_nothing() {
+        let dir = tempdir().unwrap();
+        let image = buffered(dir.path());
+        let blob = put(&image, "meshuggah rocks");
+        let cache = ChunkCache::new(0);
+
+        let mut buf = [0_u8; 15];
+        cache.fill_from_chunk(&image, blob, 0, &mut buf).unwrap();
+        remove(&image, blob);
+        cache
+            .fill_from_chunk(&image, blob, 0, &mut buf)
+            .unwrap_err();
+    }
+
+    #[test]
+    fn test_shared() {
+        let dir = tempdir().unwrap();
+        let image = buffered(dir.path());
+        let blob = put(&image, "meshuggah rocks");
+        // a second Image of the same directory is still the same image
+        let again = buffered(dir.path());
+        let cache = ChunkCache::shared(&image, 1024);
+        assert!(Arc::ptr_eq(&cache, &ChunkCache::shared(&again, 1024)));
+        assert!(!Arc::ptr_eq(&cache, &ChunkCache::shared(&image, 2048)));
+
+        // what one of them reads the other one gets for free
+        let mut buf = [0_u8; 15];
+        cache.fill_from_chunk(&image, blob, 0, &mut buf).unwrap();
+        remove(&image, blob);
+        ChunkCache::shared(&again, 1024)
+            .fill_from_chunk(&again, blob, 0, &mut buf)
+            .unwrap();
+        assert_eq!(&buf, b"meshuggah rocks");
+
+        // once nobody has it open any more, there's nothing left to share
+        drop(cache);
+        ChunkCache::shared(&again, 1024)
+            .fill_from_chunk(&again, blob, 0, &mut buf)
+            .unwrap_err();
+
+        let mem = Image::with_store(Arc::new(oci::MemBlobStore::default()));
+        let other = Image::with_store(Arc::new(oci::MemBlobStore::default()));
+        let clone = mem.clone();
+        let cache = ChunkCache::shared(&mem, 1024);
+        assert!(Arc::ptr_eq(&cache, &ChunkCache::shared(&clone, 1024)));
+        assert!(!Arc::ptr_eq(&cache, &ChunkCache::shared(&other, 1024)));
+    }
+}
@@ -0,0 +1,193 @@
+//! Asking a file in a mount for its chunks, the ones `PuzzleFS::chunks_for_inode()` lists, with
+//! an ioctl on it.
+//!
+//! The ioctl's argument is a buffer of however many bytes its request code says. Going in, it
+//! starts with the index of the first chunk wanted. Coming back, it starts with how many chunks
+//! the file has in all and how many of them follow, and then has as many chunks from that one on
+//! as fit, `CHUNK_MAP_ENTRY_SIZE` bytes each: the chunk's offset in the file, its length and its
+//! offset in its blob, and then the blob's digest as a NUL padded string like `sha256:<hex>`,
+//! which is all NULs for a hole. The numbers are all little endian u64s.
+
+use std::cmp::min;
+use std::convert::TryInto;
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+use nix::errno::Errno;
+use nix::sys::ioctl::{ioctl_num_type, SIZEBITS, SIZESHIFT};
+
+use format::{Result, WireFormatError};
+
+use crate::ChunkRange;
+
+const HEADER_SIZE: usize = 16;
+
+// "sha512:", its 128 hex digits and a NUL
+const DIGEST_SIZE: usize = 136;
+
+pub const CHUNK_MAP_ENTRY_SIZE: usize = 24 + DIGEST_SIZE;
+
+// how many chunks chunk_map() asks for at a time; request codes only have 14 bits for the size
+const CHUNKS_PER_CALL: usize = 64;
+
+/// The request code that asks for the chunk map into a buffer of `size` bytes.
+pub fn chunk_map_request(size: usize) -> ioctl_num_type {
+    nix::request_code_readwrite!(b'P', 1, size) as ioctl_num_type
+}
+
+// whether an ioctl the kernel passed along asks for the chunk map, whatever size its buffer is
+pub(crate) fn is_chunk_map_request(cmd: u32) -> bool {
+    let size_mask = ((1 << SIZEBITS) - 1) << SIZESHIFT;
+    cmd as ioctl_num_type & !size_mask == chunk_map_request(0)
+}
+
+fn u64_at(buf: &[u8], at: usize) -> Result<u64> {
+    buf.get(at..at + 8)
+        .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+        .ok_or_else(|| WireFormatError::from_errno(Errno::EINVAL))
+}
+
+// the reply to a request for the chunk map with `request` in its buffer and room for `size` bytes
+// of reply: as many of `chunks` as fit, from the one asked for on
+pub(crate) fn encode_chunk_map(
+    chunks: &[ChunkRange],
+    request: &[u8],
+    size: usize,
+) -> Result<Vec<u8>> {
+    let first = u64_at(request, 0)?;
+    let room = size
+        .checked_sub(HEADER_SIZE)
+        .ok_or_else(|| WireFormatError::from_errno(Errno::EINVAL))?
+        / CHUNK_MAP_ENTRY_SIZE;
+    let start = min(first, chunks.len() as u64) as usize;
+    let wanted = &chunks[start..min(start + room, chunks.len())];
+
+    let mut buf = Vec::with_capacity(HEADER_SIZE + wanted.len() * CHUNK_MAP_ENTRY_SIZE);
+    buf.extend_from_slice(&(chunks.len() as u64).to_le_bytes());
+    buf.extend_from_slice(&(wanted.len() as u64).to_le_bytes());
+    for chunk in wanted {
+        buf.extend_from_slice(&chunk.offset.to_le_bytes());
+        buf.extend_from_slice(&chunk.len.to_le_bytes());
+        buf.extend_from_slice(&chunk.blob_offset.to_le_bytes());
+        let mut digest = [0_u8; DIGEST_SIZE];
+        if let Some(d) = &chunk.digest {
+            let reference = d.reference();
+            digest[..reference.len()].copy_from_slice(reference.as_bytes());
+        }
+        buf.extend_from_slice(&digest);
+    }
+    Ok(buf)
+}
+
+// how many chunks there are in all, and the ones in a reply
+fn decode_chunk_map(buf: &[u8]) -> Result<(u64, Vec<ChunkRange>)> {
+    let total = u64_at(buf, 0)?;
+    let count = u64_at(buf, 8)? as usize;
+    let entries = count
+        .checked_mul(CHUNK_MAP_ENTRY_SIZE)
+        .and_then(|len| buf.get(HEADER_SIZE..HEADER_SIZE.checked_add(len)?))
+        .ok_or_else(|| WireFormatError::from_errno(Errno::EINVAL))?;
+    let chunks = entries
+        .chunks_exact(CHUNK_MAP_ENTRY_SIZE)
+        .map(|entry| {
+            let digest = &entry[24..];
+            let digest = &digest[..digest.iter().position(|b| *b == 0).unwrap_or(DIGEST_SIZE)];
+            let digest = if digest.is_empty() {
+                None
+            } else {
+                let digest = std::str::from_utf8(digest)
+                    .map_err(|_| WireFormatError::from_errno(Errno::EINVAL))?;
+                Some(
+                    digest
+                        .parse()
+                        .map_err(|_| WireFormatError::from_errno(Errno::EINVAL))?,
+                )
+            };
+            Ok(ChunkRange {
+                offset: u64_at(entry, 0)?,
+                len: u64_at(entry, 8)?,
+                blob_offset: u64_at(entry, 16)?,
+                digest,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok((total, chunks))
+}
+
+/// The chunks of a file in a mount, in order, as the mount says they are; the same as what
+/// `PuzzleFS::chunks_for_inode()` would.
+pub fn chunk_map(file: &impl AsRawFd) -> Result<Vec<ChunkRange>> {
+    let mut chunks = Vec::new();
+    let mut buf = vec![0_u8; HEADER_SIZE + CHUNKS_PER_CALL * CHUNK_MAP_ENTRY_SIZE];
+    loop {
+        buf[..8].copy_from_slice(&(chunks.len() as u64).to_le_bytes());
+        let request = chunk_map_request(buf.len());
+        if unsafe { nix::libc::ioctl(file.as_raw_fd(), request, buf.as_mut_ptr()) } < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let (total, more) = decode_chunk_map(&buf)?;
+        let done = more.is_empty();
+        chunks.extend(more);
+        if done || chunks.len() as u64 >= total {
+            return Ok(chunks);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_map_encoding() {
+        let chunks = vec![
+            ChunkRange {
+                offset: 0,
+                len: 10,
+                digest: Some(format!("sha512:{}", "ab".repeat(64)).parse().unwrap()),
+                blob_offset: 5,
+            },
+            ChunkRange {
+                offset: 10,
+                len: 20,
+                digest: None,
+                blob_offset: 0,
+            },
+            ChunkRange {
+                offset: 30,
+                len: 1,
+                digest: Some(format!("sha256:{}", "cd".repeat(32)).parse().unwrap()),
+                blob_offset: 0,
+            },
+        ];
+        let from = |first: u64, size| encode_chunk_map(&chunks, &first.to_le_bytes(), size);
+        let all = from(0, HEADER_SIZE + 3 * CHUNK_MAP_ENTRY_SIZE).unwrap();
+        assert_eq!(decode_chunk_map(&all).unwrap(), (3, chunks.clone()));
+        // as many as fit, from the one asked for on
+        let some = from(1, HEADER_SIZE + CHUNK_MAP_ENTRY_SIZE + 100).unwrap();
+        assert_eq!(some.len(), HEADER_SIZE + CHUNK_MAP_ENTRY_SIZE);
+        assert_eq!(decode_chunk_map(&some).unwrap(), (3, chunks[1..2].to_vec()));
+        assert_eq!(
+            decode_chunk_map(&from(3, 4096).unwrap()).unwrap(),
+            (3, vec![])
+        );
+        assert_eq!(
+            decode_chunk_map(&from(10, 4096).unwrap()).unwrap(),
+            (3, vec![])
+        );
+
+        from(0, HEADER_SIZE - 1).unwrap_err();
+        encode_chunk_map(&chunks, &[0; 4], 4096).unwrap_err();
+        decode_chunk_map(&all[..all.len() - 1]).unwrap_err();
+        decode_chunk_map(&all[..8]).unwrap_err();
+
+        assert!(is_chunk_map_request(chunk_map_request(16) as u32));
+        assert!(is_chunk_map_request(chunk_map_request(4096) as u32));
+        assert!(!is_chunk_map_request(
+            nix::request_code_read!(b'P', 1, 4096) as u32
+        ));
+        assert!(!is_chunk_map_request(
+            nix::request_code_readwrite!(b'P', 2, 4096) as u32
+        ));
+    }
+}
@@ -1,25 +1,126 @@
-extern crate time;
-
+use std::cmp::min;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
 use std::ffi::OsStr;
 use std::os::raw::c_int;
-use std::path::Path;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+#[cfg(feature = "async-reader")]
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use fuse::{FileAttr, FileType, Filesystem, ReplyData, ReplyEntry, ReplyOpen, Request};
+use fuser::consts::{FOPEN_DIRECT_IO, FUSE_DO_READDIRPLUS};
+use fuser::{
+    FileAttr, FileType, Filesystem, KernelConfig, ReplyData, ReplyEntry, ReplyOpen, Request,
+    TimeOrNow,
+};
 use nix::errno::Errno;
 use nix::fcntl::OFlag;
-use time::Timespec;
+use nix::sys::stat::makedev;
+use nix::unistd::AccessFlags;
+use serde::Serialize;
 
-use format::{Result, WireFormatError};
+use format::{Result, Timestamp, WireFormatError, OVERLAY_OPAQUE_XATTR};
+use oci::ChunkStream;
 
-use super::puzzlefs::{file_read, Inode, InodeMode, PuzzleFS};
+#[cfg(feature = "async-reader")]
+use super::async_read::file_read;
+use super::cache::CacheStats;
+use super::chunk_map::{encode_chunk_map, is_chunk_map_request};
+#[cfg(not(feature = "async-reader"))]
+use super::puzzlefs::file_read;
+use super::puzzlefs::{chunk_reads, Inode, InodeMode, PuzzleFS};
+use super::readahead::{Prefetcher, Readahead, DEFAULT_READAHEAD};
+#[cfg(feature = "async-reader")]
+use super::{AsyncChunkStore, OciChunkStore};
+use super::{IdMapping, MountOption};
 
 pub struct Fuse<'a> {
     pfs: PuzzleFS<'a>,
+    entry_ttl: Duration,
+    attr_ttl: Duration,
+    // reads fetch their chunks concurrently on this, once the first one starts it
+    #[cfg(feature = "async-reader")]
+    runtime: Option<tokio::runtime::Runtime>,
+    #[cfg(feature = "async-reader")]
+    store: Arc<dyn AsyncChunkStore>,
+    // the total size of the files and how many inodes there are, for statfs(); images never
+    // change, so this only needs to be counted once
+    totals: Option<(u64, u64)>,
+    // the open files, by file handle, so reads don't look up (and decode the chunk list of) the
+    // inode every time. the chunks themselves are cached by pfs.
+    handles: HashMap<u64, OpenFile<'a>>,
+    next_fh: u64,
+    // how many chunks to fetch ahead of sequential reads, and what fetches them once one does
+    readahead: u64,
+    prefetcher: Option<Prefetcher>,
+    // whether chunks are checked against their digests before they're first read
+    verify_on_read: bool,
+    // whether st_blocks leaves out holes
+    du_physical: bool,
+    // whether directories are listed along with their entries' attributes
+    readdirplus: bool,
+    // who files are shown as owned by instead of their owners in the image
+    uid: Option<u32>,
+    gid: Option<u32>,
+    idmap: Vec<IdMapping>,
+    // the directory shown as the root of the mount, which the kernel knows as FUSE_ROOT_ID
+    root: u64,
+    // when the mount started, for the status file's uptime
+    started: Instant,
+    // what the status file said when each handle to it was opened, so one read of it is all the
+    // same JSON however many pieces the kernel reads it in
+    statuses: HashMap<u64, Vec<u8>>,
     // TODO: LRU cache inodes or something. I had problems fiddling with the borrow checker for the
     // cache, so for now we just do each lookup every time.
 }
 
+struct OpenFile<'a> {
+    inode: Inode,
+    readahead: Readahead,
+    // what reads of part of a compressed chunk decompress it through, so the next read can carry
+    // on from where the last one stopped
+    stream: ChunkStream<'a>,
+}
+
+// what the kernel calls the root of a mount, whatever its inode number
+const FUSE_ROOT_ID: u64 = 1;
+
+// what statfs() counts the files' sizes in
+const BLOCK_SIZE: u64 = 4096;
+
+// what st_blocks counts in, whatever the block size
+const STAT_BLOCK_SIZE: u64 = 512;
+
+// who ids the idmap doesn't cover are shown as, the kernel's default overflowuid and overflowgid
+const OVERFLOW_ID: u32 = 65534;
+
+// every mount has a .puzzlefs/status in its root that says how it's doing, as JSON. the directory
+// isn't listed, so the mount's contents are still just the image's, and is only there for images
+// that don't have a .puzzlefs of their own in that directory.
+const STATUS_DIR: &str = ".puzzlefs";
+const STATUS_FILE: &str = "status";
+
+// inode numbers count up from 1, so no image gets near these
+const STATUS_DIR_INO: u64 = u64::MAX - 1;
+const STATUS_INO: u64 = u64::MAX;
+
+fn is_status(ino: u64) -> bool {
+    ino == STATUS_DIR_INO || ino == STATUS_INO
+}
+
+// what the status file has in it
+#[derive(Serialize)]
+struct Status<'a> {
+    // where the image is, if it's on disk
+    image: Option<PathBuf>,
+    tags: &'a [String],
+    uptime_secs: u64,
+    open_files: usize,
+    cache_capacity: u64,
+    cache: CacheStats,
+}
+
 fn mode_to_fuse_type(inode: &Inode) -> Result<FileType> {
     Ok(match inode.mode {
         InodeMode::File { .. } => FileType::RegularFile,
@@ -30,91 +131,555 @@ fn mode_to_fuse_type(inode: &Inode) -> Result<FileType> {
             format::InodeMode::Blk { .. } => FileType::BlockDevice,
             format::InodeMode::Lnk => FileType::Symlink,
             format::InodeMode::Sock => FileType::Socket,
+            // the way overlayfs spells a whiteout, so it does the right thing with images used as
+            // lower dirs
+            format::InodeMode::Wht => FileType::CharDevice,
             _ => return Err(WireFormatError::from_errno(Errno::EINVAL)),
         },
     })
 }
 
+fn system_time(t: Timestamp) -> SystemTime {
+    if t.sec >= 0 {
+        UNIX_EPOCH + Duration::new(t.sec as u64, t.nsec)
+    } else {
+        // nsec still counts forwards from sec
+        UNIX_EPOCH - Duration::from_secs(t.sec.unsigned_abs()) + Duration::from_nanos(t.nsec.into())
+    }
+}
+
+// getxattr() and listxattr() are first called with a size of zero to figure out how big the buffer
+// needs to be, and then again with the real buffer.
+fn reply_xattr(data: &[u8], size: u32, reply: fuser::ReplyXattr) {
+    if size == 0 {
+        reply.size(data.len() as u32)
+    } else if (size as usize) < data.len() {
+        reply.error(Errno::ERANGE as i32)
+    } else {
+        reply.data(data)
+    }
+}
+
 impl<'a> Fuse<'a> {
     pub fn new(pfs: PuzzleFS<'a>) -> Fuse<'a> {
-        Fuse { pfs }
+        // images are immutable, so the kernel can cache things forever
+        Fuse {
+            #[cfg(feature = "async-reader")]
+            runtime: None,
+            #[cfg(feature = "async-reader")]
+            store: Arc::new(OciChunkStore::new(pfs.oci)),
+            pfs,
+            entry_ttl: Duration::new(i64::MAX as u64, 0),
+            attr_ttl: Duration::new(i64::MAX as u64, 0),
+            totals: None,
+            handles: HashMap::new(),
+            // 0 is for the directories, which are opened without a handle
+            next_fh: 1,
+            readahead: DEFAULT_READAHEAD,
+            prefetcher: None,
+            verify_on_read: false,
+            du_physical: false,
+            readdirplus: true,
+            uid: None,
+            gid: None,
+            idmap: Vec::new(),
+            root: FUSE_ROOT_ID,
+            started: Instant::now(),
+            statuses: HashMap::new(),
+        }
+    }
+
+    pub fn with_options(mut self, options: &[MountOption]) -> Fuse<'a> {
+        for option in options {
+            match option {
+                MountOption::EntryTimeout(secs) => self.entry_ttl = Duration::from_secs(*secs),
+                MountOption::AttrTimeout(secs) => self.attr_ttl = Duration::from_secs(*secs),
+                MountOption::Readahead(chunks) => self.readahead = *chunks,
+                MountOption::VerifyOnRead => self.verify_on_read = true,
+                MountOption::DuPhysical => self.du_physical = true,
+                MountOption::NoReaddirplus => self.readdirplus = false,
+                MountOption::Uid(uid) => self.uid = Some(*uid),
+                MountOption::Gid(gid) => self.gid = Some(*gid),
+                MountOption::IdMap(mapping) => self.idmap.push(*mapping),
+                _ => {}
+            }
+        }
+        self
+    }
+
+    /// Shows the directory at `path` in the image as the root of the mount, with nothing outside
+    /// it to be found.
+    pub fn with_root(mut self, path: &Path) -> Result<Fuse<'a>> {
+        let ino = self.pfs.lookup_path(path)?;
+        if !self.pfs.find_inode(ino)?.is_dir() {
+            return Err(WireFormatError::from_errno(Errno::ENOTDIR));
+        }
+        self.root = ino;
+        Ok(self)
+    }
+
+    // the kernel's inode numbers are the image's, except for the root of the mount. nothing in a
+    // directory can be the image's root, so there's no mixing the two up.
+    fn image_ino(&self, ino: u64) -> u64 {
+        if ino == FUSE_ROOT_ID {
+            self.root
+        } else {
+            ino
+        }
+    }
+
+    fn kernel_ino(&self, ino: u64) -> u64 {
+        if ino == self.root {
+            FUSE_ROOT_ID
+        } else {
+            ino
+        }
+    }
+
+    // the uid and gid an inode is shown as owned by
+    fn owner(&self, inode: &Inode) -> (u32, u32) {
+        let map = |id| {
+            if self.idmap.is_empty() {
+                return id;
+            }
+            self.idmap
+                .iter()
+                .find_map(|mapping| mapping.map(id))
+                .unwrap_or(OVERFLOW_ID)
+        };
+        (
+            self.uid.unwrap_or_else(|| map(inode.inode.uid)),
+            self.gid.unwrap_or_else(|| map(inode.inode.gid)),
+        )
     }
 
     fn _lookup(&mut self, parent: u64, name: &OsStr) -> Result<FileAttr> {
-        let dir = self.pfs.find_inode(parent)?;
-        let ino = dir.dir_lookup(name)?;
+        if parent == STATUS_DIR_INO {
+            let ino = match name.to_str() {
+                Some(".") => STATUS_DIR_INO,
+                Some("..") => self.root,
+                Some(STATUS_FILE) => STATUS_INO,
+                _ => return Err(WireFormatError::from_errno(Errno::ENOENT)),
+            };
+            return self._getattr(ino);
+        }
+        // the root of the mount is its own parent, like the root of any filesystem
+        let ino = if parent == self.root && name == ".." {
+            parent
+        } else {
+            match self.pfs.lookup(parent, name) {
+                Err(e)
+                    if parent == self.root
+                        && name == STATUS_DIR
+                        && e.to_errno() == Errno::ENOENT as c_int =>
+                {
+                    STATUS_DIR_INO
+                }
+                result => result?,
+            }
+        };
         self._getattr(ino)
     }
 
+    // the mount's status, fresh
+    fn status(&self) -> Result<Vec<u8>> {
+        let status = Status {
+            image: self.pfs.oci.oci_dir(),
+            tags: self.pfs.tags(),
+            uptime_secs: self.started.elapsed().as_secs(),
+            open_files: self.handles.len(),
+            cache_capacity: self.pfs.cache.capacity(),
+            cache: self.pfs.cache_stats(),
+        };
+        let mut json = serde_json::to_vec(&status)?;
+        json.push(b'\n');
+        Ok(json)
+    }
+
+    // the status directory and file are owned by whoever owns the root of the mount, and can be
+    // read by everyone
+    fn status_attr(&mut self, ino: u64) -> Result<FileAttr> {
+        let mut attr = self._getattr(self.root)?;
+        attr.ino = ino;
+        if ino == STATUS_DIR_INO {
+            attr.perm = 0o555;
+            attr.nlink = 2;
+        } else {
+            attr.kind = FileType::RegularFile;
+            attr.perm = 0o444;
+            attr.nlink = 1;
+            // only a guess at what the next open will see, which is why it's opened direct_io
+            attr.size = self.status()?.len() as u64;
+            attr.blocks = (attr.size + STAT_BLOCK_SIZE - 1) / STAT_BLOCK_SIZE;
+        }
+        Ok(attr)
+    }
+
     fn _getattr(&mut self, ino: u64) -> Result<FileAttr> {
+        if is_status(ino) {
+            return self.status_attr(ino);
+        }
         let ic = self.pfs.find_inode(ino)?;
         let kind = mode_to_fuse_type(&ic)?;
         let len = ic.file_len().unwrap_or(0);
+        let stored = match &ic.mode {
+            InodeMode::File { chunks } if self.du_physical => chunks
+                .iter()
+                .filter(|c| c.blob.is_some())
+                .map(|c| c.len)
+                .sum(),
+            _ => len,
+        };
+        let (uid, gid) = self.owner(&ic);
         Ok(FileAttr {
-            ino: ic.inode.ino,
+            ino: self.kernel_ino(ic.inode.ino),
             size: len,
-            blocks: 0,
-            atime: time::Timespec::new(0, 0),
-            mtime: time::Timespec::new(0, 0),
-            ctime: time::Timespec::new(0, 0),
-            crtime: time::Timespec::new(0, 0),
+            blocks: (stored + STAT_BLOCK_SIZE - 1) / STAT_BLOCK_SIZE,
+            atime: system_time(ic.inode.atime),
+            mtime: system_time(ic.inode.mtime),
+            // there's no way to set these when building, so make the best of what we have
+            ctime: system_time(ic.inode.mtime),
+            crtime: system_time(ic.inode.mtime),
             kind,
-            perm: 0o644,
-            nlink: 0,
-            uid: ic.inode.uid,
-            gid: ic.inode.gid,
-            rdev: 0,
+            perm: ic.inode.permissions,
+            nlink: ic.inode.nlink,
+            uid,
+            gid,
+            rdev: match ic.inode.mode {
+                format::InodeMode::Chr { major, minor }
+                | format::InodeMode::Blk { major, minor } => {
+                    // the kernel's 32 bit encoding, which agrees with makedev() for all but huge
+                    // device numbers
+                    makedev(major, minor) as u32
+                }
+                _ => 0,
+            },
+            blksize: BLOCK_SIZE as u32,
             flags: 0,
         })
     }
 
-    fn _open(&self, flags_i: u32, reply: ReplyOpen) {
-        let allowed_flags =
-            OFlag::O_RDONLY | OFlag::O_PATH | OFlag::O_NONBLOCK | OFlag::O_DIRECTORY;
-        let flags = OFlag::from_bits_truncate(flags_i.try_into().unwrap());
-        if !allowed_flags.contains(flags) {
-            reply.error(Errno::EROFS as i32)
+    fn _readlink(&mut self, ino: u64) -> Result<Vec<u8>> {
+        if is_status(ino) {
+            return Err(WireFormatError::from_errno(Errno::EINVAL));
+        }
+        let inode = self.pfs.find_inode(ino)?;
+        match inode.inode.mode {
+            format::InodeMode::Lnk => Ok(inode.symlink_target()?.as_bytes().to_vec()),
+            _ => Err(WireFormatError::from_errno(Errno::EINVAL)),
+        }
+    }
+
+    fn _getxattr(&mut self, ino: u64, name: &OsStr) -> Result<Vec<u8>> {
+        if is_status(ino) {
+            return Err(WireFormatError::from_errno(Errno::ENODATA));
+        }
+        let inode = self.pfs.find_inode(ino)?;
+        if inode.is_opaque() && name == OVERLAY_OPAQUE_XATTR {
+            return Ok(b"y".to_vec());
+        }
+        inode
+            .additional
+            .as_ref()
+            .and_then(|add| add.xattrs.iter().find(|xa| xa.key == name))
+            .map(|xa| xa.val.clone().unwrap_or_default())
+            .ok_or_else(|| WireFormatError::from_errno(Errno::ENODATA))
+    }
+
+    fn _listxattr(&mut self, ino: u64) -> Result<Vec<u8>> {
+        if is_status(ino) {
+            return Ok(Vec::new());
+        }
+        let inode = self.pfs.find_inode(ino)?;
+        // the list is a bunch of NUL terminated names all stuck together
+        let mut names = Vec::new();
+        if inode.is_opaque() {
+            names.extend_from_slice(OVERLAY_OPAQUE_XATTR.as_bytes());
+            names.push(0);
+        }
+        if let Some(add) = &inode.additional {
+            for xa in &add.xattrs {
+                names.extend_from_slice(xa.key.as_bytes());
+                names.push(0);
+            }
+        }
+        Ok(names)
+    }
+
+    // only refuse things that would write; openers like overlayfs pass along other harmless flags
+    // (O_LARGEFILE, O_NOATIME, ...) that we need to allow.
+    fn check_open_flags(flags: u32) -> Result<()> {
+        let write_flags =
+            OFlag::O_WRONLY | OFlag::O_RDWR | OFlag::O_CREAT | OFlag::O_TRUNC | OFlag::O_APPEND;
+        if OFlag::from_bits_truncate(flags as i32).intersects(write_flags) {
+            return Err(WireFormatError::from_errno(Errno::EROFS));
+        }
+        Ok(())
+    }
+
+    // what the kernel would check if it did the checking itself. only the caller's primary group
+    // counts, fuse doesn't tell us the others.
+    fn _access(&mut self, ino: u64, mask: u32, uid: u32, gid: u32) -> Result<()> {
+        let mask = AccessFlags::from_bits_truncate(mask as i32);
+        if mask.contains(AccessFlags::W_OK) {
+            return Err(WireFormatError::from_errno(Errno::EROFS));
+        }
+        if is_status(ino) {
+            let granted = if ino == STATUS_DIR_INO { 0o5 } else { 0o4 };
+            if mask.bits() as u32 & !granted != 0 {
+                return Err(WireFormatError::from_errno(Errno::EACCES));
+            }
+            return Ok(());
+        }
+        let inode = self.pfs.find_inode(ino)?;
+        let permissions = inode.inode.permissions as u32;
+        let (owner, group) = self.owner(&inode);
+        let granted = if uid == 0 {
+            // root can read anything, and execute anything somebody can execute
+            if inode.is_dir() || permissions & 0o111 != 0 {
+                0o7
+            } else {
+                0o6
+            }
+        } else if uid == owner {
+            permissions >> 6 & 0o7
+        } else if gid == group {
+            permissions >> 3 & 0o7
         } else {
-            // stateless open for now, slower maybe
-            reply.opened(0, flags_i);
+            permissions & 0o7
+        };
+        // R_OK and X_OK are the same bits as r and x
+        if mask.bits() as u32 & !granted != 0 {
+            return Err(WireFormatError::from_errno(Errno::EACCES));
         }
+        Ok(())
     }
 
-    fn _read(&mut self, ino: u64, offset: u64, size: u32) -> Result<Vec<u8>> {
+    fn _open(&mut self, ino: u64, flags: u32) -> Result<u64> {
+        Self::check_open_flags(flags)?;
+        if ino == STATUS_INO {
+            let status = self.status()?;
+            let fh = self.next_fh;
+            self.next_fh += 1;
+            self.statuses.insert(fh, status);
+            return Ok(fh);
+        }
         let inode = self.pfs.find_inode(ino)?;
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        self.handles.insert(
+            fh,
+            OpenFile {
+                inode,
+                readahead: Readahead::default(),
+                stream: ChunkStream::new(self.pfs.oci),
+            },
+        );
+        Ok(fh)
+    }
+
+    fn _release(&mut self, fh: u64) {
+        self.handles.remove(&fh);
+        self.statuses.remove(&fh);
+    }
+
+    fn _read(&mut self, ino: u64, fh: u64, offset: u64, size: u32) -> Result<Vec<u8>> {
+        if let Some(status) = self.statuses.get(&fh) {
+            let start = min(offset, status.len() as u64) as usize;
+            let end = min(start + size as usize, status.len());
+            return Ok(status[start..end].to_vec());
+        }
+        let mut found;
+        let (inode, stream) = match self.handles.get_mut(&fh) {
+            Some(open) => (&open.inode, &mut open.stream),
+            None => {
+                found = (self.pfs.find_inode(ino)?, ChunkStream::new(self.pfs.oci));
+                (&found.0, &mut found.1)
+            }
+        };
+        // reads past EOF are short, so don't allocate more than we could possibly fill
+        let len = inode.file_len()?;
+        let size = min(size as u64, len.saturating_sub(offset));
+        if self.verify_on_read {
+            for read in chunk_reads(inode, offset as usize, size as usize)? {
+                if let Some(blob) = read.blob {
+                    self.pfs.cache.verify(self.pfs.oci, blob)?;
+                }
+            }
+        }
         let mut buf = vec![0_u8; size as usize];
-        let read = file_read(self.pfs.oci, &inode, offset as usize, &mut buf)?;
+        #[cfg(feature = "async-reader")]
+        let read = {
+            if self.runtime.is_none() {
+                let runtime = tokio::runtime::Builder::new_multi_thread()
+                    .worker_threads(2)
+                    .build()?;
+                self.runtime = Some(runtime);
+            }
+            let runtime = self.runtime.as_ref().unwrap();
+            let (oci, cache) = (self.pfs.oci, &self.pfs.cache);
+            let read = file_read(
+                oci,
+                &self.store,
+                Some(stream),
+                cache,
+                inode,
+                offset as usize,
+                &mut buf,
+            );
+            runtime.block_on(read)?
+        };
+        #[cfg(not(feature = "async-reader"))]
+        let read = file_read(stream, &self.pfs.cache, inode, offset as usize, &mut buf)?;
         buf.truncate(read);
+        self.read_ahead(fh, offset, read as u64);
         Ok(buf)
     }
 
-    fn _readdir(&mut self, ino: u64, offset: i64, reply: &mut fuse::ReplyDirectory) -> Result<()> {
-        let inode = self.pfs.find_inode(ino)?;
-        let entries = inode.dir_entries()?;
-        for (index, (name, ino_r)) in entries.iter().enumerate().skip(offset as usize) {
-            let ino = *ino_r;
+    fn read_ahead(&mut self, fh: u64, offset: u64, len: u64) {
+        let open = match self.handles.get_mut(&fh) {
+            Some(open) => open,
+            None => return,
+        };
+        let chunks = match &open.inode.mode {
+            InodeMode::File { chunks } => chunks,
+            _ => return,
+        };
+        // leave room in the cache for what's being read now, and for everything else
+        let max_bytes = self.pfs.cache.capacity() / 2;
+        let ahead = open
+            .readahead
+            .advance(chunks, offset, len, self.readahead, max_bytes);
+        if ahead.is_empty() {
+            return;
+        }
+        let (oci, cache) = (self.pfs.oci, &self.pfs.cache);
+        let prefetcher = self
+            .prefetcher
+            .get_or_insert_with(|| Prefetcher::new(oci, cache.clone()));
+        for blob in chunks[ahead].iter().filter_map(|c| c.blob) {
+            prefetcher.prefetch(blob);
+        }
+    }
+
+    fn _totals(&mut self) -> Result<(u64, u64)> {
+        if let Some(totals) = self.totals {
+            return Ok(totals);
+        }
+        let mut bytes = 0;
+        let mut seen = HashSet::new();
+        let mut todo = vec![self.root];
+        // hard links only count once
+        while let Some(ino) = todo.pop() {
+            if !seen.insert(ino) {
+                continue;
+            }
             let inode = self.pfs.find_inode(ino)?;
-            let kind = mode_to_fuse_type(&inode)?;
+            match &inode.mode {
+                InodeMode::File { .. } => bytes += inode.file_len()?,
+                InodeMode::Dir { entries, .. } => todo.extend(entries.iter().map(|(_, ino)| ino)),
+                _ => {}
+            }
+        }
+        self.totals = Some((bytes, seen.len() as u64));
+        Ok((bytes, seen.len() as u64))
+    }
+
+    fn _ioctl(&mut self, ino: u64, cmd: u32, in_data: &[u8], out_size: u32) -> Result<Vec<u8>> {
+        if !is_chunk_map_request(cmd) || is_status(ino) {
+            return Err(WireFormatError::from_errno(Errno::ENOTTY));
+        }
+        let chunks = match self.pfs.chunks_for_inode(ino) {
+            // only files have chunks
+            Err(e) if e.to_errno() == Errno::ENOTDIR as c_int => {
+                return Err(WireFormatError::from_errno(Errno::ENOTTY))
+            }
+            chunks => chunks?,
+        };
+        encode_chunk_map(&chunks, in_data, out_size as usize)
+    }
 
-            // if the buffer is full, let's skip the extra lookups
-            if reply.add(ino, (index + 1) as i64, kind, name) {
+    // calls add() with the image's inode number, offset and name of each of a directory's entries
+    // from `offset` on, until it says the reply is full
+    fn dir_entries<F>(&mut self, ino: u64, offset: i64, mut add: F) -> Result<()>
+    where
+        F: FnMut(&mut Self, u64, i64, &OsStr) -> Result<bool>,
+    {
+        // an entry's offset is one past its index in the directory's entries, which are sorted
+        // by name and never change, so the kernel can pick a listing back up from any offset it
+        // has been given, however long ago; 0 is the start
+        let offset: usize = offset
+            .try_into()
+            .map_err(|_| WireFormatError::from_errno(Errno::EINVAL))?;
+        if ino == STATUS_DIR_INO {
+            if offset == 0 {
+                add(self, STATUS_INO, 1, OsStr::new(STATUS_FILE))?;
+            }
+            return Ok(());
+        }
+        let inode = self.pfs.find_inode(ino)?;
+        for (index, (name, ino)) in inode.dir_entries()?.iter().enumerate().skip(offset) {
+            if add(self, *ino, (index + 1) as i64, name)? {
                 break;
             }
         }
-
         Ok(())
     }
+
+    fn _readdir(&mut self, ino: u64, offset: i64, reply: &mut fuser::ReplyDirectory) -> Result<()> {
+        self.dir_entries(ino, offset, |fuse, ino, offset, name| {
+            let kind = if is_status(ino) {
+                FileType::RegularFile
+            } else {
+                mode_to_fuse_type(&fuse.pfs.find_inode(ino)?)?
+            };
+            Ok(reply.add(fuse.kernel_ino(ino), offset, kind, name))
+        })
+    }
+
+    // readdir() with the attributes that lookup() would have come back with, so listing a
+    // directory with ls -l only takes the one request per batch of entries
+    fn _readdirplus(
+        &mut self,
+        ino: u64,
+        offset: i64,
+        reply: &mut fuser::ReplyDirectoryPlus,
+    ) -> Result<()> {
+        let ttl = min(self.entry_ttl, self.attr_ttl);
+        self.dir_entries(ino, offset, |fuse, ino, offset, name| {
+            let attr = fuse._getattr(ino)?;
+            Ok(reply.add(attr.ino, offset, name, &ttl, &attr, 0))
+        })
+    }
 }
 
 impl Filesystem for Fuse<'_> {
-    fn init(&mut self, _req: &Request) -> std::result::Result<(), c_int> {
+    fn init(
+        &mut self,
+        _req: &Request,
+        config: &mut KernelConfig,
+    ) -> std::result::Result<(), c_int> {
+        // kernels that can't do readdirplus just carry on with readdir
+        if self.readdirplus {
+            let _ = config.add_capabilities(FUSE_DO_READDIRPLUS);
+        }
         Ok(())
     }
 
-    fn destroy(&mut self, _req: &Request) {}
+    fn destroy(&mut self) {
+        // for telling whether the cache was big enough for whatever used the mount
+        let stats = self.pfs.cache_stats();
+        log::info!(
+            "chunk cache: {} hits, {} misses, {} evictions, {} bytes fetched",
+            stats.hits,
+            stats.misses,
+            stats.evictions,
+            stats.bytes_fetched
+        );
+    }
     fn forget(&mut self, _req: &Request, _ino: u64, _nlookup: u64) {}
 
-    // puzzlefs is readonly, so we can ignore a bunch of requests
+    // puzzlefs is readonly, so everything that would change something fails with EROFS
     fn setattr(
         &mut self,
         _req: &Request,
@@ -123,14 +688,15 @@ impl Filesystem for Fuse<'_> {
         _uid: Option<u32>,
         _gid: Option<u32>,
         _size: Option<u64>,
-        _atime: Option<Timespec>,
-        _mtime: Option<Timespec>,
+        _atime: Option<TimeOrNow>,
+        _mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
         _fh: Option<u64>,
-        _crtime: Option<Timespec>,
-        _chgtime: Option<Timespec>,
-        _bkuptime: Option<Timespec>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
         _flags: Option<u32>,
-        reply: fuse::ReplyAttr,
+        reply: fuser::ReplyAttr,
     ) {
         reply.error(Errno::EROFS as i32)
     }
@@ -141,6 +707,7 @@ impl Filesystem for Fuse<'_> {
         _parent: u64,
         _name: &OsStr,
         _mode: u32,
+        _umask: u32,
         _rdev: u32,
         reply: ReplyEntry,
     ) {
@@ -153,16 +720,17 @@ impl Filesystem for Fuse<'_> {
         _parent: u64,
         _name: &OsStr,
         _mode: u32,
+        _umask: u32,
         reply: ReplyEntry,
     ) {
         reply.error(Errno::EROFS as i32)
     }
 
-    fn unlink(&mut self, _req: &Request, _parent: u64, _name: &OsStr, reply: fuse::ReplyEmpty) {
+    fn unlink(&mut self, _req: &Request, _parent: u64, _name: &OsStr, reply: fuser::ReplyEmpty) {
         reply.error(Errno::EROFS as i32)
     }
 
-    fn rmdir(&mut self, _req: &Request, _parent: u64, _name: &OsStr, reply: fuse::ReplyEmpty) {
+    fn rmdir(&mut self, _req: &Request, _parent: u64, _name: &OsStr, reply: fuser::ReplyEmpty) {
         reply.error(Errno::EROFS as i32)
     }
 
@@ -184,7 +752,8 @@ impl Filesystem for Fuse<'_> {
         _name: &OsStr,
         _newparent: u64,
         _newname: &OsStr,
-        reply: fuse::ReplyEmpty,
+        _flags: u32,
+        reply: fuser::ReplyEmpty,
     ) {
         reply.error(Errno::EROFS as i32)
     }
@@ -207,21 +776,25 @@ impl Filesystem for Fuse<'_> {
         _fh: u64,
         _offset: i64,
         _data: &[u8],
-        _flags: u32,
-        reply: fuse::ReplyWrite,
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: fuser::ReplyWrite,
     ) {
         reply.error(Errno::EROFS as i32)
     }
 
+    // there's never anything to write back, and failing these would make every close() and
+    // fsync() of a file that's only being read fail too
     fn flush(
         &mut self,
         _req: &Request,
         _ino: u64,
         _fh: u64,
         _lock_owner: u64,
-        reply: fuse::ReplyEmpty,
+        reply: fuser::ReplyEmpty,
     ) {
-        reply.error(Errno::EROFS as i32)
+        reply.ok()
     }
 
     fn fsync(
@@ -230,9 +803,9 @@ impl Filesystem for Fuse<'_> {
         _ino: u64,
         _fh: u64,
         _datasync: bool,
-        reply: fuse::ReplyEmpty,
+        reply: fuser::ReplyEmpty,
     ) {
-        reply.error(Errno::EROFS as i32)
+        reply.ok()
     }
 
     fn fsyncdir(
@@ -241,9 +814,9 @@ impl Filesystem for Fuse<'_> {
         _ino: u64,
         _fh: u64,
         _datasync: bool,
-        reply: fuse::ReplyEmpty,
+        reply: fuser::ReplyEmpty,
     ) {
-        reply.error(Errno::EROFS as i32)
+        reply.ok()
     }
 
     fn setxattr(
@@ -252,14 +825,14 @@ impl Filesystem for Fuse<'_> {
         _ino: u64,
         _name: &OsStr,
         _value: &[u8],
-        _flags: u32,
+        _flags: i32,
         _position: u32,
-        reply: fuse::ReplyEmpty,
+        reply: fuser::ReplyEmpty,
     ) {
         reply.error(Errno::EROFS as i32)
     }
 
-    fn removexattr(&mut self, _req: &Request, _ino: u64, _name: &OsStr, reply: fuse::ReplyEmpty) {
+    fn removexattr(&mut self, _req: &Request, _ino: u64, _name: &OsStr, reply: fuser::ReplyEmpty) {
         reply.error(Errno::EROFS as i32)
     }
 
@@ -269,8 +842,9 @@ impl Filesystem for Fuse<'_> {
         _parent: u64,
         _name: &OsStr,
         _mode: u32,
-        _flags: u32,
-        reply: fuse::ReplyCreate,
+        _umask: u32,
+        _flags: i32,
+        reply: fuser::ReplyCreate,
     ) {
         reply.error(Errno::EROFS as i32)
     }
@@ -283,9 +857,9 @@ impl Filesystem for Fuse<'_> {
         _lock_owner: u64,
         _start: u64,
         _end: u64,
-        _typ: u32,
+        _typ: i32,
         _pid: u32,
-        reply: fuse::ReplyLock,
+        reply: fuser::ReplyLock,
     ) {
         reply.error(Errno::EROFS as i32)
     }
@@ -298,57 +872,68 @@ impl Filesystem for Fuse<'_> {
         _lock_owner: u64,
         _start: u64,
         _end: u64,
-        _typ: u32,
+        _typ: i32,
         _pid: u32,
         _sleep: bool,
-        reply: fuse::ReplyEmpty,
+        reply: fuser::ReplyEmpty,
     ) {
         reply.error(Errno::EROFS as i32)
     }
 
     fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        match self._lookup(parent, name) {
+        match self._lookup(self.image_ino(parent), name) {
             Ok(attr) => {
                 // http://libfuse.github.io/doxygen/structfuse__entry__param.html
-                let ttl = Timespec::new(std::i64::MAX, 0);
                 let generation = 0;
+                // the entry and the attributes that come with it share a timeout, so use the
+                // shorter one; otherwise the attributes could outlive attr_timeout
+                let ttl = min(self.entry_ttl, self.attr_ttl);
                 reply.entry(&ttl, &attr, generation)
             }
             Err(e) => reply.error(e.to_errno()),
         }
     }
 
-    fn getattr(&mut self, _req: &Request, ino: u64, reply: fuse::ReplyAttr) {
-        match self._getattr(ino) {
+    fn getattr(&mut self, _req: &Request, ino: u64, reply: fuser::ReplyAttr) {
+        match self._getattr(self.image_ino(ino)) {
             Ok(attr) => {
                 // http://libfuse.github.io/doxygen/structfuse__entry__param.html
-                let ttl = Timespec::new(std::i64::MAX, 0);
-                reply.attr(&ttl, &attr)
+                reply.attr(&self.attr_ttl, &attr)
             }
             Err(e) => reply.error(e.to_errno()),
         }
     }
 
-    fn readlink(&mut self, _req: &Request, _ino: u64, reply: ReplyData) {
-        reply.error(Errno::EISNAM as i32)
+    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        match self._readlink(self.image_ino(ino)) {
+            Ok(target) => reply.data(target.as_slice()),
+            Err(e) => reply.error(e.to_errno()),
+        }
     }
 
-    fn open(&mut self, _req: &Request, _ino: u64, flags: u32, reply: ReplyOpen) {
-        self._open(flags, reply)
+    fn open(&mut self, _req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+        match self._open(self.image_ino(ino), flags as u32) {
+            // the status changes, so it mustn't be read from the page cache
+            Ok(fh) if ino == STATUS_INO => reply.opened(fh, FOPEN_DIRECT_IO),
+            Ok(fh) => reply.opened(fh, flags as u32),
+            Err(e) => reply.error(e.to_errno()),
+        }
     }
 
     fn read(
         &mut self,
         _req: &Request,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
         reply: ReplyData,
     ) {
         // TODO: why i64 from the fuse API here?
         let uoffset: u64 = offset.try_into().unwrap();
-        match self._read(ino, uoffset, size) {
+        match self._read(self.image_ino(ino), fh, uoffset, size) {
             Ok(data) => reply.data(data.as_slice()),
             Err(e) => reply.error(e.to_errno()),
         }
@@ -358,18 +943,22 @@ impl Filesystem for Fuse<'_> {
         &mut self,
         _req: &Request,
         _ino: u64,
-        _fh: u64,
-        _flags: u32,
-        _lock_owner: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
         _flush: bool,
-        reply: fuse::ReplyEmpty,
+        reply: fuser::ReplyEmpty,
     ) {
-        // TODO: purge from our cache here? dcache should save us too...
+        self._release(fh);
         reply.ok()
     }
 
-    fn opendir(&mut self, _req: &Request, _ino: u64, flags: u32, reply: ReplyOpen) {
-        self._open(flags, reply)
+    fn opendir(&mut self, _req: &Request, _ino: u64, flags: i32, reply: ReplyOpen) {
+        // stateless, readdir looks the directory up every time
+        match Self::check_open_flags(flags as u32) {
+            Ok(()) => reply.opened(0, flags as u32),
+            Err(e) => reply.error(e.to_errno()),
+        }
     }
 
     fn readdir(
@@ -378,9 +967,23 @@ impl Filesystem for Fuse<'_> {
         ino: u64,
         _fh: u64,
         offset: i64,
-        mut reply: fuse::ReplyDirectory,
+        mut reply: fuser::ReplyDirectory,
     ) {
-        match self._readdir(ino, offset, &mut reply) {
+        match self._readdir(self.image_ino(ino), offset, &mut reply) {
+            Ok(_) => reply.ok(),
+            Err(e) => reply.error(e.to_errno()),
+        }
+    }
+
+    fn readdirplus(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: fuser::ReplyDirectoryPlus,
+    ) {
+        match self._readdirplus(self.image_ino(ino), offset, &mut reply) {
             Ok(_) => reply.ok(),
             Err(e) => reply.error(e.to_errno()),
         }
@@ -391,44 +994,56 @@ impl Filesystem for Fuse<'_> {
         _req: &Request,
         _ino: u64,
         _fh: u64,
-        _flags: u32,
-        reply: fuse::ReplyEmpty,
+        _flags: i32,
+        reply: fuser::ReplyEmpty,
     ) {
         // TODO: again maybe purge from cache?
         reply.ok()
     }
 
-    fn statfs(&mut self, _req: &Request, _ino: u64, reply: fuse::ReplyStatfs) {
-        reply.statfs(
-            0,   // blocks
-            0,   // bfree
-            0,   // bavail
-            0,   // files
-            0,   // ffree
-            0,   // bsize
-            256, // namelen
-            0,   // frsize
-        )
+    fn statfs(&mut self, _req: &Request, _ino: u64, reply: fuser::ReplyStatfs) {
+        match self._totals() {
+            // nothing can be written, so there is never anything free
+            Ok((bytes, inodes)) => reply.statfs(
+                (bytes + BLOCK_SIZE - 1) / BLOCK_SIZE, // blocks
+                0,                                     // bfree
+                0,                                     // bavail
+                inodes,                                // files
+                0,                                     // ffree
+                BLOCK_SIZE as u32,                     // bsize
+                256,                                   // namelen
+                BLOCK_SIZE as u32,                     // frsize
+            ),
+            Err(e) => reply.error(e.to_errno()),
+        }
     }
 
     fn getxattr(
         &mut self,
         _req: &Request,
-        _ino: u64,
-        _name: &OsStr,
-        _size: u32,
-        reply: fuse::ReplyXattr,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: fuser::ReplyXattr,
     ) {
-        // TODO: encoding for xattrs
-        reply.error(Errno::ENOMEDIUM as i32)
+        match self._getxattr(self.image_ino(ino), name) {
+            Ok(value) => reply_xattr(&value, size, reply),
+            Err(e) => reply.error(e.to_errno()),
+        }
     }
 
-    fn listxattr(&mut self, _req: &Request, _ino: u64, _size: u32, reply: fuse::ReplyXattr) {
-        reply.error(Errno::EDQUOT as i32)
+    fn listxattr(&mut self, _req: &Request, ino: u64, size: u32, reply: fuser::ReplyXattr) {
+        match self._listxattr(self.image_ino(ino)) {
+            Ok(names) => reply_xattr(&names, size, reply),
+            Err(e) => reply.error(e.to_errno()),
+        }
     }
 
-    fn access(&mut self, _req: &Request, _ino: u64, _mask: u32, reply: fuse::ReplyEmpty) {
-        reply.ok()
+    fn access(&mut self, req: &Request, ino: u64, mask: i32, reply: fuser::ReplyEmpty) {
+        match self._access(self.image_ino(ino), mask as u32, req.uid(), req.gid()) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(e.to_errno()),
+        }
     }
 
     fn bmap(
@@ -437,24 +1052,130 @@ impl Filesystem for Fuse<'_> {
         _ino: u64,
         _blocksize: u32,
         _idx: u64,
-        reply: fuse::ReplyBmap,
+        reply: fuser::ReplyBmap,
     ) {
         reply.error(Errno::ENOLCK as i32)
     }
+
+    // the chunk map, see chunk_map.rs
+    fn ioctl(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        _fh: u64,
+        _flags: u32,
+        cmd: u32,
+        in_data: &[u8],
+        out_size: u32,
+        reply: fuser::ReplyIoctl,
+    ) {
+        match self._ioctl(self.image_ino(ino), cmd, in_data, out_size) {
+            Ok(data) => reply.ioctl(0, &data),
+            Err(e) => reply.error(e.to_errno()),
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::convert::TryFrom;
     use std::fs;
-    use std::io;
+    use std::io::{self, Read};
+    use std::os::unix::fs::{FileExt, FileTypeExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+    use std::os::unix::io::{AsRawFd, IntoRawFd};
     use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
 
     extern crate hex;
     use sha2::{Digest, Sha256};
     use tempfile::tempdir;
 
-    use builder::build_test_fs;
-    use oci::Image;
+    use builder::{
+        build_initial_rootfs, build_initial_rootfs_with_options, build_test_fs, BuildOptions,
+    };
+    use format::{ChunkingAlgorithm, ChunkingConfig};
+    use nix::libc;
+    use nix::sys::statvfs::{statvfs, FsFlags};
+    use oci::{Image, MemBlobStore};
+
+    use super::*;
+    use crate::PuzzleFS;
+
+    // counts how many times the kernel asks for attributes, one way or another
+    struct Counting<'a> {
+        fuse: Fuse<'a>,
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl Filesystem for Counting<'_> {
+        fn init(
+            &mut self,
+            req: &Request,
+            config: &mut KernelConfig,
+        ) -> std::result::Result<(), c_int> {
+            self.fuse.init(req, config)
+        }
+
+        fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            self.fuse.lookup(req, parent, name, reply)
+        }
+
+        fn getattr(&mut self, req: &Request, ino: u64, reply: fuser::ReplyAttr) {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            self.fuse.getattr(req, ino, reply)
+        }
+
+        fn opendir(&mut self, req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
+            self.fuse.opendir(req, ino, flags, reply)
+        }
+
+        fn readdir(
+            &mut self,
+            req: &Request,
+            ino: u64,
+            fh: u64,
+            offset: i64,
+            reply: fuser::ReplyDirectory,
+        ) {
+            self.fuse.readdir(req, ino, fh, offset, reply)
+        }
+
+        fn readdirplus(
+            &mut self,
+            req: &Request,
+            ino: u64,
+            fh: u64,
+            offset: i64,
+            reply: fuser::ReplyDirectoryPlus,
+        ) {
+            self.fuse.readdirplus(req, ino, fh, offset, reply)
+        }
+    }
+
+    // how many times the kernel asked for attributes while stat()ing a file over and over
+    fn count_attr_calls(options: &[MountOption]) -> usize {
+        let dir = tempdir().unwrap();
+        let image = Image::new(dir.path()).unwrap();
+        let rootfs_desc = build_test_fs(&image).unwrap();
+        image.add_tag("test".to_string(), rootfs_desc).unwrap();
+        let pfs = PuzzleFS::open(&image, "test").unwrap();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counting = Counting {
+            fuse: Fuse::new(pfs).with_options(options),
+            calls: calls.clone(),
+        };
+
+        let mountpoint = tempdir().unwrap();
+        let session = fuser::Session::new(counting, mountpoint.path(), &[]).unwrap();
+        let _bg = unsafe { crate::BackgroundSession::new(session) }.unwrap();
+        let file = mountpoint.path().join("SekienAkashita.jpg");
+        for _ in 0..10 {
+            assert_eq!(fs::metadata(&file).unwrap().len(), 109466);
+        }
+        calls.load(Ordering::SeqCst)
+    }
 
     #[test]
     fn test_fuse() {
@@ -482,4 +1203,1093 @@ mod tests {
             "d9e749d9367fc908876749d6502eb212fee88c9a94892fb07da5ef3ba8bc39ed";
         assert_eq!(hex::encode(digest), FILE_DIGEST);
     }
+
+    #[test]
+    fn test_status_file() {
+        let dir = tempdir().unwrap();
+        let image = Image::new(dir.path()).unwrap();
+        let rootfs_desc = build_test_fs(&image).unwrap();
+        image.add_tag("test".to_string(), rootfs_desc).unwrap();
+        let mountpoint = tempdir().unwrap();
+        let _bg = crate::mount(&image, "test", Path::new(mountpoint.path())).unwrap();
+        // it isn't listed, so the mount is still just the image
+        let names = fs::read_dir(mountpoint.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["SekienAkashita.jpg"]);
+
+        let path = mountpoint.path().join(".puzzlefs/status");
+        let status =
+            || -> serde_json::Value { serde_json::from_slice(&fs::read(&path).unwrap()).unwrap() };
+        let before = status();
+        assert_eq!(before["image"], dir.path().to_str().unwrap());
+        assert_eq!(before["tags"], serde_json::json!(["test"]));
+        assert!(before["uptime_secs"].is_u64());
+        assert!(before["cache_capacity"].as_u64().unwrap() > 0);
+        assert!(before["cache"]["hits"].is_u64());
+        assert_eq!(before["open_files"], 0);
+
+        // what happens in the mount shows up the next time it's read
+        let jpg = mountpoint.path().join("SekienAkashita.jpg");
+        let mut f = fs::File::open(&jpg).unwrap();
+        io::copy(&mut f, &mut io::sink()).unwrap();
+        assert_eq!(status()["open_files"], 1);
+
+        let md = fs::metadata(&path).unwrap();
+        assert!(md.is_file());
+        assert_eq!(md.permissions().mode() & 0o777, 0o444);
+        assert!(fs::metadata(path.parent().unwrap()).unwrap().is_dir());
+        let names = fs::read_dir(path.parent().unwrap())
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["status"]);
+        assert!(fs::write(&path, b"").is_err());
+        assert_eq!(
+            fs::metadata(mountpoint.path().join(".puzzlefs/nope"))
+                .unwrap_err()
+                .raw_os_error(),
+            Some(libc::ENOENT)
+        );
+    }
+
+    #[test]
+    fn test_status_file_in_image() {
+        // an image's own .puzzlefs wins
+        let dir = tempdir().unwrap();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(rootfs.join(".puzzlefs")).unwrap();
+        fs::write(rootfs.join(".puzzlefs/status"), "mine").unwrap();
+        let image = Image::new(&dir.path().join("oci")).unwrap();
+        let rootfs_desc = build_initial_rootfs(&rootfs, &image).unwrap();
+        image.add_tag("test".to_string(), rootfs_desc).unwrap();
+        let mountpoint = tempdir().unwrap();
+        let _bg = crate::mount(&image, "test", Path::new(mountpoint.path())).unwrap();
+        assert_eq!(
+            fs::read_to_string(mountpoint.path().join(".puzzlefs/status")).unwrap(),
+            "mine"
+        );
+    }
+
+    #[test]
+    fn test_subdir() {
+        let dir = tempdir().unwrap();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(rootfs.join("usr/lib")).unwrap();
+        fs::write(rootfs.join("usr/lib/libc.so"), "libc").unwrap();
+        fs::write(rootfs.join("file"), "file").unwrap();
+        let image = Image::new(&dir.path().join("oci")).unwrap();
+        let rootfs_desc = build_initial_rootfs(&rootfs, &image).unwrap();
+        image.add_tag("test".to_string(), rootfs_desc).unwrap();
+
+        let fuse = |path: &str| {
+            Fuse::new(PuzzleFS::open(&image, "test").unwrap()).with_root(Path::new(path))
+        };
+        let errno = |fuse: Result<Fuse>| fuse.err().unwrap().to_errno();
+        assert_eq!(errno(fuse("/nope")), Errno::ENOENT as i32);
+        assert_eq!(errno(fuse("/file")), Errno::ENOTDIR as i32);
+        assert_eq!(errno(fuse("/usr/../file")), Errno::EINVAL as i32);
+
+        let mut fuse = fuse("usr/lib").unwrap();
+        // the kernel only ever sees the root as the root
+        let root = fuse._getattr(fuse.image_ino(FUSE_ROOT_ID)).unwrap();
+        assert_eq!(root.ino, FUSE_ROOT_ID);
+        let libc = fuse
+            ._lookup(fuse.image_ino(FUSE_ROOT_ID), OsStr::new("libc.so"))
+            .unwrap();
+        assert_eq!(libc.size, 4);
+        assert_eq!(
+            fuse._lookup(fuse.image_ino(FUSE_ROOT_ID), OsStr::new(".."))
+                .unwrap()
+                .ino,
+            FUSE_ROOT_ID
+        );
+        // just libc.so and its directory
+        assert_eq!(fuse._totals().unwrap(), (4, 2));
+    }
+
+    // one getdents64() worth of a directory listing: the names, and the offset after each one
+    fn getdents(dir: &fs::File, buf: &mut [u8]) -> Vec<(String, i64)> {
+        let n = unsafe {
+            libc::syscall(
+                libc::SYS_getdents64,
+                dir.as_raw_fd(),
+                buf.as_mut_ptr(),
+                buf.len(),
+            )
+        };
+        assert!(n >= 0, "{}", io::Error::last_os_error());
+        let mut ents = Vec::new();
+        let mut pos = 0;
+        while pos < n as usize {
+            // struct linux_dirent64: d_ino, d_off, d_reclen, d_type and then the name
+            let off = i64::from_ne_bytes(buf[pos + 8..pos + 16].try_into().unwrap());
+            let reclen = u16::from_ne_bytes(buf[pos + 16..pos + 18].try_into().unwrap()) as usize;
+            let name = &buf[pos + 19..pos + reclen];
+            let len = name.iter().position(|&b| b == 0).unwrap();
+            ents.push((String::from_utf8(name[..len].to_vec()).unwrap(), off));
+            pos += reclen;
+        }
+        ents
+    }
+
+    #[test]
+    fn test_readdir_in_pieces() {
+        let dir = tempdir().unwrap();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs).unwrap();
+        // long names, so that listing it takes the kernel a good few requests
+        let expected = (0..200).map(|i| format!("{:0>200}", i)).collect::<Vec<_>>();
+        for name in &expected {
+            fs::write(rootfs.join(name), b"").unwrap();
+        }
+        let image = Image::new(&dir.path().join("oci")).unwrap();
+        let rootfs_desc = build_initial_rootfs(&rootfs, &image).unwrap();
+        image.add_tag("test".to_string(), rootfs_desc).unwrap();
+        let mountpoint = tempdir().unwrap();
+        let _bg = crate::mount(&image, "test", mountpoint.path()).unwrap();
+
+        // a few entries at a time, like a program with a small buffer would
+        let mut buf = [0_u8; 512];
+        let listing = fs::File::open(mountpoint.path()).unwrap();
+        let mut names = Vec::new();
+        let mut halfway = None;
+        loop {
+            let ents = getdents(&listing, &mut buf);
+            if ents.is_empty() {
+                break;
+            }
+            for (name, off) in ents {
+                names.push(name);
+                if names.len() == expected.len() / 2 {
+                    halfway = Some(off);
+                }
+            }
+        }
+        assert_eq!(names, expected);
+
+        // and starting over from halfway through, in a listing of its own
+        let listing = fs::File::open(mountpoint.path()).unwrap();
+        nix::unistd::lseek(
+            listing.as_raw_fd(),
+            halfway.unwrap(),
+            nix::unistd::Whence::SeekSet,
+        )
+        .unwrap();
+        let mut rest = Vec::new();
+        loop {
+            let ents = getdents(&listing, &mut buf);
+            if ents.is_empty() {
+                break;
+            }
+            rest.extend(ents.into_iter().map(|(name, _)| name));
+        }
+        assert_eq!(rest, expected[expected.len() / 2..]);
+    }
+
+    #[test]
+    fn test_compressed_metadata() {
+        let dir = tempdir().unwrap();
+        let rootfs = dir.path().join("rootfs");
+        for d in 0..10 {
+            let sub = rootfs.join(format!("dir-{}", d));
+            fs::create_dir_all(&sub).unwrap();
+            for f in 0..100 {
+                fs::write(sub.join(format!("file-{}", f)), format!("{} {}", d, f)).unwrap();
+            }
+        }
+        let image = Image::new(&dir.path().join("oci")).unwrap();
+        let mut metadata_sizes = Vec::new();
+        let mut mountpoints = Vec::new();
+        let mut bgs = Vec::new();
+        for compress_metadata in [false, true] {
+            let options = BuildOptions {
+                compress_metadata,
+                ..BuildOptions::default()
+            };
+            let rootfs_desc = build_initial_rootfs_with_options(&rootfs, &image, &options).unwrap();
+            let tag = format!("compressed-{}", compress_metadata);
+            image.add_tag(tag.clone(), rootfs_desc).unwrap();
+            let md = image
+                .open_rootfs_blob::<compression::Noop>(&tag)
+                .unwrap()
+                .metadatas[0];
+            assert_eq!(md.compressed, compress_metadata);
+            let mut blob = image
+                .open_raw_blob(&oci::Digest::try_from(md).unwrap())
+                .unwrap();
+            metadata_sizes.push(blob.seek(io::SeekFrom::End(0)).unwrap());
+
+            let mountpoint = tempdir().unwrap();
+            bgs.push(crate::mount(&image, &tag, mountpoint.path()).unwrap());
+            mountpoints.push(mountpoint);
+        }
+        assert!(
+            metadata_sizes[1] < metadata_sizes[0] / 2,
+            "{:?}",
+            metadata_sizes
+        );
+
+        // both mounts have the same files in them
+        for d in 0..10 {
+            let sub = Path::new(&format!("dir-{}", d)).to_path_buf();
+            let list = |mountpoint: &Path| {
+                let mut names = fs::read_dir(mountpoint.join(&sub))
+                    .unwrap()
+                    .map(|ent| ent.unwrap().file_name())
+                    .collect::<Vec<_>>();
+                names.sort();
+                names
+            };
+            let names = list(mountpoints[0].path());
+            assert_eq!(names.len(), 100);
+            assert_eq!(names, list(mountpoints[1].path()));
+            for name in names {
+                let contents = mountpoints
+                    .iter()
+                    .map(|mp| fs::read(mp.path().join(&sub).join(&name)).unwrap())
+                    .collect::<Vec<_>>();
+                assert_eq!(contents[0], contents[1]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_mount_twice() {
+        let dir = tempdir().unwrap();
+        let image = Image::new(dir.path()).unwrap();
+        let rootfs_desc = build_test_fs(&image).unwrap();
+        image.add_tag("test".to_string(), rootfs_desc).unwrap();
+        let expected = fs::read("../builder/test/SekienAkashita.jpg").unwrap();
+
+        let mountpoints = [tempdir().unwrap(), tempdir().unwrap()];
+        let _bgs = mountpoints
+            .iter()
+            .map(|mp| crate::mount(&image, "test", mp.path()).unwrap())
+            .collect::<Vec<_>>();
+        let readers = mountpoints
+            .iter()
+            .flat_map(|mp| vec![mp.path().join("SekienAkashita.jpg"); 4])
+            .map(|path| std::thread::spawn(move || fs::read(path).unwrap()))
+            .collect::<Vec<_>>();
+        for reader in readers {
+            assert_eq!(reader.join().unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_fuse_from_memory() {
+        // nothing about the image touches the disk, the rootfs it is built from aside
+        let image = Image::with_store(Arc::new(MemBlobStore::default()));
+        let rootfs_desc = build_test_fs(&image).unwrap();
+        image.add_tag("test".to_string(), rootfs_desc).unwrap();
+        assert!(image.blob_path().is_none());
+
+        let mountpoint = tempdir().unwrap();
+        let _bg = crate::mount(&image, "test", Path::new(mountpoint.path())).unwrap();
+        let data = fs::read(mountpoint.path().join("SekienAkashita.jpg")).unwrap();
+        assert_eq!(
+            data,
+            fs::read("../builder/test/SekienAkashita.jpg").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_empty_and_tiny_files() {
+        let dir = tempdir().unwrap();
+        let oci_dir = dir.path().join("oci");
+        let image = Image::new(&oci_dir).unwrap();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs).unwrap();
+        fs::write(rootfs.join("empty"), b"").unwrap();
+        fs::write(rootfs.join("one"), b"x").unwrap();
+
+        let rootfs_desc = build_initial_rootfs(&rootfs, &image).unwrap();
+        image.add_tag("test".to_string(), rootfs_desc).unwrap();
+        let mountpoint = tempdir().unwrap();
+        let _bg = crate::mount(&image, "test", Path::new(mountpoint.path())).unwrap();
+
+        let empty = mountpoint.path().join("empty");
+        assert_eq!(fs::metadata(&empty).unwrap().len(), 0);
+        assert_eq!(fs::read(&empty).unwrap(), b"");
+        assert_eq!(fs::read(mountpoint.path().join("one")).unwrap(), b"x");
+    }
+
+    #[test]
+    fn test_statfs() {
+        let dir = tempdir().unwrap();
+        let oci_dir = dir.path().join("oci");
+        let image = Image::new(&oci_dir).unwrap();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(rootfs.join("dir")).unwrap();
+        // the sizes add up to four whole blocks, so nothing gets rounded up
+        let sizes = [4096, 8193, 4095];
+        for (i, size) in sizes.iter().enumerate() {
+            fs::write(rootfs.join(format!("dir/{}", i)), vec![i as u8; *size]).unwrap();
+        }
+        fs::hard_link(rootfs.join("dir/0"), rootfs.join("link")).unwrap();
+
+        let rootfs_desc = build_initial_rootfs(&rootfs, &image).unwrap();
+        image.add_tag("test".to_string(), rootfs_desc).unwrap();
+        let mountpoint = tempdir().unwrap();
+        let _bg = crate::mount(&image, "test", Path::new(mountpoint.path())).unwrap();
+
+        let stat = statvfs(mountpoint.path()).unwrap();
+        assert_eq!(
+            stat.blocks() * stat.fragment_size(),
+            sizes.iter().sum::<usize>() as u64
+        );
+        assert_eq!(stat.blocks_free(), 0);
+        assert_eq!(stat.blocks_available(), 0);
+        // the root, dir and the three files; the link is one of them
+        assert_eq!(stat.files(), 5);
+        assert!(stat.flags().contains(FsFlags::ST_RDONLY));
+    }
+
+    #[test]
+    fn test_st_blocks() {
+        let dir = tempdir().unwrap();
+        let image = Image::new(&dir.path().join("oci")).unwrap();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs).unwrap();
+        let sizes = [0, 1, 511, 512, 513, 100000];
+        for (i, size) in sizes.iter().enumerate() {
+            fs::write(rootfs.join(i.to_string()), vec![i as u8; *size]).unwrap();
+        }
+        let sparse = fs::File::create(rootfs.join("sparse")).unwrap();
+        sparse.set_len(1 << 24).unwrap();
+        sparse.write_at(b"meshuggah", 0).unwrap();
+
+        let rootfs_desc = build_initial_rootfs(&rootfs, &image).unwrap();
+        image.add_tag("test".to_string(), rootfs_desc).unwrap();
+        let du = |options: Vec<MountOption>| {
+            let mountpoint = tempdir().unwrap();
+            let options = crate::MountOptions {
+                options,
+                ..crate::MountOptions::default()
+            };
+            let _bg =
+                crate::mount_with_options(&image, "test", mountpoint.path(), &options).unwrap();
+            for (i, size) in sizes.iter().enumerate() {
+                let md = fs::metadata(mountpoint.path().join(i.to_string())).unwrap();
+                assert_eq!(md.blocks(), (*size as u64 + 511) / 512, "{}", size);
+            }
+            let sparse_blocks = fs::metadata(mountpoint.path().join("sparse"))
+                .unwrap()
+                .blocks();
+            let output = std::process::Command::new("du")
+                .args(&["-s", "-B1"])
+                .arg(mountpoint.path())
+                .output()
+                .unwrap();
+            assert!(output.status.success());
+            let total = String::from_utf8(output.stdout).unwrap();
+            let total = total.split_whitespace().next().unwrap().parse::<u64>();
+            (sparse_blocks, total.unwrap())
+        };
+
+        let files = sizes
+            .iter()
+            .map(|s| (*s as u64 + 511) / 512 * 512)
+            .sum::<u64>();
+        let (sparse_blocks, total) = du(vec![]);
+        assert_eq!(sparse_blocks, (1 << 24) / 512);
+        assert_eq!(total, files + (1 << 24));
+        // the hole is left out, the data around it isn't
+        let (sparse_blocks, total) = du(vec![MountOption::DuPhysical]);
+        assert!(sparse_blocks > 0 && sparse_blocks < (1 << 20) / 512);
+        assert_eq!(total, files + sparse_blocks * 512);
+    }
+
+    #[test]
+    fn test_attr_timeouts() {
+        // by default everything is cached forever, so only the first stat() asks
+        assert!(count_attr_calls(&[]) <= 2);
+        assert!(
+            count_attr_calls(&[
+                MountOption::EntryTimeout(3600),
+                MountOption::AttrTimeout(3600)
+            ]) <= 2
+        );
+        // without caching every stat() has to ask again
+        assert!(
+            count_attr_calls(&[MountOption::EntryTimeout(0), MountOption::AttrTimeout(0)]) >= 10
+        );
+        // the attributes that come back with a lookup mustn't be cached longer than attr_timeout
+        assert!(count_attr_calls(&[MountOption::AttrTimeout(0)]) >= 10);
+    }
+
+    #[test]
+    fn test_list_directory_attr_calls() {
+        const FILES: usize = 100;
+        let dir = tempdir().unwrap();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs).unwrap();
+        for i in 0..FILES {
+            fs::write(rootfs.join(format!("file-{}", i)), i.to_string()).unwrap();
+        }
+        let image = Image::new(&dir.path().join("oci")).unwrap();
+        let rootfs_desc = build_initial_rootfs(&rootfs, &image).unwrap();
+        image.add_tag("test".to_string(), rootfs_desc).unwrap();
+
+        // what ls -l does, twice over
+        let list = |options: &[MountOption]| {
+            let calls = Arc::new(AtomicUsize::new(0));
+            let counting = Counting {
+                fuse: Fuse::new(PuzzleFS::open(&image, "test").unwrap()).with_options(options),
+                calls: calls.clone(),
+            };
+            let mountpoint = tempdir().unwrap();
+            let session = fuser::Session::new(counting, mountpoint.path(), &[]).unwrap();
+            let _bg = unsafe { crate::BackgroundSession::new(session) }.unwrap();
+            for _ in 0..2 {
+                let mut listed = 0;
+                for ent in fs::read_dir(mountpoint.path()).unwrap() {
+                    fs::symlink_metadata(ent.unwrap().path()).unwrap();
+                    listed += 1;
+                }
+                assert_eq!(listed, FILES);
+            }
+            calls.load(Ordering::SeqCst)
+        };
+        // the entries come with their attributes, so stat()ing them doesn't ask for them again
+        let plus = list(&[]);
+        assert!(plus <= 2, "{} calls", plus);
+        // without that, a lookup for each entry the first time round, and nothing after that
+        let plain = list(&[MountOption::NoReaddirplus]);
+        assert!((FILES..=FILES + 2).contains(&plain), "{} calls", plain);
+        // and with nothing cached, a lookup for every stat() whichever it is
+        assert!(list(&[MountOption::EntryTimeout(0), MountOption::AttrTimeout(0)]) >= 2 * FILES);
+    }
+
+    #[test]
+    fn test_readlink() {
+        let dir = tempdir().unwrap();
+        let oci_dir = dir.path().join("oci");
+        let image = Image::new(&oci_dir).unwrap();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs).unwrap();
+        fs::write(rootfs.join("target"), b"meshuggah").unwrap();
+        std::os::unix::fs::symlink("target", rootfs.join("link")).unwrap();
+
+        let rootfs_desc = build_initial_rootfs(&rootfs, &image).unwrap();
+        image.add_tag("test".to_string(), rootfs_desc).unwrap();
+        let mountpoint = tempdir().unwrap();
+        let _bg = crate::mount(&image, "test", Path::new(mountpoint.path())).unwrap();
+
+        let target = fs::read_link(mountpoint.path().join("link")).unwrap();
+        assert_eq!(target, Path::new("target"));
+
+        // readlink() of something that's not a symlink should fail
+        fs::read_link(mountpoint.path().join("target")).unwrap_err();
+    }
+
+    #[test]
+    fn test_read_only() {
+        let dir = tempdir().unwrap();
+        let oci_dir = dir.path().join("oci");
+        let image = Image::new(&oci_dir).unwrap();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(rootfs.join("dir")).unwrap();
+        fs::write(rootfs.join("file"), b"meshuggah").unwrap();
+
+        let rootfs_desc = build_initial_rootfs(&rootfs, &image).unwrap();
+        image.add_tag("test".to_string(), rootfs_desc).unwrap();
+        let mountpoint = tempdir().unwrap();
+        let _bg = crate::mount(&image, "test", Path::new(mountpoint.path())).unwrap();
+        let mnt = mountpoint.path();
+
+        let erofs = |what: &str, result: io::Result<()>| {
+            assert_eq!(
+                result.unwrap_err().raw_os_error(),
+                Some(Errno::EROFS as i32),
+                "{}",
+                what
+            );
+        };
+        let nix_erofs = |what: &str, result: nix::Result<()>| {
+            assert_eq!(result, Err(nix::Error::Sys(Errno::EROFS)), "{}", what);
+        };
+        erofs(
+            "write",
+            fs::OpenOptions::new()
+                .write(true)
+                .open(mnt.join("file"))
+                .map(drop),
+        );
+        erofs("create", fs::write(mnt.join("new"), b"new"));
+        erofs("mkdir", fs::create_dir(mnt.join("newdir")));
+        erofs("unlink", fs::remove_file(mnt.join("file")));
+        erofs("rmdir", fs::remove_dir(mnt.join("dir")));
+        erofs("rename", fs::rename(mnt.join("file"), mnt.join("renamed")));
+        erofs("link", fs::hard_link(mnt.join("file"), mnt.join("linked")));
+        erofs(
+            "symlink",
+            std::os::unix::fs::symlink("file", mnt.join("symlink")),
+        );
+        erofs(
+            "setattr",
+            fs::set_permissions(mnt.join("file"), fs::Permissions::from_mode(0o600)),
+        );
+        erofs(
+            "setxattr",
+            xattr::set(mnt.join("file"), "user.new", b"value"),
+        );
+        erofs("removexattr", xattr::remove(mnt.join("file"), "user.new"));
+        nix_erofs("truncate", nix::unistd::truncate(&mnt.join("file"), 0));
+        nix_erofs(
+            "mknod",
+            nix::unistd::mkfifo(&mnt.join("fifo"), nix::sys::stat::Mode::S_IRWXU),
+        );
+        assert_eq!(fs::read(mnt.join("file")).unwrap(), b"meshuggah");
+
+        // none of that gets in the way of just reading
+        let file = fs::File::open(mnt.join("file")).unwrap();
+        file.sync_all().unwrap();
+        nix::unistd::close(file.into_raw_fd()).unwrap();
+        fs::File::open(mnt.join("dir")).unwrap().sync_all().unwrap();
+    }
+
+    #[test]
+    fn test_chunk_map_ioctl() {
+        let dir = tempdir().unwrap();
+        let image = Image::new(&dir.path().join("oci")).unwrap();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs).unwrap();
+        // more chunks than chunk_map() asks for at once
+        let data = (0..1_000_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        fs::write(rootfs.join("file"), &data).unwrap();
+        fs::write(rootfs.join("empty"), b"").unwrap();
+        let options = BuildOptions {
+            chunking: ChunkingConfig {
+                min: 0,
+                avg: 4096,
+                max: 0,
+                algo: ChunkingAlgorithm::Fixed,
+            },
+            ..BuildOptions::default()
+        };
+        let rootfs_desc = build_initial_rootfs_with_options(&rootfs, &image, &options).unwrap();
+        image.add_tag("test".to_string(), rootfs_desc).unwrap();
+        let mountpoint = tempdir().unwrap();
+        let _bg = crate::mount(&image, "test", Path::new(mountpoint.path())).unwrap();
+        let mut pfs = PuzzleFS::open(&image, "test").unwrap();
+
+        for (name, count) in &[("file", 245), ("empty", 0)] {
+            let file = fs::File::open(mountpoint.path().join(name)).unwrap();
+            let ino = pfs.lookup(1, OsStr::new(name)).unwrap();
+            let expected = pfs.chunks_for_inode(ino).unwrap();
+            assert_eq!(expected.len(), *count);
+            assert_eq!(crate::chunk_map(&file).unwrap(), expected);
+        }
+
+        // a buffer with no room for any chunks still says how many there are
+        let file = fs::File::open(mountpoint.path().join("file")).unwrap();
+        let mut buf = [0_u8; 16];
+        let request = crate::chunk_map_request(buf.len());
+        assert_eq!(
+            unsafe { libc::ioctl(file.as_raw_fd(), request, buf.as_mut_ptr()) },
+            0
+        );
+        assert_eq!(u64::from_le_bytes(buf[..8].try_into().unwrap()), 245);
+        assert_eq!(u64::from_le_bytes(buf[8..].try_into().unwrap()), 0);
+
+        // ioctls that aren't ours, and ours on things that aren't files, aren't anything
+        let errno = |path: &Path, request| {
+            let file = fs::File::open(path).unwrap();
+            let mut buf = [0_u8; 256];
+            assert_eq!(
+                unsafe { libc::ioctl(file.as_raw_fd(), request, buf.as_mut_ptr()) },
+                -1
+            );
+            io::Error::last_os_error().raw_os_error()
+        };
+        let other = nix::request_code_read!(b'P', 2, 256);
+        assert_eq!(
+            errno(&mountpoint.path().join("file"), other),
+            Some(libc::ENOTTY)
+        );
+        let ours = crate::chunk_map_request(256);
+        assert_eq!(errno(mountpoint.path(), ours), Some(libc::ENOTTY));
+        let status = mountpoint.path().join(".puzzlefs/status");
+        assert_eq!(errno(&status, ours), Some(libc::ENOTTY));
+        // and the file is none the worse for any of it
+        assert_eq!(fs::read(mountpoint.path().join("file")).unwrap(), data);
+    }
+
+    #[test]
+    fn test_special_files() {
+        let dir = tempdir().unwrap();
+        let oci_dir = dir.path().join("oci");
+        let image = Image::new(&oci_dir).unwrap();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs).unwrap();
+        nix::unistd::mkfifo(&rootfs.join("fifo"), nix::sys::stat::Mode::S_IRWXU).unwrap();
+        drop(std::os::unix::net::UnixListener::bind(rootfs.join("socket")).unwrap());
+
+        let rootfs_desc = build_initial_rootfs(&rootfs, &image).unwrap();
+        image.add_tag("test".to_string(), rootfs_desc).unwrap();
+        let mountpoint = tempdir().unwrap();
+        let _bg = crate::mount(&image, "test", Path::new(mountpoint.path())).unwrap();
+        let mnt = mountpoint.path();
+
+        let fifo = fs::symlink_metadata(mnt.join("fifo")).unwrap();
+        assert_eq!(fifo.mode() & libc::S_IFMT, libc::S_IFIFO);
+        let socket = fs::symlink_metadata(mnt.join("socket")).unwrap();
+        assert_eq!(socket.mode() & libc::S_IFMT, libc::S_IFSOCK);
+        // readdir says what they are too
+        for entry in fs::read_dir(mnt).unwrap() {
+            let entry = entry.unwrap();
+            let file_type = entry.file_type().unwrap();
+            match entry.file_name().to_str().unwrap() {
+                "fifo" => assert!(file_type.is_fifo()),
+                "socket" => assert!(file_type.is_socket()),
+                name => panic!("unexpected entry {}", name),
+            }
+        }
+
+        // the kernel serves fifos itself, so one can be opened and read (even on a readonly
+        // mount) without anything being written to it
+        let fifo = fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(mnt.join("fifo"))
+            .unwrap();
+        assert_eq!((&fifo).read(&mut [0_u8; 16]).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_xattrs() {
+        let dir = tempdir().unwrap();
+        let oci_dir = dir.path().join("oci");
+        let image = Image::new(&oci_dir).unwrap();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs).unwrap();
+        let file = rootfs.join("foo");
+        fs::write(&file, b"foo").unwrap();
+
+        xattr::set(&file, "user.empty", b"").unwrap();
+        xattr::set(&file, "user.binary", &[0, 0xff, 0, 42]).unwrap();
+
+        // security.capability requires CAP_SETFCAP, so we only test it if we're allowed to set
+        // it. this is a VFS_CAP_REVISION_2 value with cap_net_raw in the permitted set.
+        const CAPABILITY: [u8; 20] = [
+            1, 0, 0, 2, 0, 0x20, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        let privileged = xattr::set(&file, "security.capability", &CAPABILITY).is_ok();
+
+        let rootfs_desc = build_initial_rootfs(&rootfs, &image).unwrap();
+        image.add_tag("test".to_string(), rootfs_desc).unwrap();
+        let mountpoint = tempdir().unwrap();
+        let _bg = crate::mount(&image, "test", Path::new(mountpoint.path())).unwrap();
+
+        let mounted = mountpoint.path().join("foo");
+        assert_eq!(xattr::get(&mounted, "user.empty").unwrap().unwrap(), b"");
+        assert_eq!(
+            xattr::get(&mounted, "user.binary").unwrap().unwrap(),
+            &[0, 0xff, 0, 42]
+        );
+        assert!(xattr::get(&mounted, "user.missing").unwrap().is_none());
+
+        let mut names = xattr::list(&mounted)
+            .unwrap()
+            .filter(|n| n.to_string_lossy().starts_with("user."))
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, vec!["user.binary", "user.empty"]);
+
+        if privileged {
+            assert_eq!(
+                xattr::get(&mounted, "security.capability")
+                    .unwrap()
+                    .unwrap(),
+                &CAPABILITY
+            );
+        }
+
+        // and of course we're readonly
+        xattr::set(&mounted, "user.new", b"value").unwrap_err();
+    }
+
+    #[test]
+    fn test_pread_odd_offsets() {
+        use std::os::unix::fs::FileExt;
+
+        let dir = tempdir().unwrap();
+        let image = Image::new(dir.path()).unwrap();
+        let options = BuildOptions {
+            chunking: ChunkingConfig {
+                min: 0,
+                avg: 4096,
+                max: 0,
+                algo: ChunkingAlgorithm::Fixed,
+            },
+            ..BuildOptions::default()
+        };
+        let rootfs_desc =
+            build_initial_rootfs_with_options(Path::new("../builder/test"), &image, &options)
+                .unwrap();
+        image.add_tag("test".to_string(), rootfs_desc).unwrap();
+        let mountpoint = tempdir().unwrap();
+        let _bg = crate::mount(&image, "test", Path::new(mountpoint.path())).unwrap();
+
+        let expected = fs::read("../builder/test/SekienAkashita.jpg").unwrap();
+        let f = fs::File::open(mountpoint.path().join("SekienAkashita.jpg")).unwrap();
+        let len = expected.len();
+
+        // (offset, size): across one chunk boundary, across two, and from the middle of one chunk
+        // to the middle of the next
+        for (offset, size) in [(4095, 2), (4000, 5000), (1234, 4567), (len - 5000, 4999)] {
+            let mut buf = vec![0_u8; size];
+            assert_eq!(f.read_at(&mut buf, offset as u64).unwrap(), size);
+            assert_eq!(buf, &expected[offset..offset + size], "{} {}", offset, size);
+        }
+
+        // reads that go past EOF are short
+        let mut buf = vec![0_u8; 8192];
+        assert_eq!(f.read_at(&mut buf, (len - 100) as u64).unwrap(), 100);
+        assert_eq!(&buf[..100], &expected[len - 100..]);
+        assert_eq!(f.read_at(&mut buf, len as u64 + 10).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_mount_options() {
+        let dir = tempdir().unwrap();
+        let image = Image::new(dir.path()).unwrap();
+        let rootfs_desc = build_test_fs(&image).unwrap();
+        image.add_tag("test".to_string(), rootfs_desc).unwrap();
+        let mountpoint = tempdir().unwrap();
+
+        let mut options = crate::MountOptions::default();
+        options
+            .add_options("ro,entry_timeout=0,attr_timeout=1")
+            .unwrap();
+        let _bg = crate::mount_with_options(&image, "test", Path::new(mountpoint.path()), &options)
+            .unwrap();
+        let md = fs::metadata(mountpoint.path().join("SekienAkashita.jpg")).unwrap();
+        assert_eq!(md.len(), 109466);
+    }
+
+    #[test]
+    fn test_access() {
+        let dir = tempdir().unwrap();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs).unwrap();
+        for (name, mode) in &[("private", 0o600), ("shared", 0o640), ("script", 0o755)] {
+            let path = rootfs.join(name);
+            fs::write(&path, b"#!/bin/sh\n").unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(*mode)).unwrap();
+        }
+        let md = fs::metadata(rootfs.join("private")).unwrap();
+        let (owner, group) = (md.uid(), md.gid());
+        let (other, other_group) = (owner + 1, group + 1);
+
+        let image = Image::new(&dir.path().join("oci")).unwrap();
+        let rootfs_desc = build_initial_rootfs(&rootfs, &image).unwrap();
+        image.add_tag("test".to_string(), rootfs_desc).unwrap();
+        let mut fuse = Fuse::new(PuzzleFS::open(&image, "test").unwrap());
+        let private = fuse.pfs.lookup(1, OsStr::new("private")).unwrap();
+        let shared = fuse.pfs.lookup(1, OsStr::new("shared")).unwrap();
+        let script = fuse.pfs.lookup(1, OsStr::new("script")).unwrap();
+        assert_eq!(fuse._getattr(private).unwrap().perm, 0o600);
+
+        let (r, w, x) = (
+            AccessFlags::R_OK.bits() as u32,
+            AccessFlags::W_OK.bits() as u32,
+            AccessFlags::X_OK.bits() as u32,
+        );
+        let errno = |res: Result<()>| res.unwrap_err().to_errno();
+        // someone else can't read the owner's private file, or see whether it exists
+        assert_eq!(
+            errno(fuse._access(private, r, other, other_group)),
+            Errno::EACCES as i32
+        );
+        fuse._access(private, 0, other, other_group).unwrap();
+        fuse._access(private, r, owner, group).unwrap();
+        assert_eq!(
+            errno(fuse._access(private, x, owner, group)),
+            Errno::EACCES as i32
+        );
+        // the group can read, but not anyone else
+        fuse._access(shared, r, other, group).unwrap();
+        assert_eq!(
+            errno(fuse._access(shared, r, other, other_group)),
+            Errno::EACCES as i32
+        );
+        fuse._access(script, r | x, other, other_group).unwrap();
+        // root can read anything, but only execute what's executable
+        fuse._access(private, r, 0, 0).unwrap();
+        assert_eq!(errno(fuse._access(private, x, 0, 0)), Errno::EACCES as i32);
+        fuse._access(script, x, 0, 0).unwrap();
+        // and nobody can write
+        for ino in &[1, private, script] {
+            assert_eq!(errno(fuse._access(*ino, w, 0, 0)), Errno::EROFS as i32);
+            assert_eq!(
+                errno(fuse._access(*ino, r | w, owner, group)),
+                Errno::EROFS as i32
+            );
+        }
+    }
+
+    #[test]
+    fn test_file_handles() {
+        let dir = tempdir().unwrap();
+        let image = Image::new(dir.path()).unwrap();
+        let rootfs_desc = build_test_fs(&image).unwrap();
+        image.add_tag("test".to_string(), rootfs_desc).unwrap();
+        let pfs = PuzzleFS::open(&image, "test").unwrap();
+        let mut fuse = Fuse::new(pfs);
+        let expected = fs::read("../builder/test/SekienAkashita.jpg").unwrap();
+
+        let write_flags = [
+            OFlag::O_WRONLY,
+            OFlag::O_RDWR,
+            OFlag::O_TRUNC,
+            OFlag::O_APPEND,
+        ];
+        for flags in &write_flags {
+            let err = fuse._open(2, flags.bits() as u32).unwrap_err();
+            assert_eq!(err.to_errno(), Errno::EROFS as i32);
+        }
+        assert!(fuse.handles.is_empty());
+
+        let fh = fuse._open(2, OFlag::O_RDONLY.bits() as u32).unwrap();
+        let other = fuse._open(2, OFlag::O_RDONLY.bits() as u32).unwrap();
+        assert_ne!(fh, other);
+        let mut data = Vec::new();
+        loop {
+            let read = fuse._read(2, fh, data.len() as u64, 10000).unwrap();
+            if read.is_empty() {
+                break;
+            }
+            data.extend(read);
+        }
+        assert_eq!(data, expected);
+
+        fuse._release(fh);
+        fuse._release(other);
+        assert!(fuse.handles.is_empty());
+        fuse._open(1000, OFlag::O_RDONLY.bits() as u32).unwrap_err();
+    }
+
+    #[test]
+    fn test_readahead() {
+        const CHUNK_SIZE: u64 = 4096;
+        let dir = tempdir().unwrap();
+        let image = Image::new(&dir.path().join("oci")).unwrap();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs).unwrap();
+        // compressed, so the chunks are read through the cache rather than mapped
+        let mut x = 1_u64;
+        let data = (0..64 * CHUNK_SIZE)
+            .map(|_| {
+                x = x
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (x >> 60) as u8
+            })
+            .collect::<Vec<_>>();
+        fs::write(rootfs.join("file"), &data).unwrap();
+        let options = BuildOptions {
+            chunking: ChunkingConfig {
+                min: 0,
+                avg: CHUNK_SIZE,
+                max: 0,
+                algo: ChunkingAlgorithm::Fixed,
+            },
+            compression: builder::ChunkCompression::Zstd { level: 3 },
+            ..BuildOptions::default()
+        };
+        let desc = build_initial_rootfs_with_options(&rootfs, &image, &options).unwrap();
+        image.add_tag("test".to_string(), desc).unwrap();
+
+        let read_all = |fuse: &mut Fuse, fh| {
+            let mut read = Vec::new();
+            // odd sizes, so reads straddle chunks
+            while let Ok(more) = fuse._read(2, fh, read.len() as u64, 3000) {
+                if more.is_empty() {
+                    break;
+                }
+                read.extend(more);
+            }
+            read
+        };
+
+        // different cache capacities, so the two don't share a cache
+        let pfs = PuzzleFS::open_with_cache_capacity(&image, "test", 1 << 20).unwrap();
+        let mut on_demand = Fuse::new(pfs).with_options(&[MountOption::Readahead(0)]);
+        let fh = on_demand._open(2, 0).unwrap();
+        assert_eq!(read_all(&mut on_demand, fh), data);
+        assert!(on_demand.prefetcher.is_none());
+
+        let pfs = PuzzleFS::open_with_cache_capacity(&image, "test", 2 << 20).unwrap();
+        let mut fuse = Fuse::new(pfs).with_options(&[MountOption::Readahead(8)]);
+        let chunks = match fuse.pfs.find_inode(2).unwrap().mode {
+            InodeMode::File { chunks } => chunks,
+            mode => panic!("bad inode mode {:?}", mode),
+        };
+        let digests = chunks
+            .iter()
+            .map(|c| oci::Digest::try_from(c.blob.unwrap()).unwrap())
+            .collect::<Vec<_>>();
+        let fh = fuse._open(2, 0).unwrap();
+        assert_eq!(fuse._read(2, fh, 0, 100).unwrap(), &data[..100]);
+        for _ in 0..100 {
+            if digests[..8]
+                .iter()
+                .all(|d| fuse.pfs.cache.get(&d.underlying()).is_some())
+            {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(50));
+        }
+        // reads of the chunks we fetched ahead of time can only be served from the cache now
+        for digest in &digests[1..8] {
+            image.store().delete_blob(digest).unwrap();
+        }
+        let mut read = data[..100].to_vec();
+        while read.len() < 8 * CHUNK_SIZE as usize {
+            read.extend(fuse._read(2, fh, read.len() as u64, 3000).unwrap());
+        }
+        assert_eq!(read, &data[..read.len()]);
+    }
+
+    #[test]
+    fn test_partial_read_of_compressed_chunk() {
+        const CHUNK_SIZE: u64 = 4 << 20;
+        let dir = tempdir().unwrap();
+        let image = Image::new(&dir.path().join("oci")).unwrap();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs).unwrap();
+        let mut x = 1_u64;
+        let data = (0..CHUNK_SIZE)
+            .map(|_| {
+                x = x
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (x >> 56) as u8
+            })
+            .collect::<Vec<_>>();
+        fs::write(rootfs.join("file"), &data).unwrap();
+        // the whole file is one zstd chunk
+        let options = BuildOptions {
+            chunking: ChunkingConfig {
+                min: 0,
+                avg: CHUNK_SIZE,
+                max: 0,
+                algo: ChunkingAlgorithm::Fixed,
+            },
+            compression: builder::ChunkCompression::Zstd { level: 3 },
+            ..BuildOptions::default()
+        };
+        let desc = build_initial_rootfs_with_options(&rootfs, &image, &options).unwrap();
+        image.add_tag("test".to_string(), desc).unwrap();
+        let mountpoint = tempdir().unwrap();
+        let _bg = crate::mount(&image, "test", Path::new(mountpoint.path())).unwrap();
+
+        let f = fs::File::open(mountpoint.path().join("file")).unwrap();
+        let mut buf = vec![0_u8; 4096];
+        f.read_exact_at(&mut buf, 1 << 20).unwrap();
+        assert_eq!(buf, &data[1 << 20..(1 << 20) + 4096]);
+
+        // only as much of the chunk as the kernel asked for was decompressed, not all of it
+        let status: serde_json::Value =
+            serde_json::from_slice(&fs::read(mountpoint.path().join(".puzzlefs/status")).unwrap())
+                .unwrap();
+        let fetched = status["cache"]["bytes_fetched"].as_u64().unwrap();
+        assert!(fetched > 0);
+        assert!(fetched < CHUNK_SIZE / 2, "fetched {} bytes", fetched);
+    }
+
+    #[test]
+    fn test_verify_on_read() {
+        let dir = tempdir().unwrap();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs).unwrap();
+        fs::write(rootfs.join("file"), b"meshuggah rocks every day").unwrap();
+        let image = Image::with_store(Arc::new(MemBlobStore::default()));
+        let options = BuildOptions {
+            chunking: ChunkingConfig {
+                min: 0,
+                avg: 16,
+                max: 0,
+                algo: ChunkingAlgorithm::Fixed,
+            },
+            ..BuildOptions::default()
+        };
+        let desc = build_initial_rootfs_with_options(&rootfs, &image, &options).unwrap();
+        image.add_tag("test".to_string(), desc).unwrap();
+
+        let mut pfs = PuzzleFS::open(&image, "test").unwrap();
+        let blob = match pfs.find_inode(2).unwrap().mode {
+            InodeMode::File { chunks } => chunks[0].blob.unwrap(),
+            mode => panic!("bad inode mode {:?}", mode),
+        };
+        let digest = oci::Digest::try_from(blob).unwrap();
+        // the same length, so only the digest gives it away
+        image
+            .store()
+            .put_blob(&digest, &mut &b"MESHUGGAH ROCKS "[..])
+            .unwrap();
+
+        let mut fuse = Fuse::new(pfs);
+        assert_eq!(
+            fuse._read(2, 0, 0, 100).unwrap(),
+            b"MESHUGGAH ROCKS every day"
+        );
+
+        let pfs = PuzzleFS::open(&image, "test").unwrap();
+        let mut strict = Fuse::new(pfs).with_options(&[MountOption::VerifyOnRead]);
+        let err = strict._read(2, 0, 0, 100).unwrap_err();
+        assert_eq!(err.to_errno(), Errno::EIO as i32);
+        // reads that don't touch the bad chunk are fine
+        assert_eq!(strict._read(2, 0, 16, 100).unwrap(), b"every day");
+    }
+
+    #[test]
+    fn test_owner_options() {
+        let dir = tempdir().unwrap();
+        let oci_dir = dir.path().join("oci");
+        let image = Image::new(&oci_dir).unwrap();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(rootfs.join("dir")).unwrap();
+        fs::write(rootfs.join("dir/file"), b"file").unwrap();
+        let rootfs_desc = build_initial_rootfs(&rootfs, &image).unwrap();
+        image.add_tag("test".to_string(), rootfs_desc).unwrap();
+
+        let mountpoint = tempdir().unwrap();
+        let options = crate::MountOptions {
+            options: vec![MountOption::Uid(1000)],
+            ..crate::MountOptions::default()
+        };
+        let _bg = crate::mount_with_options(&image, "test", mountpoint.path(), &options).unwrap();
+        let gid = fs::metadata(&rootfs).unwrap().gid();
+        for path in &["", "dir", "dir/file"] {
+            let md = fs::metadata(mountpoint.path().join(path)).unwrap();
+            assert_eq!((md.uid(), md.gid()), (1000, gid), "{}", path);
+        }
+
+        // the file belongs to whoever built it, the ids around it aren't mapped
+        let owner = fs::metadata(rootfs.join("dir/file")).unwrap().uid();
+        let pfs = PuzzleFS::open(&image, "test").unwrap();
+        let mut fuse = Fuse::new(pfs).with_options(&[
+            MountOption::IdMap(IdMapping {
+                image: owner,
+                host: 100000,
+                count: 1,
+            }),
+            MountOption::Gid(5),
+        ]);
+        let attr = fuse._getattr(3).unwrap();
+        assert_eq!((attr.uid, attr.gid), (100000, 5));
+        let pfs = PuzzleFS::open(&image, "test").unwrap();
+        let mut fuse = Fuse::new(pfs).with_options(&[MountOption::IdMap(IdMapping {
+            image: owner.wrapping_add(1),
+            host: 100000,
+            count: 1,
+        })]);
+        let attr = fuse._getattr(3).unwrap();
+        assert_eq!((attr.uid, attr.gid), (OVERFLOW_ID, OVERFLOW_ID));
+    }
 }
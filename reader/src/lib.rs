@@ -1,27 +1,149 @@
-extern crate fuse as fuse_ffi;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
 
-use std::path::Path;
+use fuser::{Filesystem, Session};
+use nix::errno::Errno;
+use nix::mount::{umount, umount2, MntFlags};
+use thread_scoped::JoinGuard;
 
 use format::Result;
 use oci::Image;
 
+#[cfg(feature = "async-reader")]
+mod async_read;
+#[cfg(feature = "async-reader")]
+pub use async_read::{AsyncChunkStore, ChunkFuture, OciChunkStore};
+
+mod cache;
+pub use cache::{CacheStats, ChunkCache, DEFAULT_CACHE_CAPACITY};
+
+mod readahead;
+pub use readahead::DEFAULT_READAHEAD;
+
 mod puzzlefs;
-pub use puzzlefs::{Inode, InodeMode, PuzzleFS};
+pub use puzzlefs::{ChunkRange, FileReader, Inode, InodeMode, PuzzleFS, PuzzleFile};
 
 pub mod fuse;
 pub use crate::fuse::Fuse;
 
 mod walk;
-pub use walk::WalkPuzzleFS;
+pub use walk::{DirEntry, WalkEntry, WalkPuzzleFS};
+
+mod options;
+pub use options::{IdMapping, MountOption, MountOptions};
+
+mod stats;
+pub use stats::{BlobDiff, ChunkInfo, ChunkSizes, ImageStats};
+
+mod chunk_map;
+pub use chunk_map::{chunk_map, chunk_map_request, CHUNK_MAP_ENTRY_SIZE};
 
-pub fn mount<'a>(
+pub fn mount<'a>(image: &'a Image, tag: &str, mountpoint: &Path) -> Result<BackgroundSession<'a>> {
+    mount_with_options(image, tag, mountpoint, &MountOptions::default())
+}
+
+pub fn mount_with_options<'a>(
     image: &'a Image,
     tag: &str,
     mountpoint: &Path,
-) -> Result<fuse_ffi::BackgroundSession<'a>> {
-    let pfs = PuzzleFS::open(image, tag)?;
-    let fuse = Fuse::new(pfs);
-    let session = fuse_ffi::Session::new(fuse, mountpoint, &[])?;
-    let bg = unsafe { fuse_ffi::BackgroundSession::new(session) }?;
+    options: &MountOptions,
+) -> Result<BackgroundSession<'a>> {
+    mount_stack_with_options(image, &[tag], mountpoint, options)
+}
+
+/// Mounts several tags stacked on top of each other, bottom first; see `PuzzleFS::open_stack()`.
+pub fn mount_stack_with_options<'a>(
+    image: &'a Image,
+    tags: &[&str],
+    mountpoint: &Path,
+    options: &MountOptions,
+) -> Result<BackgroundSession<'a>> {
+    let session = session_stack_with_options(image, tags, mountpoint, options)?;
+    let bg = unsafe { BackgroundSession::new(session) }?;
     Ok(bg)
 }
+
+/// Like `mount_stack_with_options()`, but leaves running the session to the caller: `run()` serves
+/// requests on the calling thread until the filesystem is unmounted, e.g. with `unmount()`.
+pub fn session_stack_with_options<'a>(
+    image: &'a Image,
+    tags: &[&str],
+    mountpoint: &Path,
+    options: &MountOptions,
+) -> Result<Session<Fuse<'a>>> {
+    let pfs = PuzzleFS::open_stack_with_cache_capacity(image, tags, options.cache_capacity)?;
+    let mut fuse = Fuse::new(pfs).with_options(&options.options);
+    if let Some(subdir) = &options.subdir {
+        fuse = fuse.with_root(subdir)?;
+    }
+    Ok(Session::new(fuse, mountpoint, &options.fuse_options())?)
+}
+
+/// A mount whose requests are served on a thread of its own. Dropping it unmounts it.
+pub struct BackgroundSession<'a> {
+    mountpoint: PathBuf,
+    _guard: JoinGuard<'a, io::Result<()>>,
+}
+
+impl<'a> BackgroundSession<'a> {
+    /// Starts serving `session` on another thread.
+    ///
+    /// # Safety
+    ///
+    /// The filesystem can borrow things, so like `thread_scoped::scoped()`, this is only safe if
+    /// the returned session is dropped (rather than e.g. leaked) before anything it borrows is.
+    pub unsafe fn new<FS: Filesystem + Send + 'a>(
+        mut session: Session<FS>,
+    ) -> io::Result<BackgroundSession<'a>> {
+        let mountpoint = session.mountpoint().to_path_buf();
+        let guard = thread_scoped::scoped(move || session.run());
+        Ok(BackgroundSession {
+            mountpoint,
+            _guard: guard,
+        })
+    }
+}
+
+impl Drop for BackgroundSession<'_> {
+    fn drop(&mut self) {
+        // lazily, like fusermount -u -z, so this can't fail with files still open. the session
+        // stops once they're closed, which dropping the guard waits for.
+        let result = match umount2(&self.mountpoint, MntFlags::MNT_DETACH) {
+            Err(nix::Error::Sys(Errno::EPERM)) => Command::new("fusermount")
+                .arg("-u")
+                .arg("-z")
+                .arg(&self.mountpoint)
+                .status()
+                .map(drop),
+            result => result.map_err(|e| io::Error::new(io::ErrorKind::Other, e)),
+        };
+        if let Err(e) = result {
+            log::error!("couldn't unmount {}: {}", self.mountpoint.display(), e);
+        }
+    }
+}
+
+/// Unmounts a puzzlefs mount, which makes the session serving it return.
+pub fn unmount(mountpoint: &Path) -> io::Result<()> {
+    match umount(mountpoint) {
+        Ok(()) => Ok(()),
+        // only root gets to unmount directly, everyone else has to go through the setuid helper
+        Err(nix::Error::Sys(Errno::EPERM)) => {
+            let status = Command::new("fusermount")
+                .arg("-u")
+                .arg(mountpoint)
+                .status()?;
+            if status.success() {
+                Ok(())
+            } else {
+                Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("fusermount -u failed: {}", status),
+                ))
+            }
+        }
+        Err(nix::Error::Sys(e)) => Err(io::Error::from(e)),
+        Err(e) => Err(io::Error::new(io::ErrorKind::Other, e)),
+    }
+}
@@ -0,0 +1,265 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::cache::DEFAULT_CACHE_CAPACITY;
+
+const SUPPORTED_OPTIONS: &str = "allow_other, allow_root, auto_unmount, ro, \
+    entry_timeout=<seconds>, attr_timeout=<seconds>, readahead=<chunks>, verify_on_read, \
+    du_physical, no_readdirplus, uid=<uid>, gid=<gid>, idmap=<image id>:<host id>:<count>";
+
+/// A range of ids in the image and the ids they are shown as instead, like a line of a user
+/// namespace's uid_map: `count` ids starting at `image` become the ones starting at `host`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IdMapping {
+    pub image: u32,
+    pub host: u32,
+    pub count: u32,
+}
+
+impl IdMapping {
+    pub fn map(&self, id: u32) -> Option<u32> {
+        let offset = id.checked_sub(self.image)?;
+        if offset >= self.count {
+            return None;
+        }
+        self.host.checked_add(offset)
+    }
+}
+
+impl FromStr for IdMapping {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let ids = s
+            .split(':')
+            .map(|id| id.parse::<u32>())
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|e| format!("bad id mapping {}: {}", s, e))?;
+        match ids[..] {
+            [image, host, count] => Ok(IdMapping { image, host, count }),
+            _ => Err(format!(
+                "bad id mapping {}, it should be <image id>:<host id>:<count>",
+                s
+            )),
+        }
+    }
+}
+
+/// A mount option, in the -o syntax of mount(8).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MountOption {
+    AllowOther,
+    AllowRoot,
+    AutoUnmount,
+    ReadOnly,
+    // these two are ours rather than the kernel's: how long the kernel may cache lookups and
+    // attributes for. images are immutable, so by default that's forever.
+    EntryTimeout(u64),
+    AttrTimeout(u64),
+    // how many chunks to fetch ahead of reads that go through a file in order; 0 turns it off
+    Readahead(u64),
+    // check each chunk against its digest the first time it's read, and fail reads of the ones
+    // that don't match with EIO
+    VerifyOnRead,
+    // count only the parts of files that are actually stored (i.e. not holes) in st_blocks, rather
+    // than their whole size
+    DuPhysical,
+    // list directories with plain readdir, which leaves the kernel to look up each entry's
+    // attributes on its own, rather than readdirplus
+    NoReaddirplus,
+    // who every file is shown as owned by, whoever owns it in the image, like sshfs' options
+    Uid(u32),
+    Gid(u32),
+    // shows the owners of files as other ids, for both users and groups. ids that aren't in any
+    // of the ranges are shown as nobody, the way a user namespace does. uid and gid win over this.
+    IdMap(IdMapping),
+}
+
+impl MountOption {
+    // the option to hand to fuser, if it needs to know about it
+    fn fuse_option(&self) -> Option<fuser::MountOption> {
+        match self {
+            MountOption::AllowOther => Some(fuser::MountOption::AllowOther),
+            MountOption::AllowRoot => Some(fuser::MountOption::AllowRoot),
+            MountOption::AutoUnmount => Some(fuser::MountOption::AutoUnmount),
+            MountOption::ReadOnly => Some(fuser::MountOption::RO),
+            MountOption::EntryTimeout(..)
+            | MountOption::AttrTimeout(..)
+            | MountOption::Readahead(..)
+            | MountOption::VerifyOnRead
+            | MountOption::DuPhysical
+            | MountOption::NoReaddirplus
+            | MountOption::Uid(..)
+            | MountOption::Gid(..)
+            | MountOption::IdMap(..) => None,
+        }
+    }
+}
+
+impl FromStr for MountOption {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let unknown = || {
+            format!(
+                "unknown mount option {}, supported options are: {}",
+                s, SUPPORTED_OPTIONS
+            )
+        };
+        let seconds = |v: &str| {
+            v.parse::<u64>()
+                .map_err(|e| format!("bad timeout in mount option {}: {}", s, e))
+        };
+        let id = |v: &str| {
+            v.parse::<u32>()
+                .map_err(|e| format!("bad id in mount option {}: {}", s, e))
+        };
+
+        match s.split_once('=') {
+            None => match s {
+                "allow_other" => Ok(MountOption::AllowOther),
+                "allow_root" => Ok(MountOption::AllowRoot),
+                "auto_unmount" => Ok(MountOption::AutoUnmount),
+                "ro" => Ok(MountOption::ReadOnly),
+                "verify_on_read" => Ok(MountOption::VerifyOnRead),
+                "du_physical" => Ok(MountOption::DuPhysical),
+                "no_readdirplus" => Ok(MountOption::NoReaddirplus),
+                _ => Err(unknown()),
+            },
+            Some(("entry_timeout", v)) => Ok(MountOption::EntryTimeout(seconds(v)?)),
+            Some(("attr_timeout", v)) => Ok(MountOption::AttrTimeout(seconds(v)?)),
+            Some(("readahead", v)) => {
+                Ok(MountOption::Readahead(v.parse().map_err(|e| {
+                    format!("bad chunk count in mount option {}: {}", s, e)
+                })?))
+            }
+            Some(("uid", v)) => Ok(MountOption::Uid(id(v)?)),
+            Some(("gid", v)) => Ok(MountOption::Gid(id(v)?)),
+            Some(("idmap", v)) => Ok(MountOption::IdMap(v.parse()?)),
+            Some(_) => Err(unknown()),
+        }
+    }
+}
+
+pub struct MountOptions {
+    /// How many bytes of chunk data to keep cached in memory.
+    pub cache_capacity: u64,
+    pub options: Vec<MountOption>,
+    /// The directory in the image to mount rather than its root.
+    pub subdir: Option<PathBuf>,
+}
+
+impl MountOptions {
+    /// Parses a comma separated list of options, as given to -o.
+    pub fn add_options(&mut self, options: &str) -> std::result::Result<(), String> {
+        for option in options.split(',').filter(|o| !o.is_empty()) {
+            self.options.push(option.parse()?);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn fuse_options(&self) -> Vec<fuser::MountOption> {
+        // images can't be written to, so the mount never can either
+        let mut fuse_options = vec![fuser::MountOption::RO];
+        for option in self.options.iter().filter_map(MountOption::fuse_option) {
+            if !fuse_options.contains(&option) {
+                fuse_options.push(option);
+            }
+        }
+        fuse_options
+    }
+}
+
+impl Default for MountOptions {
+    fn default() -> Self {
+        MountOptions {
+            cache_capacity: DEFAULT_CACHE_CAPACITY,
+            options: Vec::new(),
+            subdir: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_id_mapping() {
+        let mapping = IdMapping {
+            image: 10,
+            host: 1000,
+            count: 5,
+        };
+        assert_eq!(mapping.map(9), None);
+        assert_eq!(mapping.map(10), Some(1000));
+        assert_eq!(mapping.map(14), Some(1004));
+        assert_eq!(mapping.map(15), None);
+        let wraps = IdMapping {
+            image: 0,
+            host: u32::MAX,
+            count: 2,
+        };
+        assert_eq!(wraps.map(1), None);
+    }
+
+    #[test]
+    fn test_parse_options() {
+        let mut options = MountOptions::default();
+        options
+            .add_options("allow_other,ro,entry_timeout=5")
+            .unwrap();
+        options
+            .add_options("attr_timeout=10,readahead=0,verify_on_read,du_physical")
+            .unwrap();
+        assert_eq!(
+            options.options,
+            vec![
+                MountOption::AllowOther,
+                MountOption::ReadOnly,
+                MountOption::EntryTimeout(5),
+                MountOption::AttrTimeout(10),
+                MountOption::Readahead(0),
+                MountOption::VerifyOnRead,
+                MountOption::DuPhysical,
+            ]
+        );
+        assert_eq!(
+            options.fuse_options(),
+            vec![fuser::MountOption::RO, fuser::MountOption::AllowOther]
+        );
+
+        let err = options.add_options("ro,bogus").unwrap_err();
+        assert!(err.contains("bogus"), "{}", err);
+        assert!(err.contains(SUPPORTED_OPTIONS), "{}", err);
+        options.add_options("entry_timeout=soon").unwrap_err();
+        options.add_options("readahead=-1").unwrap_err();
+        options.add_options("allow_other=1").unwrap_err();
+
+        let mut options = MountOptions::default();
+        options
+            .add_options("uid=1000,gid=0,idmap=0:100000:65536")
+            .unwrap();
+        assert_eq!(
+            options.options,
+            vec![
+                MountOption::Uid(1000),
+                MountOption::Gid(0),
+                MountOption::IdMap(IdMapping {
+                    image: 0,
+                    host: 100000,
+                    count: 65536
+                }),
+            ]
+        );
+        assert_eq!(options.fuse_options(), vec![fuser::MountOption::RO]);
+        options.add_options("uid=-1").unwrap_err();
+        options.add_options("idmap=0:100000").unwrap_err();
+        options.add_options("idmap=0:x:1").unwrap_err();
+
+        assert_eq!(
+            MountOptions::default().fuse_options(),
+            vec![fuser::MountOption::RO]
+        );
+    }
+}
@@ -1,12 +1,20 @@
 use std::cmp::min;
+use std::collections::{BTreeMap, HashMap};
 use std::convert::TryFrom;
 use std::ffi::{OsStr, OsString};
 use std::io;
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
 
 use nix::errno::Errno;
 
-use format::{FileChunk, Ino, InodeAdditional, MetadataBlob, Result, WireFormatError};
-use oci::{Digest, Image};
+use format::{
+    BlobRef, ChunkingConfig, FileChunk, Ino, InodeAdditional, MetadataBlob, Result, WireFormatError,
+};
+use oci::{ChunkStream, Image};
+
+use crate::cache::{CacheStats, ChunkCache, DEFAULT_CACHE_CAPACITY};
 
 #[derive(Debug)]
 pub struct Inode {
@@ -23,14 +31,17 @@ impl Inode {
                 InodeMode::File { chunks }
             }
             format::InodeMode::Dir { offset } => {
-                let mut entries = layer
-                    .read_dir_list(offset)?
+                let mut dir_list = layer.read_dir_list(offset)?;
+                let mut entries = dir_list
                     .entries
                     .iter_mut()
                     .map(|de| (de.name.clone(), de.ino))
                     .collect::<Vec<(OsString, Ino)>>();
                 entries.sort_by(|(a, _), (b, _)| a.cmp(b));
-                InodeMode::Dir { entries }
+                InodeMode::Dir {
+                    entries,
+                    look_below: dir_list.look_below,
+                }
             }
             _ => InodeMode::Other,
         };
@@ -49,11 +60,30 @@ impl Inode {
 
     pub fn dir_entries(&self) -> Result<&Vec<(OsString, Ino)>> {
         match &self.mode {
-            InodeMode::Dir { entries } => Ok(entries),
+            InodeMode::Dir { entries, .. } => Ok(entries),
             _ => Err(WireFormatError::from_errno(Errno::ENOTDIR)),
         }
     }
 
+    pub fn is_dir(&self) -> bool {
+        matches!(self.mode, InodeMode::Dir { .. })
+    }
+
+    /// Whether this directory hides whatever lower layers have in it.
+    pub fn is_opaque(&self) -> bool {
+        matches!(
+            self.mode,
+            InodeMode::Dir {
+                look_below: false,
+                ..
+            }
+        )
+    }
+
+    pub fn is_whiteout(&self) -> bool {
+        matches!(self.inode.mode, format::InodeMode::Wht)
+    }
+
     pub fn dir_lookup(&self, name: &OsStr) -> Result<u64> {
         let entries = self.dir_entries()?;
         entries
@@ -72,6 +102,20 @@ impl Inode {
         Ok(chunks.iter().map(|c| c.len).sum())
     }
 
+    /// Checks a file's chunk list against the digest the build stored for it (see
+    /// `BuildOptions::file_digests`), without reading any of its content. Returns whether there
+    /// was one to check.
+    pub fn verify_file_digest(&self) -> Result<bool> {
+        let chunks = match &self.mode {
+            InodeMode::File { chunks } => chunks,
+            _ => return Err(WireFormatError::from_errno(Errno::ENOTDIR)),
+        };
+        match self.additional.as_ref().and_then(|a| a.file_digest) {
+            Some(expected) => format::verify_chunk_list(chunks, &expected).map(|_| true),
+            None => Ok(false),
+        }
+    }
+
     pub fn symlink_target(&self) -> Result<&OsString> {
         self.additional
             .as_ref()
@@ -83,105 +127,518 @@ impl Inode {
 
 #[derive(Debug)]
 pub enum InodeMode {
-    File { chunks: Vec<FileChunk> },
-    Dir { entries: Vec<(OsString, Ino)> },
+    File {
+        chunks: Vec<FileChunk>,
+    },
+    Dir {
+        entries: Vec<(OsString, Ino)>,
+        look_below: bool,
+    },
     Other,
 }
 
-pub(crate) fn file_read(
-    oci: &Image,
-    inode: &Inode,
-    offset: usize,
-    data: &mut [u8],
-) -> Result<usize> {
+/// One of the pieces a file is made of: `len` bytes at `offset` into the file, which are the bytes
+/// at `blob_offset` into the chunk blob `digest`, or zeros for a hole (no digest). Files are
+/// chunked as one stream, so a blob can hold the end of one file and the start of the next; the
+/// same bytes are the same digest at the same blob offset.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChunkRange {
+    pub offset: u64,
+    pub len: u64,
+    pub digest: Option<oci::Digest>,
+    pub blob_offset: u64,
+}
+
+// one chunk's share of a read: which chunk (None for a hole), how far into it the read starts,
+// where that goes in the read's buffer, and whether that's less than the whole chunk
+pub(crate) struct ChunkRead {
+    pub(crate) blob: Option<BlobRef>,
+    pub(crate) addl_offset: u64,
+    pub(crate) buf: Range<usize>,
+    pub(crate) partial: bool,
+}
+
+// the chunks a read of `len` bytes at `offset` into a file has to look at, in order
+pub(crate) fn chunk_reads(inode: &Inode, offset: usize, len: usize) -> Result<Vec<ChunkRead>> {
     let chunks = match &inode.mode {
         InodeMode::File { chunks } => chunks,
         _ => return Err(WireFormatError::from_errno(Errno::ENOTDIR)),
     };
 
-    // TODO: fix all this casting...
-    let end = offset + data.len();
+    // where each chunk starts in the file; the first chunk we need is the last one starting at or
+    // before the offset.
+    let starts = chunks
+        .iter()
+        .scan(0, |start, c| {
+            let cur = *start;
+            *start += c.len as usize;
+            Some(cur)
+        })
+        .collect::<Vec<usize>>();
+    let first = starts
+        .partition_point(|&start| start <= offset)
+        .saturating_sub(1);
 
-    let mut file_offset = 0;
+    // TODO: fix all this casting...
+    let mut reads = Vec::new();
     let mut buf_offset = 0;
-    for chunk in chunks {
+    for (chunk, file_offset) in chunks[first..].iter().zip(&starts[first..]) {
         // have we read enough?
-        if file_offset > end {
+        if buf_offset == len {
             break;
         }
 
-        // should we skip this chunk?
-        if file_offset + (chunk.len as usize) < offset {
-            file_offset += chunk.len as usize;
+        // past EOF (or an empty chunk)?
+        let chunk_len = chunk.len as usize;
+        let addl_offset = offset + buf_offset - file_offset;
+        if addl_offset >= chunk_len {
             continue;
         }
 
         // ok, need to read this chunk; how much?
-        let left_in_buf = data.len() - buf_offset;
-        let to_read = min(left_in_buf, chunk.len as usize);
-
-        let start = buf_offset as usize;
-        let finish = start + to_read;
-        let addl_offset = if offset > file_offset {
-            offset - file_offset
-        } else {
-            0
-        };
-        file_offset += addl_offset;
+        let to_read = min(len - buf_offset, chunk_len - addl_offset);
+        reads.push(ChunkRead {
+            blob: chunk.blob,
+            addl_offset: addl_offset as u64,
+            buf: buf_offset..buf_offset + to_read,
+            partial: addl_offset > 0 || to_read < chunk_len,
+        });
+        buf_offset += to_read;
+    }
+    Ok(reads)
+}
 
+// reads from the image `stream` reads from; the stream is there for the reads that only need part
+// of a compressed chunk, see ChunkCache::fill_part_from_chunk()
+pub(crate) fn file_read(
+    stream: &mut ChunkStream,
+    cache: &ChunkCache,
+    inode: &Inode,
+    offset: usize,
+    data: &mut [u8],
+) -> Result<usize> {
+    let mut buf_offset = 0;
+    for read in chunk_reads(inode, offset, data.len())? {
+        let to_read = read.buf.len();
         // how many did we actually read?
-        let n = oci.fill_from_chunk(chunk.blob, addl_offset as u64, &mut data[start..finish])?;
-        file_offset += n;
+        let n = match read.blob {
+            Some(blob) if read.partial => {
+                cache.fill_part_from_chunk(stream, blob, read.addl_offset, &mut data[read.buf])?
+            }
+            Some(blob) => cache.fill_from_chunk(
+                stream.image(),
+                blob,
+                read.addl_offset,
+                &mut data[read.buf],
+            )?,
+            None => {
+                data[read.buf].iter_mut().for_each(|b| *b = 0);
+                to_read
+            }
+        };
         buf_offset += n;
+        if n < to_read {
+            // the blob is shorter than its chunk claims; don't read the next chunk into the hole
+            break;
+        }
     }
 
     // discard any extra if we hit EOF
-    Ok(buf_offset as usize)
+    Ok(buf_offset)
 }
 
 pub struct PuzzleFS<'a> {
-    pub(crate) oci: &'a Image<'a>,
-    layers: Vec<format::MetadataBlob>,
+    pub(crate) oci: &'a Image,
+    // the metadata blobs of each tag, bottom of the stack first
+    layers: Vec<Vec<format::MetadataBlob>>,
+    // when stacking tags, each layer's version of the directories we've come across so far (top
+    // first), by merged inode number
+    dirs: HashMap<Ino, Vec<(usize, Ino)>>,
+    // the entries of every directory lookup() has been asked about, by name, and the directory
+    // each thing it found is in
+    lookups: HashMap<Ino, HashMap<OsString, Ino>>,
+    parents: HashMap<Ino, Ino>,
+    chunking: ChunkingConfig,
+    pub(crate) cache: Arc<ChunkCache>,
+    // what was opened, bottom of the stack first
+    tags: Vec<String>,
 }
 
 impl<'a> PuzzleFS<'a> {
     pub fn open(oci: &'a Image, tag: &str) -> format::Result<PuzzleFS<'a>> {
-        let rootfs = oci.open_rootfs_blob::<compression::Noop>(tag)?;
-        let layers = rootfs
-            .metadatas
+        Self::open_with_cache_capacity(oci, tag, DEFAULT_CACHE_CAPACITY)
+    }
+
+    /// Like open(), for the tag Image::default_tag() picks.
+    pub fn open_default(oci: &'a Image) -> format::Result<PuzzleFS<'a>> {
+        Self::open(oci, &oci.default_tag()?)
+    }
+
+    /// Like open(), but keeps at most `cache_capacity` bytes of chunk data cached in memory. The
+    /// cache is shared with anything else that has the image open with the same capacity, see
+    /// ChunkCache::shared().
+    pub fn open_with_cache_capacity(
+        oci: &'a Image,
+        tag: &str,
+        cache_capacity: u64,
+    ) -> format::Result<PuzzleFS<'a>> {
+        Self::open_stack_with_cache_capacity(oci, &[tag], cache_capacity)
+    }
+
+    /// Opens several tags stacked on top of each other, bottom first, the way overlayfs would
+    /// stack them: files in upper tags shadow the ones below, directories are merged, and
+    /// whiteouts and opaque directories hide things from lower tags.
+    pub fn open_stack(oci: &'a Image, tags: &[&str]) -> format::Result<PuzzleFS<'a>> {
+        Self::open_stack_with_cache_capacity(oci, tags, DEFAULT_CACHE_CAPACITY)
+    }
+
+    pub fn open_stack_with_cache_capacity(
+        oci: &'a Image,
+        tags: &[&str],
+        cache_capacity: u64,
+    ) -> format::Result<PuzzleFS<'a>> {
+        let mut layers = Vec::new();
+        let mut chunking = None;
+        for tag in tags {
+            let rootfs = oci.open_rootfs_blob::<compression::Noop>(tag)?;
+            let metadatas = rootfs
+                .metadatas
+                .iter()
+                .map(|md| oci.open_rootfs_metadata(md))
+                .collect::<format::Result<Vec<MetadataBlob>>>()?;
+            layers.push(metadatas);
+            // the top of the stack is the newest, so it's the one worth building on
+            chunking = Some(rootfs.chunking);
+        }
+        let chunking = chunking.ok_or_else(|| WireFormatError::from_errno(Errno::EINVAL))?;
+
+        let mut pfs = PuzzleFS {
+            oci,
+            layers,
+            dirs: HashMap::new(),
+            lookups: HashMap::new(),
+            parents: HashMap::new(),
+            chunking,
+            cache: ChunkCache::shared(oci, cache_capacity),
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+        };
+        if pfs.layers.len() > 1 {
+            let mut root = Vec::new();
+            for layer in (0..pfs.layers.len()).rev() {
+                root.push((layer, 1));
+                if pfs.find_layer_inode(layer, 1)?.is_opaque() {
+                    break;
+                }
+            }
+            pfs.dirs.insert(1, root);
+        }
+        Ok(pfs)
+    }
+
+    /// The tags this was opened with, bottom of the stack first.
+    pub fn tags(&self) -> &[String] {
+        &self.tags
+    }
+
+    /// The parameters this image's file content was chunked with at build time.
+    pub fn chunking_config(&self) -> &ChunkingConfig {
+        &self.chunking
+    }
+
+    /// How the chunk cache has done so far, e.g. for picking a cache capacity. The cache may be
+    /// shared (see ChunkCache::shared()), in which case this counts everything sharing it.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    /// Opens the file with inode number `ino` for reading, without going through a mount.
+    pub fn open_file(&mut self, ino: u64) -> Result<PuzzleFile<'a>> {
+        let inode = self.find_inode(ino)?;
+        PuzzleFile::new(self.oci, self.cache.clone(), inode)
+    }
+
+    /// The chunks of the file with inode number `ino`, in order, e.g. for telling which parts of
+    /// it two images have in common.
+    pub fn chunks_for_inode(&mut self, ino: u64) -> Result<Vec<ChunkRange>> {
+        let chunks = match self.find_inode(ino)?.mode {
+            InodeMode::File { chunks } => chunks,
+            _ => return Err(WireFormatError::from_errno(Errno::ENOTDIR)),
+        };
+        let mut offset = 0;
+        chunks
             .iter()
-            .map(|md| -> Result<MetadataBlob> {
-                let digest = &<Digest>::try_from(md)?;
-                oci.open_metadata_blob::<compression::Noop>(digest)
-                    .map_err(|e| e.into())
+            .map(|chunk| {
+                let (digest, blob_offset) = match chunk.blob {
+                    Some(blob) => (Some(oci::Digest::try_from(blob)?), blob.offset),
+                    None => (None, 0),
+                };
+                let range = ChunkRange {
+                    offset,
+                    len: chunk.len,
+                    digest,
+                    blob_offset,
+                };
+                offset += chunk.len;
+                Ok(range)
             })
-            .collect::<format::Result<Vec<MetadataBlob>>>()?;
-        Ok(PuzzleFS { oci, layers })
+            .collect()
     }
 
+    /// Finds an inode by its number. In a stack of tags, directories are only known once their
+    /// parent has been looked at, since that's where we find out what's below them.
     pub fn find_inode(&mut self, ino: u64) -> Result<Inode> {
-        for mut layer in self.layers.iter_mut() {
-            if let Some(inode) = layer.find_inode(ino)? {
-                return Inode::new(&mut layer, inode);
+        if self.layers.len() == 1 {
+            return self.find_layer_inode(0, ino);
+        }
+        if let Some(versions) = self.dirs.get(&ino) {
+            let versions = versions.clone();
+            return self.merge_dir(ino, &versions);
+        }
+        if ino == 0 {
+            return Err(WireFormatError::from_errno(Errno::ENOENT));
+        }
+        let (layer, layer_ino) = self.unstack_ino(ino);
+        let mut inode = self.find_layer_inode(layer, layer_ino)?;
+        if inode.is_dir() {
+            return Err(WireFormatError::from_errno(Errno::ENOENT));
+        }
+        inode.inode.ino = ino;
+        Ok(inode)
+    }
+
+    /// Finds `name` in the directory `parent`, `.` and `..` included. The first lookup in a
+    /// directory indexes all of its entries, so later ones neither decode nor scan it again.
+    pub fn lookup(&mut self, parent: Ino, name: &OsStr) -> Result<Ino> {
+        if name == "." {
+            self.index_dir(parent)?;
+            return Ok(parent);
+        }
+        if name == ".." {
+            return self.parent_of(parent);
+        }
+        let ino = self
+            .index_dir(parent)?
+            .get(name)
+            .copied()
+            .ok_or_else(|| WireFormatError::from_errno(Errno::ENOENT))?;
+        self.parents.insert(ino, parent);
+        Ok(ino)
+    }
+
+    /// Finds a path, which is relative to the root of the image whether it starts with / or not.
+    pub fn lookup_path(&mut self, path: &Path) -> Result<Ino> {
+        let mut ino = 1;
+        for component in path.components() {
+            match component {
+                Component::Normal(name) => ino = self.lookup(ino, name)?,
+                Component::RootDir | Component::CurDir => (),
+                Component::ParentDir | Component::Prefix(_) => {
+                    return Err(WireFormatError::from_errno(Errno::EINVAL))
+                }
+            }
+        }
+        Ok(ino)
+    }
+
+    /// Every path `ino` is at, e.g. for saying which file something went wrong with: one for a
+    /// directory, one per hard link for anything else. Directories are found by going up through
+    /// their parents, anything else may mean looking through every directory in the image.
+    pub fn path_for_inode(&mut self, ino: Ino) -> Result<Vec<PathBuf>> {
+        let inode = self.find_inode(ino)?;
+        if inode.is_dir() {
+            return Ok(vec![self.dir_path(ino)?]);
+        }
+        let mut paths = Vec::new();
+        let mut todo = vec![(1, PathBuf::from("/"))];
+        while let Some((dir, path)) = todo.pop() {
+            let entries = self
+                .index_dir(dir)?
+                .iter()
+                .map(|(name, child)| (name.clone(), *child))
+                .collect::<Vec<_>>();
+            for (name, child) in entries {
+                if child == ino {
+                    paths.push(path.join(name));
+                } else if self.find_inode(child)?.is_dir() {
+                    self.parents.insert(child, dir);
+                    todo.push((child, path.join(name)));
+                }
+            }
+            // no need to look any further once every link has turned up
+            if paths.len() >= inode.inode.nlink as usize {
+                break;
+            }
+        }
+        if paths.is_empty() {
+            return Err(WireFormatError::from_errno(Errno::ENOENT));
+        }
+        paths.sort();
+        Ok(paths)
+    }
+
+    fn dir_path(&mut self, ino: Ino) -> Result<PathBuf> {
+        let mut names = Vec::new();
+        let mut dir = ino;
+        while dir != 1 {
+            let parent = self.parent_of(dir)?;
+            let name = self
+                .index_dir(parent)?
+                .iter()
+                .find(|(_, child)| **child == dir)
+                .map(|(name, _)| name.clone())
+                .ok_or_else(|| WireFormatError::from_errno(Errno::ENOENT))?;
+            names.push(name);
+            dir = parent;
+        }
+        Ok(Path::new("/").join(names.iter().rev().collect::<PathBuf>()))
+    }
+
+    fn index_dir(&mut self, ino: Ino) -> Result<&HashMap<OsString, Ino>> {
+        if self.lookups.get(&ino).is_none() {
+            let dir = self.find_inode(ino)?;
+            let index = dir.dir_entries()?.iter().cloned().collect();
+            self.lookups.insert(ino, index);
+        }
+        Ok(&self.lookups[&ino])
+    }
+
+    fn parent_of(&mut self, ino: Ino) -> Result<Ino> {
+        self.index_dir(ino)?;
+        // the root is its own parent
+        if ino == 1 {
+            return Ok(1);
+        }
+        if let Some(parent) = self.parents.get(&ino) {
+            return Ok(*parent);
+        }
+        // we were never asked to look this directory up, so go looking for it from the top
+        let mut todo = vec![1];
+        while let Some(dir) = todo.pop() {
+            let children = match self.index_dir(dir) {
+                Ok(index) => index.values().copied().collect::<Vec<_>>(),
+                Err(_) => continue,
+            };
+            for child in children {
+                self.parents.insert(child, dir);
+                if child == ino {
+                    return Ok(dir);
+                }
+                todo.push(child);
+            }
+        }
+        Err(WireFormatError::from_errno(Errno::ENOENT))
+    }
+
+    fn find_layer_inode(&mut self, layer: usize, ino: Ino) -> Result<Inode> {
+        for mut md in self.layers[layer].iter_mut() {
+            if let Some(inode) = md.find_inode(ino)? {
+                return Inode::new(&mut md, inode);
             }
         }
 
         Err(format::WireFormatError::from_errno(Errno::ENOENT))
     }
+
+    // every layer starts numbering its inodes at 1, so they're interleaved to not collide in the
+    // merged namespace. with a single layer nothing changes.
+    fn stack_ino(&self, layer: usize, ino: Ino) -> Ino {
+        (ino - 1) * self.layers.len() as u64 + layer as u64 + 1
+    }
+
+    fn unstack_ino(&self, ino: Ino) -> (usize, Ino) {
+        let layers = self.layers.len() as u64;
+        (((ino - 1) % layers) as usize, (ino - 1) / layers + 1)
+    }
+
+    // a directory as the stack sees it: the entries of all of its versions, upper ones shadowing
+    // lower ones, minus whatever was whited out. the metadata comes from the top version.
+    fn merge_dir(&mut self, ino: Ino, versions: &[(usize, Ino)]) -> Result<Inode> {
+        enum Merged {
+            Hidden,
+            Other(Ino),
+            // the versions of a subdirectory, and whether to keep looking for more below
+            Dir(Vec<(usize, Ino)>, bool),
+        }
+
+        let mut merged = BTreeMap::<OsString, Merged>::new();
+        let mut top = None;
+        let mut look_below = true;
+        for &(layer, dir_ino) in versions {
+            let dir = self.find_layer_inode(layer, dir_ino)?;
+            look_below = !dir.is_opaque();
+            for (name, child_ino) in dir.dir_entries()? {
+                match merged.get(name) {
+                    None | Some(Merged::Dir(_, true)) => {}
+                    Some(_) => continue,
+                }
+                let child = self.find_layer_inode(layer, *child_ino)?;
+                match merged.get_mut(name) {
+                    // a directory merges with directories below it, but hides anything else
+                    Some(Merged::Dir(child_versions, looking)) => {
+                        if child.is_dir() {
+                            child_versions.push((layer, *child_ino));
+                            *looking = !child.is_opaque();
+                        } else {
+                            *looking = false;
+                        }
+                    }
+                    _ => {
+                        let m = if child.is_whiteout() {
+                            Merged::Hidden
+                        } else if child.is_dir() {
+                            Merged::Dir(vec![(layer, *child_ino)], !child.is_opaque())
+                        } else {
+                            Merged::Other(self.stack_ino(layer, *child_ino))
+                        };
+                        merged.insert(name.clone(), m);
+                    }
+                }
+            }
+            top.get_or_insert(dir);
+        }
+
+        let mut entries = Vec::new();
+        let mut subdirs = 0;
+        for (name, m) in merged {
+            match m {
+                Merged::Hidden => {}
+                Merged::Other(child_ino) => entries.push((name, child_ino)),
+                Merged::Dir(child_versions, _) => {
+                    // named after the lowest version, which belongs to no other directory
+                    let (layer, lowest) = child_versions[child_versions.len() - 1];
+                    let child_ino = self.stack_ino(layer, lowest);
+                    self.dirs.insert(child_ino, child_versions);
+                    entries.push((name, child_ino));
+                    subdirs += 1;
+                }
+            }
+        }
+
+        let mut inode = top.ok_or_else(|| WireFormatError::from_errno(Errno::ENOENT))?;
+        inode.inode.ino = ino;
+        inode.inode.nlink = 2 + subdirs;
+        inode.mode = InodeMode::Dir {
+            entries,
+            look_below,
+        };
+        Ok(inode)
+    }
 }
 
 pub struct FileReader<'a> {
-    oci: &'a Image<'a>,
+    stream: ChunkStream<'a>,
+    cache: &'a ChunkCache,
     inode: &'a Inode,
     offset: usize,
     len: usize,
 }
 
 impl<'a> FileReader<'a> {
-    pub fn new(oci: &'a Image<'a>, inode: &'a Inode) -> Result<FileReader<'a>> {
+    pub fn new(oci: &'a Image, cache: &'a ChunkCache, inode: &'a Inode) -> Result<FileReader<'a>> {
         let len = inode.file_len()? as usize;
         Ok(FileReader {
-            oci,
+            stream: ChunkStream::new(oci),
+            cache,
             inode,
             offset: 0,
             len,
@@ -191,28 +648,262 @@ impl<'a> FileReader<'a> {
 
 impl io::Read for FileReader<'_> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let to_read = min(self.len - self.offset, buf.len());
+        let to_read = min(self.len.saturating_sub(self.offset), buf.len());
         if to_read == 0 {
             return Ok(0);
         }
 
-        let read = file_read(self.oci, self.inode, self.offset, &mut buf[0..to_read])
-            .map_err(|e| io::Error::from_raw_os_error(e.to_errno()))?;
+        let read = file_read(
+            &mut self.stream,
+            self.cache,
+            self.inode,
+            self.offset,
+            &mut buf[0..to_read],
+        )
+        .map_err(|e| io::Error::from_raw_os_error(e.to_errno()))?;
         self.offset += read;
         Ok(read)
     }
 }
 
+impl io::Seek for FileReader<'_> {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let offset = seek_offset(pos, self.offset as u64, self.len as u64)?;
+        self.offset = offset as usize;
+        Ok(offset)
+    }
+}
+
+/// A read-only handle to a file's contents, which fetches the chunks covering each read from the
+/// image as they're needed. Compressed chunks that aren't cached are only decompressed as far as
+/// reads need, with each read carrying on from where the last one stopped.
+pub struct PuzzleFile<'a> {
+    stream: ChunkStream<'a>,
+    cache: Arc<ChunkCache>,
+    inode: Inode,
+    offset: u64,
+    len: u64,
+}
+
+impl<'a> PuzzleFile<'a> {
+    fn new(oci: &'a Image, cache: Arc<ChunkCache>, inode: Inode) -> Result<PuzzleFile<'a>> {
+        let len = inode.file_len()?;
+        Ok(PuzzleFile {
+            stream: ChunkStream::new(oci),
+            cache,
+            inode,
+            offset: 0,
+            len,
+        })
+    }
+
+    pub fn inode(&self) -> &Inode {
+        &self.inode
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl io::Read for PuzzleFile<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let to_read = min(self.len.saturating_sub(self.offset), buf.len() as u64) as usize;
+        if to_read == 0 {
+            return Ok(0);
+        }
+
+        let read = file_read(
+            &mut self.stream,
+            &self.cache,
+            &self.inode,
+            self.offset as usize,
+            &mut buf[0..to_read],
+        )
+        .map_err(|e| io::Error::from_raw_os_error(e.to_errno()))?;
+        self.offset += read as u64;
+        Ok(read)
+    }
+}
+
+impl io::Seek for PuzzleFile<'_> {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.offset = seek_offset(pos, self.offset, self.len)?;
+        Ok(self.offset)
+    }
+}
+
+// where a seek from `offset` in a file of `len` bytes ends up. like a regular file, seeking past
+// the end is fine and reads there just return EOF.
+fn seek_offset(pos: io::SeekFrom, offset: u64, len: u64) -> io::Result<u64> {
+    let (base, delta) = match pos {
+        io::SeekFrom::Start(n) => return Ok(n),
+        io::SeekFrom::End(delta) => (len, delta),
+        io::SeekFrom::Current(delta) => (offset, delta),
+    };
+    let offset = if delta >= 0 {
+        base.checked_add(delta as u64)
+    } else {
+        base.checked_sub(delta.unsigned_abs())
+    };
+    offset.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid seek to a negative offset",
+        )
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use sha2::{Digest, Sha256};
     use tempfile::tempdir;
 
-    use builder::build_test_fs;
+    use std::fs;
+    use std::io::{Read, Seek, SeekFrom};
+    use std::os::unix::fs::FileExt;
+    use std::path::Path;
+
+    use builder::{
+        build_initial_rootfs, build_initial_rootfs_with_options, build_test_fs, BuildOptions,
+        ChunkCompression,
+    };
+    use format::{ChunkingAlgorithm, ChunkingConfig, Rootfs};
     use oci::Image;
 
     use super::*;
 
+    #[test]
+    fn test_chunking_config() {
+        let oci_dir = tempdir().unwrap();
+        let image = Image::new(oci_dir.path()).unwrap();
+        let rootfs_desc = build_test_fs(&image).unwrap();
+        image.add_tag("test".to_string(), rootfs_desc).unwrap();
+        let pfs = PuzzleFS::open(&image, "test").unwrap();
+        assert_eq!(pfs.chunking_config(), &BuildOptions::default().chunking);
+    }
+
+    #[test]
+    fn test_open_older_format_version() {
+        let oci_dir = tempdir().unwrap();
+        let image = Image::new(oci_dir.path()).unwrap();
+        let rootfs_desc = build_test_fs(&image).unwrap();
+        let rootfs = Rootfs::open(
+            image
+                .open_compressed_blob::<compression::Noop>(&rootfs_desc.digest)
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(rootfs.version, format::FORMAT_VERSION);
+
+        // what the very first puzzlefs built, whose rootfs had no version, and whose blob refs
+        // and inodes are laid out differently; it's refused as too old rather than as corrupt
+        let old = Image::open(Path::new("fixtures/v0-image")).unwrap();
+        match PuzzleFS::open(&old, "old") {
+            Err(WireFormatError::UnsupportedFormatVersion(0, _)) => {}
+            r => panic!("expected version 0 to be refused, got {:?}", r.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_chunks_for_inode() {
+        let dir = tempdir().unwrap();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs).unwrap();
+        let data = (0..10_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        fs::write(rootfs.join("a"), &data[..3000]).unwrap();
+        fs::write(rootfs.join("b"), &data).unwrap();
+        let image = Image::new(&dir.path().join("oci")).unwrap();
+        let options = BuildOptions {
+            chunking: ChunkingConfig {
+                min: 0,
+                avg: 4096,
+                max: 0,
+                algo: ChunkingAlgorithm::Fixed,
+            },
+            ..BuildOptions::default()
+        };
+        let rootfs_desc = build_initial_rootfs_with_options(&rootfs, &image, &options).unwrap();
+        image.add_tag("test".to_string(), rootfs_desc).unwrap();
+        let mut pfs = PuzzleFS::open(&image, "test").unwrap();
+
+        for (name, len) in &[("a", 3000), ("b", 10_000)] {
+            let ino = pfs.lookup(1, OsStr::new(name)).unwrap();
+            let chunks = pfs.chunks_for_inode(ino).unwrap();
+            let mut offset = 0;
+            for chunk in &chunks {
+                assert_eq!(chunk.offset, offset);
+                assert!(chunk.digest.is_some());
+                offset += chunk.len;
+            }
+            assert_eq!(offset, *len);
+        }
+        // b starts in the blob a ends in
+        let b = pfs.lookup(1, OsStr::new("b")).unwrap();
+        assert_eq!(pfs.chunks_for_inode(b).unwrap()[0].blob_offset, 3000);
+        pfs.chunks_for_inode(1).unwrap_err();
+    }
+
+    #[test]
+    fn test_file_digests() {
+        let dir = tempdir().unwrap();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs).unwrap();
+        let data = (0..10_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        fs::write(rootfs.join("a"), &data[..3000]).unwrap();
+        fs::write(rootfs.join("b"), &data).unwrap();
+        let image = Image::new(&dir.path().join("oci")).unwrap();
+        let mut options = BuildOptions {
+            chunking: ChunkingConfig {
+                min: 0,
+                avg: 4096,
+                max: 0,
+                algo: ChunkingAlgorithm::Fixed,
+            },
+            ..BuildOptions::default()
+        };
+        let rootfs_desc = build_initial_rootfs_with_options(&rootfs, &image, &options).unwrap();
+        image.add_tag("plain".to_string(), rootfs_desc).unwrap();
+        options.file_digests = true;
+        let rootfs_desc = build_initial_rootfs_with_options(&rootfs, &image, &options).unwrap();
+        image.add_tag("digests".to_string(), rootfs_desc).unwrap();
+
+        let mut pfs = PuzzleFS::open(&image, "plain").unwrap();
+        let ino = pfs.lookup(1, OsStr::new("b")).unwrap();
+        assert!(!pfs.find_inode(ino).unwrap().verify_file_digest().unwrap());
+
+        let mut pfs = PuzzleFS::open(&image, "digests").unwrap();
+        pfs.find_inode(1).unwrap().verify_file_digest().unwrap_err();
+        let ino = pfs.lookup(1, OsStr::new("b")).unwrap();
+        let inode = pfs.find_inode(ino).unwrap();
+        assert!(inode.verify_file_digest().unwrap());
+
+        // any change to where the content comes from is caught
+        let tamper: &[fn(&mut Vec<FileChunk>)] = &[
+            |chunks| chunks[0].len -= 1,
+            |chunks| chunks.swap(0, 1),
+            |chunks| chunks[1].blob.as_mut().unwrap().offset += 1,
+            |chunks| chunks[2].blob = None,
+            |chunks| {
+                chunks.pop();
+            },
+        ];
+        for f in tamper {
+            let mut tampered = pfs.find_inode(ino).unwrap();
+            if let InodeMode::File { chunks } = &mut tampered.mode {
+                f(chunks);
+            }
+            assert!(matches!(
+                tampered.verify_file_digest(),
+                Err(WireFormatError::FileDigestMismatch(..))
+            ));
+        }
+    }
+
     #[test]
     fn test_file_reader() {
         // make ourselves a test image
@@ -223,7 +914,7 @@ mod tests {
         let mut pfs = PuzzleFS::open(&image, "test").unwrap();
 
         let inode = pfs.find_inode(2).unwrap();
-        let mut reader = FileReader::new(&image, &inode).unwrap();
+        let mut reader = FileReader::new(&image, &pfs.cache, &inode).unwrap();
         let mut hasher = Sha256::new();
 
         assert_eq!(io::copy(&mut reader, &mut hasher).unwrap(), 109466);
@@ -233,4 +924,503 @@ mod tests {
             "d9e749d9367fc908876749d6502eb212fee88c9a94892fb07da5ef3ba8bc39ed"
         );
     }
+
+    #[test]
+    fn test_file_reader_zstd() {
+        let oci_dir = tempdir().unwrap();
+        let image = Image::new(oci_dir.path()).unwrap();
+        let options = BuildOptions {
+            compression: ChunkCompression::Zstd {
+                level: compression::DEFAULT_ZSTD_LEVEL,
+            },
+            ..BuildOptions::default()
+        };
+        let rootfs_desc =
+            build_initial_rootfs_with_options(Path::new("../builder/test"), &image, &options)
+                .unwrap();
+        image.add_tag("test".to_string(), rootfs_desc).unwrap();
+        let mut pfs = PuzzleFS::open(&image, "test").unwrap();
+
+        let inode = pfs.find_inode(2).unwrap();
+        let mut reader = FileReader::new(&image, &pfs.cache, &inode).unwrap();
+        let mut hasher = Sha256::new();
+
+        assert_eq!(io::copy(&mut reader, &mut hasher).unwrap(), 109466);
+        let digest = hasher.finalize();
+        assert_eq!(
+            hex::encode(digest),
+            "d9e749d9367fc908876749d6502eb212fee88c9a94892fb07da5ef3ba8bc39ed"
+        );
+    }
+
+    #[test]
+    fn test_open_file_seek_across_chunks() {
+        let oci_dir = tempdir().unwrap();
+        let image = Image::new(oci_dir.path()).unwrap();
+        let options = BuildOptions {
+            chunking: ChunkingConfig {
+                min: 0,
+                avg: 4096,
+                max: 0,
+                algo: ChunkingAlgorithm::Fixed,
+            },
+            ..BuildOptions::default()
+        };
+        let rootfs_desc =
+            build_initial_rootfs_with_options(Path::new("../builder/test"), &image, &options)
+                .unwrap();
+        image.add_tag("test".to_string(), rootfs_desc).unwrap();
+        let mut pfs = PuzzleFS::open(&image, "test").unwrap();
+        let expected = fs::read("../builder/test/SekienAkashita.jpg").unwrap();
+
+        let mut file = pfs.open_file(2).unwrap();
+        assert_eq!(file.len(), expected.len() as u64);
+        match &file.inode().mode {
+            InodeMode::File { chunks } => assert!(chunks.len() > 2),
+            mode => panic!("bad inode mode: {:?}", mode),
+        }
+
+        // a range that starts in the middle of the first chunk and ends in the middle of the third
+        let mut buf = vec![0_u8; 6000];
+        assert_eq!(file.seek(SeekFrom::Start(3000)).unwrap(), 3000);
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, &expected[3000..9000]);
+        assert_eq!(file.seek(SeekFrom::Current(0)).unwrap(), 9000);
+
+        // the tail of the file, and then EOF
+        let mut tail = Vec::new();
+        file.seek(SeekFrom::End(-100)).unwrap();
+        file.read_to_end(&mut tail).unwrap();
+        assert_eq!(tail, &expected[expected.len() - 100..]);
+        file.seek(SeekFrom::End(10)).unwrap();
+        assert_eq!(file.read(&mut buf).unwrap(), 0);
+
+        file.seek(SeekFrom::Current(-(expected.len() as i64) - 20))
+            .unwrap_err();
+    }
+
+    #[test]
+    fn test_sparse_file_reads_zeros() {
+        let dir = tempdir().unwrap();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs).unwrap();
+        let f = fs::File::create(rootfs.join("sparse")).unwrap();
+        f.set_len(1 << 30).unwrap();
+        f.write_at(b"meshuggah", 1 << 20).unwrap();
+
+        let image = Image::new(&dir.path().join("oci")).unwrap();
+        let rootfs_desc = build_initial_rootfs(&rootfs, &image).unwrap();
+        image.add_tag("test".to_string(), rootfs_desc).unwrap();
+        let mut pfs = PuzzleFS::open(&image, "test").unwrap();
+        let mut file = pfs.open_file(2).unwrap();
+        assert_eq!(file.len(), 1 << 30);
+
+        // a read that starts in the hole before the data and ends in the one after it
+        let mut buf = vec![0xff_u8; 8192];
+        file.seek(SeekFrom::Start((1 << 20) - 4096)).unwrap();
+        file.read_exact(&mut buf).unwrap();
+        let mut expected = vec![0_u8; 8192];
+        expected[4096..4096 + 9].copy_from_slice(b"meshuggah");
+        assert_eq!(buf, expected);
+
+        // and the very end, which is all hole
+        buf.iter_mut().for_each(|b| *b = 0xff);
+        file.seek(SeekFrom::End(-100)).unwrap();
+        assert_eq!(file.read(&mut buf).unwrap(), 100);
+        assert!(buf[..100].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_file_read_odd_offsets() {
+        let oci_dir = tempdir().unwrap();
+        let image = Image::new(oci_dir.path()).unwrap();
+        let options = BuildOptions {
+            chunking: ChunkingConfig {
+                min: 0,
+                avg: 4096,
+                max: 0,
+                algo: ChunkingAlgorithm::Fixed,
+            },
+            ..BuildOptions::default()
+        };
+        let rootfs_desc =
+            build_initial_rootfs_with_options(Path::new("../builder/test"), &image, &options)
+                .unwrap();
+        image.add_tag("test".to_string(), rootfs_desc).unwrap();
+        let mut pfs = PuzzleFS::open(&image, "test").unwrap();
+        let inode = pfs.find_inode(2).unwrap();
+        let expected = fs::read("../builder/test/SekienAkashita.jpg").unwrap();
+        let len = expected.len();
+        let cache = ChunkCache::new(0);
+
+        for (offset, size) in [(0, 1), (4095, 2), (4096, 4096), (4097, 9000), (len - 1, 1)] {
+            let mut buf = vec![0_u8; size];
+            assert_eq!(
+                file_read(
+                    &mut ChunkStream::new(&image),
+                    &cache,
+                    &inode,
+                    offset,
+                    &mut buf
+                )
+                .unwrap(),
+                size
+            );
+            assert_eq!(buf, &expected[offset..offset + size], "{} {}", offset, size);
+        }
+
+        let mut buf = vec![0_u8; 100];
+        assert_eq!(
+            file_read(
+                &mut ChunkStream::new(&image),
+                &cache,
+                &inode,
+                len - 10,
+                &mut buf
+            )
+            .unwrap(),
+            10
+        );
+        assert_eq!(
+            file_read(&mut ChunkStream::new(&image), &cache, &inode, len, &mut buf).unwrap(),
+            0
+        );
+        assert_eq!(
+            file_read(
+                &mut ChunkStream::new(&image),
+                &cache,
+                &inode,
+                len + 4096,
+                &mut buf
+            )
+            .unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_cached_reads_skip_the_image() {
+        let oci_dir = tempdir().unwrap();
+        let image = Image::new(oci_dir.path()).unwrap();
+        let options = BuildOptions {
+            chunking: ChunkingConfig {
+                min: 0,
+                avg: 4096,
+                max: 0,
+                algo: ChunkingAlgorithm::Fixed,
+            },
+            ..BuildOptions::default()
+        };
+        let rootfs_desc =
+            build_initial_rootfs_with_options(Path::new("../builder/test"), &image, &options)
+                .unwrap();
+        image.add_tag("test".to_string(), rootfs_desc).unwrap();
+        let mut pfs = PuzzleFS::open(&image, "test").unwrap();
+        let expected = fs::read("../builder/test/SekienAkashita.jpg").unwrap();
+
+        let mut first = Vec::new();
+        let mut file = pfs.open_file(2).unwrap();
+        file.read_to_end(&mut first).unwrap();
+        assert_eq!(first, expected);
+
+        // with all the chunks gone, the second read can only be served from the cache
+        let chunks = match &file.inode().mode {
+            InodeMode::File { chunks } => chunks,
+            mode => panic!("bad inode mode: {:?}", mode),
+        };
+        for chunk in chunks {
+            let digest = oci::Digest::try_from(chunk.blob.unwrap()).unwrap();
+            let _ = fs::remove_file(image.blob_path().unwrap().join(digest.to_string()));
+        }
+
+        let mut second = Vec::new();
+        pfs.open_file(2).unwrap().read_to_end(&mut second).unwrap();
+        assert_eq!(second, expected);
+    }
+
+    #[test]
+    fn test_cache_stats() {
+        let dir = tempdir().unwrap();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir(&rootfs).unwrap();
+        fs::write(rootfs.join("file"), "meshuggah rocks").unwrap();
+        let image = Image::new(&dir.path().join("oci")).unwrap();
+        // compressed, so the chunk is read through the cache rather than mapped
+        let options = BuildOptions {
+            compression: ChunkCompression::Zstd { level: 3 },
+            ..BuildOptions::default()
+        };
+        let rootfs_desc = build_initial_rootfs_with_options(&rootfs, &image, &options).unwrap();
+        image.add_tag("test".to_string(), rootfs_desc).unwrap();
+        let mut pfs = PuzzleFS::open(&image, "test").unwrap();
+        assert_eq!(pfs.cache_stats(), CacheStats::default());
+
+        let ino = pfs.lookup(1, OsStr::new("file")).unwrap();
+        for _ in 0..2 {
+            let mut buf = [0_u8; 64];
+            let n = pfs.open_file(ino).unwrap().read(&mut buf).unwrap();
+            assert_eq!(&buf[..n], b"meshuggah rocks");
+        }
+        assert_eq!(
+            pfs.cache_stats(),
+            CacheStats {
+                hits: 1,
+                misses: 1,
+                evictions: 0,
+                bytes_fetched: 15,
+            }
+        );
+    }
+
+    #[test]
+    fn test_read_part_of_compressed_chunk() {
+        const LEN: usize = 8 << 20;
+        let dir = tempdir().unwrap();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir(&rootfs).unwrap();
+        let mut x = 1_u64;
+        let data = (0..LEN)
+            .map(|_| {
+                x = x
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (x >> 60) as u8
+            })
+            .collect::<Vec<_>>();
+        fs::write(rootfs.join("file"), &data).unwrap();
+        let image = Image::new(&dir.path().join("oci")).unwrap();
+        // all in one chunk, which is more than one zstd frame
+        let options = BuildOptions {
+            chunking: ChunkingConfig {
+                min: 0,
+                avg: LEN as u64,
+                max: 0,
+                algo: ChunkingAlgorithm::Fixed,
+            },
+            compression: ChunkCompression::Zstd { level: 3 },
+            ..BuildOptions::default()
+        };
+        let rootfs_desc = build_initial_rootfs_with_options(&rootfs, &image, &options).unwrap();
+        image.add_tag("test".to_string(), rootfs_desc).unwrap();
+        let mut pfs = PuzzleFS::open(&image, "test").unwrap();
+        let ino = pfs.lookup(1, OsStr::new("file")).unwrap();
+
+        let mut file = pfs.open_file(ino).unwrap();
+        let offset = 6 << 20;
+        file.seek(SeekFrom::Start(offset as u64)).unwrap();
+        let mut buf = [0_u8; 4096];
+        for i in 0..4 {
+            file.read_exact(&mut buf).unwrap();
+            let start = offset + i * buf.len();
+            assert_eq!(&buf[..], &data[start..start + buf.len()]);
+        }
+        // only what was read was decompressed, and none of it was kept
+        assert_eq!(
+            pfs.cache_stats(),
+            CacheStats {
+                hits: 0,
+                misses: 4,
+                evictions: 0,
+                bytes_fetched: 4 * buf.len() as u64,
+            }
+        );
+    }
+
+    #[test]
+    fn test_fallback_store() {
+        let dir = tempdir().unwrap();
+        let replica = Image::new(&dir.path().join("replica")).unwrap();
+        build_test_fs(&replica).unwrap();
+        let image = Image::new(&dir.path().join("oci")).unwrap();
+        let rootfs_desc = build_test_fs(&image).unwrap();
+        image.add_tag("test".to_string(), rootfs_desc).unwrap();
+        let expected = fs::read("../builder/test/SekienAkashita.jpg").unwrap();
+
+        let mut pfs = PuzzleFS::open(&image, "test").unwrap();
+        let chunks = match pfs.find_inode(2).unwrap().mode {
+            InodeMode::File { chunks } => chunks,
+            mode => panic!("bad inode mode: {:?}", mode),
+        };
+        let paths = chunks
+            .iter()
+            .map(|chunk| {
+                let digest = oci::Digest::try_from(chunk.blob.unwrap()).unwrap();
+                image.blob_path().unwrap().join(digest.to_string())
+            })
+            .collect::<Vec<_>>();
+        for path in &paths {
+            let _ = fs::remove_file(path);
+        }
+        let mut data = Vec::new();
+        pfs.open_file(2)
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap_err();
+
+        // the replica has them all, and they're kept once they've been fetched
+        let image = image.with_fallback(replica.store().clone());
+        let mut pfs = PuzzleFS::open(&image, "test").unwrap();
+        let mut data = Vec::new();
+        pfs.open_file(2).unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(data, expected);
+        assert!(paths.iter().all(|path| path.exists()));
+    }
+
+    #[test]
+    fn test_open_stack() {
+        let dir = tempdir().unwrap();
+        let oci_dir = dir.path().join("oci");
+        let image = Image::new(&oci_dir).unwrap();
+
+        let lower = dir.path().join("lower");
+        for d in &["dir", "opaque"] {
+            fs::create_dir_all(lower.join(d)).unwrap();
+        }
+        for f in &["foo", "gone", "dir/old", "opaque/hidden"] {
+            fs::write(lower.join(f), "lower").unwrap();
+        }
+        let desc = build_initial_rootfs(&lower, &image).unwrap();
+        image.add_tag("lower".to_string(), desc).unwrap();
+
+        let upper = dir.path().join("upper");
+        for d in &["dir", "opaque"] {
+            fs::create_dir_all(upper.join(d)).unwrap();
+        }
+        for f in &[
+            "foo",
+            ".wh.gone",
+            "dir/new",
+            "opaque/.wh..wh..opq",
+            "opaque/new",
+        ] {
+            fs::write(upper.join(f), "upper").unwrap();
+        }
+        let options = BuildOptions {
+            whiteouts: true,
+            ..BuildOptions::default()
+        };
+        let desc = build_initial_rootfs_with_options(&upper, &image, &options).unwrap();
+        image.add_tag("upper".to_string(), desc).unwrap();
+
+        let mut pfs = PuzzleFS::open_stack(&image, &["lower", "upper"]).unwrap();
+        let mut inos = Vec::new();
+        let mut paths = Vec::new();
+        for de in crate::WalkPuzzleFS::walk(&mut pfs).unwrap() {
+            let de = de.unwrap();
+            if let InodeMode::File { .. } = de.inode.mode {
+                let mut data = String::new();
+                de.open().unwrap().read_to_string(&mut data).unwrap();
+                let expected = if de.path.ends_with("old") {
+                    "lower"
+                } else {
+                    "upper"
+                };
+                assert_eq!(data, expected, "{:?}", de.path);
+            }
+            inos.push(de.inode.inode.ino);
+            paths.push(de.path.to_string_lossy().into_owned());
+        }
+        paths.sort();
+        assert_eq!(
+            paths,
+            vec![
+                "/",
+                "/dir",
+                "/dir/new",
+                "/dir/old",
+                "/foo",
+                "/opaque",
+                "/opaque/new"
+            ]
+        );
+        inos.sort_unstable();
+        inos.dedup();
+        assert_eq!(inos.len(), paths.len());
+    }
+
+    #[test]
+    fn test_lookup() {
+        let dir = tempdir().unwrap();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(rootfs.join("a/b")).unwrap();
+        fs::write(rootfs.join("a/b/file"), b"file").unwrap();
+        fs::write(rootfs.join("top"), b"top").unwrap();
+        let image = Image::new(&dir.path().join("oci")).unwrap();
+        let desc = build_initial_rootfs(&rootfs, &image).unwrap();
+        image.add_tag("test".to_string(), desc).unwrap();
+        let mut pfs = PuzzleFS::open(&image, "test").unwrap();
+
+        let root = 1;
+        let a = pfs.lookup(root, OsStr::new("a")).unwrap();
+        let b = pfs.lookup(a, OsStr::new("b")).unwrap();
+        let file = pfs.lookup(b, OsStr::new("file")).unwrap();
+        assert_eq!(
+            a,
+            pfs.find_inode(root)
+                .unwrap()
+                .dir_lookup(OsStr::new("a"))
+                .unwrap()
+        );
+        assert_eq!(
+            file,
+            pfs.find_inode(b)
+                .unwrap()
+                .dir_lookup(OsStr::new("file"))
+                .unwrap()
+        );
+
+        assert_eq!(pfs.lookup(b, OsStr::new(".")).unwrap(), b);
+        assert_eq!(pfs.lookup(b, OsStr::new("..")).unwrap(), a);
+        assert_eq!(pfs.lookup(a, OsStr::new("..")).unwrap(), root);
+        assert_eq!(pfs.lookup(root, OsStr::new("..")).unwrap(), root);
+
+        let errno = |e: WireFormatError| e.to_errno();
+        assert_eq!(
+            errno(pfs.lookup(a, OsStr::new("nope")).unwrap_err()),
+            Errno::ENOENT as i32
+        );
+        assert_eq!(
+            errno(pfs.lookup(file, OsStr::new("x")).unwrap_err()),
+            Errno::ENOTDIR as i32
+        );
+        assert_eq!(
+            errno(pfs.lookup(file, OsStr::new(".")).unwrap_err()),
+            Errno::ENOTDIR as i32
+        );
+
+        // a fresh filesystem hasn't seen b get looked up, so it has to go find its parent
+        let mut pfs = PuzzleFS::open(&image, "test").unwrap();
+        assert_eq!(pfs.lookup(b, OsStr::new("..")).unwrap(), a);
+    }
+
+    #[test]
+    fn test_path_for_inode() {
+        let dir = tempdir().unwrap();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(rootfs.join("a/b")).unwrap();
+        fs::create_dir_all(rootfs.join("c")).unwrap();
+        fs::write(rootfs.join("a/b/file"), b"file").unwrap();
+        fs::hard_link(rootfs.join("a/b/file"), rootfs.join("c/link")).unwrap();
+        let image = Image::new(&dir.path().join("oci")).unwrap();
+        let desc = build_initial_rootfs(&rootfs, &image).unwrap();
+        image.add_tag("test".to_string(), desc).unwrap();
+
+        let mut pfs = PuzzleFS::open(&image, "test").unwrap();
+        let a = pfs.lookup(1, OsStr::new("a")).unwrap();
+        let b = pfs.lookup(a, OsStr::new("b")).unwrap();
+        let file = pfs.lookup(b, OsStr::new("file")).unwrap();
+
+        // a fresh filesystem, so nothing has been looked up yet
+        let mut pfs = PuzzleFS::open(&image, "test").unwrap();
+        assert_eq!(
+            pfs.path_for_inode(file).unwrap(),
+            vec![PathBuf::from("/a/b/file"), PathBuf::from("/c/link")]
+        );
+        assert_eq!(pfs.path_for_inode(b).unwrap(), vec![PathBuf::from("/a/b")]);
+        assert_eq!(pfs.path_for_inode(1).unwrap(), vec![PathBuf::from("/")]);
+        assert_eq!(
+            pfs.path_for_inode(1000).unwrap_err().to_errno(),
+            Errno::ENOENT as i32
+        );
+    }
 }
@@ -0,0 +1,165 @@
+use std::cmp::{max, min};
+use std::convert::TryFrom;
+use std::ops::Range;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use format::{BlobRef, FileChunk, Result};
+use oci::{Digest, Image};
+
+use crate::cache::ChunkCache;
+
+/// How many chunks past a sequential read are fetched ahead of time, unless the mount says
+/// otherwise.
+pub const DEFAULT_READAHEAD: u64 = 8;
+
+// fetching a chunk is mostly decompressing it, so do a few at once
+const PREFETCH_THREADS: usize = 2;
+
+/// Reads chunks into a ChunkCache in the background, so they're already there by the time a read
+/// asks for them.
+pub(crate) struct Prefetcher {
+    chunks: Sender<BlobRef>,
+}
+
+impl Prefetcher {
+    pub(crate) fn new(image: &Image, cache: Arc<ChunkCache>) -> Prefetcher {
+        let (chunks, queue) = channel::<BlobRef>();
+        let queue = Arc::new(Mutex::new(queue));
+        for _ in 0..PREFETCH_THREADS {
+            let (image, cache, queue) = (image.clone(), cache.clone(), queue.clone());
+            // the threads go away along with the Prefetcher, once everything queued is done
+            thread::spawn(move || loop {
+                let chunk = match queue.lock().unwrap().recv() {
+                    Ok(chunk) => chunk,
+                    Err(_) => return,
+                };
+                // if this fails, the read that actually needs the chunk gets to say why
+                let _ = prefetch(&image, &cache, chunk);
+            });
+        }
+        Prefetcher { chunks }
+    }
+
+    pub(crate) fn prefetch(&self, chunk: BlobRef) {
+        // the threads can only have gone away if they panicked, in which case reads will just be
+        // slower
+        let _ = self.chunks.send(chunk);
+    }
+}
+
+fn prefetch(image: &Image, cache: &ChunkCache, chunk: BlobRef) -> Result<()> {
+    // mapped blobs are read straight from the page cache, which does its own readahead
+    if image.map_chunk_blob(chunk)?.is_some() {
+        return Ok(());
+    }
+    let digest = Digest::try_from(chunk)?.underlying();
+    if !cache.start_fetch(digest) {
+        return Ok(());
+    }
+    let data = image.read_chunk_blob(chunk).map(Arc::new);
+    cache.finish_fetch(digest, data.as_ref().ok().cloned());
+    data.map(|_| ())
+}
+
+/// Notices when the reads of an open file follow on from each other, and which chunks to fetch
+/// ahead of them when they do.
+#[derive(Default)]
+pub(crate) struct Readahead {
+    // where the next read starts if it's sequential
+    next_offset: u64,
+    // the chunks before this one have already been queued
+    queued: usize,
+}
+
+impl Readahead {
+    /// The chunks to fetch after a read of `len` bytes at `offset`: up to `window` of them, and
+    /// no more than `max_bytes` worth, starting with the one the next read would start in, less
+    /// any that were already fetched.
+    pub(crate) fn advance(
+        &mut self,
+        chunks: &[FileChunk],
+        offset: u64,
+        len: u64,
+        window: u64,
+        max_bytes: u64,
+    ) -> Range<usize> {
+        let sequential = offset == self.next_offset;
+        self.next_offset = offset + len;
+        if !sequential {
+            // the file is being read all over the place, start counting over
+            self.queued = 0;
+            return 0..0;
+        }
+
+        let mut next = 0;
+        let mut start = 0;
+        for chunk in chunks {
+            if start + chunk.len > self.next_offset {
+                break;
+            }
+            start += chunk.len;
+            next += 1;
+        }
+        // chunks fetched so far ahead that they push the ones about to be read out of the cache
+        // only make things slower
+        let mut to = next;
+        let mut bytes = 0;
+        while to < min(next + window as usize, chunks.len()) && bytes + chunks[to].len <= max_bytes
+        {
+            bytes += chunks[to].len;
+            to += 1;
+        }
+        let from = max(next, self.queued);
+        if from >= to {
+            return 0..0;
+        }
+        self.queued = to;
+        from..to
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunks(n: usize, len: u64) -> Vec<FileChunk> {
+        (0..n)
+            .map(|i| FileChunk {
+                blob: Some(Digest::from([i as u8; 32]).blob_ref(true)),
+                len,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_advance() {
+        let chunks = chunks(10, 100);
+        let mut ra = Readahead::default();
+        // the first read is in chunk 0 and the next will be too
+        assert_eq!(ra.advance(&chunks, 0, 50, 3, u64::MAX), 0..3);
+        assert_eq!(ra.advance(&chunks, 50, 50, 3, u64::MAX), 3..4);
+        // still in chunk 1, nothing new to fetch
+        assert_eq!(ra.advance(&chunks, 100, 50, 3, u64::MAX), 0..0);
+        assert_eq!(ra.advance(&chunks, 150, 300, 3, u64::MAX), 4..7);
+
+        // a seek isn't sequential, but reading on from there is
+        assert_eq!(ra.advance(&chunks, 0, 10, 3, u64::MAX), 0..0);
+        assert_eq!(ra.advance(&chunks, 10, 10, 3, u64::MAX), 0..3);
+
+        // near the end of the file, and past it
+        assert_eq!(ra.advance(&chunks, 20, 880, 3, u64::MAX), 9..10);
+        assert_eq!(ra.advance(&chunks, 900, 100, 3, u64::MAX), 0..0);
+        assert_eq!(ra.advance(&chunks, 1000, 0, 3, u64::MAX), 0..0);
+
+        let mut ra = Readahead::default();
+        assert_eq!(ra.advance(&chunks, 0, 50, 0, u64::MAX), 0..0);
+
+        // only as much as fits
+        let mut ra = Readahead::default();
+        assert_eq!(ra.advance(&chunks, 0, 50, 5, 250), 0..2);
+        assert_eq!(ra.advance(&chunks, 50, 100, 5, 250), 2..3);
+        assert_eq!(ra.advance(&chunks, 150, 100, 5, 50), 0..0);
+    }
+}
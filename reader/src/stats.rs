@@ -0,0 +1,292 @@
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+
+use format::Result;
+use oci::Digest;
+
+use crate::{InodeMode, PuzzleFS, WalkPuzzleFS};
+
+/// How well the chunks of an image deduplicate. Sizes are of the uncompressed data, so
+/// compression doesn't muddy the numbers.
+#[derive(Debug)]
+pub struct ImageStats {
+    /// The total size of all the files in the image, less the holes in sparse files. Hard links
+    /// only count once.
+    pub logical_bytes: u64,
+    // every distinct chunk, and how big it is
+    chunks: HashMap<Digest, u64>,
+    // how many pieces of files each chunk has in it
+    refs: HashMap<Digest, u64>,
+}
+
+impl ImageStats {
+    pub fn new<'a>(pfs: &'a mut PuzzleFS<'a>) -> Result<ImageStats> {
+        let mut logical_bytes = 0;
+        let mut chunks = HashMap::new();
+        let mut refs = HashMap::new();
+        let mut seen = HashSet::new();
+        for de in WalkPuzzleFS::walk(pfs)? {
+            let de = de?;
+            if !seen.insert(de.inode.inode.ino) {
+                continue;
+            }
+            if let InodeMode::File {
+                chunks: file_chunks,
+            } = &de.inode.mode
+            {
+                for chunk in file_chunks {
+                    // holes don't take up any space, so they'd only make the ratio look better
+                    let blob = match chunk.blob {
+                        Some(blob) => blob,
+                        None => continue,
+                    };
+                    logical_bytes += chunk.len;
+                    // a chunk may be split across several files, piece it back together
+                    let digest = Digest::try_from(blob)?;
+                    let len = chunks.entry(digest.clone()).or_insert(0);
+                    *len = (*len).max(blob.offset + chunk.len);
+                    *refs.entry(digest).or_insert(0) += 1;
+                }
+            }
+        }
+        Ok(ImageStats {
+            logical_bytes,
+            chunks,
+            refs,
+        })
+    }
+
+    /// The total size of the distinct chunks, i.e. what it takes to store the files' data.
+    pub fn unique_bytes(&self) -> u64 {
+        self.chunks.values().sum()
+    }
+
+    /// How many times smaller the image's data is thanks to deduplication; 1 means nothing was
+    /// shared.
+    pub fn dedup_ratio(&self) -> f64 {
+        match self.unique_bytes() {
+            0 => 1.0,
+            unique => self.logical_bytes as f64 / unique as f64,
+        }
+    }
+
+    pub fn chunk_count(&self) -> usize {
+        self.chunks.len()
+    }
+
+    /// Every distinct chunk, sorted by digest.
+    pub fn chunks(&self) -> Vec<ChunkInfo> {
+        let mut chunks = self
+            .chunks
+            .iter()
+            .map(|(digest, len)| ChunkInfo {
+                digest: digest.clone(),
+                len: *len,
+                refs: self.refs[digest],
+            })
+            .collect::<Vec<_>>();
+        chunks.sort_by_key(|c| c.digest.underlying());
+        chunks
+    }
+
+    /// The distribution of the sizes of the distinct chunks, or `None` if there aren't any.
+    pub fn chunk_sizes(&self) -> Option<ChunkSizes> {
+        ChunkSizes::new(self.chunks.values().copied().collect())
+    }
+
+    /// The total size of the chunks both images have.
+    pub fn shared_bytes(&self, other: &ImageStats) -> u64 {
+        self.chunks
+            .iter()
+            .filter(|(k, _)| other.chunks.contains_key(*k))
+            .map(|(_, len)| len)
+            .sum()
+    }
+
+    /// Sorts the chunks of this image and `other` into the ones they share and the ones only one
+    /// of them has.
+    pub fn diff(&self, other: &ImageStats) -> BlobDiff {
+        let mut diff = BlobDiff::default();
+        for (digest, len) in self.chunks.iter() {
+            let blob = (digest.clone(), *len);
+            if other.chunks.contains_key(digest) {
+                diff.shared.push(blob);
+            } else {
+                diff.only_ours.push(blob);
+            }
+        }
+        for (digest, len) in other.chunks.iter() {
+            if !self.chunks.contains_key(digest) {
+                diff.only_theirs.push((digest.clone(), *len));
+            }
+        }
+        for blobs in [&mut diff.shared, &mut diff.only_ours, &mut diff.only_theirs] {
+            blobs.sort_by_key(|(digest, _)| digest.underlying());
+        }
+        diff
+    }
+}
+
+/// One of the distinct chunks of an image, and how much it gets used.
+#[derive(Debug)]
+pub struct ChunkInfo {
+    pub digest: Digest,
+    pub len: u64,
+    /// How many of the pieces the files are made of are in this chunk; a file counts once for
+    /// every time it has the chunk in it.
+    pub refs: u64,
+}
+
+/// The chunk blobs of two images, and how big they are, sorted by digest.
+#[derive(Debug, Default)]
+pub struct BlobDiff {
+    pub shared: Vec<(Digest, u64)>,
+    pub only_ours: Vec<(Digest, u64)>,
+    pub only_theirs: Vec<(Digest, u64)>,
+}
+
+impl BlobDiff {
+    pub fn bytes(blobs: &[(Digest, u64)]) -> u64 {
+        blobs.iter().map(|(_, len)| len).sum()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ChunkSizes {
+    pub min: u64,
+    pub avg: u64,
+    pub max: u64,
+    pub p50: u64,
+    pub p90: u64,
+}
+
+impl ChunkSizes {
+    fn new(mut sizes: Vec<u64>) -> Option<ChunkSizes> {
+        if sizes.is_empty() {
+            return None;
+        }
+        sizes.sort_unstable();
+        // nearest rank
+        let percentile = |p: usize| sizes[((sizes.len() * p + 99) / 100).max(1) - 1];
+        Some(ChunkSizes {
+            min: sizes[0],
+            avg: sizes.iter().sum::<u64>() / sizes.len() as u64,
+            max: sizes[sizes.len() - 1],
+            p50: percentile(50),
+            p90: percentile(90),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use tempfile::tempdir;
+
+    use builder::{build_initial_rootfs_with_options, BuildOptions};
+    use format::{ChunkingAlgorithm, ChunkingConfig};
+    use oci::Image;
+
+    use super::*;
+
+    // the default chunks are much bigger than the files here; with chunks that line up with the
+    // files we know exactly how much should be shared
+    const CHUNK_SIZE: usize = 4096;
+    const FILE_SIZE: usize = 16 * CHUNK_SIZE;
+
+    fn build(rootfs: &Path, image: &Image, tag: &str) {
+        let options = BuildOptions {
+            chunking: ChunkingConfig {
+                min: 0,
+                avg: CHUNK_SIZE as u64,
+                max: 0,
+                algo: ChunkingAlgorithm::Fixed,
+            },
+            ..BuildOptions::default()
+        };
+        let rootfs_desc = build_initial_rootfs_with_options(rootfs, image, &options).unwrap();
+        image.add_tag(tag.to_string(), rootfs_desc).unwrap();
+    }
+
+    #[test]
+    fn test_dedup_stats() {
+        let dir = tempdir().unwrap();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(rootfs.join("copies")).unwrap();
+        let data = (0..FILE_SIZE).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        // four copies of the same file, and one hard link that doesn't count
+        for name in &["a", "copies/b", "copies/c", "copies/d"] {
+            fs::write(rootfs.join(name), &data).unwrap();
+        }
+        fs::hard_link(rootfs.join("a"), rootfs.join("link")).unwrap();
+
+        let oci_dir = dir.path().join("oci");
+        let image = Image::new(&oci_dir).unwrap();
+        build(&rootfs, &image, "test");
+        let mut pfs = PuzzleFS::open(&image, "test").unwrap();
+        let stats = ImageStats::new(&mut pfs).unwrap();
+
+        assert_eq!(stats.logical_bytes, 4 * FILE_SIZE as u64);
+        assert_eq!(stats.unique_bytes(), FILE_SIZE as u64);
+        assert!((stats.dedup_ratio() - 4.0).abs() < f64::EPSILON);
+        assert_eq!(stats.chunk_count(), FILE_SIZE / CHUNK_SIZE);
+        let sizes = stats.chunk_sizes().unwrap();
+        assert_eq!(sizes.min, CHUNK_SIZE as u64);
+        assert_eq!(sizes.max, CHUNK_SIZE as u64);
+
+        let chunks = stats.chunks();
+        assert_eq!(chunks.len(), stats.chunk_count());
+        assert!(chunks
+            .iter()
+            .all(|c| c.len == CHUNK_SIZE as u64 && c.refs == 4));
+    }
+
+    #[test]
+    fn test_chunk_sizes() {
+        assert_eq!(ChunkSizes::new(vec![]), None);
+        assert_eq!(
+            ChunkSizes::new((1..=10).rev().collect()),
+            Some(ChunkSizes {
+                min: 1,
+                avg: 5,
+                max: 10,
+                p50: 5,
+                p90: 9,
+            })
+        );
+        assert_eq!(ChunkSizes::new(vec![7]).unwrap().p90, 7);
+    }
+
+    #[test]
+    fn test_shared_bytes() {
+        let dir = tempdir().unwrap();
+        let oci_dir = dir.path().join("oci");
+        let image = Image::new(&oci_dir).unwrap();
+
+        let data = (0..FILE_SIZE).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        for (tag, extra) in &[("one", "x"), ("two", "y")] {
+            let rootfs = dir.path().join(tag);
+            fs::create_dir_all(&rootfs).unwrap();
+            fs::write(rootfs.join("shared"), &data).unwrap();
+            fs::write(rootfs.join("mine"), extra.repeat(CHUNK_SIZE)).unwrap();
+            build(&rootfs, &image, tag);
+        }
+
+        let mut one = PuzzleFS::open(&image, "one").unwrap();
+        let one = ImageStats::new(&mut one).unwrap();
+        let mut two = PuzzleFS::open(&image, "two").unwrap();
+        let two = ImageStats::new(&mut two).unwrap();
+        assert_eq!(one.shared_bytes(&two), FILE_SIZE as u64);
+        assert_eq!(two.shared_bytes(&one), FILE_SIZE as u64);
+
+        let diff = one.diff(&two);
+        assert_eq!(diff.shared.len(), FILE_SIZE / CHUNK_SIZE);
+        assert_eq!(BlobDiff::bytes(&diff.shared), FILE_SIZE as u64);
+        assert_eq!(diff.only_ours.len(), 1);
+        assert_eq!(BlobDiff::bytes(&diff.only_ours), CHUNK_SIZE as u64);
+        assert_eq!(diff.only_theirs.len(), 1);
+        assert_ne!(diff.only_ours[0].0, diff.only_theirs[0].0);
+    }
+}
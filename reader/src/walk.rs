@@ -1,17 +1,32 @@
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, VecDeque};
 use std::path::PathBuf;
+use std::sync::Arc;
+
+use serde::Serialize;
 
 use format::Result;
 use oci::Image;
 
+use super::cache::ChunkCache;
 use super::puzzlefs::{FileReader, Inode, InodeMode, PuzzleFS};
 
+// decides something about an entry, e.g. whether to yield it
+type Predicate<'a> = Box<dyn FnMut(&DirEntry) -> bool + 'a>;
+
 /// A in iterator over a PuzzleFS filesystem. This iterates breadth first, since file content is
 /// stored that way in a puzzlefs image so it'll be faster reading actual content if clients want
-/// to do that.
+/// to do that. Either way, a directory always comes before anything that's in it.
+///
+/// By default it goes through everything; filter(), prune() and max_depth() narrow that down, e.g.
+/// `WalkPuzzleFS::walk(&mut pfs)?.max_depth(1)` for just the root and what's in it. Directories
+/// that aren't descended into don't have their entries looked up at all.
 pub struct WalkPuzzleFS<'a> {
     pfs: &'a mut PuzzleFS<'a>,
-    q: VecDeque<DirEntry<'a>>,
+    // the entries to come, and how deep each is; the root is at depth 0
+    q: VecDeque<(DirEntry<'a>, usize)>,
+    filter: Option<Predicate<'a>>,
+    prune: Option<Predicate<'a>>,
+    max_depth: Option<usize>,
 }
 
 impl<'a> WalkPuzzleFS<'a> {
@@ -21,23 +36,55 @@ impl<'a> WalkPuzzleFS<'a> {
         let inode = pfs.find_inode(1)?; // root inode number
         let de = DirEntry {
             oci: pfs.oci,
+            cache: pfs.cache.clone(),
             path: PathBuf::from("/"),
             inode,
         };
-        q.push_back(de);
-        Ok(WalkPuzzleFS { pfs, q })
+        q.push_back((de, 0));
+        Ok(WalkPuzzleFS {
+            pfs,
+            q,
+            filter: None,
+            prune: None,
+            max_depth: None,
+        })
+    }
+
+    /// Only yields the entries `filter` returns true for. The others are still descended into, so
+    /// e.g. filtering on the kind of inode finds all of that kind.
+    pub fn filter<F: FnMut(&DirEntry) -> bool + 'a>(mut self, filter: F) -> Self {
+        self.filter = Some(Box::new(filter));
+        self
+    }
+
+    /// Doesn't descend into the directories `prune` returns true for. They're still yielded
+    /// themselves, just not anything in them.
+    pub fn prune<F: FnMut(&DirEntry) -> bool + 'a>(mut self, prune: F) -> Self {
+        self.prune = Some(Box::new(prune));
+        self
     }
 
-    fn add_dir_entries(&mut self, dir: &DirEntry) -> Result<()> {
-        if let InodeMode::Dir { ref entries } = dir.inode.mode {
+    /// Doesn't go any deeper than `depth`: 0 is just the root, 1 the root and what's in it, and
+    /// so on.
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = Some(depth);
+        self
+    }
+
+    fn add_dir_entries(&mut self, dir: &DirEntry, depth: usize) -> Result<()> {
+        if let InodeMode::Dir { ref entries, .. } = dir.inode.mode {
             for (name, ino) in entries {
                 let inode = self.pfs.find_inode(*ino)?;
                 let path = dir.path.join(name);
-                self.q.push_back(DirEntry {
-                    oci: self.pfs.oci,
-                    path,
-                    inode,
-                })
+                self.q.push_back((
+                    DirEntry {
+                        oci: self.pfs.oci,
+                        cache: self.pfs.cache.clone(),
+                        path,
+                        inode,
+                    },
+                    depth,
+                ))
             }
         };
 
@@ -49,13 +96,25 @@ impl<'a> Iterator for WalkPuzzleFS<'a> {
     type Item = Result<DirEntry<'a>>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let de = self.q.pop_front()?;
-        Some(self.add_dir_entries(&de).map(|_| de))
+        loop {
+            let (de, depth) = self.q.pop_front()?;
+            let descend = self.max_depth.map_or(true, |max| depth < max)
+                && !self.prune.as_mut().map_or(false, |prune| prune(&de));
+            if descend {
+                if let Err(e) = self.add_dir_entries(&de, depth + 1) {
+                    return Some(Err(e));
+                }
+            }
+            if self.filter.as_mut().map_or(true, |filter| filter(&de)) {
+                return Some(Ok(de));
+            }
+        }
     }
 }
 
 pub struct DirEntry<'a> {
-    oci: &'a Image<'a>,
+    oci: &'a Image,
+    cache: Arc<ChunkCache>,
     pub path: PathBuf,
     pub inode: Inode,
 }
@@ -63,7 +122,68 @@ pub struct DirEntry<'a> {
 impl<'a> DirEntry<'a> {
     /// Opens this DirEntry if it is a file.
     pub fn open(&'a self) -> Result<FileReader<'a>> {
-        FileReader::new(self.oci, &self.inode)
+        FileReader::new(self.oci, &self.cache, &self.inode)
+    }
+}
+
+/// A serializable summary of a DirEntry, for tools that want to consume a listing of an image
+/// (e.g. as newline delimited JSON) rather than link against us.
+///
+/// Names in an image are arbitrary bytes but JSON strings have to be UTF-8, so paths and xattr
+/// names that aren't valid UTF-8 are converted lossily: each invalid sequence becomes U+FFFD.
+/// Xattr values are binary, so they are hex encoded.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct WalkEntry {
+    pub path: String,
+    pub ino: u64,
+    pub mode: &'static str,
+    pub size: u64,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub xattrs: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub symlink_target: Option<String>,
+}
+
+impl From<&DirEntry<'_>> for WalkEntry {
+    fn from(de: &DirEntry) -> WalkEntry {
+        let inode = &de.inode;
+        let mut xattrs = BTreeMap::new();
+        if let Some(additional) = &inode.additional {
+            for xa in &additional.xattrs {
+                let val = xa.val.as_deref().map(hex::encode).unwrap_or_default();
+                xattrs.insert(xa.key.to_string_lossy().into_owned(), val);
+            }
+        }
+        WalkEntry {
+            path: de.path.to_string_lossy().into_owned(),
+            ino: inode.inode.ino,
+            mode: mode_name(&inode.inode.mode),
+            size: inode.file_len().unwrap_or(0),
+            nlink: inode.inode.nlink,
+            uid: inode.inode.uid,
+            gid: inode.inode.gid,
+            xattrs,
+            symlink_target: inode
+                .symlink_target()
+                .ok()
+                .map(|t| t.to_string_lossy().into_owned()),
+        }
+    }
+}
+
+fn mode_name(mode: &format::InodeMode) -> &'static str {
+    match mode {
+        format::InodeMode::Unknown => "unknown",
+        format::InodeMode::Fifo => "fifo",
+        format::InodeMode::Chr { .. } => "char",
+        format::InodeMode::Dir { .. } => "dir",
+        format::InodeMode::Blk { .. } => "block",
+        format::InodeMode::Reg { .. } => "file",
+        format::InodeMode::Lnk => "symlink",
+        format::InodeMode::Sock => "socket",
+        format::InodeMode::Wht => "whiteout",
     }
 }
 
@@ -73,9 +193,15 @@ mod tests {
 
     use tempfile::tempdir;
 
+    use std::ffi::OsStr;
     use std::fs;
+    use std::io::Read;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::MetadataExt;
 
-    use builder::{build_initial_rootfs, build_test_fs};
+    use builder::{
+        build_initial_rootfs, build_initial_rootfs_with_options, build_test_fs, BuildOptions,
+    };
     use oci::Image;
 
     use super::*;
@@ -102,6 +228,111 @@ mod tests {
         assert_eq!(jpg_file.inode.file_len().unwrap(), 109466);
     }
 
+    #[test]
+    fn test_walk_filters() {
+        let dir = tempdir().unwrap();
+        let oci_dir = dir.path().join("oci");
+        let image = Image::new(&oci_dir).unwrap();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(rootfs.join("a/b/c")).unwrap();
+        fs::create_dir_all(rootfs.join("skip/deeper")).unwrap();
+        fs::write(rootfs.join("file"), b"file").unwrap();
+        std::os::unix::fs::symlink("file", rootfs.join("link")).unwrap();
+        std::os::unix::fs::symlink("../file", rootfs.join("a/b/link")).unwrap();
+        std::os::unix::fs::symlink("../file", rootfs.join("skip/link")).unwrap();
+
+        let rootfs_desc = build_initial_rootfs(&rootfs, &image).unwrap();
+        image.add_tag("test".to_string(), rootfs_desc).unwrap();
+        let paths = |walker: WalkPuzzleFS| {
+            walker
+                .map(|de| de.unwrap().path.to_string_lossy().into_owned())
+                .collect::<Vec<_>>()
+        };
+
+        let mut pfs = PuzzleFS::open(&image, "test").unwrap();
+        let walker = WalkPuzzleFS::walk(&mut pfs).unwrap().max_depth(1);
+        assert_eq!(paths(walker), vec!["/", "/a", "/file", "/link", "/skip"]);
+
+        let mut pfs = PuzzleFS::open(&image, "test").unwrap();
+        let walker = WalkPuzzleFS::walk(&mut pfs).unwrap().max_depth(0);
+        assert_eq!(paths(walker), vec!["/"]);
+
+        let mut pfs = PuzzleFS::open(&image, "test").unwrap();
+        let walker = WalkPuzzleFS::walk(&mut pfs)
+            .unwrap()
+            .filter(|de| de.inode.inode.mode == format::InodeMode::Lnk)
+            .prune(|de| de.path.ends_with("skip"));
+        assert_eq!(paths(walker), vec!["/link", "/a/b/link"]);
+    }
+
+    #[test]
+    fn test_walk_entry_json() {
+        let dir = tempdir().unwrap();
+        let oci_dir = dir.path().join("oci");
+        let image = Image::new(&oci_dir).unwrap();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs).unwrap();
+        fs::write(rootfs.join("bar"), b"bar").unwrap();
+        xattr::set(rootfs.join("bar"), "user.meshuggah", b"rocks").unwrap();
+        std::os::unix::fs::symlink("bar", rootfs.join("foo")).unwrap();
+        // not valid UTF-8
+        fs::write(rootfs.join(OsStr::from_bytes(b"caf\xe9")), b"").unwrap();
+
+        let rootfs_desc = build_initial_rootfs(&rootfs, &image).unwrap();
+        image.add_tag("test".to_string(), rootfs_desc).unwrap();
+        let mut pfs = PuzzleFS::open(&image, "test").unwrap();
+        let entries = WalkPuzzleFS::walk(&mut pfs)
+            .unwrap()
+            .map(|de| serde_json::to_value(WalkEntry::from(&de.unwrap())).unwrap())
+            .collect::<Vec<_>>();
+
+        let md = fs::metadata(&rootfs).unwrap();
+        let expected = vec![
+            serde_json::json!({
+                "path": "/",
+                "ino": 1,
+                "mode": "dir",
+                "size": 0,
+                "nlink": 2,
+                "uid": md.uid(),
+                "gid": md.gid(),
+                "xattrs": {},
+            }),
+            serde_json::json!({
+                "path": "/bar",
+                "ino": 2,
+                "mode": "file",
+                "size": 3,
+                "nlink": 1,
+                "uid": md.uid(),
+                "gid": md.gid(),
+                "xattrs": {"user.meshuggah": "726f636b73"},
+            }),
+            serde_json::json!({
+                "path": "/caf\u{fffd}",
+                "ino": 3,
+                "mode": "file",
+                "size": 0,
+                "nlink": 1,
+                "uid": md.uid(),
+                "gid": md.gid(),
+                "xattrs": {},
+            }),
+            serde_json::json!({
+                "path": "/foo",
+                "ino": 4,
+                "mode": "symlink",
+                "size": 0,
+                "nlink": 1,
+                "uid": md.uid(),
+                "gid": md.gid(),
+                "xattrs": {},
+                "symlink_target": "bar",
+            }),
+        ];
+        assert_eq!(entries, expected);
+    }
+
     #[test]
     fn test_xattrs() {
         // since walk provides us a nice API, we test some other basics of the builder here too.
@@ -150,4 +381,116 @@ mod tests {
         assert_eq!(foo_i.path.to_string_lossy(), "/foo");
         check_inode_xattrs(foo_i.inode);
     }
+
+    #[test]
+    fn test_exclude() {
+        let dir = tempdir().unwrap();
+        let oci_dir = dir.path().join("oci");
+        let image = Image::new(&oci_dir).unwrap();
+        let rootfs = dir.path().join("rootfs");
+        for d in &[".git/objects", "cache/deep", "src/.git", "src/cache"] {
+            fs::create_dir_all(rootfs.join(d)).unwrap();
+        }
+        for f in &[
+            ".git/HEAD",
+            "cache/deep/blob",
+            "src/main.rs",
+            "src/cache/x",
+            "src/x.tmp",
+        ] {
+            fs::write(rootfs.join(f), f).unwrap();
+        }
+
+        let options = BuildOptions {
+            exclude: vec![
+                ".git".to_string(),
+                "/cache".to_string(),
+                "*.tmp".to_string(),
+            ],
+            ..BuildOptions::default()
+        };
+        let rootfs_desc = build_initial_rootfs_with_options(&rootfs, &image, &options).unwrap();
+        image.add_tag("test".to_string(), rootfs_desc).unwrap();
+        let mut pfs = PuzzleFS::open(&image, "test").unwrap();
+        let paths = WalkPuzzleFS::walk(&mut pfs)
+            .unwrap()
+            .map(|de| de.unwrap().path.to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        // .git goes everywhere, but /cache only at the top
+        assert_eq!(
+            paths,
+            vec!["/", "/src", "/src/cache", "/src/main.rs", "/src/cache/x"]
+        );
+
+        let options = BuildOptions {
+            exclude: vec!["[".to_string()],
+            ..BuildOptions::default()
+        };
+        build_initial_rootfs_with_options(&rootfs, &image, &options).unwrap_err();
+    }
+
+    #[test]
+    fn test_symlinks() {
+        let dir = tempdir().unwrap();
+        let oci_dir = dir.path().join("oci");
+        let image = Image::new(&oci_dir).unwrap();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(&rootfs).unwrap();
+        fs::write(rootfs.join("file"), b"meshuggah").unwrap();
+        std::os::unix::fs::symlink("file", rootfs.join("link")).unwrap();
+
+        let build = |tag: &str, dereference| {
+            let options = BuildOptions {
+                dereference,
+                ..BuildOptions::default()
+            };
+            let desc = build_initial_rootfs_with_options(&rootfs, &image, &options).unwrap();
+            image.add_tag(tag.to_string(), desc).unwrap();
+        };
+        build("preserved", false);
+        build("dereferenced", true);
+
+        let mut pfs = PuzzleFS::open(&image, "preserved").unwrap();
+        let link = WalkPuzzleFS::walk(&mut pfs)
+            .unwrap()
+            .nth(2)
+            .unwrap()
+            .unwrap();
+        assert_eq!(link.path.to_string_lossy(), "/link");
+        assert_eq!(link.inode.inode.mode, format::InodeMode::Lnk);
+        assert_eq!(link.inode.symlink_target().unwrap(), "file");
+
+        // the link and the file it points to are the same file on the host, so they end up as a
+        // hard link
+        let mut pfs = PuzzleFS::open(&image, "dereferenced").unwrap();
+        let link = WalkPuzzleFS::walk(&mut pfs)
+            .unwrap()
+            .nth(2)
+            .unwrap()
+            .unwrap();
+        assert_eq!(link.path.to_string_lossy(), "/link");
+        assert!(matches!(link.inode.mode, InodeMode::File { .. }));
+        assert_eq!(link.inode.inode.nlink, 2);
+        let mut content = String::new();
+        link.open().unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!(content, "meshuggah");
+    }
+
+    #[test]
+    fn test_dereference_cycle() {
+        let dir = tempdir().unwrap();
+        let image = Image::new(&dir.path().join("oci")).unwrap();
+        let rootfs = dir.path().join("rootfs");
+        fs::create_dir_all(rootfs.join("a")).unwrap();
+        std::os::unix::fs::symlink("..", rootfs.join("a/up")).unwrap();
+
+        // without following it, it's just a link
+        build_initial_rootfs(&rootfs, &image).unwrap();
+        let options = BuildOptions {
+            dereference: true,
+            ..BuildOptions::default()
+        };
+        let err = build_initial_rootfs_with_options(&rootfs, &image, &options).unwrap_err();
+        assert!(err.to_string().contains("loop"), "{}", err);
+    }
 }